@@ -0,0 +1,20 @@
+#![no_main]
+
+// screen-stream is a binary crate, so the wire modules are pulled in by path
+#[path = "../../src/packet.rs"]
+#[allow(dead_code)]
+mod packet;
+
+#[path = "../../src/comm.rs"]
+#[allow(dead_code)]
+mod comm;
+
+use comm::Message;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok((session, message)) = Message::from_bytes(data) {
+        // Anything we accept must survive a round trip
+        assert_eq!(Message::from_bytes(&message.to_bytes(session)), Ok((session, message)));
+    }
+});
@@ -0,0 +1,18 @@
+#![no_main]
+
+// screen-stream is a binary crate, so the wire modules are pulled in by path
+#[path = "../../src/packet.rs"]
+#[allow(dead_code)]
+mod packet;
+
+use libfuzzer_sys::fuzz_target;
+use packet::Packet;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(packet) = Packet::from_bytes(data) {
+        // Anything we accept must survive a round trip
+        let decoded = Packet::from_bytes(&packet.to_bytes()).expect("re-encoded packet must decode");
+        assert!(decoded == packet);
+        assert_eq!(decoded.data, packet.data);
+    }
+});
@@ -0,0 +1,36 @@
+#![no_main]
+
+// screen-stream is a binary crate, so the wire modules are pulled in by path
+#[path = "../../src/rtp.rs"]
+#[allow(dead_code)]
+mod rtp;
+
+use libfuzzer_sys::fuzz_target;
+use rtp::{Depayloader, Jpeg, Payloader};
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(jpeg) = Jpeg::parse(data) {
+        // Any JPEG we send must come out of a player's depayloader as the same picture
+        let mut depayloader = Depayloader::new();
+        let mut rebuilt = None;
+        for packet in Payloader::new(0x1234_5678, 0xfff0, 200).payload(&jpeg, 90_000) {
+            rebuilt = depayloader.push(&packet).expect("Payloaded packet rejected");
+        }
+
+        let rebuilt = rebuilt.expect("Frame not rebuilt");
+        let again = Jpeg::parse(&rebuilt).expect("Rebuilt JPEG rejected");
+        assert_eq!((again.kind, again.width, again.height), (jpeg.kind, jpeg.width, jpeg.height));
+        assert_eq!(again.tables, jpeg.tables);
+        assert_eq!(again.scan, jpeg.scan);
+        return;
+    }
+
+    // Otherwise packets, each with a length byte in front, which must never panic the depayloader
+    let mut depayloader = Depayloader::new();
+    let mut rest = data;
+    while let Some((&length, tail)) = rest.split_first() {
+        let (packet, tail) = tail.split_at((length as usize).min(tail.len()));
+        let _ = depayloader.push(packet);
+        rest = tail;
+    }
+});
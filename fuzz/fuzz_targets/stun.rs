@@ -0,0 +1,28 @@
+#![no_main]
+
+// screen-stream is a binary crate, so the wire modules are pulled in by path
+#[path = "../../src/stun.rs"]
+#[allow(dead_code)]
+mod stun;
+
+use libfuzzer_sys::fuzz_target;
+use stun::{Message, Writer, BINDING_REQUEST, USERNAME};
+
+const PASSWORD: &[u8] = b"0123456789abcdef01234567";
+
+fuzz_target!(|data: &[u8]| {
+    // Anyone can send to a WebRTC port: lookups on whatever parses must never panic
+    if let Ok(message) = Message::parse(data) {
+        let _ = message.attribute(USERNAME);
+        let _ = message.verify(PASSWORD);
+        let _ = message.mapped_address();
+    }
+
+    // What we write must parse back, with the integrity checking out
+    let transaction = [7u8; stun::TRANSACTION_SIZE];
+    let username = &data[..data.len().min(512)];
+    let written = Writer::new(BINDING_REQUEST, &transaction).attribute(USERNAME, username).finish(Some(PASSWORD));
+    let message = Message::parse(&written).expect("Written message rejected");
+    assert_eq!(message.attribute(USERNAME), Some(username));
+    assert!(message.verify(PASSWORD));
+});
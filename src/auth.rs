@@ -0,0 +1,292 @@
+use std::time::{Duration, Instant};
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::comm::{Credential, SessionId, COOKIE_SIZE, PROOF_SIZE, SESSION_SECRET_SIZE};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Size of the nonce a server challenges joining clients with
+pub const NONCE_SIZE: usize = 16;
+
+/// Picks a fresh challenge, never reused so an answer can't be replayed
+pub fn nonce() -> Result<[u8; NONCE_SIZE], getrandom::Error> {
+    let mut nonce = [0u8; NONCE_SIZE];
+    getrandom::getrandom(&mut nonce)?;
+    Ok(nonce)
+}
+
+/// Picks the secret of a new session, the server hands it to the client in the accept
+pub fn session_secret() -> Result<[u8; SESSION_SECRET_SIZE], getrandom::Error> {
+    let mut secret = [0u8; SESSION_SECRET_SIZE];
+    getrandom::getrandom(&mut secret)?;
+    Ok(secret)
+}
+
+/// Put in front of the cookie a proof is the HMAC of, so it never equals the answer to a challenge
+const PROOF_LABEL: &[u8] = b"session-proof";
+
+/// Proof a client rejoining from another address owns its session: HMAC of the cookie its new address got,
+/// keyed with the session secret. It is bound to that address and expires with the cookie
+pub fn prove(secret: &[u8; SESSION_SECRET_SIZE], cookie: &[u8; COOKIE_SIZE]) -> [u8; PROOF_SIZE] {
+    respond(secret, &[PROOF_LABEL, cookie].concat())
+}
+
+pub fn verify_proof(
+    secret: &[u8; SESSION_SECRET_SIZE],
+    cookie: &[u8; COOKIE_SIZE],
+    proof: &[u8; PROOF_SIZE],
+) -> bool {
+    verify(secret, &[PROOF_LABEL, cookie].concat(), proof)
+}
+
+fn mac(secret: &[u8]) -> HmacSha256 {
+    HmacSha256::new_from_slice(secret).expect("HMAC accepts keys of any length")
+}
+
+/// HMAC of the challenge keyed with the secret, proves the secret is known without sending it
+fn respond(secret: &[u8], challenge: &[u8]) -> [u8; 32] {
+    let mut mac = mac(secret);
+    mac.update(challenge);
+    mac.finalize().into_bytes().into()
+}
+
+/// Constant time, so the MAC can't be guessed byte by byte from response times
+fn verify(secret: &[u8], challenge: &[u8], expected: &[u8; 32]) -> bool {
+    let mut mac = mac(secret);
+    mac.update(challenge);
+    mac.verify_slice(expected).is_ok()
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+pub fn from_hex<const N: usize>(value: &str) -> Option<[u8; N]> {
+    // Checked up front, `from_str_radix` would take a sign
+    if value.len() != N * 2 || !value.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    let mut bytes = [0u8; N];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&value[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(bytes)
+}
+
+/// Single use invitation to join the stream, until it expires
+/// Handed out as a `<id>-<secret>` token in hex
+/// Once used, it belongs to the session that used it, which can use it again to rejoin
+pub struct Invite {
+    id: [u8; 8],
+    secret: [u8; 16],
+    expires: Instant,
+    used_by: Option<SessionId>,
+}
+
+impl Invite {
+    pub fn generate(ttl: Duration) -> Result<Self, getrandom::Error> {
+        let mut id = [0u8; 8];
+        let mut secret = [0u8; 16];
+        getrandom::getrandom(&mut id)?;
+        getrandom::getrandom(&mut secret)?;
+
+        Ok(Self { id, secret, expires: Instant::now() + ttl, used_by: None })
+    }
+
+    /// What to give the viewer, for `connect --token`
+    pub fn token(&self) -> String {
+        format!("{}-{}", to_hex(&self.id), to_hex(&self.secret))
+    }
+
+    /// Whether `session` can join with this invite
+    fn admits(&self, session: SessionId) -> bool {
+        match self.used_by {
+            Some(used_by) => used_by == session,
+            None => Instant::now() < self.expires,
+        }
+    }
+}
+
+/// What a viewer knows to join a stream, from `connect --password` or `--token`
+pub enum Secret {
+    Password(Vec<u8>),
+    Invite { id: [u8; 8], secret: [u8; 16] },
+}
+
+impl Secret {
+    /// Parses an invite token printed by `start --invite`
+    pub fn from_token(token: &str) -> Result<Self, String> {
+        let invalid = || String::from("Invalid invite token, expected the <id>-<secret> printed by the server");
+
+        let (id, secret) = token.trim().split_once('-').ok_or_else(invalid)?;
+        Ok(Secret::Invite {
+            id: from_hex(id).ok_or_else(invalid)?,
+            secret: from_hex(secret).ok_or_else(invalid)?,
+        })
+    }
+
+    /// Answer to a challenge
+    pub fn respond(&self, nonce: &[u8; NONCE_SIZE]) -> Credential {
+        match self {
+            Secret::Password(password) => Credential::Password { mac: respond(password, nonce) },
+            Secret::Invite { id, secret } => Credential::Invite { id: *id, mac: respond(secret, nonce) },
+        }
+    }
+}
+
+/// What the server accepts from joining clients: the stream password and the invites not used yet
+pub struct Credentials {
+    password: Option<Vec<u8>>,
+    invites: Vec<Invite>,
+    required: bool, // Stays set once every invite is used, the stream doesn't open up
+}
+
+impl Credentials {
+    pub fn new(password: Option<String>, invites: Vec<Invite>) -> Self {
+        let required = password.is_some() || !invites.is_empty();
+        Self { password: password.map(String::into_bytes), invites, required }
+    }
+
+    /// Whether clients must authenticate before they are streamed to
+    pub fn required(&self) -> bool {
+        self.required
+    }
+
+    /// Checks the answer to a challenge from a client that claims to be `session`
+    /// For an invite, its id is returned: it must be given to `bind` once the client joined
+    pub fn verify(
+        &self,
+        nonce: &[u8; NONCE_SIZE],
+        credential: &Credential,
+        session: SessionId,
+    ) -> Result<Option<[u8; 8]>, &'static str> {
+        match credential {
+            Credential::Password { mac } => match &self.password {
+                Some(password) if verify(password, nonce, mac) => Ok(None),
+                Some(_) => Err("Wrong password"),
+                None => Err("This stream only accepts invite tokens"),
+            },
+            Credential::Invite { id, mac } => self
+                .invites
+                .iter()
+                .find(|invite| invite.id == *id && invite.admits(session) && verify(&invite.secret, nonce, mac))
+                .map(|invite| Some(invite.id))
+                .ok_or("Invite token is unknown, expired or already used"),
+        }
+    }
+
+    /// Uses up an invite, only `session` can join with it from now on
+    pub fn bind(&mut self, invite: [u8; 8], session: SessionId) {
+        if let Some(invite) = self.invites.iter_mut().find(|candidate| candidate.id == invite) {
+            invite.used_by = Some(session);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NONCE: [u8; NONCE_SIZE] = [7; NONCE_SIZE];
+
+    fn invited(ttl: Duration) -> (Credentials, Secret) {
+        let invite = Invite::generate(ttl).unwrap();
+        let secret = Secret::from_token(&invite.token()).unwrap();
+        (Credentials::new(None, vec![invite]), secret)
+    }
+
+    #[test]
+    fn invite_is_single_use() {
+        let (mut credentials, secret) = invited(Duration::from_secs(60));
+        let answer = secret.respond(&NONCE);
+        let Secret::Invite { id, .. } = secret else { unreachable!() };
+
+        assert_eq!(credentials.verify(&NONCE, &answer, 1), Ok(Some(id)));
+        credentials.bind(id, 1);
+        assert!(credentials.verify(&NONCE, &answer, 2).is_err());
+
+        // The session that used it can rejoin with it, even once it expired
+        credentials.invites[0].expires = Instant::now();
+        assert_eq!(credentials.verify(&NONCE, &answer, 1), Ok(Some(id)));
+        assert!(credentials.required());
+    }
+
+    #[test]
+    fn expired_invite_is_refused() {
+        let (credentials, secret) = invited(Duration::ZERO);
+        assert!(credentials.verify(&NONCE, &secret.respond(&NONCE), 1).is_err());
+    }
+
+    #[test]
+    fn invite_answer_is_bound_to_the_nonce_and_secret() {
+        let (credentials, secret) = invited(Duration::from_secs(60));
+        assert!(credentials.verify(&[8; NONCE_SIZE], &secret.respond(&NONCE), 1).is_err());
+
+        let Secret::Invite { id, mut secret } = secret else { unreachable!() };
+        secret[0] ^= 1;
+        assert!(credentials.verify(&NONCE, &Secret::Invite { id, secret }.respond(&NONCE), 1).is_err());
+    }
+
+    #[test]
+    fn wrong_password_is_refused() {
+        let credentials = Credentials::new(Some(String::from("hunter2")), Vec::new());
+        assert!(credentials.required());
+
+        let right = Secret::Password(b"hunter2".to_vec());
+        assert_eq!(credentials.verify(&NONCE, &right.respond(&NONCE), 1), Ok(None));
+        assert_eq!(credentials.verify(&[8; NONCE_SIZE], &right.respond(&NONCE), 1), Err("Wrong password"));
+        let wrong = Secret::Password(b"hunter3".to_vec());
+        assert_eq!(credentials.verify(&NONCE, &wrong.respond(&NONCE), 1), Err("Wrong password"));
+
+        let (invites_only, _) = invited(Duration::from_secs(60));
+        assert_eq!(invites_only.verify(&NONCE, &right.respond(&NONCE), 1), Err("This stream only accepts invite tokens"));
+    }
+
+    #[test]
+    fn proof_is_bound_to_the_cookie_and_secret() {
+        let (secret, cookie) = ([1; SESSION_SECRET_SIZE], [2; COOKIE_SIZE]);
+        let proof = prove(&secret, &cookie);
+        assert!(verify_proof(&secret, &cookie, &proof));
+
+        assert!(!verify_proof(&secret, &[3; COOKIE_SIZE], &proof));
+        assert!(!verify_proof(&[4; SESSION_SECRET_SIZE], &cookie, &proof));
+
+        // Not the answer to a challenge of the same bytes, with the secret as the password
+        assert_ne!(proof, respond(&secret, &cookie));
+        assert!(!verify(&secret, &cookie, &proof));
+    }
+
+    #[test]
+    fn token_round_trip() {
+        let invite = Invite::generate(Duration::from_secs(60)).unwrap();
+        let token = invite.token();
+        assert_eq!(token.len(), 16 + 1 + 32);
+
+        let Ok(Secret::Invite { id, secret }) = Secret::from_token(&format!(" {}\n", token)) else {
+            panic!("token not parsed");
+        };
+        assert_eq!((id, secret), (invite.id, invite.secret));
+
+        for bad in ["", "0011223344556677", "0011223344556677-", "001122334455667-00112233445566778899aabbccddeeff"] {
+            assert!(Secret::from_token(bad).is_err(), "{:?}", bad);
+        }
+        assert!(Secret::from_token("001122334455667g-00112233445566778899aabbccddeeff").is_err());
+    }
+
+    #[test]
+    fn hex_round_trip_and_invalid_input() {
+        assert_eq!(to_hex(&[0x00, 0xab, 0xff]), "00abff");
+        assert_eq!(from_hex::<3>("00abff"), Some([0x00, 0xab, 0xff]));
+        assert_eq!(from_hex::<3>("00ABFF"), Some([0x00, 0xab, 0xff]));
+
+        assert_eq!(from_hex::<2>("abc"), None);
+        assert_eq!(from_hex::<2>("abcde"), None);
+        assert_eq!(from_hex::<2>("zz00"), None);
+        assert_eq!(from_hex::<2>("+1ff"), None);
+        assert_eq!(from_hex::<2>("éé"), None);
+        assert_eq!(from_hex::<0>(""), Some([]));
+    }
+}
@@ -1,32 +1,124 @@
 #![allow(clippy::unnecessary_wraps)]
 
 use std::{
-    io,
-    net::UdpSocket,
+    borrow::Cow,
+    collections::HashMap,
+    fmt, io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, ToSocketAddrs, UdpSocket},
     process::exit,
+    time::{Duration, Instant},
 };
 
 use crate::{
+    auth::{self, Secret},
     frame_buffer::{FrameBuffer, GetFrameResult},
-    comm::Actions,
-    packet::Packet,
+    comm::{
+        Capabilities, Codec, Message, ReceiverReport, Rendition, SessionId, COOKIE_SIZE, NO_SESSION, SESSION_SECRET_SIZE,
+    },
+    commands::ConnectCmd,
+    crypto::{Channel, PreSharedKey, Session},
+    multicast,
+    packet::{Header, Kind, Packet, ProtocolError, StreamId},
+    transport::Link,
 };
 use ggez::{
     event,
     glam::*,
     graphics::{self, DrawParam, Drawable},
+    input::keyboard::{KeyCode, KeyInput},
     Context, GameResult,
 };
  
 
+/// Counters of what the client received
+#[derive(Default)]
+struct Stats {
+    packets: u64,   // Packets accepted into the frame buffer
+    malformed: u64, // Datagrams that failed to decode
+    corrupted: u64, // Datagrams whose checksum did not match their contents
+    undecryptable: u64, // Datagrams that failed to decrypt
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "packets: {}, malformed: {}, corrupted: {}, undecryptable: {}",
+            self.packets, self.malformed, self.corrupted, self.undecryptable
+        )
+    }
+}
+
+/// Multicast group the server sends the watched rendition to
+struct Group {
+    address: SocketAddrV4,
+    rendition: u8,            // Lost packets are asked for by rendition, the server only resends those of the current one
+    socket: UdpSocket,
+    session: Option<Session>, // The group's, the same for every viewer in it, when the stream is encrypted
+}
+
+/// Where the viewer is in its connection to the server
+#[derive(Clone, Copy)]
+enum Connection {
+    Joining,   // First hello sent, waiting for the stream
+    Streaming, // Server is answering
+    Reconnecting { attempt: u32, next_attempt: Instant }, // Server went silent, joining again
+}
+
 struct MainState {
     texture: Option<graphics::Image>,
-    frames: FrameBuffer,
-    socket: UdpSocket
+    frames: HashMap<StreamId, FrameBuffer>, // Frames of each stream packets arrived for
+    stream_name: String,       // Stream to watch, empty for the server's first one
+    stream: Option<StreamId>,  // Id of that stream, from the accept or else the first packet
+    link: Link,
+    key: Option<PreSharedKey>,
+    session: Option<Session>, // Encryption state, when the stream is encrypted
+    session_id: SessionId,    // Assigned by the server when it accepts us, kept to rejoin
+    session_secret: [u8; SESSION_SECRET_SIZE], // Comes with the session id, proves we own it when rejoining
+    secret: Option<Secret>,   // Password or invite token to answer the server's challenge with
+    capabilities: Capabilities, // What this viewer can handle, sent in every hello
+    subscription: Option<u8>,   // Rendition the viewer wants, None to let the server pick
+    renditions: Vec<Rendition>, // What the server offers this viewer, largest first
+    multicast: Option<Ipv4Addr>, // Interface to join the server's multicast group on, when the viewer can
+    group: Option<Group>,        // Multicast group frames come from, once the server named one
+    connection: Connection,
+    last_received: Instant, // Last datagram from the server, to notice it is gone
+    stats: Stats,
+    last_stats: Instant, // Last time stats were printed
+    last_report: Instant, // Last time a receiver report was sent
+    last_ping: Instant,  // Last time a ping was sent
+    started: Instant,    // Clock used for ping timestamps
+    rtt: Option<Duration>, // Smoothed round trip time to the server, from pongs
+    decode_time: Duration,     // Time spent decoding the last frame
+    display_latency: Duration, // Time from first packet of the last frame to it being displayed
 }
 
 impl MainState {
-    fn new(socket:UdpSocket, _ctx: &mut Context) -> GameResult<MainState> {
+    const STATS_INTERVAL: Duration = Duration::from_secs(5);
+    const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+    /// Pings keep the client alive on the server and measure the round trip time
+    const PING_INTERVAL: Duration = Duration::from_secs(1);
+
+    /// Upper bound of datagrams read per update, so a flood can't freeze the window
+    const MAX_DATAGRAMS_PER_UPDATE: usize = 1024;
+
+    /// Silence after which the server is considered gone, it pongs every ping so it is never idle
+    const SERVER_TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// Wait before the first reconnection attempt, doubled after every failed one
+    const MIN_BACKOFF: Duration = Duration::from_millis(500);
+    const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+    fn new(
+        link: Link,
+        key: Option<PreSharedKey>,
+        secret: Option<Secret>,
+        capabilities: Capabilities,
+        subscription: Option<u8>,
+        stream_name: String,
+        _ctx: &mut Context,
+    ) -> GameResult<MainState> {
         _ctx.gfx
             .set_resizable(true)
             .expect("Error setting window to resizable");
@@ -34,118 +126,510 @@ impl MainState {
 
         
 
+        let session = key.as_ref().map(|key| Session::client(key).expect("Error generating session salt"));
+
         Ok(MainState { 
             texture: None,
-            frames: FrameBuffer::new(),
-            socket
+            frames: HashMap::new(),
+            stream_name,
+            stream: None,
+            link,
+            key,
+            session,
+            session_id: NO_SESSION,
+            session_secret: [0; SESSION_SECRET_SIZE],
+            secret,
+            capabilities,
+            subscription,
+            renditions: Vec::new(),
+            multicast: None,
+            group: None,
+            connection: Connection::Joining,
+            last_received: Instant::now(),
+            stats: Stats::default(),
+            last_stats: Instant::now(),
+            last_report: Instant::now(),
+            last_ping: Instant::now(),
+            started: Instant::now(),
+            rtt: None,
+            decode_time: Duration::ZERO,
+            display_latency: Duration::ZERO,
         })
     }
-}
 
-impl event::EventHandler<ggez::GameError> for MainState {
-    fn quit_event(&mut self, _ctx: &mut Context) -> Result<bool, ggez::GameError> {
-        // Send disconnection notification
-        self.socket
-            .send(&[Actions::Disconnection as u8])
-            .expect("Error sending disconnection notification to server");
+    /// Sends a control message to the server, encrypted if the stream is
+    fn send(&mut self, message: &Message) -> io::Result<usize> {
+        let bytes = message.to_bytes(self.session_id);
+        match &mut self.session {
+            Some(session) => self.link.send(&session.seal(Channel::Control, &bytes)),
+            None => self.link.send(&bytes),
+        }
+    }
 
-        return Ok(false);
+    /// Joins the stream, the first hello is answered with a cookie to send back in the next one
+    /// With the cookie goes a proof that we own the session we had, in case we now come from another address
+    fn hello(&mut self, cookie: Option<[u8; COOKIE_SIZE]>) -> io::Result<usize> {
+        let stream = self.stream_name.clone();
+        let proof = cookie
+            .filter(|_| self.session_id != NO_SESSION)
+            .map(|cookie| auth::prove(&self.session_secret, &cookie));
+        self.send(&Message::Hello { capabilities: self.capabilities.clone(), cookie, stream, proof })
     }
 
-    fn update(&mut self, ctx: &mut Context) -> GameResult {
-        // Check if stream is still open
-        if self.socket.send(&[Actions::Ping as u8]).is_err() {
-            println!("Stream is closed");
-            exit(0);
+    /// Frames of the stream being watched
+    fn watched(&self) -> Option<&FrameBuffer> {
+        self.frames.get(&self.stream?)
+    }
+
+    /// Asks the server for a rendition, None to let it pick from the bandwidth
+    fn subscribe(&mut self, rendition: Option<u8>) {
+        self.subscription = rendition;
+        let subscribe = Message::Subscribe { rendition: rendition.unwrap_or(Rendition::AUTOMATIC) };
+        if let Err(e) = self.send(&subscribe) {
+            eprintln!("Error sending subscription: {}", e);
         }
+    }
 
-        // * Frame will be sent in packets of CHUNK_SIZE
-        let mut buffer = [0u8; 65000 * 1];
+    /// The server stopped answering: show it and start joining again
+    fn lost(&mut self, reason: &str) {
+        if let Connection::Reconnecting { .. } = self.connection {
+            return;
+        }
 
-        match self.socket.recv(&mut buffer) {
-            Ok(bytes_read) => {
-                println!("Bytes read: {}", bytes_read);
+        println!("Lost the server: {}, reconnecting", reason);
+        self.connection = Connection::Reconnecting { attempt: 0, next_attempt: Instant::now() };
+    }
 
-                // No bytes read means server closed the connection
-                if bytes_read == 0 {
-                    println!("Server closed the connection");
-                    exit(0);
-                }
-                // If not even minimum bytes are read
-                else if bytes_read < Packet::META_SIZE {
-                    eprintln!("Invalid packet received, Expected at least: {} bytes, recieved: {}", Packet::META_SIZE, bytes_read);
-                    return Ok(());
+    /// Joins again with a new session, the server may have restarted and forgotten the old one
+    /// Reusing it would also reuse nonces if it did
+    fn rejoin(&mut self, attempt: u32) {
+        if let Some(key) = &self.key {
+            self.session = Some(Session::client(key).expect("Error generating session salt"));
+        }
+
+        // Frame ids start over on a restarted server, and stream ids may have changed
+        self.frames.clear();
+        self.stream = None;
+        self.group = None;
+
+        let backoff = Self::MIN_BACKOFF.saturating_mul(1 << attempt.min(16)).min(Self::MAX_BACKOFF);
+        println!("Reconnecting (attempt {}), next attempt in {:?}", attempt + 1, backoff);
+
+        // Over TCP the old connection died with the server, a new one is needed before saying hello
+        match self.link.reconnect() {
+            Ok(()) => {
+                if let Err(e) = self.hello(None) {
+                    eprintln!("Error sending hello to server: {}", e);
                 }
+            }
+            Err(e) => eprintln!("Error connecting to server: {}", e),
+        }
 
-                if bytes_read <= Packet::CHUNK_SIZE {
-                    let packet = Packet::from_bytes(buffer[..bytes_read].to_vec());
-                    // println!(
-                    //     "Single packet received frame: {} index: {}",
-                    //     packet.frame_id, packet.index
-                    // );
+        self.connection = Connection::Reconnecting { attempt: attempt + 1, next_attempt: Instant::now() + backoff };
+    }
 
-                    self.frames.add_packet(packet);
+    /// Decrypts a datagram when the stream is encrypted
+    /// Nothing in clear is acted on then, anyone could have sent it. The server rejects a wrong or missing
+    /// key in clear, so such a reject is shown, but joining goes on until the viewer is closed
+    fn open<'a>(&mut self, bytes: &'a [u8]) -> Result<Cow<'a, [u8]>, ProtocolError> {
+        match (&mut self.session, Header::read(bytes)?) {
+            (Some(session), Kind::Sealed) => session.open(bytes).map(Cow::Owned),
+            (None, Kind::Sealed) => Err(ProtocolError::UnexpectedEncryption),
+            (Some(_), Kind::Control) => {
+                if let Ok((_, Message::Reject { reason })) = Message::from_bytes(bytes) {
+                    eprintln!("Unauthenticated reject from the server, ignored: {}", reason);
                 }
+                Err(ProtocolError::NotEncrypted)
+            }
+            (Some(_), _) => Err(ProtocolError::NotEncrypted),
+            (None, _) => Ok(Cow::Borrowed(bytes)),
+        }
+    }
+
+    /// Routes a datagram from the server to the frame buffer or the control handler
+    fn handle_datagram(&mut self, bytes: &[u8]) {
+        let result = self.open(bytes).and_then(|datagram| match Header::read(&datagram)? {
+            // Checksum is verified while decoding, so corrupted data never reaches the frame buffer
+            Kind::Frame => Packet::from_bytes(&datagram).map(|packet| self.add_packet(packet)),
+            Kind::Control => Message::from_bytes(&datagram).map(|(id, message)| self.handle_message(id, message)),
+            // Sealed inside sealed
+            Kind::Sealed => Err(ProtocolError::UnknownKind(Kind::Sealed as u8)),
+        });
+
+        self.count(result);
+    }
+
+    /// Takes a datagram from the multicast group, where the server only sends frame packets
+    /// They are sealed with the group's session when the stream is encrypted
+    fn handle_group_datagram(&mut self, bytes: &[u8]) {
+        let session = match &mut self.group {
+            Some(group) => &mut group.session,
+            None => return,
+        };
+
+        let datagram = match (session, Header::read(bytes)) {
+            (_, Err(e)) => Err(e),
+            (Some(session), Ok(Kind::Sealed)) => session.open(bytes).map(Cow::Owned),
+            (None, Ok(Kind::Sealed)) => Err(ProtocolError::UnexpectedEncryption),
+            (Some(_), Ok(_)) => Err(ProtocolError::NotEncrypted),
+            (None, Ok(_)) => Ok(Cow::Borrowed(bytes)),
+        };
+
+        let result = datagram.and_then(|datagram| Packet::from_bytes(&datagram)).map(|packet| self.add_packet(packet));
+        self.count(result);
+    }
 
-                // // * If multiple packets are received
-                // // Split the buffer into packets
-                // let mut index = 0;
-                // while index < bytes_read {
-                //     // At least meta size bytes are required
-                //     if index + Packet::META_SIZE > bytes_read {
-                //         break;
-                //     }
+    fn add_packet(&mut self, packet: Packet) {
+        // The accept may have been lost, packets tell which stream the server sends
+        let stream = *self.stream.get_or_insert(packet.stream);
+        if packet.stream != stream {
+            return; // Not the stream watched, e.g. still in flight from before a rejoin
+        }
+        self.stats.packets += 1;
+        self.frames.entry(stream).or_default().add_packet(packet);
+        self.connection = Connection::Streaming;
+    }
 
-                //     let end = std::cmp::min(index + Packet::CHUNK_SIZE, bytes_read);
-                //     let packet = Packet::from_bytes(buffer[index..end].to_vec());
+    /// Notes that the server is there, or counts why a datagram from it was dropped
+    fn count(&mut self, result: Result<(), ProtocolError>) {
+        match result {
+            Ok(()) => self.last_received = Instant::now(),
+            Err(e @ ProtocolError::BadChecksum { .. }) => {
+                self.stats.corrupted += 1;
+                eprintln!("Corrupted packet ({} so far): {}", self.stats.corrupted, e);
+            }
+            Err(e @ (ProtocolError::Undecryptable | ProtocolError::NotEncrypted | ProtocolError::UnexpectedEncryption)) => {
+                self.stats.undecryptable += 1;
+                eprintln!("Undecryptable datagram ({} so far): {}", self.stats.undecryptable, e);
+            }
+            Err(e) => {
+                self.stats.malformed += 1;
+                eprintln!("Malformed datagram ({} so far): {}", self.stats.malformed, e);
+            }
+        }
+    }
 
-                //     self.frames.add_packet(packet);
+    fn print_stats(&self) {
+        let rtt = self.rtt.map_or(String::from("n/a"), |rtt| format!("{:.1?}", rtt));
+        match self.watched() {
+            Some(frames) => println!("Stats: {}, {}, rtt: {}", self.stats, frames.stats, rtt),
+            None => println!("Stats: {}, no frames yet, rtt: {}", self.stats, rtt),
+        }
+    }
 
-                //     index = end;
+    /// What the client received since it joined, for the server's congestion control
+    fn receiver_report(&self) -> ReceiverReport {
+        let frames = self.watched();
+        ReceiverReport {
+            received: frames.map_or(0, |frames| frames.stats.received),
+            lost: frames.map_or(0, |frames| frames.stats.lost),
+            jitter_us: frames.map_or(0, FrameBuffer::jitter_us),
+            last_frame: frames.and_then(FrameBuffer::last_frame).unwrap_or(0),
+            decode_us: self.decode_time.as_micros() as u32,
+            display_latency_us: self.display_latency.as_micros() as u32,
+        }
+    }
 
-                //     // If last packet is less than 4096 bytes
-                //     if end == bytes_read {
-                //         break;
-                //     }
-                // }
+    /// Receives frames from the group the server named, leaving the one it sent to before
+    /// A group that can't be joined is given up on: the viewer says hello again without multicast, to be sent to alone
+    fn join(&mut self, rendition: u8, address: SocketAddrV4, salt: Option<[u8; Session::SALT_SIZE]>) {
+        let interface = match self.multicast {
+            Some(interface) => interface,
+            None => {
+                println!("Unexpected multicast group from server: {}", address);
+                return;
             }
+        };
+
+        if self.group.as_ref().is_some_and(|group| group.address == address) {
+            return;
+        }
+
+        // Packets of a group are numbered on their own, and so are those the server sends directly
+        if let Some(frames) = self.stream.and_then(|stream| self.frames.get_mut(&stream)) {
+            frames.resync();
+        }
+
+        let socket = match multicast::join(address, interface) {
+            Ok(socket) => socket,
             Err(e) => {
-                match e.kind() {
-                    io::ErrorKind::WouldBlock => {
-                        // println!("No data available");
-                    }
-                    io::ErrorKind::ConnectionReset => {
-                        println!("Connection reset by server");
-                        exit(0);
-                    }
-                    _ => {
-                        eprintln!("Error receiving data: {:?}", e);
+                eprintln!("Error joining multicast group {}: {}, receiving frames from the server instead", address, e);
+                self.multicast = None;
+                self.group = None;
+                self.capabilities.multicast = false;
+                if let Err(e) = self.hello(None) {
+                    eprintln!("Error sending hello to server: {}", e);
+                }
+                return;
+            }
+        };
+
+        println!("Receiving rendition {} from multicast group {}", rendition, address);
+        let session = self.key.as_ref().zip(salt).map(|(key, salt)| Session::group(key, salt));
+        self.group = Some(Group { address, rendition, socket, session });
+    }
+
+    /// Asks the server for the packets the group lost since the last time, once each
+    fn nack(&mut self) {
+        let (rendition, frames) = match (&self.group, self.stream.and_then(|stream| self.frames.get_mut(&stream))) {
+            (Some(group), Some(frames)) => (group.rendition, frames),
+            _ => return,
+        };
+
+        // The newest ones are the most likely to still make it in time
+        let mut seqs = frames.take_missing();
+        if seqs.is_empty() {
+            return;
+        }
+        let seqs = seqs.split_off(seqs.len().saturating_sub(Message::MAX_NACKS));
+
+        if let Err(e) = self.send(&Message::Nack { rendition, seqs }) {
+            eprintln!("Error asking for lost packets: {}", e);
+        }
+    }
+
+    /// `id` is the session the server sent the message for
+    fn handle_message(&mut self, id: SessionId, message: Message) {
+        match message {
+            Message::Accept { params, secret } => {
+                if id != self.session_id {
+                    println!("Joined as session {:016x}", id);
+                    self.session_id = id;
+                }
+                self.session_secret = secret;
+                println!("Stream accepted: {:?}", params);
+                // Frames of another stream, watched before, won't be shown
+                self.stream = Some(params.stream);
+                self.frames.retain(|&stream, _| stream == params.stream);
+                self.connection = Connection::Streaming;
+            }
+            Message::Pong { timestamp } => {
+                let sent = Duration::from_micros(timestamp);
+                let sample = self.started.elapsed().saturating_sub(sent);
+                // Smoothed like TCP's SRTT (RFC 6298)
+                self.rtt = Some(match self.rtt {
+                    Some(rtt) => rtt.mul_f64(0.875) + sample.mul_f64(0.125),
+                    None => sample,
+                });
+            }
+            Message::Cookie { cookie } => {
+                // Sent from a server session that ends with it, the next one starts with the answer
+                if let Some(session) = &mut self.session {
+                    session.forget_server();
+                }
+                if let Err(e) = self.hello(Some(cookie)) {
+                    eprintln!("Error sending hello to server: {}", e);
+                }
+            }
+            Message::Challenge { nonce } => {
+                let credential = match &self.secret {
+                    Some(secret) => secret.respond(&nonce),
+                    None => {
+                        eprintln!("Server requires authentication, connect with --password or --token");
                         exit(1);
                     }
+                };
+
+                if let Err(e) = self.send(&Message::Authenticate(credential)) {
+                    eprintln!("Error answering the server's challenge: {}", e);
                 }
             }
+            Message::Reject { reason } => {
+                eprintln!("Server rejected the connection: {}", reason);
+                exit(1);
+            }
+            Message::Renditions { current, automatic, renditions } => {
+                if renditions != self.renditions {
+                    println!("Renditions (keys 1-{} to pick one, A to let the server pick):", renditions.len());
+                    for (i, rendition) in renditions.iter().enumerate() {
+                        println!(
+                            "  {}: rendition {}, {}x{} at quality {}",
+                            i + 1, rendition.id, rendition.width, rendition.height, rendition.quality
+                        );
+                    }
+                    self.renditions = renditions;
+                }
+
+                match self.renditions.iter().find(|rendition| rendition.id == current) {
+                    Some(rendition) => println!(
+                        "Receiving rendition {}: {}x{}{}",
+                        rendition.id, rendition.width, rendition.height, if automatic { " (automatic)" } else { "" }
+                    ),
+                    None => println!("Receiving rendition {}", current),
+                }
+
+                // After a rejoin the server doesn't know what this viewer picked
+                let subscribed = match self.subscription {
+                    Some(wanted) => !automatic && wanted == current,
+                    None => automatic,
+                };
+                if !subscribed {
+                    self.subscribe(self.subscription);
+                }
+            }
+            // The server may come back, keep trying until the viewer is closed
+            Message::Goodbye { reason } => {
+                self.lost(&format!("server closed the stream ({})", reason));
+            }
+            Message::Multicast { rendition, group, salt } => self.join(rendition, group, salt),
+            message => {
+                println!("Unexpected message from server: {:?}", message);
+            }
         }
+    }
+}
 
-        // No frames -> return
-        if self.frames.len() == 0 {
-            return Ok(());
+impl event::EventHandler<ggez::GameError> for MainState {
+    fn quit_event(&mut self, _ctx: &mut Context) -> Result<bool, ggez::GameError> {
+        // Send disconnection notification, the server may already be gone
+        if let Err(e) = self.send(&Message::Goodbye { reason: String::from("Viewer closed") }) {
+            eprintln!("Error sending disconnection notification to server: {}", e);
         }
+        self.link.close();
 
-        // println!("Frame buffer count: {}", self.frames.len());
+        self.print_stats();
 
-        // Sort frames by frame_id (low to high)
+        Ok(false)
+    }
 
-        let buffer = match self.frames.get_frame() {
-            GetFrameResult::NoFrame => {
+    /// Number keys pick a rendition from the list the server sent, A lets the server pick
+    fn key_down_event(&mut self, ctx: &mut Context, input: KeyInput, _repeated: bool) -> GameResult {
+        let index = match input.keycode {
+            Some(KeyCode::Escape) => {
+                ctx.request_quit();
                 return Ok(());
             }
+            Some(KeyCode::A) => {
+                self.subscribe(None);
+                return Ok(());
+            }
+            Some(KeyCode::Key1) => 0,
+            Some(KeyCode::Key2) => 1,
+            Some(KeyCode::Key3) => 2,
+            Some(KeyCode::Key4) => 3,
+            Some(KeyCode::Key5) => 4,
+            Some(KeyCode::Key6) => 5,
+            Some(KeyCode::Key7) => 6,
+            Some(KeyCode::Key8) => 7,
+            Some(KeyCode::Key9) => 8,
+            _ => return Ok(()),
+        };
 
-            GetFrameResult::NonSequential(packets) => {
-                println!(
-                    "Not sequential packet: {:?}",
-                    packets.iter().map(|p| p.index).collect::<Vec<u8>>()
-                );
+        if let Some(rendition) = self.renditions.get(index) {
+            self.subscribe(Some(rendition.id));
+        }
+        Ok(())
+    }
+
+    fn update(&mut self, ctx: &mut Context) -> GameResult {
+        match self.connection {
+            Connection::Reconnecting { attempt, next_attempt } => {
+                if Instant::now() >= next_attempt {
+                    self.rejoin(attempt);
+                }
+            }
+            Connection::Joining | Connection::Streaming => {
+                if self.last_received.elapsed() >= Self::SERVER_TIMEOUT {
+                    self.lost(&format!("nothing received for {:?}", Self::SERVER_TIMEOUT));
+                }
+            }
+        }
 
+        // Check if stream is still open
+        if matches!(self.connection, Connection::Streaming) && self.last_ping.elapsed() >= Self::PING_INTERVAL {
+            let ping = Message::Ping { timestamp: self.started.elapsed().as_micros() as u64 };
+            if let Err(e) = self.send(&ping) {
+                self.lost(&format!("error sending ping ({})", e));
+            }
+            self.last_ping = Instant::now();
+        }
+
+        // * Frame will be sent in packets of CHUNK_SIZE
+        // Buffer fits the largest possible datagram so oversized ones are detected instead of truncated
+        let mut buffer = vec![0u8; Packet::MAX_DATAGRAM + 1];
+
+        // * Read every pending datagram, a frame is usually split in several
+        for _ in 0..Self::MAX_DATAGRAMS_PER_UPDATE {
+            match self.link.recv(&mut buffer) {
+                Ok(bytes_read) => {
+                    // Empty datagrams carry nothing
+                    if bytes_read == 0 {
+                        continue;
+                    }
+
+                    self.handle_datagram(&buffer[..bytes_read]);
+                }
+                Err(e) => {
+                    match e.kind() {
+                        io::ErrorKind::WouldBlock => {
+                            // println!("No data available");
+                            break;
+                        }
+                        // Nothing listens on the server's port anymore
+                        io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionRefused => {
+                            self.lost("connection refused by server");
+                            break;
+                        }
+                        // The server closed the TCP connection, or what came on it can't be framed
+                        io::ErrorKind::UnexpectedEof | io::ErrorKind::InvalidData => {
+                            self.lost(&e.to_string());
+                            break;
+                        }
+                        _ => {
+                            eprintln!("Error receiving data: {:?}", e);
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+
+        // * Frames sent to the multicast group, lost packets come back from the server
+        for _ in 0..Self::MAX_DATAGRAMS_PER_UPDATE {
+            let received = match &self.group {
+                Some(group) => group.socket.recv(&mut buffer),
+                None => break,
+            };
+
+            match received {
+                Ok(0) => continue,
+                Ok(bytes_read) => self.handle_group_datagram(&buffer[..bytes_read]),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    eprintln!("Error receiving from the multicast group: {:?}", e);
+                    break;
+                }
+            }
+        }
+        self.nack();
+
+        if matches!(self.connection, Connection::Streaming) && self.last_report.elapsed() >= Self::REPORT_INTERVAL {
+            let report = Message::ReceiverReport(self.receiver_report());
+            if let Err(e) = self.send(&report) {
+                eprintln!("Error sending receiver report: {}", e);
+            }
+            self.last_report = Instant::now();
+        }
+
+        if self.last_stats.elapsed() >= Self::STATS_INTERVAL {
+            self.print_stats();
+            self.last_stats = Instant::now();
+        }
+
+        let frames = match self.stream.and_then(|stream| self.frames.get_mut(&stream)) {
+            Some(frames) => frames,
+            None => return Ok(()),
+        };
+
+        // No frames -> return
+        if frames.is_empty() {
+            return Ok(());
+        }
+
+        // println!("Frame buffer count: {}", frames.len());
+
+        let buffer = match frames.get_frame() {
+            GetFrameResult::NoFrame => {
                 return Ok(());
             }
 
@@ -153,8 +637,13 @@ impl event::EventHandler<ggez::GameError> for MainState {
         };
 
         // * Convert image to texture
+        let decode_start = Instant::now();
         match graphics::Image::from_bytes(&ctx.gfx, &buffer) {
             Ok(texture) => {
+                self.decode_time = decode_start.elapsed();
+                if let Some(arrival) = frames.last_arrival() {
+                    self.display_latency = arrival.elapsed();
+                }
                 self.texture = Some(texture);
             }
             Err(e) => {
@@ -181,42 +670,125 @@ impl event::EventHandler<ggez::GameError> for MainState {
                 DrawParam::new()
                     .dest(dest_point)
                     .transform(ggez::mint::ColumnMatrix4 {
-                        x: Vec4::new(w as f32 / texture.width() as f32, 0.0, 0.0, 0.0).into(),
-                        y: Vec4::new(0.0, h as f32 / texture.height() as f32, 0.0, 0.0).into(),
+                        x: Vec4::new(w / texture.width() as f32, 0.0, 0.0, 0.0).into(),
+                        y: Vec4::new(0.0, h / texture.height() as f32, 0.0, 0.0).into(),
                         z: Vec4::new(0.0, 0.0, 1.0, 0.0).into(),
                         w: Vec4::new(0.0, 0.0, 0.0, 1.0).into(),
                     }),
             );
         }
 
+        // Tell the viewer why the picture is frozen
+        let status = match self.connection {
+            Connection::Streaming => None,
+            Connection::Joining => Some(String::from("Connecting…")),
+            Connection::Reconnecting { attempt, .. } => Some(format!("Reconnecting… (attempt {})", attempt)),
+        };
+
+        if let Some(status) = status {
+            let (w, h) = ctx.gfx.size();
+            let mut text = graphics::Text::new(status);
+            text.set_scale(32.0).set_layout(graphics::TextLayout::center());
+            canvas.draw(&text, DrawParam::new().dest(Vec2::new(w / 2.0, h / 2.0)).color(graphics::Color::WHITE));
+        }
+
         canvas.finish(ctx)?;
 
         Ok(())
     }
 }
 
-pub fn run(address: String) -> GameResult {
-    let cb: ggez::ContextBuilder = ggez::ContextBuilder::new("ss-client", "nova");
-    let (mut ctx, event_loop) = cb.build()?;
+/// Looks up the server address, which may be a hostname
+/// With `--bind`, only addresses of the same family can be reached, otherwise the first one wins
+fn resolve(address: &str, bind: Option<SocketAddr>) -> Result<SocketAddr, String> {
+    let candidates: Vec<SocketAddr> = address
+        .to_socket_addrs()
+        .map_err(|e| format!("Error resolving {}: {}", address, e))?
+        .collect();
+
+    candidates
+        .iter()
+        .find(|candidate| bind.is_none_or(|bind| bind.is_ipv4() == candidate.is_ipv4()))
+        .copied()
+        .ok_or_else(|| match bind {
+            Some(bind) => format!("{} has no address reachable from {}", address, bind),
+            None => format!("{} has no address", address),
+        })
+}
 
+pub fn run(options: ConnectCmd) -> GameResult {
+    let key = match PreSharedKey::load(&options.encryption) {
+        Ok(key) => key,
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(2);
+        }
+    };
 
-    let socket: UdpSocket = UdpSocket::bind(format!("0.0.0.0:{}", 8899))
-        .expect("Error binding to address");
+    let secret = match (options.password, options.token) {
+        (Some(password), _) => Some(Secret::Password(password.into_bytes())),
+        (None, Some(token)) => match Secret::from_token(&token) {
+            Ok(secret) => Some(secret),
+            Err(e) => {
+                eprintln!("{}", e);
+                exit(2);
+            }
+        },
+        (None, None) => None,
+    };
+
+    let address = match resolve(&options.address, options.bind) {
+        Ok(address) => address,
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(2);
+        }
+    };
 
-    socket.set_nonblocking(true).expect("Error setting socket to non-blocking");
+    // Any free port on the server's address family unless told otherwise
+    let bind = options.bind.unwrap_or_else(|| match address {
+        SocketAddr::V4(_) => SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+        SocketAddr::V6(_) => SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0),
+    });
 
-    socket
-        .connect(&address)
-        .expect("Error connecting to address");
+    let cb: ggez::ContextBuilder = ggez::ContextBuilder::new("ss-client", "nova");
+    let (mut ctx, event_loop) = cb.build()?;
 
-    // 1 = Connection notification
-    socket
-        .send(&[Actions::NewConnection as u8])
-        .expect("Error sending connection notification to server");
 
-    println!("Connected to: {}", address);
+    let link = Link::connect(options.transport, address, bind, options.fingerprint)
+        .expect("Error connecting to address");
 
-    let state = MainState::new(socket, &mut ctx)?;
+    // QUIC datagrams have to fit in one packet of the path
+    let mtu = match link.max_datagram_size() {
+        Some(max) => options.mtu.min(u16::try_from(max).unwrap_or(u16::MAX)),
+        None => options.mtu,
+    };
+
+    // Advertise what this viewer can handle, the server answers with the stream parameters
+    let (max_width, max_height) = options.max_resolution.unwrap_or((u16::MAX, u16::MAX));
+    let capabilities = Capabilities {
+        codecs: Codec::Jpeg.bit(),
+        max_width,
+        max_height,
+        max_bitrate_kbps: options.max_bitrate,
+        mtu,
+        multicast: options.multicast,
+    };
+
+    println!(
+        "Connected to: {} from {} over {:?}{}",
+        address,
+        link.local_addr().expect("Error reading local address"),
+        options.transport,
+        if key.is_some() { " (encrypted)" } else { "" }
+    );
+
+    let mut state = MainState::new(link, key, secret, capabilities, options.rendition, options.stream, &mut ctx)?;
+    state.multicast = options.multicast.then(|| options.multicast_interface.unwrap_or(Ipv4Addr::UNSPECIFIED));
+
+    state
+        .hello(None)
+        .expect("Error sending connection notification to server");
 
     event::run(ctx, event_loop, state);
 }
@@ -1,27 +1,657 @@
+use std::net::{Ipv4Addr, SocketAddrV4};
+
+use crate::packet::{Header, Kind, ProtocolError, StreamId};
+
+/// Size of the cookie a server hands out before letting a client join
+pub const COOKIE_SIZE: usize = 16;
+
+/// Size of the secret a server hands a client with its session, see `auth::prove`
+pub const SESSION_SECRET_SIZE: usize = 32;
+
+/// Size of the proof a client rejoining from another address sends for its session
+pub const PROOF_SIZE: usize = 32;
+
+/// Server assigned id of a viewer's session, carried by every control message
+/// Random, so knowing one session doesn't tell anything about another
+pub type SessionId = u64;
+
+/// Session id of control messages sent before the server assigned one
+pub const NO_SESSION: SessionId = 0;
 
 /// Communication of server and clients
-pub enum Actions {
-    // * Unknown action
-    Unknown = -1,
+/// Every control message is a single datagram: | header | session id (8) | tag (1) | payload |
+/// Integers are little-endian, strings are prefixed by their length as a u8
+/// Trailing bytes after the payload are ignored so newer peers can append fields
+/// Hellos are padded to `MIN_HELLO_SIZE`, so what the server answers a stranger with is never larger
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    // * Hello - Client to server to join the stream, advertising what it can handle
+    // The first hello of a client has no cookie, it is answered with one to echo in the next hello
+    // A client rejoining, from the same or another address, sends it with the session id it had, and
+    // with its cookie a proof that it owns that session
+    // `stream` is the name of the stream to watch, empty for the server's first one
+    Hello {
+        capabilities: Capabilities,
+        cookie: Option<[u8; COOKIE_SIZE]>,
+        stream: String,
+        proof: Option<[u8; PROOF_SIZE]>,
+    },
+
+    // * Cookie - Server to client, proves the client can receive at the address it claims
+    Cookie { cookie: [u8; COOKIE_SIZE] },
+
+    // * Accept - Server to client, the parameters the stream will be sent with
+    // The session id it carries is the one the client uses from then on, also to rejoin from another address
+    // `secret` stays the same for the whole session, the client proves it knows it to rejoin
+    Accept { params: StreamParams, secret: [u8; SESSION_SECRET_SIZE] },
+
+    // * Reject - Server to client, the client can't be served
+    Reject { reason: String },
+
+    // * Challenge - Server to client, the stream needs a password or invite token
+    // The client answers with an authenticate proving it knows one, bound to this nonce
+    Challenge { nonce: [u8; 16] },
+
+    // * Authenticate - Client to server, answer to a challenge
+    Authenticate(Credential),
+
+    // * Ping - Either side, to know if the other is still alive
+    // Timestamp is in microseconds on the sender's clock, it is echoed back in the pong
+    Ping { timestamp: u64 },
 
-    // * Ping - Client to server to know if server is still alive
-    Ping = 1,
+    // * Pong - Answer to a ping
+    Pong { timestamp: u64 },
 
-    // * New connection - Client to server to notify of new connection
-    NewConnection = 2,
+    // * Receiver report - Client to server, what the client has been receiving
+    ReceiverReport(ReceiverReport),
 
-    // * Disconnection - Client to server to notify of disconnection
-    Disconnection = 3,
+    // * Keyframe request - Client to server, the client needs a frame it can decode on its own
+    KeyframeRequest,
 
-} 
+    // * Quality request - Client to server, ask for a different quality and frame rate
+    QualityRequest { quality: u8, fps: u8 },
 
-impl From<u8> for Actions {
-    fn from(value: u8) -> Self {
+    // * Goodbye - Either side, the session is over
+    Goodbye { reason: String },
+
+    // * Renditions - Server to client, the renditions the client can subscribe to, largest first
+    // Sent after the accept and whenever the server switches the client to another rendition
+    Renditions { current: u8, automatic: bool, renditions: Vec<Rendition> },
+
+    // * Subscribe - Client to server, switch to a rendition, or `Rendition::AUTOMATIC` to let the server pick
+    Subscribe { rendition: u8 },
+
+    // * Multicast - Server to client, the group `rendition` is sent to, for clients that can join one
+    // Sent after the renditions whenever they are. `salt` is the group's encryption session, when the stream is encrypted
+    Multicast { rendition: u8, group: SocketAddrV4, salt: Option<[u8; 16]> },
+
+    // * Nack - Client to server, sequence numbers of packets of `rendition` the group lost on the way
+    // They are sent again to the client alone, if the server still has them
+    Nack { rendition: u8, seqs: Vec<u32> },
+}
+
+/// Encodings a frame can be sent in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    Jpeg = 0,
+}
+
+impl Codec {
+    /// Bit of the codec in `Capabilities::codecs`
+    pub fn bit(self) -> u32 {
+        1 << self as u32
+    }
+}
+
+impl TryFrom<u8> for Codec {
+    type Error = ProtocolError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
         match value {
-            1 => Actions::Ping,
-            2 => Actions::NewConnection,
-            3 => Actions::Disconnection,
-            _ => Actions::Unknown,
+            0 => Ok(Codec::Jpeg),
+            _ => Err(ProtocolError::UnknownCodec(value)),
+        }
+    }
+}
+
+/// What a client can handle, sent in its hello
+/// Zero in `max_bitrate_kbps` means no limit
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Capabilities {
+    pub codecs: u32,           // Bit set of `Codec::bit`
+    pub max_width: u16,
+    pub max_height: u16,
+    pub max_bitrate_kbps: u32,
+    pub mtu: u16,              // Largest datagram the client wants to receive
+    pub multicast: bool,       // Whether the client can receive frames from a multicast group
+}
+
+impl Capabilities {
+    pub fn supports(&self, codec: Codec) -> bool {
+        self.codecs & codec.bit() != 0
+    }
+}
+
+/// Parameters the server chose for a client, sent in the accept
+/// Zero in `max_bitrate_kbps` means no limit
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StreamParams {
+    pub stream: StreamId, // Id of the stream in the frame packets
+    pub codec: Codec,
+    pub width: u16,
+    pub height: u16,
+    pub fps: u8,
+    pub quality: u8,
+    pub max_bitrate_kbps: u32,
+    pub mtu: u16,
+}
+
+/// One of the sizes and qualities the server encodes each capture in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rendition {
+    pub id: u8,
+    pub width: u16,
+    pub height: u16,
+    pub quality: u8, // Highest quality this rendition is encoded at
+}
+
+impl Rendition {
+    /// Subscription to whichever rendition the client's bandwidth allows
+    pub const AUTOMATIC: u8 = u8::MAX;
+}
+
+/// Proof that a client knows the stream password or an invite token
+/// The MAC is HMAC-SHA256 of the challenge nonce, keyed with the password or the token secret
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Credential {
+    Password { mac: [u8; 32] },
+    Invite { id: [u8; 8], mac: [u8; 32] }, // Id tells the server which invite secret to check with
+}
+
+impl Credential {
+    const PASSWORD: u8 = 0;
+    const INVITE: u8 = 1;
+}
+
+/// Statistics a client sends back to the server
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReceiverReport {
+    pub received: u64,           // Packets received
+    pub lost: u64,               // Packets lost
+    pub jitter_us: u32,          // Inter-arrival jitter
+    pub last_frame: u32,         // Last frame id displayed
+    pub decode_us: u32,          // Time spent decoding the last frame
+    pub display_latency_us: u32, // Time from first packet of the last frame to display
+}
+
+impl Message {
+    const HELLO: u8 = 1;
+    const PING: u8 = 2;
+    const PONG: u8 = 3;
+    const RECEIVER_REPORT: u8 = 4;
+    const KEYFRAME_REQUEST: u8 = 5;
+    const QUALITY_REQUEST: u8 = 6;
+    const GOODBYE: u8 = 7;
+    const ACCEPT: u8 = 8;
+    const REJECT: u8 = 9;
+    const CHALLENGE: u8 = 10;
+    const AUTHENTICATE: u8 = 11;
+    const COOKIE: u8 = 12;
+    const RENDITIONS: u8 = 13;
+    const SUBSCRIBE: u8 = 14;
+    const MULTICAST: u8 = 15;
+    const NACK: u8 = 16;
+
+    /// Largest control message we accept
+    pub const MAX_SIZE: usize = 512;
+
+    /// Size hellos are padded to
+    pub const MIN_HELLO_SIZE: usize = 128;
+
+    /// Most sequence numbers a client puts in a nack, and a server resends for one
+    pub const MAX_NACKS: usize = 32;
+
+    pub fn to_bytes(&self, session: SessionId) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(Header::SIZE + 40);
+        Header::write(Kind::Control, &mut bytes);
+        bytes.extend_from_slice(&session.to_le_bytes());
+
+        match self {
+            Message::Hello { capabilities, cookie, stream, proof } => {
+                bytes.push(Self::HELLO);
+                bytes.extend_from_slice(&capabilities.codecs.to_le_bytes());
+                bytes.extend_from_slice(&capabilities.max_width.to_le_bytes());
+                bytes.extend_from_slice(&capabilities.max_height.to_le_bytes());
+                bytes.extend_from_slice(&capabilities.max_bitrate_kbps.to_le_bytes());
+                bytes.extend_from_slice(&capabilities.mtu.to_le_bytes());
+                bytes.push(capabilities.multicast as u8);
+                match cookie {
+                    Some(cookie) => {
+                        bytes.push(1);
+                        bytes.extend_from_slice(cookie);
+                    }
+                    None => bytes.push(0),
+                }
+                write_string(stream, &mut bytes);
+                match proof {
+                    Some(proof) => {
+                        bytes.push(1);
+                        bytes.extend_from_slice(proof);
+                    }
+                    None => bytes.push(0),
+                }
+                bytes.resize(bytes.len().max(Self::MIN_HELLO_SIZE), 0);
+            }
+            Message::Cookie { cookie } => {
+                bytes.push(Self::COOKIE);
+                bytes.extend_from_slice(cookie);
+            }
+            Message::Accept { params, secret } => {
+                bytes.push(Self::ACCEPT);
+                bytes.push(params.stream);
+                bytes.push(params.codec as u8);
+                bytes.extend_from_slice(&params.width.to_le_bytes());
+                bytes.extend_from_slice(&params.height.to_le_bytes());
+                bytes.push(params.fps);
+                bytes.push(params.quality);
+                bytes.extend_from_slice(&params.max_bitrate_kbps.to_le_bytes());
+                bytes.extend_from_slice(&params.mtu.to_le_bytes());
+                bytes.extend_from_slice(secret);
+            }
+            Message::Reject { reason } => {
+                bytes.push(Self::REJECT);
+                write_string(reason, &mut bytes);
+            }
+            Message::Challenge { nonce } => {
+                bytes.push(Self::CHALLENGE);
+                bytes.extend_from_slice(nonce);
+            }
+            Message::Authenticate(credential) => {
+                bytes.push(Self::AUTHENTICATE);
+                match credential {
+                    Credential::Password { mac } => {
+                        bytes.push(Credential::PASSWORD);
+                        bytes.extend_from_slice(mac);
+                    }
+                    Credential::Invite { id, mac } => {
+                        bytes.push(Credential::INVITE);
+                        bytes.extend_from_slice(id);
+                        bytes.extend_from_slice(mac);
+                    }
+                }
+            }
+            Message::Ping { timestamp } => {
+                bytes.push(Self::PING);
+                bytes.extend_from_slice(&timestamp.to_le_bytes());
+            }
+            Message::Pong { timestamp } => {
+                bytes.push(Self::PONG);
+                bytes.extend_from_slice(&timestamp.to_le_bytes());
+            }
+            Message::ReceiverReport(report) => {
+                bytes.push(Self::RECEIVER_REPORT);
+                bytes.extend_from_slice(&report.received.to_le_bytes());
+                bytes.extend_from_slice(&report.lost.to_le_bytes());
+                bytes.extend_from_slice(&report.jitter_us.to_le_bytes());
+                bytes.extend_from_slice(&report.last_frame.to_le_bytes());
+                bytes.extend_from_slice(&report.decode_us.to_le_bytes());
+                bytes.extend_from_slice(&report.display_latency_us.to_le_bytes());
+            }
+            Message::KeyframeRequest => {
+                bytes.push(Self::KEYFRAME_REQUEST);
+            }
+            Message::QualityRequest { quality, fps } => {
+                bytes.push(Self::QUALITY_REQUEST);
+                bytes.push(*quality);
+                bytes.push(*fps);
+            }
+            Message::Goodbye { reason } => {
+                bytes.push(Self::GOODBYE);
+                write_string(reason, &mut bytes);
+            }
+            Message::Renditions { current, automatic, renditions } => {
+                bytes.push(Self::RENDITIONS);
+                bytes.push(*current);
+                bytes.push(*automatic as u8);
+                bytes.push(renditions.len().min(u8::MAX as usize) as u8);
+                for rendition in renditions.iter().take(u8::MAX as usize) {
+                    bytes.push(rendition.id);
+                    bytes.extend_from_slice(&rendition.width.to_le_bytes());
+                    bytes.extend_from_slice(&rendition.height.to_le_bytes());
+                    bytes.push(rendition.quality);
+                }
+            }
+            Message::Subscribe { rendition } => {
+                bytes.push(Self::SUBSCRIBE);
+                bytes.push(*rendition);
+            }
+            Message::Multicast { rendition, group, salt } => {
+                bytes.push(Self::MULTICAST);
+                bytes.push(*rendition);
+                bytes.extend_from_slice(&group.ip().octets());
+                bytes.extend_from_slice(&group.port().to_le_bytes());
+                match salt {
+                    Some(salt) => {
+                        bytes.push(1);
+                        bytes.extend_from_slice(salt);
+                    }
+                    None => bytes.push(0),
+                }
+            }
+            Message::Nack { rendition, seqs } => {
+                bytes.push(Self::NACK);
+                bytes.push(*rendition);
+                bytes.push(seqs.len().min(u8::MAX as usize) as u8);
+                for seq in seqs.iter().take(u8::MAX as usize) {
+                    bytes.extend_from_slice(&seq.to_le_bytes());
+                }
+            }
+        }
+
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<(SessionId, Self), ProtocolError> {
+        match Header::read(bytes)? {
+            Kind::Control => {}
+            kind => return Err(ProtocolError::UnknownKind(kind as u8)),
         }
+
+        if bytes.len() > Self::MAX_SIZE {
+            return Err(ProtocolError::Oversized(bytes.len()));
+        }
+
+        let mut reader = Reader { bytes, offset: Header::SIZE };
+
+        let session = reader.u64()?;
+        let message = match reader.u8()? {
+            Self::HELLO => Message::Hello {
+                capabilities: Capabilities {
+                    codecs: reader.u32()?,
+                    max_width: reader.u16()?,
+                    max_height: reader.u16()?,
+                    max_bitrate_kbps: reader.u32()?,
+                    mtu: reader.u16()?,
+                    multicast: reader.u8()? != 0,
+                },
+                cookie: match reader.u8()? {
+                    0 => None,
+                    _ => Some(reader.array()?),
+                },
+                stream: reader.string()?,
+                proof: match reader.u8()? {
+                    0 => None,
+                    _ => Some(reader.array()?),
+                },
+            },
+            Self::COOKIE => Message::Cookie { cookie: reader.array()? },
+            Self::ACCEPT => Message::Accept {
+                params: StreamParams {
+                    stream: reader.u8()?,
+                    codec: Codec::try_from(reader.u8()?)?,
+                    width: reader.u16()?,
+                    height: reader.u16()?,
+                    fps: reader.u8()?,
+                    quality: reader.u8()?,
+                    max_bitrate_kbps: reader.u32()?,
+                    mtu: reader.u16()?,
+                },
+                secret: reader.array()?,
+            },
+            Self::REJECT => Message::Reject { reason: reader.string()? },
+            Self::CHALLENGE => Message::Challenge { nonce: reader.array()? },
+            Self::AUTHENTICATE => Message::Authenticate(match reader.u8()? {
+                Credential::PASSWORD => Credential::Password { mac: reader.array()? },
+                Credential::INVITE => Credential::Invite { id: reader.array()?, mac: reader.array()? },
+                kind => return Err(ProtocolError::UnknownCredential(kind)),
+            }),
+            Self::PING => Message::Ping { timestamp: reader.u64()? },
+            Self::PONG => Message::Pong { timestamp: reader.u64()? },
+            Self::RECEIVER_REPORT => Message::ReceiverReport(ReceiverReport {
+                received: reader.u64()?,
+                lost: reader.u64()?,
+                jitter_us: reader.u32()?,
+                last_frame: reader.u32()?,
+                decode_us: reader.u32()?,
+                display_latency_us: reader.u32()?,
+            }),
+            Self::KEYFRAME_REQUEST => Message::KeyframeRequest,
+            Self::QUALITY_REQUEST => Message::QualityRequest { quality: reader.u8()?, fps: reader.u8()? },
+            Self::GOODBYE => Message::Goodbye { reason: reader.string()? },
+            Self::RENDITIONS => {
+                let current = reader.u8()?;
+                let automatic = reader.u8()? != 0;
+                let count = reader.u8()?;
+                let renditions = (0..count)
+                    .map(|_| {
+                        Ok(Rendition {
+                            id: reader.u8()?,
+                            width: reader.u16()?,
+                            height: reader.u16()?,
+                            quality: reader.u8()?,
+                        })
+                    })
+                    .collect::<Result<_, ProtocolError>>()?;
+                Message::Renditions { current, automatic, renditions }
+            }
+            Self::SUBSCRIBE => Message::Subscribe { rendition: reader.u8()? },
+            Self::MULTICAST => Message::Multicast {
+                rendition: reader.u8()?,
+                group: SocketAddrV4::new(Ipv4Addr::from(reader.array::<4>()?), reader.u16()?),
+                salt: match reader.u8()? {
+                    0 => None,
+                    _ => Some(reader.array()?),
+                },
+            },
+            Self::NACK => {
+                let rendition = reader.u8()?;
+                let count = reader.u8()?;
+                let seqs = (0..count).map(|_| reader.u32()).collect::<Result<_, _>>()?;
+                Message::Nack { rendition, seqs }
+            }
+            tag => return Err(ProtocolError::UnknownMessage(tag)),
+        };
+
+        Ok((session, message))
+    }
+}
+
+/// Strings longer than 255 bytes are cut at a char boundary
+fn write_string(value: &str, bytes: &mut Vec<u8>) {
+    let mut end = value.len().min(u8::MAX as usize);
+    while !value.is_char_boundary(end) {
+        end -= 1;
+    }
+
+    bytes.push(end as u8);
+    bytes.extend_from_slice(&value.as_bytes()[..end]);
+}
+
+/// Bounds checked reads over a control message
+struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, amount: usize) -> Result<&'a [u8], ProtocolError> {
+        let end = self.offset + amount;
+        if end > self.bytes.len() {
+            return Err(ProtocolError::Truncated { expected: end, actual: self.bytes.len() });
+        }
+
+        let slice = &self.bytes[self.offset..end];
+        self.offset = end;
+        Ok(slice)
+    }
+
+    fn u8(&mut self) -> Result<u8, ProtocolError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, ProtocolError> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, ProtocolError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn u64(&mut self) -> Result<u64, ProtocolError> {
+        let mut buffer = [0u8; 8];
+        buffer.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(buffer))
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], ProtocolError> {
+        let mut buffer = [0u8; N];
+        buffer.copy_from_slice(self.take(N)?);
+        Ok(buffer)
+    }
+
+    fn string(&mut self) -> Result<String, ProtocolError> {
+        let length = self.u8()? as usize;
+        let bytes = self.take(length)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| ProtocolError::InvalidString)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::Packet;
+
+    const SESSION: SessionId = 0x0123_4567_89ab_cdef;
+
+    fn capabilities() -> Capabilities {
+        Capabilities { codecs: Codec::Jpeg.bit(), max_width: 1280, max_height: 720, max_bitrate_kbps: 4000, mtu: 1400, multicast: true }
+    }
+
+    /// A message of every tag, optional fields both set and left out
+    fn messages() -> Vec<Message> {
+        let params = StreamParams {
+            stream: 1,
+            codec: Codec::Jpeg,
+            width: 1920,
+            height: 1080,
+            fps: 30,
+            quality: 80,
+            max_bitrate_kbps: 8000,
+            mtu: 1200,
+        };
+        let rendition = Rendition { id: 1, width: 960, height: 540, quality: 70 };
+        let group = SocketAddrV4::new(Ipv4Addr::new(239, 255, 0, 1), 5000);
+
+        vec![
+            Message::Hello { capabilities: capabilities(), cookie: None, stream: String::new(), proof: None },
+            Message::Hello {
+                capabilities: capabilities(),
+                cookie: Some([1; COOKIE_SIZE]),
+                stream: String::from("écran"),
+                proof: Some([2; PROOF_SIZE]),
+            },
+            Message::Cookie { cookie: [3; COOKIE_SIZE] },
+            Message::Accept { params, secret: [4; SESSION_SECRET_SIZE] },
+            Message::Reject { reason: String::from("wrong password") },
+            Message::Challenge { nonce: [5; 16] },
+            Message::Authenticate(Credential::Password { mac: [6; 32] }),
+            Message::Authenticate(Credential::Invite { id: [7; 8], mac: [8; 32] }),
+            Message::Ping { timestamp: u64::MAX },
+            Message::Pong { timestamp: 1 },
+            Message::ReceiverReport(ReceiverReport {
+                received: 1000,
+                lost: 3,
+                jitter_us: 1500,
+                last_frame: 77,
+                decode_us: 2000,
+                display_latency_us: 9000,
+            }),
+            Message::KeyframeRequest,
+            Message::QualityRequest { quality: 60, fps: 24 },
+            Message::Goodbye { reason: String::from("leaving") },
+            Message::Renditions { current: 1, automatic: true, renditions: vec![rendition; 3] },
+            Message::Subscribe { rendition: Rendition::AUTOMATIC },
+            Message::Multicast { rendition: 0, group, salt: None },
+            Message::Multicast { rendition: 1, group, salt: Some([9; 16]) },
+            Message::Nack { rendition: 1, seqs: vec![0, 1, u32::MAX] },
+        ]
+    }
+
+    /// Bytes of a control message with `tag` and `payload`
+    fn raw(tag: u8, payload: &[u8]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        Header::write(Kind::Control, &mut bytes);
+        bytes.extend_from_slice(&SESSION.to_le_bytes());
+        bytes.push(tag);
+        bytes.extend_from_slice(payload);
+        bytes
+    }
+
+    #[test]
+    fn every_message_round_trips() {
+        let messages = messages();
+        let mut tags: Vec<u8> = Vec::new();
+        for message in messages {
+            let bytes = message.to_bytes(SESSION);
+            assert!(bytes.len() <= Message::MAX_SIZE);
+            assert_eq!(Message::from_bytes(&bytes), Ok((SESSION, message)));
+            tags.push(bytes[Header::SIZE + 8]);
+        }
+
+        tags.dedup();
+        assert_eq!(tags.len(), 16, "every tag is covered");
+    }
+
+    #[test]
+    fn hellos_are_padded_and_trailing_bytes_ignored() {
+        let hello = Message::Hello { capabilities: capabilities(), cookie: None, stream: String::new(), proof: None };
+        let mut bytes = hello.to_bytes(NO_SESSION);
+        assert_eq!(bytes.len(), Message::MIN_HELLO_SIZE);
+
+        bytes.extend_from_slice(b"from a newer peer");
+        assert_eq!(Message::from_bytes(&bytes), Ok((NO_SESSION, hello)));
+    }
+
+    #[test]
+    fn long_strings_are_cut_at_a_char_boundary() {
+        let reason = "é".repeat(200);
+        let (_, message) = Message::from_bytes(&Message::Goodbye { reason: reason.clone() }.to_bytes(SESSION)).unwrap();
+        assert_eq!(message, Message::Goodbye { reason: "é".repeat(127) });
+    }
+
+    #[test]
+    fn malformed_messages_are_refused() {
+        let ping = Message::Ping { timestamp: 1 }.to_bytes(SESSION);
+        let truncated = &ping[..ping.len() - 1];
+        let expected = ProtocolError::Truncated { expected: ping.len(), actual: ping.len() - 1 };
+        assert_eq!(Message::from_bytes(truncated), Err(expected));
+
+        let mut magic = ping.clone();
+        magic[0] = b'X';
+        assert_eq!(Message::from_bytes(&magic), Err(ProtocolError::BadMagic(*b"XS")));
+
+        let mut version = ping.clone();
+        version[2] = Packet::VERSION + 1;
+        assert_eq!(Message::from_bytes(&version), Err(ProtocolError::BadVersion(Packet::VERSION + 1)));
+
+        let oversized = raw(Message::PING, &[0; Message::MAX_SIZE]);
+        assert_eq!(Message::from_bytes(&oversized), Err(ProtocolError::Oversized(oversized.len())));
+
+        let frame = Packet::new(0, 0, 0, 0, 1, b"jpeg").to_bytes();
+        assert_eq!(Message::from_bytes(&frame), Err(ProtocolError::UnknownKind(Kind::Frame as u8)));
+
+        assert_eq!(Message::from_bytes(&raw(0, &[])), Err(ProtocolError::UnknownMessage(0)));
+        assert_eq!(Message::from_bytes(&raw(17, &[])), Err(ProtocolError::UnknownMessage(17)));
+
+        assert_eq!(Message::from_bytes(&raw(Message::REJECT, &[2, 0xc3, 0x28])), Err(ProtocolError::InvalidString));
+
+        let mut accept = raw(Message::ACCEPT, &[0, 1]);
+        accept.resize(Message::MAX_SIZE, 0);
+        assert_eq!(Message::from_bytes(&accept), Err(ProtocolError::UnknownCodec(1)));
+
+        let authenticate = raw(Message::AUTHENTICATE, &[2; 41]);
+        assert_eq!(Message::from_bytes(&authenticate), Err(ProtocolError::UnknownCredential(2)));
     }
-}
\ No newline at end of file
+}
@@ -1,4 +1,7 @@
-use clap::{Args, Subcommand};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, ToSocketAddrs};
+use std::path::PathBuf;
+
+use clap::{Args, Subcommand, ValueEnum};
 
 
 
@@ -15,6 +18,72 @@ pub struct StartCmd {
     #[arg(short, long, default_value = "8080")]
     pub port: u16,
 
+    #[arg(long, help = "Address to listen on (default: every IPv6 and IPv4 address)")]
+    pub bind: Option<IpAddr>,
+
+    #[arg(long, value_enum, default_value = "udp", help = "Transport viewers connect with")]
+    pub transport: Transport,
+
+    #[arg(
+        long,
+        value_parser = parse_listen,
+        help = "Also accept viewers connecting with --transport quic on [<address>]:<port>, e.g. :8443, \
+                next to the --transport ones"
+    )]
+    pub quic: Option<(Option<IpAddr>, u16)>,
+
+    #[arg(
+        long,
+        help = "PEM file keeping the QUIC certificate and its key, written on the first start, \
+                so the fingerprint viewers pin stays the same across restarts"
+    )]
+    pub certificate: Option<PathBuf>,
+
+    #[arg(
+        long,
+        value_parser = parse_listen,
+        help = "Also serve a browser viewer, MJPEG and snapshots over HTTP on [<address>]:<port>, \
+                e.g. :8081 for every address"
+    )]
+    pub web: Option<(Option<IpAddr>, u16)>,
+
+    #[arg(
+        long,
+        value_parser = parse_listen,
+        help = "Also serve the streams to RTSP players as RTP/JPEG on [<address>]:<port>, e.g. :8554"
+    )]
+    pub rtsp: Option<(Option<IpAddr>, u16)>,
+
+    #[arg(long, requires = "web", help = "Send browsers that can take it AV1 video over WebRTC rather than JPEGs")]
+    pub webrtc: bool,
+
+    #[arg(
+        long = "stun",
+        requires = "webrtc",
+        value_parser = parse_stun,
+        help = "STUN server to learn the public address WebRTC browsers can reach from, e.g. stun.example.com:3478, \
+                can be given more than once"
+    )]
+    pub stun_servers: Vec<SocketAddr>,
+
+    #[arg(
+        long,
+        value_parser = parse_multicast,
+        help = "Send frames once to an IPv4 multicast group, e.g. 239.255.0.1:5000, for viewers connecting with \
+                --multicast. Each rendition of each stream gets its own group, at the next addresses"
+    )]
+    pub multicast: Option<SocketAddrV4>,
+
+    #[arg(long, default_value = "1", help = "Routers multicast packets may cross, 1 keeps them on the local network")]
+    pub multicast_ttl: u32,
+
+    #[arg(
+        long,
+        requires = "multicast",
+        help = "Address of the interface to send to the multicast groups from (default: the one routing picks)"
+    )]
+    pub multicast_interface: Option<Ipv4Addr>,
+
     #[arg(short, long, default_value = "25", help = "Quality of the stream")]
     pub quality: u8, 
 
@@ -23,10 +92,228 @@ pub struct StartCmd {
 
     #[arg(long, default_value = "30", help = "Frames per second")]
     pub fps: u8,
+
+    #[arg(
+        long = "rendition",
+        value_parser = parse_rendition,
+        default_values = ["1920x1080", "960x540"],
+        help = "Smaller rendition viewers can subscribe to besides the full capture, <width>x<height>[@quality]"
+    )]
+    pub renditions: Vec<(u16, u16, Option<u8>)>,
+
+    #[arg(
+        long = "stream",
+        value_parser = parse_stream,
+        help = "Stream to publish as <name>=<source>, source being display:<index>, \
+                region:<display>:<x>,<y>:<width>x<height> or file:<image or directory of images> \
+                (default: the primary display, as \"screen\")"
+    )]
+    pub streams: Vec<(String, SourceSpec)>,
+
+    #[command(flatten)]
+    pub encryption: KeyArgs,
+
+    #[arg(long, help = "Password viewers must know to join")]
+    pub password: Option<String>,
+
+    #[arg(
+        long,
+        num_args = 0..=1,
+        default_missing_value = "1",
+        help = "Print single use invite tokens viewers can join with (1 if no count is given)"
+    )]
+    pub invite: Option<u32>,
+
+    #[arg(long, default_value = "3600", help = "Seconds before an invite token expires")]
+    pub invite_ttl: u64,
+
+    #[arg(long, default_value = "10", help = "Seconds without hearing from a viewer before it is dropped")]
+    pub client_timeout: u64,
+}
+
+/// Pre-shared key to encrypt the stream with, both sides must use the same one
+#[derive(Args)]
+pub struct KeyArgs {
+    #[arg(long, conflicts_with = "key_file", help = "Pre-shared key to encrypt the stream with")]
+    pub key: Option<String>,
+
+    #[arg(long, help = "File containing the pre-shared key to encrypt the stream with")]
+    pub key_file: Option<PathBuf>,
 }
 
 
 #[derive(Args)]
 pub struct ConnectCmd {
+    #[arg(help = "Server to connect to: <host>:<port>, <IPv4>:<port> or [<IPv6>]:<port>")]
     pub address: String,
-}
\ No newline at end of file
+
+    #[arg(long, help = "Local address to receive the stream on (default: any address, any free port)")]
+    pub bind: Option<SocketAddr>,
+
+    #[arg(long, value_enum, default_value = "udp", help = "Transport of the server, tcp where UDP is blocked")]
+    pub transport: Transport,
+
+    #[arg(long, value_parser = parse_fingerprint, help = "Certificate fingerprint a QUIC server printed, to pin it")]
+    pub fingerprint: Option<[u8; 32]>,
+
+    #[arg(long, value_parser = parse_resolution, help = "Largest resolution this viewer can display, e.g. 1280x720")]
+    pub max_resolution: Option<(u16, u16)>,
+
+    #[arg(long, default_value = "0", help = "Largest bitrate this viewer can receive in kbps (0 = no limit)")]
+    pub max_bitrate: u32,
+
+    #[arg(long, default_value = "65000", help = "Largest datagram this viewer wants to receive")]
+    pub mtu: u16,
+
+    #[arg(long, default_value = "", help = "Name of the stream to watch (default: the first one the server publishes)")]
+    pub stream: String,
+
+    #[arg(long, help = "Rendition to subscribe to, as listed when joining (default: picked by the server from the bandwidth)")]
+    pub rendition: Option<u8>,
+
+    #[arg(long, help = "Receive frames from the server's multicast group, when it has one, only control goes to the server")]
+    pub multicast: bool,
+
+    #[arg(
+        long,
+        requires = "multicast",
+        help = "Address of the interface to join the multicast group on (default: the one routing picks)"
+    )]
+    pub multicast_interface: Option<Ipv4Addr>,
+
+    #[command(flatten)]
+    pub encryption: KeyArgs,
+
+    #[arg(long, conflicts_with = "token", help = "Password of the stream")]
+    pub password: Option<String>,
+
+    #[arg(long, help = "Invite token printed by `start --invite`")]
+    pub token: Option<String>,
+}
+
+/// How datagrams travel between the server and its viewers
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Transport {
+    Udp,
+    Tcp,  // Every datagram prefixed with its length on one connection per viewer
+    Quic, // Frames as QUIC datagrams, control messages on a reliable stream of the viewer's connection
+}
+
+/// Parses a `[<address>]:<port>` to listen on, every address when it is left out
+pub fn parse_listen(value: &str) -> Result<(Option<IpAddr>, u16), String> {
+    let (ip, port) = value
+        .rsplit_once(':')
+        .ok_or_else(|| format!("Expected [<address>]:<port>, got: {}", value))?;
+
+    let port = port.parse().map_err(|e| format!("Invalid port {}: {}", port, e))?;
+    let ip = match ip.trim_start_matches('[').trim_end_matches(']') {
+        "" => None,
+        ip => Some(ip.parse().map_err(|e| format!("Invalid address {}: {}", ip, e))?),
+    };
+
+    Ok((ip, port))
+}
+
+/// Parses a `<host>:<port>` STUN server, looking the host up
+pub fn parse_stun(value: &str) -> Result<SocketAddr, String> {
+    let address = value.strip_prefix("stun:").unwrap_or(value);
+    address
+        .to_socket_addrs()
+        .map_err(|e| format!("Invalid STUN server {}: {}", value, e))?
+        .next()
+        .ok_or_else(|| format!("STUN server {} has no address", value))
+}
+
+/// Parses an IPv4 multicast `<group>:<port>`
+pub fn parse_multicast(value: &str) -> Result<SocketAddrV4, String> {
+    let group: SocketAddrV4 = value
+        .parse()
+        .map_err(|e| format!("Expected <IPv4 group>:<port>, got: {} ({})", value, e))?;
+
+    if !group.ip().is_multicast() {
+        return Err(format!("{} is not a multicast address (224.0.0.0 to 239.255.255.255)", group.ip()));
+    }
+    Ok(group)
+}
+
+/// Parses a SHA-256 certificate fingerprint, in hex as the server prints it
+pub fn parse_fingerprint(value: &str) -> Result<[u8; 32], String> {
+    crate::auth::from_hex(value.trim()).ok_or_else(|| format!("Expected 64 hex digits, got: {}", value))
+}
+
+/// Parses a `<width>x<height>` resolution
+pub fn parse_resolution(value: &str) -> Result<(u16, u16), String> {
+    let (width, height) = value
+        .split_once('x')
+        .ok_or_else(|| format!("Expected <width>x<height>, got: {}", value))?;
+
+    let width = width.trim().parse().map_err(|e| format!("Invalid width {}: {}", width, e))?;
+    let height = height.trim().parse().map_err(|e| format!("Invalid height {}: {}", height, e))?;
+
+    Ok((width, height))
+}
+/// Parses a `<width>x<height>[@quality]` rendition
+pub fn parse_rendition(value: &str) -> Result<(u16, u16, Option<u8>), String> {
+    let (resolution, quality) = match value.split_once('@') {
+        Some((resolution, quality)) => {
+            let quality = quality.trim().parse().map_err(|e| format!("Invalid quality {}: {}", quality, e))?;
+            (resolution, Some(quality))
+        }
+        None => (value, None),
+    };
+
+    let (width, height) = parse_resolution(resolution)?;
+    Ok((width, height, quality))
+}
+
+/// Where the frames of a stream come from
+#[derive(Clone, Debug)]
+pub enum SourceSpec {
+    Display(usize), // Index in the list of displays, 0 being the first one
+    Region { display: usize, x: usize, y: usize, width: usize, height: usize },
+    Files(PathBuf), // Still image, or a directory of images played in a loop
+}
+
+/// Longest stream name, it has to fit in a hello
+pub const MAX_STREAM_NAME: usize = 64;
+
+/// Parses a `<name>=<source>` stream
+pub fn parse_stream(value: &str) -> Result<(String, SourceSpec), String> {
+    let (name, source) = value
+        .split_once('=')
+        .ok_or_else(|| format!("Expected <name>=<source>, got: {}", value))?;
+
+    if name.is_empty() || name.len() > MAX_STREAM_NAME {
+        return Err(format!("Stream name must be 1 to {} bytes long, got: {:?}", MAX_STREAM_NAME, name));
+    }
+
+    let (kind, arguments) = source.split_once(':').unwrap_or((source, ""));
+    let index = |value: &str| value.trim().parse::<usize>().map_err(|e| format!("Invalid display {}: {}", value, e));
+
+    let source = match kind {
+        "display" => SourceSpec::Display(index(arguments)?),
+        "region" => {
+            let parts: Vec<&str> = arguments.split(':').collect();
+            let [display, position, size] = parts[..] else {
+                return Err(format!("Expected region:<display>:<x>,<y>:<width>x<height>, got: {}", source));
+            };
+
+            let (x, y) = position
+                .split_once(',')
+                .ok_or_else(|| format!("Expected <x>,<y>, got: {}", position))?;
+            let (width, height) = parse_resolution(size)?;
+
+            SourceSpec::Region {
+                display: index(display)?,
+                x: x.trim().parse().map_err(|e| format!("Invalid x {}: {}", x, e))?,
+                y: y.trim().parse().map_err(|e| format!("Invalid y {}: {}", y, e))?,
+                width: width as usize,
+                height: height as usize,
+            }
+        }
+        "file" if !arguments.is_empty() => SourceSpec::Files(PathBuf::from(arguments)),
+        _ => return Err(format!("Unknown source {:?}, expected display:, region: or file:", source)),
+    };
+
+    Ok((name.to_string(), source))
+}
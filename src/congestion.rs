@@ -0,0 +1,169 @@
+use std::time::{Duration, Instant};
+
+use crate::comm::ReceiverReport;
+
+/// AIMD congestion controller for a single client, driven by its receiver reports
+/// A report with too much loss or jitter (queuing delay shows up as jitter) is congestion:
+/// the bitrate is cut multiplicatively. Every clean report raises it back additively, up to what
+/// was negotiated. Frames are encoded once for every client of a rendition, so a congested client
+/// is not sent a lower quality: it skips frames to stay under its target, and moves to a smaller rendition
+pub struct Congestion {
+    ceiling_kbps: u32,     // Negotiated maximum bitrate, u32::MAX without limit
+    target_kbps: u32,      // Bitrate currently allowed
+    last_received: u64,    // Cumulative counters of the previous report
+    last_lost: u64,
+    window_start: Instant, // Start of the current one second rate window
+    window_bytes: usize,   // Bytes sent in the current window
+    sent_kbps: u32,        // Rate measured over the last complete window
+}
+
+impl Congestion {
+    const WINDOW: Duration = Duration::from_secs(1);
+
+    /// Fraction of packets lost between two reports that counts as congestion
+    const LOSS_THRESHOLD: f64 = 0.02;
+
+    /// Jitter that counts as congestion
+    const JITTER_THRESHOLD_US: u32 = 30_000;
+
+    const MIN_KBPS: u32 = 500;
+    const INCREASE_KBPS: u32 = 500;
+    const DECREASE: f64 = 0.7;
+
+    /// Zero in `max_bitrate_kbps` means no limit
+    pub fn new(max_bitrate_kbps: u32) -> Self {
+        let ceiling_kbps = if max_bitrate_kbps == 0 { u32::MAX } else { max_bitrate_kbps };
+
+        Self {
+            ceiling_kbps,
+            target_kbps: ceiling_kbps,
+            last_received: 0,
+            last_lost: 0,
+            window_start: Instant::now(),
+            window_bytes: 0,
+            sent_kbps: 0,
+        }
+    }
+
+    /// Whether `size` more bytes can be sent in the current window without going over the target
+    pub fn allows(&mut self, size: usize) -> bool {
+        self.allows_at(size, Instant::now())
+    }
+
+    fn allows_at(&mut self, size: usize, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.window_start);
+        if elapsed >= Self::WINDOW {
+            self.sent_kbps = (self.window_bytes as f64 * 8.0 / 1000.0 / elapsed.as_secs_f64()) as u32;
+            self.window_start = now;
+            self.window_bytes = 0;
+        }
+
+        let budget = self.target_kbps as u64 * 1000 / 8;
+        (self.window_bytes + size) as u64 <= budget
+    }
+
+    /// Records bytes sent to the client
+    pub fn on_sent(&mut self, size: usize) {
+        self.window_bytes += size;
+    }
+
+    /// Updates the target bitrate from a receiver report
+    /// `estimated_kbps` is the bandwidth the client was measured to receive, if known
+    /// Returns true if the report showed congestion
+    pub fn on_report(&mut self, report: &ReceiverReport, estimated_kbps: Option<f64>) -> bool {
+        let received = report.received.saturating_sub(self.last_received);
+        let lost = report.lost.saturating_sub(self.last_lost);
+        self.last_received = report.received;
+        self.last_lost = report.lost;
+
+        let loss = if received + lost == 0 { 0.0 } else { lost as f64 / (received + lost) as f64 };
+        let congested = loss > Self::LOSS_THRESHOLD || report.jitter_us > Self::JITTER_THRESHOLD_US;
+
+        if congested {
+            // Cut from what actually gets through, the target may be far above it
+            let measured = estimated_kbps.map_or(self.sent_kbps, |estimate| estimate as u32);
+            let current = self.target_kbps.min(measured.max(Self::MIN_KBPS));
+            self.target_kbps = ((current as f64 * Self::DECREASE) as u32).max(Self::MIN_KBPS);
+        } else {
+            self.target_kbps = self.target_kbps.saturating_add(Self::INCREASE_KBPS).min(self.ceiling_kbps);
+        }
+
+        congested
+    }
+
+    pub fn target_kbps(&self) -> u32 {
+        self.target_kbps
+    }
+
+    /// Rate measured over the last complete window
+    pub fn sent_kbps(&self) -> u32 {
+        self.sent_kbps
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(received: u64, lost: u64, jitter_us: u32) -> ReceiverReport {
+        ReceiverReport { received, lost, jitter_us, ..ReceiverReport::default() }
+    }
+
+    #[test]
+    fn loss_over_two_percent_cuts_from_the_estimate() {
+        let mut congestion = Congestion::new(10_000);
+        assert_eq!(congestion.target_kbps(), 10_000);
+
+        // 20 of 1000 is not over the threshold
+        assert!(!congestion.on_report(&report(980, 20, 0), Some(8_000.0)));
+        assert_eq!(congestion.target_kbps(), 10_000);
+        assert!(congestion.on_report(&report(1_950, 50, 0), Some(8_000.0)));
+        assert_eq!(congestion.target_kbps(), 5_600);
+    }
+
+    #[test]
+    fn jitter_over_30_ms_is_congestion() {
+        let mut congestion = Congestion::new(10_000);
+        assert!(!congestion.on_report(&report(100, 0, 30_000), Some(10_000.0)));
+        assert!(congestion.on_report(&report(200, 0, 30_001), Some(10_000.0)));
+        assert_eq!(congestion.target_kbps(), 7_000);
+    }
+
+    #[test]
+    fn target_stays_above_the_floor_and_recovers_once_clear() {
+        let mut congestion = Congestion::new(2_000);
+        for i in 1..=10 {
+            assert!(congestion.on_report(&report(i * 100, i * 10, 0), Some(100.0)));
+            assert_eq!(congestion.target_kbps(), Congestion::MIN_KBPS);
+        }
+
+        // Additively back, never past what was negotiated
+        let targets: Vec<u32> = (11..=15)
+            .map(|i| {
+                congestion.on_report(&report(i * 100, 100, 0), None);
+                congestion.target_kbps()
+            })
+            .collect();
+        assert_eq!(targets, [1_000, 1_500, 2_000, 2_000, 2_000]);
+    }
+
+    #[test]
+    fn budget_is_per_window_and_the_sent_rate_is_measured_over_it() {
+        let mut congestion = Congestion::new(1_000);
+        let start = congestion.window_start;
+
+        // 1000 kbps is 125000 bytes a second
+        assert!(congestion.allows_at(125_000, start));
+        congestion.on_sent(100_000);
+        assert!(congestion.allows_at(25_000, start + Duration::from_millis(500)));
+        assert!(!congestion.allows_at(25_001, start + Duration::from_millis(999)));
+        assert_eq!(congestion.sent_kbps(), 0);
+
+        assert!(congestion.allows_at(125_000, start + Congestion::WINDOW));
+        assert_eq!(congestion.sent_kbps(), 800);
+
+        // Without an estimate, a cut starts from the measured rate
+        assert!(congestion.on_report(&report(10, 10, 0), None));
+        assert_eq!(congestion.target_kbps(), 560);
+    }
+}
@@ -0,0 +1,248 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::time::{Duration, Instant};
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::comm::COOKIE_SIZE;
+
+/// Stateless proof that a host receives what is sent to the address it claims
+/// Cookie: | issued (4) | HMAC-SHA256(secret, address | issued) truncated (12) |
+/// `issued` is in seconds since the jar was created. Nothing is stored per host, so a flood of
+/// spoofed hellos costs the server one small reply each and no memory
+pub struct CookieJar {
+    secret: [u8; 32],
+    epoch: Instant,
+}
+
+impl CookieJar {
+    /// How long a cookie can be echoed back
+    const LIFETIME: Duration = Duration::from_secs(30);
+
+    pub fn new() -> Result<Self, getrandom::Error> {
+        let mut secret = [0u8; 32];
+        getrandom::getrandom(&mut secret)?;
+        Ok(Self { secret, epoch: Instant::now() })
+    }
+
+    fn now(&self) -> u32 {
+        self.epoch.elapsed().as_secs() as u32
+    }
+
+    fn mac(&self, address: SocketAddr, issued: u32) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.secret).expect("HMAC accepts keys of any length");
+        match address.ip() {
+            IpAddr::V4(ip) => mac.update(&ip.octets()),
+            IpAddr::V6(ip) => mac.update(&ip.octets()),
+        }
+        mac.update(&address.port().to_le_bytes());
+        mac.update(&issued.to_le_bytes());
+        mac
+    }
+
+    pub fn issue(&self, address: SocketAddr) -> [u8; COOKIE_SIZE] {
+        self.issue_at(address, self.now())
+    }
+
+    fn issue_at(&self, address: SocketAddr, issued: u32) -> [u8; COOKIE_SIZE] {
+        let tag = self.mac(address, issued).finalize().into_bytes();
+
+        let mut cookie = [0u8; COOKIE_SIZE];
+        cookie[..4].copy_from_slice(&issued.to_le_bytes());
+        cookie[4..].copy_from_slice(&tag[..COOKIE_SIZE - 4]);
+        cookie
+    }
+
+    /// Whether the cookie was issued to `address` and has not expired
+    pub fn verify(&self, address: SocketAddr, cookie: &[u8; COOKIE_SIZE]) -> bool {
+        self.verify_at(address, cookie, self.now())
+    }
+
+    fn verify_at(&self, address: SocketAddr, cookie: &[u8; COOKIE_SIZE], now: u32) -> bool {
+        let issued = u32::from_le_bytes([cookie[0], cookie[1], cookie[2], cookie[3]]);
+
+        if issued > now || now - issued > Self::LIFETIME.as_secs() as u32 {
+            return false;
+        }
+
+        self.mac(address, issued).verify_truncated_left(&cookie[4..]).is_ok()
+    }
+}
+
+/// Token bucket per source IP, bounds how often a host can try to join
+/// Ports are ignored, they cost nothing to change, and so are the low 64 bits of IPv6 addresses:
+/// a single host is usually handed a whole /64
+pub struct RateLimiter {
+    rate: f64,  // Tokens added per second
+    burst: f64, // Most tokens a bucket holds
+    buckets: HashMap<IpAddr, Bucket>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    /// Upper bound of sources tracked, so spoofed sources can't grow memory
+    const MAX_SOURCES: usize = 4096;
+
+    pub fn new(rate: f64, burst: u32) -> Self {
+        Self { rate, burst: burst as f64, buckets: HashMap::new() }
+    }
+
+    /// Takes a token from the source's bucket, false if it has none left
+    pub fn allow(&mut self, ip: IpAddr) -> bool {
+        self.allow_at(ip, Instant::now())
+    }
+
+    /// What a source is tracked by: IPv4 addresses (mapped ones included) and IPv6 /64 prefixes
+    fn source(ip: IpAddr) -> IpAddr {
+        match ip {
+            IpAddr::V4(_) => ip,
+            IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+                Some(v4) => IpAddr::V4(v4),
+                None => {
+                    let mut octets = v6.octets();
+                    octets[8..].fill(0);
+                    IpAddr::V6(Ipv6Addr::from(octets))
+                }
+            },
+        }
+    }
+
+    fn allow_at(&mut self, ip: IpAddr, now: Instant) -> bool {
+        let ip = Self::source(ip);
+
+        if !self.buckets.contains_key(&ip) && self.buckets.len() >= Self::MAX_SOURCES {
+            // Buckets that refilled completely are the same as no bucket
+            let (rate, burst) = (self.rate, self.burst);
+            self.buckets.retain(|_, bucket| Self::refill(bucket, now, rate, burst) < burst);
+
+            // Still full: the table is being flooded, new sources wait for it to drain
+            if self.buckets.len() >= Self::MAX_SOURCES {
+                return false;
+            }
+        }
+
+        let bucket = self.buckets.entry(ip).or_insert(Bucket { tokens: self.burst, updated: now });
+        if Self::refill(bucket, now, self.rate, self.burst) < 1.0 {
+            return false;
+        }
+
+        bucket.tokens -= 1.0;
+        true
+    }
+
+    fn refill(bucket: &mut Bucket, now: Instant, rate: f64, burst: f64) -> f64 {
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(burst);
+        bucket.updated = now;
+        bucket.tokens
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn address(address: &str) -> SocketAddr {
+        address.parse().unwrap()
+    }
+
+    #[test]
+    fn cookie_round_trip() {
+        let jar = CookieJar::new().unwrap();
+        let host = address("192.0.2.1:4000");
+        assert!(jar.verify(host, &jar.issue(host)));
+    }
+
+    #[test]
+    fn cookie_expires() {
+        let jar = CookieJar::new().unwrap();
+        let host = address("192.0.2.1:4000");
+        let cookie = jar.issue_at(host, 100);
+
+        assert!(jar.verify_at(host, &cookie, 100 + 30));
+        assert!(!jar.verify_at(host, &cookie, 100 + 31));
+        assert!(!jar.verify_at(host, &cookie, 99), "issued in the future");
+    }
+
+    #[test]
+    fn cookie_is_bound_to_its_address() {
+        let jar = CookieJar::new().unwrap();
+        let cookie = jar.issue_at(address("192.0.2.1:4000"), 10);
+
+        assert!(!jar.verify_at(address("192.0.2.2:4000"), &cookie, 10));
+        assert!(!jar.verify_at(address("192.0.2.1:4001"), &cookie, 10));
+        assert!(!jar.verify_at(address("[2001:db8::1]:4000"), &cookie, 10));
+    }
+
+    #[test]
+    fn cookie_is_bound_to_its_server() {
+        let (jar, other) = (CookieJar::new().unwrap(), CookieJar::new().unwrap());
+        let host = address("192.0.2.1:4000");
+        assert!(!other.verify_at(host, &jar.issue_at(host, 10), 10));
+    }
+
+    #[test]
+    fn cookie_issue_time_is_authenticated() {
+        let jar = CookieJar::new().unwrap();
+        let host = address("192.0.2.1:4000");
+        let mut cookie = jar.issue_at(host, 10);
+        cookie[..4].copy_from_slice(&50u32.to_le_bytes());
+        assert!(!jar.verify_at(host, &cookie, 50));
+    }
+
+    #[test]
+    fn bucket_refills() {
+        let mut limiter = RateLimiter::new(2.0, 3);
+        let ip = "192.0.2.1".parse().unwrap();
+        let start = Instant::now();
+
+        for _ in 0..3 {
+            assert!(limiter.allow_at(ip, start));
+        }
+        assert!(!limiter.allow_at(ip, start));
+
+        // One token every half second, never more than the burst
+        assert!(!limiter.allow_at(ip, start + Duration::from_millis(400)));
+        assert!(limiter.allow_at(ip, start + Duration::from_millis(500)));
+        assert!(!limiter.allow_at(ip, start + Duration::from_millis(500)));
+
+        let later = start + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert!(limiter.allow_at(ip, later));
+        }
+        assert!(!limiter.allow_at(ip, later));
+    }
+
+    #[test]
+    fn sources_share_buckets() {
+        let mut limiter = RateLimiter::new(1.0, 1);
+        let now = Instant::now();
+
+        assert!(limiter.allow_at("2001:db8::1".parse().unwrap(), now));
+        assert!(!limiter.allow_at("2001:db8::ffff:2".parse().unwrap(), now), "same /64");
+        assert!(limiter.allow_at("2001:db8:0:1::1".parse().unwrap(), now));
+
+        assert!(limiter.allow_at("192.0.2.1".parse().unwrap(), now));
+        assert!(!limiter.allow_at("::ffff:192.0.2.1".parse().unwrap(), now), "mapped IPv4");
+    }
+
+    #[test]
+    fn flood_of_sources_is_bounded() {
+        let mut limiter = RateLimiter::new(1.0, 2);
+        let now = Instant::now();
+
+        for i in 0..RateLimiter::MAX_SOURCES as u32 {
+            assert!(limiter.allow_at(IpAddr::V4(i.into()), now));
+        }
+        assert!(!limiter.allow_at("192.0.2.1".parse().unwrap(), now));
+
+        // Once the buckets refilled they are dropped to make room
+        assert!(limiter.allow_at("192.0.2.1".parse().unwrap(), now + Duration::from_secs(2)));
+        assert!(limiter.buckets.len() <= RateLimiter::MAX_SOURCES);
+    }
+}
@@ -0,0 +1,402 @@
+use std::fs;
+
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hkdf::Hkdf;
+use sha2::Sha256;
+
+use crate::commands::KeyArgs;
+use crate::packet::{Header, Kind, ProtocolError};
+
+/// Key both sides were started with, every session key is derived from it
+#[derive(Clone)]
+pub struct PreSharedKey(Vec<u8>);
+
+impl PreSharedKey {
+    /// Reads the key from `--key` or `--key-file`, None if neither was given
+    pub fn load(args: &KeyArgs) -> Result<Option<Self>, String> {
+        let key = match (&args.key, &args.key_file) {
+            (Some(key), _) => key.clone(),
+            (None, Some(path)) => fs::read_to_string(path)
+                .map_err(|e| format!("Error reading key file {}: {}", path.display(), e))?
+                .trim()
+                .to_string(),
+            (None, None) => return Ok(None),
+        };
+
+        if key.is_empty() {
+            return Err(String::from("Key must not be empty"));
+        }
+
+        Ok(Some(Self(key.into_bytes())))
+    }
+}
+
+/// Sliding window of the last sequence numbers received, to reject replayed datagrams
+#[derive(Default)]
+struct ReplayWindow {
+    highest: Option<u64>,
+    window: u64, // Bit i set -> `highest - i` was received
+}
+
+impl ReplayWindow {
+    fn check(&self, seq: u64) -> bool {
+        match self.highest {
+            None => true,
+            Some(highest) if seq > highest => true,
+            Some(highest) => {
+                let behind = highest - seq;
+                behind < 64 && self.window & (1 << behind) == 0
+            }
+        }
+    }
+
+    /// Only called once the datagram authenticated, so forged sequence numbers can't move the window
+    fn accept(&mut self, seq: u64) {
+        match self.highest {
+            Some(highest) if seq <= highest => self.window |= 1 << (highest - seq),
+            Some(highest) => {
+                let ahead = seq - highest;
+                self.window = if ahead >= 64 { 1 } else { (self.window << ahead) | 1 };
+                self.highest = Some(seq);
+            }
+            None => {
+                self.highest = Some(seq);
+                self.window = 1;
+            }
+        }
+    }
+}
+
+/// Sequence space a datagram is sealed in, each with its own counter and replay window
+/// Frames and control messages take different paths (QUIC datagrams and a stream, or the frame and
+/// control queues of a sender), a burst of one must not push the other out of the replay window
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Channel {
+    Frames = 0,
+    Control = 1,
+}
+
+impl Channel {
+    /// Top bit of the sequence numbers of the channel, so the two spaces never share a nonce
+    fn bit(self) -> u64 {
+        (self as u64) << 63
+    }
+
+    fn of(seq: u64) -> Self {
+        if seq >> 63 == 0 {
+            Channel::Frames
+        } else {
+            Channel::Control
+        }
+    }
+}
+
+/// Keys and replay windows for the datagrams of one sender
+struct Peer {
+    salt: [u8; Session::SALT_SIZE],
+    receive: ChaCha20Poly1305,
+    replay: [ReplayWindow; 2], // Indexed by `Channel`
+}
+
+/// Encryption state of one client-server session
+/// Sealed datagram: | header | salt (16) | seq (8) | ciphertext + tag (16) |
+/// Each side picks a random salt and writes it in the datagrams it seals. The client to server key is
+/// derived from the client's salt and the pre-shared key with HKDF-SHA256, the server to client key from
+/// both salts, so a server session never reuses the keys of another even if its hello was replayed.
+/// The nonce is the datagram sequence number, which never repeats under a key: its top bit is the
+/// `Channel` and the rest counts up in that channel. The header, salt and sequence number are
+/// authenticated as associated data
+pub struct Session {
+    key: PreSharedKey,
+    id: [u8; Session::SALT_SIZE], // Salt of the client, or of the group, the session is found by
+    salt: [u8; Session::SALT_SIZE], // Written in the datagrams we seal
+    send: ChaCha20Poly1305,
+    next_seq: [u64; 2],                     // Indexed by `Channel`
+    peer: Option<Peer>,                     // None until a client hears from the server
+    retired: Vec<[u8; Session::SALT_SIZE]>, // Server sessions a client moved on from, never taken back
+}
+
+impl Session {
+    pub const SALT_SIZE: usize = 16;
+    const TAG_SIZE: usize = 16;
+    const PREFIX_SIZE: usize = Header::SIZE + Self::SALT_SIZE + 8;
+
+    /// Bytes sealing adds to a datagram
+    pub const OVERHEAD: usize = Self::PREFIX_SIZE + Self::TAG_SIZE;
+
+    const CLIENT_TO_SERVER: &'static [u8] = b"screen-stream client to server";
+    const SERVER_TO_CLIENT: &'static [u8] = b"screen-stream server to client";
+    const GROUP: &'static [u8] = b"screen-stream group";
+
+    /// Starts a new session with a random salt, the server's keys are learnt from its first datagram
+    pub fn client(key: &PreSharedKey) -> Result<Self, getrandom::Error> {
+        let salt = Self::random_salt()?;
+        let send = Self::derive(key, &salt, Self::CLIENT_TO_SERVER);
+        Ok(Self { key: key.clone(), id: salt, salt, send, next_seq: [0; 2], peer: None, retired: Vec::new() })
+    }
+
+    /// Answers the client whose datagrams carry `client_salt`, with keys no other server session has
+    pub fn server(key: &PreSharedKey, client_salt: [u8; Self::SALT_SIZE]) -> Result<Self, getrandom::Error> {
+        let salt = Self::random_salt()?;
+        let send = Self::derive(key, &[client_salt, salt].concat(), Self::SERVER_TO_CLIENT);
+        let receive = Self::derive(key, &client_salt, Self::CLIENT_TO_SERVER);
+        let peer = Peer { salt: client_salt, receive, replay: Default::default() };
+        Ok(Self {
+            key: key.clone(),
+            id: client_salt,
+            salt,
+            send,
+            next_seq: [0; 2],
+            peer: Some(peer),
+            retired: Vec::new(),
+        })
+    }
+
+    /// Session of a multicast group, the server seals and every viewer opens with the same key
+    /// The server picks a new random `salt` for every group it opens
+    pub fn group(key: &PreSharedKey, salt: [u8; Self::SALT_SIZE]) -> Self {
+        let peer = Peer { salt, receive: Self::derive(key, &salt, Self::GROUP), replay: Default::default() };
+        let send = Self::derive(key, &salt, Self::GROUP);
+        Self { key: key.clone(), id: salt, salt, send, next_seq: [0; 2], peer: Some(peer), retired: Vec::new() }
+    }
+
+    fn random_salt() -> Result<[u8; Self::SALT_SIZE], getrandom::Error> {
+        let mut salt = [0u8; Self::SALT_SIZE];
+        getrandom::getrandom(&mut salt)?;
+        Ok(salt)
+    }
+
+    fn derive(key: &PreSharedKey, salt: &[u8], info: &[u8]) -> ChaCha20Poly1305 {
+        let mut derived = [0u8; 32];
+        Hkdf::<Sha256>::new(Some(salt), &key.0)
+            .expand(info, &mut derived)
+            .expect("32 bytes is a valid HKDF-SHA256 output length");
+        ChaCha20Poly1305::new(Key::from_slice(&derived))
+    }
+
+    /// Salt of the client that started the session (or of the group), the one its datagrams carry
+    pub fn salt(&self) -> [u8; Self::SALT_SIZE] {
+        self.id
+    }
+
+    /// Stops accepting the server session heard from so far, for good, and takes the next one that
+    /// authenticates. A client does it once it got a cookie, which the server sends from a session it doesn't keep
+    pub fn forget_server(&mut self) {
+        if let Some(peer) = self.peer.take() {
+            self.retired.push(peer.salt);
+        }
+    }
+
+    fn nonce(seq: u64) -> [u8; 12] {
+        let mut nonce = [0u8; 12];
+        nonce[4..].copy_from_slice(&seq.to_le_bytes());
+        nonce
+    }
+
+    /// Encrypts a datagram, a frame packet or a control message depending on `channel`
+    pub fn seal(&mut self, channel: Channel, datagram: &[u8]) -> Vec<u8> {
+        let seq = self.next_seq[channel as usize] | channel.bit();
+        self.next_seq[channel as usize] += 1;
+
+        let mut bytes = Vec::with_capacity(Self::OVERHEAD + datagram.len());
+        Header::write(Kind::Sealed, &mut bytes);
+        bytes.extend_from_slice(&self.salt);
+        bytes.extend_from_slice(&seq.to_le_bytes());
+
+        let ciphertext = self
+            .send
+            .encrypt(Nonce::from_slice(&Self::nonce(seq)), Payload { msg: datagram, aad: &bytes })
+            .expect("ChaCha20-Poly1305 can encrypt any datagram");
+
+        bytes.extend_from_slice(&ciphertext);
+        bytes
+    }
+
+    /// Decrypts a sealed datagram, rejecting anything forged, tampered with or replayed
+    pub fn open(&mut self, sealed: &[u8]) -> Result<Vec<u8>, ProtocolError> {
+        let salt = Session::salt_of(sealed)?;
+        let (prefix, ciphertext) = sealed.split_at(Self::PREFIX_SIZE);
+
+        let mut seq = [0u8; 8];
+        seq.copy_from_slice(&prefix[Header::SIZE + Self::SALT_SIZE..]);
+        let seq = u64::from_le_bytes(seq);
+        let nonce = Self::nonce(seq);
+        let channel = Channel::of(seq) as usize;
+
+        match &mut self.peer {
+            Some(peer) if peer.salt == salt => {
+                if !peer.replay[channel].check(seq) {
+                    return Err(ProtocolError::Replayed(seq));
+                }
+
+                let datagram = peer
+                    .receive
+                    .decrypt(Nonce::from_slice(&nonce), Payload { msg: ciphertext, aad: prefix })
+                    .map_err(|_| ProtocolError::Undecryptable)?;
+
+                peer.replay[channel].accept(seq);
+                Ok(datagram)
+            }
+            Some(_) => Err(ProtocolError::Undecryptable),
+            None if self.retired.contains(&salt) => Err(ProtocolError::Undecryptable),
+            // First datagram of a server session, only taken once it authenticated
+            None => {
+                let receive = Self::derive(&self.key, &[self.id, salt].concat(), Self::SERVER_TO_CLIENT);
+                let datagram = receive
+                    .decrypt(Nonce::from_slice(&nonce), Payload { msg: ciphertext, aad: prefix })
+                    .map_err(|_| ProtocolError::Undecryptable)?;
+
+                let mut replay: [ReplayWindow; 2] = Default::default();
+                replay[channel].accept(seq);
+                self.peer = Some(Peer { salt, receive, replay });
+                Ok(datagram)
+            }
+        }
+    }
+
+    /// Reads the salt of a sealed datagram, the sender's, to find the session it belongs to
+    pub fn salt_of(sealed: &[u8]) -> Result<[u8; Self::SALT_SIZE], ProtocolError> {
+        match Header::read(sealed)? {
+            Kind::Sealed => {}
+            kind => return Err(ProtocolError::UnknownKind(kind as u8)),
+        }
+
+        if sealed.len() < Self::OVERHEAD {
+            return Err(ProtocolError::Truncated { expected: Self::OVERHEAD, actual: sealed.len() });
+        }
+
+        let mut salt = [0u8; Self::SALT_SIZE];
+        salt.copy_from_slice(&sealed[Header::SIZE..Header::SIZE + Self::SALT_SIZE]);
+        Ok(salt)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key() -> PreSharedKey {
+        PreSharedKey(b"correct horse battery staple".to_vec())
+    }
+
+    /// A client session and the server session that answers it
+    fn pair() -> (Session, Session) {
+        let mut client = Session::client(&key()).unwrap();
+        let hello = client.seal(Channel::Control, b"hello");
+        let mut server = Session::server(&key(), Session::salt_of(&hello).unwrap()).unwrap();
+        assert_eq!(server.open(&hello).unwrap(), b"hello");
+        (client, server)
+    }
+
+    #[test]
+    fn round_trip() {
+        let (mut client, mut server) = pair();
+        assert_eq!(client.open(&server.seal(Channel::Control, b"accept")).unwrap(), b"accept");
+        assert_eq!(server.open(&client.seal(Channel::Control, b"ping")).unwrap(), b"ping");
+        assert_eq!(server.salt(), client.salt());
+    }
+
+    #[test]
+    fn replayed_hello_gets_new_server_keys() {
+        let mut client = Session::client(&key()).unwrap();
+        let hello = client.seal(Channel::Control, b"hello");
+        let salt = Session::salt_of(&hello).unwrap();
+
+        let mut first = Session::server(&key(), salt).unwrap();
+        let mut second = Session::server(&key(), salt).unwrap();
+        first.open(&hello).unwrap();
+        second.open(&hello).unwrap();
+
+        // Same sequence number and plaintext, yet nothing in common
+        let (a, b) = (first.seal(Channel::Control, b"accept"), second.seal(Channel::Control, b"accept"));
+        assert_ne!(
+            a[Header::SIZE..Header::SIZE + Session::SALT_SIZE],
+            b[Header::SIZE..Header::SIZE + Session::SALT_SIZE]
+        );
+        assert_ne!(a[Session::PREFIX_SIZE..], b[Session::PREFIX_SIZE..]);
+
+        // The client sticks to the server session it heard first
+        assert!(client.open(&a).is_ok());
+        assert!(matches!(client.open(&b), Err(ProtocolError::Undecryptable)));
+    }
+
+    #[test]
+    fn rejects_replays_and_forgeries() {
+        let (mut client, mut server) = pair();
+        let sealed = server.seal(Channel::Frames, b"frame");
+        assert!(client.open(&sealed).is_ok());
+        assert!(matches!(client.open(&sealed), Err(ProtocolError::Replayed(0))));
+
+        let mut tampered = server.seal(Channel::Frames, b"frame");
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(matches!(client.open(&tampered), Err(ProtocolError::Undecryptable)));
+
+        let other = PreSharedKey(b"wrong key".to_vec());
+        let mut stranger = Session::server(&other, client.salt()).unwrap();
+        let mut fresh = Session::client(&key()).unwrap();
+        let forged = stranger.seal(Channel::Control, b"cookie");
+        assert!(matches!(fresh.open(&forged), Err(ProtocolError::Undecryptable)));
+    }
+
+    #[test]
+    fn forgotten_server_sessions_are_not_taken_back() {
+        let mut client = Session::client(&key()).unwrap();
+        let hello = client.seal(Channel::Control, b"hello");
+        let salt = Session::salt_of(&hello).unwrap();
+
+        let mut cookie = Session::server(&key(), salt).unwrap();
+        let first = cookie.seal(Channel::Control, b"cookie");
+        let second = cookie.seal(Channel::Control, b"cookie");
+        client.open(&first).unwrap();
+        client.forget_server();
+        assert!(matches!(client.open(&second), Err(ProtocolError::Undecryptable)));
+
+        let mut server = Session::server(&key(), salt).unwrap();
+        assert_eq!(client.open(&server.seal(Channel::Control, b"accept")).unwrap(), b"accept");
+    }
+
+    #[test]
+    fn group() {
+        let salt = [7u8; Session::SALT_SIZE];
+        let mut server = Session::group(&key(), salt);
+        let mut viewer = Session::group(&key(), salt);
+        let sealed = server.seal(Channel::Frames, b"frame");
+        assert_eq!(viewer.open(&sealed).unwrap(), b"frame");
+        assert!(matches!(viewer.open(&sealed), Err(ProtocolError::Replayed(0))));
+    }
+
+    #[test]
+    fn control_has_its_own_sequence_space() {
+        let (mut client, mut server) = pair();
+        let accept = server.seal(Channel::Control, b"accept");
+        let frames: Vec<Vec<u8>> = (0..100).map(|_| server.seal(Channel::Frames, b"frame")).collect();
+        let ping = client.seal(Channel::Control, b"ping");
+        assert_eq!(server.open(&ping).unwrap(), b"ping");
+
+        // A burst of frames arrives before the control message sealed ahead of it, which still opens
+        for frame in &frames {
+            assert_eq!(client.open(frame).unwrap(), b"frame");
+        }
+        assert_eq!(client.open(&accept).unwrap(), b"accept");
+        assert!(matches!(client.open(&accept), Err(ProtocolError::Replayed(_))));
+
+        // The first of each channel is sequence 0 of its space, under different nonces
+        assert_ne!(
+            accept[Header::SIZE + Session::SALT_SIZE..Session::PREFIX_SIZE],
+            frames[0][Header::SIZE + Session::SALT_SIZE..Session::PREFIX_SIZE]
+        );
+    }
+
+    #[test]
+    fn replay_window() {
+        let mut window = ReplayWindow::default();
+        for seq in [5, 3, 70, 69] {
+            assert!(window.check(seq));
+            window.accept(seq);
+            assert!(!window.check(seq));
+        }
+        assert!(!window.check(5), "too far behind");
+        assert!(window.check(68));
+    }
+}
@@ -1,32 +1,168 @@
+use std::cmp::Ordering;
 use std::collections::HashMap;
+use std::fmt;
+use std::time::{Duration, Instant};
 
-use crate::packet::Packet;
+use crate::packet::{seq_cmp, Packet};
+
+/// Loss, reordering and duplication statistics computed from packet sequence numbers
+#[derive(Default, Clone, Copy)]
+pub struct SequenceStats {
+    pub received: u64,       // Packets seen, including duplicates
+    pub lost: u64,           // Sequence numbers skipped and not filled in later
+    pub reordered: u64,      // Packets that arrived after a newer one
+    pub duplicates: u64,     // Packets whose sequence number was already seen
+    pub late: u64,           // Packets that arrived after their frame was shown or dropped
+    pub frames_dropped: u64, // Frames that never completed before a newer one did
+    highest: Option<u32>,    // Highest sequence number seen so far
+    window: u64,             // Bit i set -> sequence number `highest - i` was received
+    checked: Option<u32>,    // Highest sequence number `take_missing` looked for gaps up to
+}
+
+impl SequenceStats {
+    /// How far behind the highest sequence number packets are still tracked
+    const WINDOW: u32 = 64;
+
+    /// Records a packet sequence number
+    /// Returns false if the packet is a duplicate or too old to be tracked
+    pub fn record(&mut self, seq: u32) -> bool {
+        self.received += 1;
+
+        let highest = match self.highest {
+            Some(highest) => highest,
+            None => {
+                self.highest = Some(seq);
+                self.window = 1;
+                self.checked = Some(seq);
+                return true;
+            }
+        };
+
+        // Newer than anything seen -> everything in between is (for now) lost
+        if seq_cmp(seq, highest) == Ordering::Greater {
+            let ahead = seq.wrapping_sub(highest);
+            self.lost += u64::from(ahead - 1);
+            self.window = if ahead >= Self::WINDOW { 1 } else { (self.window << ahead) | 1 };
+            self.highest = Some(seq);
+            return true;
+        }
+
+        let behind = highest.wrapping_sub(seq);
+        if behind >= Self::WINDOW {
+            self.late += 1;
+            return false;
+        }
+
+        let bit = 1u64 << behind;
+        if self.window & bit != 0 {
+            self.duplicates += 1;
+            return false;
+        }
+
+        // Fills a gap that was counted as lost
+        self.window |= bit;
+        self.reordered += 1;
+        self.lost = self.lost.saturating_sub(1);
+        true
+    }
+
+    /// Highest sequence number seen so far
+    pub fn highest(&self) -> Option<u32> {
+        self.highest
+    }
+
+    /// Sequence numbers skipped since the last call and still missing, oldest first
+    /// Gaps that fell out of the window are given up on
+    pub fn take_missing(&mut self) -> Vec<u32> {
+        let highest = match self.highest {
+            Some(highest) => highest,
+            None => return Vec::new(),
+        };
+
+        let checked = self.checked.replace(highest).unwrap_or(highest);
+        let span = highest.wrapping_sub(checked).min(Self::WINDOW);
+        (1..span)
+            .rev()
+            .filter(|&behind| self.window & (1 << behind) == 0)
+            .map(|behind| highest.wrapping_sub(behind))
+            .collect()
+    }
+
+    /// Starts over from the next sequence number, counters are kept
+    pub fn restart(&mut self) {
+        self.highest = None;
+        self.window = 0;
+        self.checked = None;
+    }
+}
+
+impl fmt::Display for SequenceStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "received: {}, lost: {}, reordered: {}, duplicates: {}, late: {}, frames dropped: {}",
+            self.received, self.lost, self.reordered, self.duplicates, self.late, self.frames_dropped
+        )
+    }
+}
 
 /// Data structure to store frame packets
 /// Ensures that only 3 frames are stored at a time
+/// Frames are ordered by frame id (wrapping), not by arrival
 pub struct FrameBuffer {
     pub frames : HashMap<u32, Vec<Packet>>,
-    order: Vec<u32> // Order of frames
+    order: Vec<u32>, // Order of frames, oldest first
+    arrivals: HashMap<u32, Instant>, // When the first packet of each frame arrived
+    last_frame: Option<u32>, // Last frame returned, anything at or before it is stale
+    last_arrival: Option<Instant>, // First packet arrival of the last frame returned
+    pub stats: SequenceStats,
+    jitter: Jitter,
+}
+
+/// Variation of the time between frames, smoothed like RTP inter-arrival jitter (RFC 3550)
+/// Packets carry no send timestamp, so the frame interval stands in for transit time
+#[derive(Default)]
+struct Jitter {
+    previous: Option<Instant>,        // Arrival of the previous new frame
+    previous_interval: Option<Duration>,
+    value_us: f64,
+}
+
+impl Jitter {
+    fn on_frame(&mut self, arrival: Instant) {
+        if let Some(previous) = self.previous {
+            let interval = arrival.saturating_duration_since(previous);
+            if let Some(previous_interval) = self.previous_interval {
+                let difference = (interval.as_secs_f64() - previous_interval.as_secs_f64()).abs() * 1e6;
+                self.value_us += (difference - self.value_us) / 16.0;
+            }
+            self.previous_interval = Some(interval);
+        }
+        self.previous = Some(arrival);
+    }
 }
 
 
 /// Possible results when getting a frame from the frame buffer
-/// NoFrame - No frame is available
-/// NonSequential - Frame is not complete or packets are sequential
+/// NoFrame - No frame is complete yet
 /// Ok(Vec<u8>) - Frame is complete and the data is returned as a Vec<u8>
 pub enum GetFrameResult {
     NoFrame,
-    NonSequential(Vec<Packet>),
     Ok(Vec<u8>)
 }
 
 impl FrameBuffer {
-    const MAX_FRAMES: usize = 2;
+    const MAX_FRAMES: usize = 3;
 
     pub fn new() -> Self {
         Self {
             frames: HashMap::new(),
-            order: Vec::new()
+            order: Vec::new(),
+            arrivals: HashMap::new(),
+            last_frame: None,
+            last_arrival: None,
+            stats: SequenceStats::default(),
+            jitter: Jitter::default(),
         }
     }
 
@@ -35,7 +171,11 @@ impl FrameBuffer {
     /// Ensures packets are added in order
     /// This function should be called after adding the frame
     fn add_packet_to_frame(&mut self, packet: Packet) {
-        let frame = self.frames.get_mut(&packet.frame_id).unwrap(); 
+        let frame = match self.frames.get_mut(&packet.frame_id) {
+            Some(frame) => frame,
+            None => return,
+        };
+
         if !frame.contains(&packet) {
             // Find the index to insert the packet
             let index = frame.iter().position(|p| p.index > packet.index).unwrap_or(frame.len());
@@ -43,99 +183,241 @@ impl FrameBuffer {
         }
     }
 
-    /// Creates a new frame
-    /// If the frame is already present, it will be overwritten
+    /// Creates a new frame, keeping frames sorted by id
     /// If the frame buffer has more than 3 frames, the oldest frame will be removed
-    fn create_frame(&mut self, frame_id: u32) {
-        if self.frames.len() >= Self::MAX_FRAMES {
+    /// Only frames newer than every buffered one count for the jitter, reordered ones say nothing of the interval
+    fn create_frame(&mut self, frame_id: u32, now: Instant) {
+        let index = self
+            .order
+            .iter()
+            .position(|&id| seq_cmp(id, frame_id) == Ordering::Greater)
+            .unwrap_or(self.order.len());
+
+        if index == self.order.len() {
+            self.jitter.on_frame(now);
+        }
+        self.order.insert(index, frame_id);
+        self.frames.insert(frame_id, Vec::new());
+        self.arrivals.insert(frame_id, now);
+
+        if self.order.len() > Self::MAX_FRAMES {
             let oldest_frame = self.order.remove(0);
             self.frames.remove(&oldest_frame);
+            self.arrivals.remove(&oldest_frame);
+            self.stats.frames_dropped += 1;
         }
-
-        self.frames.insert(frame_id, Vec::new());
-        self.order.push(frame_id);
     }
 
     /// Add a packet to the frame buffer
     /// If the frame is not present, create a new frame
+    /// Duplicates and packets of frames that were already returned are discarded
     pub fn add_packet(&mut self, packet: Packet) {
+        self.add_packet_at(packet, Instant::now());
+    }
+
+    fn add_packet_at(&mut self, packet: Packet, now: Instant) {
+        if !self.stats.record(packet.seq) {
+            return;
+        }
+
+        if let Some(last_frame) = self.last_frame {
+            if seq_cmp(packet.frame_id, last_frame) != Ordering::Greater {
+                self.stats.late += 1;
+                return;
+            }
+        }
 
         // Create new frame if not present
         if !self.frames.contains_key(&packet.frame_id) {
-            self.create_frame(packet.frame_id);
+            // With the buffer full, a frame older than all of it would be evicted at once: the packet is only late
+            let full = self.order.len() >= Self::MAX_FRAMES;
+            if full && seq_cmp(packet.frame_id, self.order[0]) == Ordering::Less {
+                self.stats.late += 1;
+                return;
+            }
+            self.create_frame(packet.frame_id, now);
         }
         // add packet to the frame
         self.add_packet_to_frame(packet);
-    }    
-
-
+    }
 
-    /// Get the oldest frame
-    /// If frame buffer is not *complete* next oldest frame will be returned
-    /// A complete frame is that whose last packet data size is lass than Packet::CHUNK_SIZE
-    /// If no frame is complete, None will be returned
-    pub fn get_frame(&mut self) -> GetFrameResult {
-        if self.frames.len() == 0 {
-            return GetFrameResult::NoFrame;
+    /// A complete frame has every packet from 0 to count - 1
+    fn is_complete(&self, frame_id: &u32) -> bool {
+        match self.frames.get(frame_id) {
+            Some(packets) => packets.first().is_some_and(|p| packets.len() == p.count as usize),
+            None => false,
         }
+    }
 
-        let mut frame_id = 0;
-
-        loop {
-            let frame = match self.frames.get(&self.order[frame_id]) {
-                Some(frame) => frame,
-                None => return GetFrameResult::NoFrame
-            };
 
-            // Check if frame is complete -- has a last packet
-            if frame.last().unwrap().data.len() < Packet::CHUNK_SIZE {
-                break;
-            }
-
-            frame_id += 1;
-            if frame_id >= Self::MAX_FRAMES {
-                return GetFrameResult::NoFrame;
-            }
-        }
-
-        let packets = self.frames.get(&self.order[frame_id]).unwrap();
+    /// Get the oldest complete frame
+    /// Incomplete frames older than it are dropped, they can no longer be shown in order
+    /// If no frame is complete, NoFrame will be returned
+    pub fn get_frame(&mut self) -> GetFrameResult {
+        let position = match self.order.iter().position(|id| self.is_complete(id)) {
+            Some(position) => position,
+            None => return GetFrameResult::NoFrame,
+        };
 
-        // Check if packets are sequential
-        if packets
-            .iter()
-            .enumerate()
-            .any(|(i, packet)| packet.index as usize != i)
-        {
-            return GetFrameResult::NonSequential(packets.to_vec());
+        for frame_id in self.order.drain(..position) {
+            self.frames.remove(&frame_id);
+            self.arrivals.remove(&frame_id);
+            self.stats.frames_dropped += 1;
         }
 
+        let frame_id = self.order.remove(0);
+        let packets = self.frames.remove(&frame_id).unwrap();
+        self.last_frame = Some(frame_id);
+        self.last_arrival = self.arrivals.remove(&frame_id);
 
         // Create frame buffer
         let buffer_size = packets
             .iter()
             .fold(0, |acc, packet| acc + packet.data.len());
-        
-        let mut buffer: Vec<u8> = Vec::with_capacity(buffer_size.into());
+
+        let mut buffer: Vec<u8> = Vec::with_capacity(buffer_size);
 
         for packet in packets {
-            // println!(
-            //     "Building frame: {} index: {}",
-            //     packet.frame_id, packet.index
-            // );
             buffer.extend_from_slice(&packet.data);
         }
 
-        // Remove frame from the buffer
-        self.frames.remove(&self.order[frame_id]);
+        GetFrameResult::Ok(buffer)
+    }
+
 
-        return GetFrameResult::Ok(buffer);
+    /// Sequence numbers lost since the last call, see `SequenceStats::take_missing`
+    pub fn take_missing(&mut self) -> Vec<u32> {
+        self.stats.take_missing()
     }
 
+    /// The next packet starts a new sequence: the server sends through another multicast group, numbered on its own
+    /// Frames are kept, their ids are the stream's whatever the group
+    pub fn resync(&mut self) {
+        self.stats.restart();
+    }
+
+    /// Id of the last frame returned
+    pub fn last_frame(&self) -> Option<u32> {
+        self.last_frame
+    }
+
+    /// When the first packet of the last frame returned arrived
+    pub fn last_arrival(&self) -> Option<Instant> {
+        self.last_arrival
+    }
+
+    /// Smoothed variation of the time between frames, in microseconds
+    pub fn jitter_us(&self) -> u32 {
+        self.jitter.value_us as u32
+    }
 
     /// Returns the number of frames in the buffer
     pub fn len(&self) -> usize {
         self.frames.len()
     }
+
+    /// Returns true if there are no frames in the buffer
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+}
+
+impl Default for FrameBuffer {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
 
+    fn packet(frame_id: u32, seq: u32, index: u8, count: u8) -> Packet {
+        Packet::new(0, frame_id, seq, index, count, &[index])
+    }
+
+    fn frame(buffer: &mut FrameBuffer) -> Option<Vec<u8>> {
+        match buffer.get_frame() {
+            GetFrameResult::Ok(data) => Some(data),
+            GetFrameResult::NoFrame => None,
+        }
+    }
+
+    #[test]
+    fn loss_is_counted_across_the_wrap() {
+        let mut stats = SequenceStats::default();
+        for seq in [u32::MAX - 2, u32::MAX - 1, 1, 2] {
+            assert!(stats.record(seq));
+        }
+        assert_eq!((stats.lost, stats.highest()), (2, Some(2)), "u32::MAX and 0 are missing");
+
+        // Filling one of the gaps after the wrap is a reorder, seeing it again a duplicate
+        assert!(stats.record(u32::MAX));
+        assert!(!stats.record(u32::MAX));
+        assert!(!stats.record(2));
+        assert_eq!((stats.received, stats.lost, stats.reordered, stats.duplicates), (7, 1, 1, 2));
+
+        // Too far behind to be tracked
+        assert!(!stats.record(2u32.wrapping_sub(SequenceStats::WINDOW)));
+        assert_eq!(stats.late, 1);
+    }
+
+    #[test]
+    fn missing_sequence_numbers_are_taken_across_the_wrap() {
+        let mut stats = SequenceStats::default();
+        for seq in [u32::MAX - 3, u32::MAX, 2] {
+            stats.record(seq);
+        }
+        assert_eq!(stats.take_missing(), [u32::MAX - 2, u32::MAX - 1, 0, 1]);
+        assert!(stats.take_missing().is_empty(), "each gap is only given once");
+
+        stats.record(5);
+        stats.record(1); // Late fill, already given up on
+        assert_eq!(stats.take_missing(), [3, 4]);
+    }
+
+    #[test]
+    fn frames_are_returned_in_wrapped_id_order() {
+        let mut buffer = FrameBuffer::new();
+        buffer.add_packet(packet(1, 10, 0, 1));
+        buffer.add_packet(packet(u32::MAX, 8, 0, 2));
+        buffer.add_packet(packet(0, 9, 0, 1));
+        assert_eq!(buffer.len(), 3);
+
+        // The oldest frame is incomplete, the newer complete one is shown and the oldest dropped
+        assert_eq!(frame(&mut buffer), Some(vec![0]));
+        assert_eq!((buffer.last_frame(), buffer.stats.frames_dropped), (Some(0), 1));
+        assert_eq!(frame(&mut buffer), Some(vec![0]));
+        assert_eq!(buffer.last_frame(), Some(1));
+        assert!(frame(&mut buffer).is_none());
+
+        // Packets of frames already shown are late
+        buffer.add_packet(packet(u32::MAX, 11, 1, 2));
+        assert_eq!(buffer.stats.late, 1);
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn packet_older_than_a_full_buffer_is_only_late() {
+        let mut buffer = FrameBuffer::new();
+        let start = Instant::now();
+        for (i, frame_id) in [0u32, 1, 2].into_iter().enumerate() {
+            buffer.add_packet_at(packet(frame_id.wrapping_sub(1), 10 + i as u32, 0, 2), start + Duration::from_millis(33 * i as u64));
+        }
+        assert_eq!(buffer.jitter_us(), 0, "steady frames");
+
+        // A reordered packet of a frame before the three buffered ones, arriving long after
+        buffer.add_packet_at(packet(u32::MAX - 1, 9, 0, 2), start + Duration::from_millis(500));
+        assert_eq!((buffer.stats.late, buffer.stats.frames_dropped, buffer.len()), (1, 0, 3));
+        assert_eq!(buffer.jitter_us(), 0, "old frames don't count for the jitter");
+
+        // A frame between buffered ones is kept but doesn't count either
+        let mut buffer = FrameBuffer::new();
+        for (i, frame_id) in [0u32, 2].into_iter().enumerate() {
+            buffer.add_packet_at(packet(frame_id, i as u32, 0, 2), start + Duration::from_millis(33 * i as u64));
+        }
+        buffer.add_packet_at(packet(1, 2, 0, 2), start + Duration::from_millis(500));
+        buffer.add_packet_at(packet(3, 3, 0, 2), start + Duration::from_millis(66));
+        assert_eq!((buffer.len(), buffer.jitter_us()), (3, 0));
+    }
+}
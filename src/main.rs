@@ -1,11 +1,29 @@
 use clap::{Parser};
 
+mod auth;
 mod client;
+mod cookie;
+mod multicast;
 pub mod packet;
+mod peer;
+mod quic;
+mod rtp;
+mod rtsp;
 mod server;
+mod srtp;
+mod stun;
+mod web;
+mod source;
 pub mod frame_buffer;
 pub mod commands;
 pub mod comm;
+pub mod congestion;
+pub mod crypto;
+pub mod pacer;
+pub mod scale;
+pub mod sender;
+pub mod stats;
+pub mod transport;
 
 use commands::Cmds;
 
@@ -31,7 +49,7 @@ fn main() {
         }
 
         Cmds::Connect(connect) => {
-            let _ = client::run(connect.address);
+            let _ = client::run(connect);
         }
     }
 }
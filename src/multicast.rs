@@ -0,0 +1,252 @@
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::time::Duration;
+
+use socket2::{Domain, Protocol, Socket, Type};
+
+use crate::comm::Message;
+use crate::crypto::{self, PreSharedKey, Session};
+use crate::packet::{Packet, StreamId};
+use crate::sender::Sender;
+
+/// Largest datagram sent to a group, viewers that can't take it are sent to on their own
+/// A few IP fragments: losing one costs little to resend, and a 4K frame still fits in the 255 packets of a frame
+pub const MTU: usize = 8192;
+
+/// Frames of a channel kept to resend what viewers lost, about as many as their frame buffers hold
+const HISTORY_FRAMES: usize = 3;
+
+/// What a frame cost once sent to a group
+pub struct Sent {
+    pub bytes: usize,
+    pub packets: usize,
+    pub replaced: usize, // Frames replaced in the channel's queue before they were sent
+}
+
+/// One rendition of one stream, sent to a group of its own so viewers only receive the rendition they joined
+struct Channel {
+    group: SocketAddrV4,
+    sender: Sender,
+    session: Option<Session>, // Shared by every viewer of the group, when the stream is encrypted
+    next_seq: u32,            // Sequence number of the next packet sent to the group, wraps around
+    history: VecDeque<(u32, Vec<Vec<u8>>)>, // Packets of the last frames, before sealing, by sequence number of the first
+}
+
+/// The multicast groups of a server, one for every rendition of every stream: the group given to `start --multicast`
+/// for the first, then the next addresses in order, all on the same port. Packets the groups lose are resent to each
+/// viewer on its own, over its unicast session
+/// Without a key, nothing authenticates the packets of a group: any host that can reach it can send viewers frames
+pub struct Multicast {
+    channels: HashMap<(StreamId, u8), Channel>,
+}
+
+impl Multicast {
+    /// Opens a group for each `(stream, rendition)`, sent to from `interface` (the routing table picks one when
+    /// unspecified) with `ttl` as the time to live, 1 keeping packets on the local network
+    pub fn open(
+        group: SocketAddrV4,
+        ttl: u32,
+        interface: Ipv4Addr,
+        renditions: impl IntoIterator<Item = (StreamId, u8)>,
+        key: Option<&PreSharedKey>,
+    ) -> io::Result<Self> {
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+        socket.set_multicast_ttl_v4(ttl)?;
+        socket.set_multicast_if_v4(&interface)?;
+        socket.bind(&SocketAddr::from((interface, 0)).into())?;
+        // A full send buffer drops a packet rather than stall the group, viewers ask for it again
+        socket.set_nonblocking(true)?;
+        let socket: UdpSocket = socket.into();
+
+        let mut channels = HashMap::new();
+        for (offset, (stream, rendition)) in renditions.into_iter().enumerate() {
+            let ip = u32::from(*group.ip())
+                .checked_add(offset as u32)
+                .map(Ipv4Addr::from)
+                .filter(Ipv4Addr::is_multicast)
+                .ok_or_else(|| {
+                    let message = format!("{} groups from {} run past the multicast range", offset + 1, group.ip());
+                    io::Error::new(io::ErrorKind::InvalidInput, message)
+                })?;
+
+            // Every viewer of the group derives the same keys, from a salt the server picks and tells them
+            let session = match key {
+                Some(key) => {
+                    let mut salt = [0u8; Session::SALT_SIZE];
+                    getrandom::getrandom(&mut salt).map_err(|e| io::Error::other(e.to_string()))?;
+                    Some(Session::group(key, salt))
+                }
+                None => None,
+            };
+
+            let channel = Channel {
+                group: SocketAddrV4::new(ip, group.port()),
+                sender: Sender::spawn(socket.try_clone()?)?,
+                session,
+                next_seq: 0,
+                history: VecDeque::new(),
+            };
+            channels.insert((stream, rendition), channel);
+        }
+
+        Ok(Self { channels })
+    }
+
+    /// Group `rendition` of `stream` is sent to
+    pub fn group(&self, stream: StreamId, rendition: u8) -> Option<SocketAddrV4> {
+        self.channels.get(&(stream, rendition)).map(|channel| channel.group)
+    }
+
+    /// Tells a viewer of `rendition` of `stream` which group to join
+    pub fn message(&self, stream: StreamId, rendition: u8) -> Option<Message> {
+        self.channels.get(&(stream, rendition)).map(|channel| Message::Multicast {
+            rendition,
+            group: channel.group,
+            salt: channel.session.as_ref().map(Session::salt),
+        })
+    }
+
+    /// Packetizes a frame of `rendition` of `stream` and queues it on the group's sender, paced over `span`
+    pub fn send(
+        &mut self,
+        stream: StreamId,
+        rendition: u8,
+        frame_id: u32,
+        bytes: &[u8],
+        span: Duration,
+    ) -> Result<Sent, String> {
+        let channel = self
+            .channels
+            .get_mut(&(stream, rendition))
+            .ok_or_else(|| format!("No group for rendition {} of stream {}", rendition, stream))?;
+
+        let overhead = if channel.session.is_some() { Session::OVERHEAD } else { 0 };
+        let chunks: Vec<&[u8]> = bytes.chunks(MTU - Packet::META_SIZE - overhead).collect();
+        let count = u8::try_from(chunks.len()).map_err(|_| {
+            format!("Frame too large for {}: {} bytes in {} packets", channel.group, bytes.len(), chunks.len())
+        })?;
+
+        let first = channel.next_seq;
+        let packets: Vec<Vec<u8>> = chunks
+            .iter()
+            .enumerate()
+            .map(|(i, chunk)| {
+                let packet = Packet::new(stream, frame_id, channel.next_seq, i as u8, count, chunk);
+                channel.next_seq = channel.next_seq.wrapping_add(1);
+                packet.to_bytes()
+            })
+            .collect();
+
+        let group = SocketAddr::V4(channel.group);
+        let datagrams: Vec<(SocketAddr, Vec<u8>)> = packets
+            .iter()
+            .map(|packet| {
+                let datagram = match &mut channel.session {
+                    Some(session) => session.seal(crypto::Channel::Frames, packet),
+                    None => packet.clone(),
+                };
+                (group, datagram)
+            })
+            .collect();
+
+        let sent = Sent {
+            bytes: datagrams.iter().map(|(_, datagram)| datagram.len()).sum(),
+            packets: datagrams.len(),
+            replaced: channel.sender.frame(datagrams, span),
+        };
+
+        channel.history.push_back((first, packets));
+        if channel.history.len() > HISTORY_FRAMES {
+            channel.history.pop_front();
+        }
+        Ok(sent)
+    }
+
+    /// Packet `seq` of the group of `rendition` of `stream` before sealing, if it is recent enough to be resent
+    pub fn packet(&self, stream: StreamId, rendition: u8, seq: u32) -> Option<&[u8]> {
+        let channel = self.channels.get(&(stream, rendition))?;
+        channel.history.iter().find_map(|(first, packets)| {
+            packets.get(seq.wrapping_sub(*first) as usize).map(Vec::as_slice)
+        })
+    }
+
+    /// Why sending to a group failed, once: multicast doesn't work on this network
+    pub fn failure(&self) -> Option<(SocketAddrV4, io::Error)> {
+        self.channels.values().find_map(|channel| channel.sender.failure().map(|e| (channel.group, e)))
+    }
+}
+
+/// Socket a viewer receives what the server sends to `group` on, joined on the interface of `interface`, or the
+/// one the routing table picks when unspecified
+pub fn join(group: SocketAddrV4, interface: Ipv4Addr) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    // Several viewers on one host can join the same group
+    socket.set_reuse_address(true)?;
+    // Bound to the group so the socket doesn't get what the other groups send to the port. Windows can't bind to a
+    // multicast address, but only hands a socket the groups it joined anyway
+    let ip = if cfg!(windows) { Ipv4Addr::UNSPECIFIED } else { *group.ip() };
+    socket.bind(&SocketAddr::from((ip, group.port())).into())?;
+    socket.join_multicast_v4(group.ip(), &interface)?;
+    socket.set_nonblocking(true)?;
+    Ok(socket.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Groups sent to from the loopback interface, nothing has to receive them
+    fn multicast(key: Option<&PreSharedKey>) -> Multicast {
+        let group = SocketAddrV4::new(Ipv4Addr::new(239, 255, 77, 1), 5000);
+        Multicast::open(group, 0, Ipv4Addr::LOCALHOST, [(0, 0), (0, 1)], key).unwrap()
+    }
+
+    fn data(packet: &[u8]) -> Vec<u8> {
+        Packet::from_bytes(packet).unwrap().data
+    }
+
+    #[test]
+    fn groups_follow_each_other() {
+        let multicast = multicast(None);
+        assert_eq!(multicast.group(0, 1), Some(SocketAddrV4::new(Ipv4Addr::new(239, 255, 77, 2), 5000)));
+        assert_eq!(multicast.group(1, 0), None);
+
+        let last = SocketAddrV4::new(Ipv4Addr::new(239, 255, 255, 255), 5000);
+        assert!(Multicast::open(last, 0, Ipv4Addr::LOCALHOST, [(0, 0), (0, 1)], None).is_err());
+    }
+
+    #[test]
+    fn lost_packets_are_resent_until_they_are_too_old() {
+        let mut multicast = multicast(None);
+        let frame: Vec<u8> = (0..MTU * 2).map(|i| i as u8).collect();
+        let sent = multicast.send(0, 0, 0, &frame, Duration::ZERO).unwrap();
+        assert_eq!(sent.packets, 3);
+
+        // Lost by a viewer, and asked for again
+        let resent = multicast.packet(0, 0, 1).unwrap();
+        assert_eq!(Packet::from_bytes(resent).unwrap().seq, 1);
+        assert_eq!(data(resent), frame[MTU - Packet::META_SIZE..2 * (MTU - Packet::META_SIZE)]);
+        assert_eq!(multicast.packet(0, 0, 3), None, "not sent yet");
+        assert_eq!(multicast.packet(0, 1, 1), None, "sequence numbers are per group");
+
+        for frame_id in 1..=HISTORY_FRAMES as u32 {
+            multicast.send(0, 0, frame_id, &[frame_id as u8; 100], Duration::ZERO).unwrap();
+        }
+        assert_eq!(multicast.packet(0, 0, 1), None, "older than the history");
+        let newest = 3 + HISTORY_FRAMES as u32 - 1;
+        assert_eq!(data(multicast.packet(0, 0, newest).unwrap()), [HISTORY_FRAMES as u8; 100]);
+    }
+
+    #[test]
+    fn packets_are_kept_before_sealing() {
+        let args = crate::commands::KeyArgs { key: Some(String::from("correct horse battery staple")), key_file: None };
+        let key = PreSharedKey::load(&args).unwrap().unwrap();
+        let mut multicast = multicast(Some(&key));
+        multicast.send(0, 0, 0, b"frame", Duration::ZERO).unwrap();
+
+        // Resent over the viewer's own session, so kept in the clear
+        assert_eq!(data(multicast.packet(0, 0, 0).unwrap()), b"frame");
+        assert!(matches!(multicast.message(0, 0), Some(Message::Multicast { salt: Some(_), .. })));
+    }
+}
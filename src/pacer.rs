@@ -0,0 +1,148 @@
+use std::io;
+use std::net::SocketAddr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::transport::Transmit;
+
+/// Spreads datagrams over a time span instead of sending them in one burst
+/// Each datagram is due once the bytes before it would have been sent at an even rate
+pub struct Pacer {
+    span: Duration,
+}
+
+/// Outcome of sending a batch of datagrams
+#[derive(Default)]
+pub struct PaceResult {
+    pub sent: usize,                         // Bytes sent
+    pub dropped: usize,                      // Datagrams dropped because the socket buffer was full
+    pub failed: Vec<(SocketAddr, io::Error)>, // Destinations that failed, nothing more was sent to them
+}
+
+impl Pacer {
+    /// Sleeping for less than this is too imprecise, such datagrams are sent right away
+    const MIN_SLEEP: Duration = Duration::from_millis(1);
+
+    pub fn new(span: Duration) -> Self {
+        Self { span }
+    }
+
+    pub fn set_span(&mut self, span: Duration) {
+        self.span = span;
+    }
+
+    /// Sends every datagram in order, pacing them across the span
+    pub fn send(&self, socket: &impl Transmit, datagrams: &[(SocketAddr, Vec<u8>)]) -> PaceResult {
+        let mut result = PaceResult::default();
+
+        let total: usize = datagrams.iter().map(|(_, bytes)| bytes.len()).sum();
+        if total == 0 {
+            return result;
+        }
+
+        for ((address, bytes), due) in datagrams.iter().zip(self.schedule(datagrams, Instant::now())) {
+            if result.failed.iter().any(|(failed, _)| failed == address) {
+                continue;
+            }
+
+            let now = Instant::now();
+            if due > now + Self::MIN_SLEEP {
+                thread::sleep(due - now);
+            }
+
+            match socket.send_to(bytes, *address) {
+                Ok(amount) => result.sent += amount,
+                // Socket buffer is full, losing one datagram is better than stalling
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => result.dropped += 1,
+                Err(e) => result.failed.push((*address, e)),
+            }
+        }
+
+        result
+    }
+
+    /// When each datagram is due, for a batch that starts at `start`
+    fn schedule(&self, datagrams: &[(SocketAddr, Vec<u8>)], start: Instant) -> Vec<Instant> {
+        let total: usize = datagrams.iter().map(|(_, bytes)| bytes.len()).sum();
+        let mut offset = 0; // Bytes scheduled before the current datagram
+
+        datagrams
+            .iter()
+            .map(|(_, bytes)| {
+                let due = start + self.span.mul_f64(offset as f64 / total.max(1) as f64);
+                offset += bytes.len();
+                due
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// Records what it sends, refuses to send to `failing`, and has a full buffer for `full`
+    #[derive(Default)]
+    struct Recorder {
+        sent: Mutex<Vec<(SocketAddr, usize)>>,
+        failing: Option<SocketAddr>,
+        full: Option<SocketAddr>,
+    }
+
+    impl Transmit for Recorder {
+        fn send_to(&self, bytes: &[u8], address: SocketAddr) -> io::Result<usize> {
+            if Some(address) == self.failing {
+                return Err(io::ErrorKind::ConnectionRefused.into());
+            }
+            if Some(address) == self.full {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            self.sent.lock().unwrap().push((address, bytes.len()));
+            Ok(bytes.len())
+        }
+    }
+
+    fn address(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn datagrams_are_spread_over_the_span_by_size() {
+        let mut pacer = Pacer::new(Duration::from_millis(100));
+        let datagrams: Vec<_> = [100, 300, 100, 500].into_iter().map(|size| (address(1), vec![0; size])).collect();
+        let start = Instant::now();
+
+        let due: Vec<Duration> = pacer.schedule(&datagrams, start).into_iter().map(|due| due - start).collect();
+        assert_eq!(due, [0, 10, 40, 50].map(Duration::from_millis));
+
+        pacer.set_span(Duration::ZERO);
+        assert!(pacer.schedule(&datagrams, start).into_iter().all(|due| due == start));
+        assert_eq!(pacer.schedule(&[(address(1), Vec::new())], start), [start]);
+    }
+
+    #[test]
+    fn batch_takes_as_long_as_its_last_datagram_is_due() {
+        let pacer = Pacer::new(Duration::from_millis(40));
+        let datagrams: Vec<_> = (0..4).map(|_| (address(1), vec![0; 1000])).collect();
+        let recorder = Recorder::default();
+
+        let start = Instant::now();
+        let result = pacer.send(&recorder, &datagrams);
+        assert!(start.elapsed() >= Duration::from_millis(30) - Pacer::MIN_SLEEP, "sent in {:?}", start.elapsed());
+        assert_eq!((result.sent, result.dropped), (4000, 0));
+        assert_eq!(recorder.sent.lock().unwrap().len(), 4);
+    }
+
+    #[test]
+    fn full_buffers_drop_and_failed_destinations_are_skipped() {
+        let pacer = Pacer::new(Duration::ZERO);
+        let recorder = Recorder { failing: Some(address(2)), full: Some(address(3)), ..Recorder::default() };
+        let datagrams: Vec<_> = [1, 2, 3, 1, 2, 3].into_iter().map(|port| (address(port), vec![0; 10])).collect();
+
+        let result = pacer.send(&recorder, &datagrams);
+        assert_eq!((result.sent, result.dropped), (20, 2));
+        assert_eq!(result.failed.iter().map(|(failed, _)| *failed).collect::<Vec<_>>(), [address(2)]);
+        assert_eq!(*recorder.sent.lock().unwrap(), [(address(1), 10), (address(1), 10)]);
+    }
+}
@@ -1,43 +1,258 @@
 use std::cmp::Ordering;
+use std::fmt;
+
+/// Errors produced while decoding anything that came off the wire
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProtocolError {
+    /// Datagram is shorter than the header (or the payload it announces)
+    Truncated { expected: usize, actual: usize },
+
+    /// Datagram does not start with `Packet::MAGIC`
+    BadMagic([u8; 2]),
+
+    /// Datagram was produced by an incompatible protocol version
+    BadVersion(u8),
+
+    /// Datagram is larger than anything a peer is allowed to send
+    Oversized(usize),
+
+    /// Datagram kind is not one we know how to handle
+    UnknownKind(u8),
+
+    /// Control message tag is not one we know how to handle
+    UnknownMessage(u8),
+
+    /// String field is not valid UTF-8
+    InvalidString,
+
+    /// Codec id is not one we know
+    UnknownCodec(u8),
+
+    /// Credential kind is not one we know
+    UnknownCredential(u8),
+
+    /// Sealed datagram failed to authenticate: wrong key, or tampered with
+    Undecryptable,
+
+    /// Sealed datagram was already received
+    Replayed(u64),
+
+    /// Cleartext datagram while the stream is encrypted
+    NotEncrypted,
+
+    /// Sealed datagram while the stream is not encrypted
+    UnexpectedEncryption,
+
+    /// Checksum in the header does not match the datagram contents
+    BadChecksum { expected: u32, actual: u32 },
+
+    /// Packet index is outside of the frame it claims to belong to
+    BadIndex { index: u8, count: u8 },
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::Truncated { expected, actual } => {
+                write!(f, "truncated datagram: expected at least {} bytes, got {}", expected, actual)
+            }
+            ProtocolError::BadMagic(magic) => write!(f, "bad magic: {:02x?}", magic),
+            ProtocolError::BadVersion(version) => {
+                write!(f, "unsupported protocol version: {} (expected {})", version, Packet::VERSION)
+            }
+            ProtocolError::Oversized(size) => {
+                write!(f, "oversized datagram: {} bytes (limit {})", size, Packet::MAX_DATAGRAM)
+            }
+            ProtocolError::UnknownKind(kind) => write!(f, "unknown datagram kind: {}", kind),
+            ProtocolError::UnknownMessage(tag) => write!(f, "unknown control message: {}", tag),
+            ProtocolError::InvalidString => write!(f, "string field is not valid UTF-8"),
+            ProtocolError::UnknownCodec(codec) => write!(f, "unknown codec: {}", codec),
+            ProtocolError::UnknownCredential(kind) => write!(f, "unknown credential kind: {}", kind),
+            ProtocolError::Undecryptable => {
+                write!(f, "could not decrypt datagram: the key is wrong or the data was tampered with")
+            }
+            ProtocolError::Replayed(seq) => write!(f, "replayed datagram: sequence {} was already received", seq),
+            ProtocolError::NotEncrypted => write!(f, "stream is encrypted but the datagram is not, a key is required"),
+            ProtocolError::UnexpectedEncryption => {
+                write!(f, "datagram is encrypted but the stream is not, no key should be used")
+            }
+            ProtocolError::BadChecksum { expected, actual } => {
+                write!(f, "checksum mismatch: header says {:08x}, contents hash to {:08x}", expected, actual)
+            }
+            ProtocolError::BadIndex { index, count } => {
+                write!(f, "packet index {} out of range for a frame of {} packets", index, count)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+/// What a datagram carries, second byte after the magic and version
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Frame = 0,
+    Control = 1,
+    Sealed = 2, // Encrypted frame packet or control message, see `crypto::Session`
+}
+
+impl TryFrom<u8> for Kind {
+    type Error = ProtocolError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Kind::Frame),
+            1 => Ok(Kind::Control),
+            2 => Ok(Kind::Sealed),
+            _ => Err(ProtocolError::UnknownKind(value)),
+        }
+    }
+}
+
+/// Common datagram header
+/// | magic (2) | version (1) | kind (1) |
+pub struct Header;
+
+impl Header {
+    pub const SIZE: usize = 4;
+
+    pub fn write(kind: Kind, bytes: &mut Vec<u8>) {
+        bytes.extend_from_slice(&Packet::MAGIC);
+        bytes.push(Packet::VERSION);
+        bytes.push(kind as u8);
+    }
+
+    /// Validates the header and returns the kind of the datagram
+    pub fn read(bytes: &[u8]) -> Result<Kind, ProtocolError> {
+        if bytes.len() > Packet::MAX_DATAGRAM {
+            return Err(ProtocolError::Oversized(bytes.len()));
+        }
+
+        if bytes.len() < Self::SIZE {
+            return Err(ProtocolError::Truncated { expected: Self::SIZE, actual: bytes.len() });
+        }
+
+        if bytes[0..2] != Packet::MAGIC {
+            return Err(ProtocolError::BadMagic([bytes[0], bytes[1]]));
+        }
+
+        if bytes[2] != Packet::VERSION {
+            return Err(ProtocolError::BadVersion(bytes[2]));
+        }
+
+        Kind::try_from(bytes[3])
+    }
+}
+
+/// Serial number comparison (RFC 1982) for wrapping u32 sequences
+/// `a` is considered newer than `b` if it is less than half the sequence space ahead of it
+pub fn seq_cmp(a: u32, b: u32) -> Ordering {
+    (a.wrapping_sub(b) as i32).cmp(&0)
+}
+
+/// Id of one of the streams a server publishes, in every frame packet
+pub type StreamId = u8;
 
 // UDP packet
+// | header | stream (1) | frame_id (4) | seq (4) | index (1) | count (1) | crc32 (4) | data |
 pub struct Packet {
-    pub index: u8,      // First byte Index of the packet
-    pub frame_id: u32,  // Frame ID
+    pub stream: StreamId, // Stream the frame belongs to
+    pub frame_id: u32,  // Frame sequence number, increases by one per frame and wraps
+    pub seq: u32,       // Packet sequence number, increases by one per packet sent and wraps
+    pub index: u8,      // Index of the packet within the frame
+    pub count: u8,      // Number of packets the frame was split into
     pub data: Vec<u8>, // Data of the packet
 }
 
 impl Packet {
-    pub const META_SIZE : usize = 5;
-    
+    pub const MAGIC: [u8; 2] = *b"SS";
+    pub const VERSION: u8 = 10;
+
+    // Header + stream + frame_id + seq + index + count + crc32
+    pub const META_SIZE : usize = Header::SIZE + 15;
+
+    // Offset of the CRC32, it covers every byte of the datagram except itself
+    const CHECKSUM_OFFSET : usize = Header::SIZE + 11;
+
     // Limit 65507
     pub const CHUNK_SIZE : usize = 65000;
 
-    pub fn new(index: u8, frame_id: u32, data: &[u8]) -> Self {
-        Self { index, frame_id, data: data.to_vec() }
+    // Largest UDP payload over IPv4
+    pub const MAX_DATAGRAM : usize = 65507;
+
+    pub fn new(stream: StreamId, frame_id: u32, seq: u32, index: u8, count: u8, data: &[u8]) -> Self {
+        Self { stream, frame_id, seq, index, count, data: data.to_vec() }
     }
 
     pub fn to_bytes(&self) -> Vec<u8> {
-        let mut bytes = vec![self.index.to_le_bytes()[0]];
+        let mut bytes = Vec::with_capacity(Self::META_SIZE + self.data.len());
+        Header::write(Kind::Frame, &mut bytes);
+        bytes.push(self.stream);
         bytes.extend_from_slice(&self.frame_id.to_le_bytes());
+        bytes.extend_from_slice(&self.seq.to_le_bytes());
+        bytes.push(self.index);
+        bytes.push(self.count);
+        bytes.extend_from_slice(&[0; 4]);
         bytes.extend_from_slice(&self.data);
+
+        let checksum = Self::checksum(&bytes);
+        bytes[Self::CHECKSUM_OFFSET..Self::META_SIZE].copy_from_slice(&checksum.to_le_bytes());
         bytes
     }
 
-    pub fn from_bytes(bytes: Vec<u8>) -> Self {
-        Self {
-            index: bytes[0],
-            frame_id: u32::from_le_bytes([bytes[1], bytes[2], bytes[3], bytes[4]]),
-            data: bytes[5..].to_vec(),
+    /// CRC32 of a serialized packet, skipping the checksum field
+    fn checksum(bytes: &[u8]) -> u32 {
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&bytes[..Self::CHECKSUM_OFFSET]);
+        hasher.update(&bytes[Self::META_SIZE..]);
+        hasher.finalize()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        match Header::read(bytes)? {
+            Kind::Frame => {}
+            kind => return Err(ProtocolError::UnknownKind(kind as u8)),
+        }
+
+        if bytes.len() < Self::META_SIZE {
+            return Err(ProtocolError::Truncated { expected: Self::META_SIZE, actual: bytes.len() });
+        }
+
+        if bytes.len() > Self::CHUNK_SIZE {
+            return Err(ProtocolError::Oversized(bytes.len()));
+        }
+
+        let body = &bytes[Header::SIZE..];
+
+        let expected = u32::from_le_bytes([body[11], body[12], body[13], body[14]]);
+        let actual = Self::checksum(bytes);
+        if expected != actual {
+            return Err(ProtocolError::BadChecksum { expected, actual });
         }
+
+        let packet = Self {
+            stream: body[0],
+            frame_id: u32::from_le_bytes([body[1], body[2], body[3], body[4]]),
+            seq: u32::from_le_bytes([body[5], body[6], body[7], body[8]]),
+            index: body[9],
+            count: body[10],
+            data: bytes[Self::META_SIZE..].to_vec(),
+        };
+
+        // A frame always has at least one packet and the index must fall inside it
+        if packet.index >= packet.count {
+            return Err(ProtocolError::BadIndex { index: packet.index, count: packet.count });
+        }
+
+        Ok(packet)
     }
 }
 
 
 impl PartialOrd for Packet {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        // Different frame_id -> Not comparable
-        if self.frame_id != other.frame_id {
+        // Different stream or frame_id -> Not comparable
+        if self.stream != other.stream || self.frame_id != other.frame_id {
             return None;
         }
 
@@ -47,17 +262,95 @@ impl PartialOrd for Packet {
 }
 
 impl PartialEq for Packet {
-    fn eq(&self, other: &Self) -> bool {    
-        self.index == other.index && self.frame_id == other.frame_id
+    fn eq(&self, other: &Self) -> bool {
+        self.index == other.index && self.frame_id == other.frame_id && self.stream == other.stream
     }
 }
 
 impl Clone for Packet {
     fn clone(&self) -> Self {
         Self {
-            index: self.index,
+            stream: self.stream,
             frame_id: self.frame_id,
+            seq: self.seq,
+            index: self.index,
+            count: self.count,
             data: self.data.clone(),
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet() -> Vec<u8> {
+        Packet::new(2, 0x0102_0304, 0xfffe_fffd, 1, 3, b"jpeg").to_bytes()
+    }
+
+    /// Serialized packet with `f` applied, and its checksum fixed up so only the change is wrong
+    fn edited(f: impl FnOnce(&mut Vec<u8>)) -> Vec<u8> {
+        let mut bytes = packet();
+        f(&mut bytes);
+        let checksum = Packet::checksum(&bytes);
+        bytes[Packet::CHECKSUM_OFFSET..Packet::META_SIZE].copy_from_slice(&checksum.to_le_bytes());
+        bytes
+    }
+
+    #[test]
+    fn packet_round_trip() {
+        let packet = Packet::from_bytes(&packet()).unwrap();
+        assert_eq!((packet.stream, packet.frame_id, packet.seq), (2, 0x0102_0304, 0xfffe_fffd));
+        assert_eq!((packet.index, packet.count), (1, 3));
+        assert_eq!(packet.data, b"jpeg");
+        assert_eq!(packet.to_bytes(), self::packet());
+    }
+
+    #[test]
+    fn malformed_headers_are_refused() {
+        assert_eq!(Header::read(b"SS"), Err(ProtocolError::Truncated { expected: Header::SIZE, actual: 2 }));
+        assert_eq!(Header::read(b"XY\x0a\x00"), Err(ProtocolError::BadMagic(*b"XY")));
+        assert_eq!(Header::read(b"SS\x09\x00"), Err(ProtocolError::BadVersion(9)));
+        assert_eq!(Header::read(b"SS\x0a\x03"), Err(ProtocolError::UnknownKind(3)));
+        let oversized = vec![0u8; Packet::MAX_DATAGRAM + 1];
+        assert_eq!(Header::read(&oversized), Err(ProtocolError::Oversized(Packet::MAX_DATAGRAM + 1)));
+        assert_eq!(Header::read(&packet()), Ok(Kind::Frame));
+    }
+
+    #[test]
+    fn malformed_packets_are_refused() {
+        let bytes = packet();
+        let truncated = &bytes[..Packet::META_SIZE - 1];
+        let expected = ProtocolError::Truncated { expected: Packet::META_SIZE, actual: Packet::META_SIZE - 1 };
+        assert_eq!(Packet::from_bytes(truncated).err(), Some(expected));
+
+        let mut control = bytes.clone();
+        control[3] = Kind::Control as u8;
+        assert_eq!(Packet::from_bytes(&control).err(), Some(ProtocolError::UnknownKind(Kind::Control as u8)));
+
+        let oversized = Packet::new(0, 0, 0, 0, 1, &[0; Packet::CHUNK_SIZE]).to_bytes();
+        assert_eq!(Packet::from_bytes(&oversized).err(), Some(ProtocolError::Oversized(oversized.len())));
+
+        let mut corrupted = bytes.clone();
+        *corrupted.last_mut().unwrap() ^= 1;
+        assert!(matches!(Packet::from_bytes(&corrupted).err(), Some(ProtocolError::BadChecksum { .. })));
+
+        // Index 3 of 3, and a frame of no packets
+        let index = Header::SIZE + 9;
+        let past = edited(|bytes| bytes[index] = 3);
+        assert_eq!(Packet::from_bytes(&past).err(), Some(ProtocolError::BadIndex { index: 3, count: 3 }));
+        let empty = edited(|bytes| bytes[index..index + 2].copy_from_slice(&[0, 0]));
+        assert_eq!(Packet::from_bytes(&empty).err(), Some(ProtocolError::BadIndex { index: 0, count: 0 }));
+    }
+
+    #[test]
+    fn sequence_numbers_compare_across_the_wrap() {
+        assert_eq!(seq_cmp(0, u32::MAX), Ordering::Greater);
+        assert_eq!(seq_cmp(u32::MAX, 0), Ordering::Less);
+        assert_eq!(seq_cmp(5, u32::MAX - 5), Ordering::Greater);
+        assert_eq!(seq_cmp(7, 7), Ordering::Equal);
+        // Half the space ahead is as far as newer goes
+        assert_eq!(seq_cmp(1 << 31, 1), Ordering::Greater);
+        assert_eq!(seq_cmp(1 << 31, 0), Ordering::Less);
+    }
+}
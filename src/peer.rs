@@ -0,0 +1,1285 @@
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use openssl::error::ErrorStack;
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::ssl::{ErrorCode, Ssl, SslContext, SslMethod, SslOptions, SslStream, SslVerifyMode};
+use openssl::x509::X509;
+use rav1e::prelude::{ChromaSampling, Config, Context, EncoderConfig, EncoderStatus, FrameParameters, FrameTypeOverride, Rational};
+
+use crate::auth;
+use crate::rtp::{self, Av1Payloader};
+use crate::srtp::{self, MASTER_KEY_SIZE, MASTER_SALT_SIZE};
+use crate::stun::{self, Message, BINDING_REQUEST, BINDING_SUCCESS, USERNAME, USE_CANDIDATE};
+
+/// Largest datagram sent, below the usual Ethernet MTU with room for the IP and UDP headers
+const MTU: usize = 1200;
+
+/// abs-send-time header extension: RTP headers grow by this much when the browser wants it
+const ABS_SEND_TIME: &str = "http://www.webrtc.org/experiments/rtp-hdrext/abs-send-time";
+const ABS_SEND_TIME_SIZE: usize = 8;
+
+/// Bitrate before the browser sends its first estimate, in bits per second
+const START_BITRATE: u64 = 1_000_000;
+
+/// Bounds of the bitrate the encoder is set to, whatever the browser estimates
+const MIN_BITRATE: u64 = 100_000;
+const MAX_BITRATE: u64 = 20_000_000;
+
+/// Share of the estimate the encoder aims for, the rest absorbs keyframes and retransmissions
+const TARGET_SHARE: f64 = 0.85;
+
+/// The encoder is only set up again for a new estimate this far from its bitrate, at most this often,
+/// since it starts over with a keyframe
+const RETUNE_RATIO: f64 = 1.5;
+const RETUNE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Longest burst sent over the estimate before frames are skipped
+const BURST: Duration = Duration::from_millis(500);
+
+/// Frames between keyframes, browsers ask for one sooner when they lose a frame
+const KEYFRAME_INTERVAL: u64 = 600;
+
+/// Packets kept to answer retransmission requests with
+const HISTORY: usize = 512;
+
+/// Browsers check the connection every few seconds, it is over when they stop (RFC 7675)
+const CONSENT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Longest a browser has to connect once it has the answer
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Browsers sent AV1 at once, each has a JPEG decoder and an AV1 encoder of its own, about a core apiece.
+/// Past it, pages stay on JPEGs and WHEP players are turned away
+pub const MAX_WEBRTC_VIEWERS: usize = 8;
+
+/// How long to wait for each answer of a STUN server, asked twice
+const STUN_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Length of the ICE username fragment and password of the server, in random bytes written as hex
+const UFRAG_SIZE: usize = 4;
+const PASSWORD_SIZE: usize = 12;
+
+/// Priorities of host and server reflexive candidates, type preference 126 and 100 (RFC 8445 5.1.2)
+const HOST_PRIORITY: u32 = 2_130_706_431;
+const REFLEXIVE_PRIORITY: u32 = 1_694_498_815;
+
+// RTCP packet types and feedback formats the server reads
+const RTPFB: u8 = 205;
+const PSFB: u8 = 206;
+const NACK: u8 = 1;
+const PLI: u8 = 1;
+const FIR: u8 = 4;
+const REMB: u8 = 15;
+
+/// What every WebRTC connection of the server shares
+pub struct Settings {
+    context: SslContext,
+    fingerprint: String,           // Of the certificate, as the SDP gives it
+    stun_servers: Vec<SocketAddr>, // To learn the public address of the server, offered as a candidate
+    peers: Arc<AtomicUsize>,       // Connections answered and not dropped yet
+}
+
+impl Settings {
+    /// Makes a new self-signed certificate, browsers check it against the fingerprint in the answer
+    pub fn new(stun_servers: Vec<SocketAddr>) -> io::Result<Self> {
+        let certificate = rcgen::generate_simple_self_signed(vec![String::from("screen-stream")]).map_err(io::Error::other)?;
+        let key = PKey::private_key_from_pkcs8(&certificate.key_pair.serialize_der())?;
+        let certificate = X509::from_der(certificate.cert.der())?;
+        let fingerprint = certificate.digest(MessageDigest::sha256())?;
+
+        let mut context = SslContext::builder(SslMethod::dtls())?;
+        context.set_certificate(&certificate)?;
+        context.set_private_key(&key)?;
+        context.set_tlsext_use_srtp("SRTP_AES128_CM_SHA1_80")?;
+        context.set_options(SslOptions::NO_QUERY_MTU);
+        // Browsers have self-signed certificates too, theirs is checked against the offer once connected
+        context.set_verify_callback(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT, |_, _| true);
+
+        Ok(Self {
+            context: context.build(),
+            fingerprint: fingerprint.iter().map(|byte| format!("{:02X}", byte)).collect::<Vec<_>>().join(":"),
+            stun_servers,
+            peers: Arc::default(),
+        })
+    }
+
+    /// Whether there are `MAX_WEBRTC_VIEWERS` connections already
+    pub fn full(&self) -> bool {
+        self.peers.load(Ordering::Relaxed) >= MAX_WEBRTC_VIEWERS
+    }
+
+    /// The STUN servers as the page gives them to its browser, separated by spaces
+    pub fn ice_servers(&self) -> String {
+        self.stun_servers.iter().map(|server| format!("stun:{}", server)).collect::<Vec<_>>().join(" ")
+    }
+}
+
+/// One of the `MAX_WEBRTC_VIEWERS` connections, given back when dropped
+struct Slot(Arc<AtomicUsize>);
+
+impl Slot {
+    fn take(peers: &Arc<AtomicUsize>) -> Option<Self> {
+        peers.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |taken| (taken < MAX_WEBRTC_VIEWERS).then_some(taken + 1)).ok()?;
+        Some(Self(Arc::clone(peers)))
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Where a peer connection is at
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum State {
+    Connecting,
+    Connected,
+    Failed, // Or closed, frames have to go another way
+}
+
+/// What the server needs from a browser's offer
+struct Offer {
+    ufrag: String,
+    fingerprint: Vec<u8>,      // SHA-256 of the browser's certificate
+    mid: String,
+    payload_type: u8,          // Of AV1, main profile
+    abs_send_time: Option<u8>, // Header extension id, when the browser wants send times
+}
+
+impl Offer {
+    /// Reads the session and first video section of an SDP offer
+    fn parse(sdp: &str) -> Result<Self, String> {
+        let (mut ufrag, mut fingerprint, mut setup) = (None, None, None);
+        let (mut mid, mut av1, mut abs_send_time) = (None, Vec::new(), None);
+        let mut profiles = Vec::new();
+        let mut section = "session";
+
+        for line in sdp.lines().map(str::trim) {
+            if let Some(media) = line.strip_prefix("m=") {
+                if section == "video" {
+                    break; // Only the first video section is answered
+                }
+                section = if media.starts_with("video ") { "video" } else { "other" };
+                continue;
+            }
+            if section == "other" {
+                continue;
+            }
+
+            let (name, value) = match line.strip_prefix("a=") {
+                Some(attribute) => attribute.split_once(':').unwrap_or((attribute, "")),
+                None => continue,
+            };
+            match name {
+                "ice-ufrag" => ufrag = Some(value.to_string()),
+                "setup" => setup = Some(value.to_string()),
+                "fingerprint" => {
+                    if let Some((_, hex)) = value.split_once(' ').filter(|(hash, _)| hash.eq_ignore_ascii_case("sha-256")) {
+                        fingerprint = hex.trim().split(':').map(|byte| u8::from_str_radix(byte, 16).ok()).collect::<Option<Vec<_>>>();
+                    }
+                }
+                "mid" => mid = Some(value.to_string()),
+                "rtpmap" => {
+                    if let Some((payload_type, codec)) = value.split_once(' ') {
+                        if codec.eq_ignore_ascii_case("AV1/90000") {
+                            av1.extend(payload_type.parse::<u8>().ok());
+                        }
+                    }
+                }
+                "fmtp" => {
+                    if let Some((payload_type, parameters)) = value.split_once(' ') {
+                        let profile = parameters.split(';').find_map(|parameter| parameter.trim().strip_prefix("profile="));
+                        if let (Ok(payload_type), Some(profile)) = (payload_type.parse::<u8>(), profile) {
+                            profiles.push((payload_type, profile.to_string()));
+                        }
+                    }
+                }
+                "extmap" => {
+                    if let Some((id, uri)) = value.split_once(' ') {
+                        if uri.trim() == ABS_SEND_TIME {
+                            abs_send_time = id.split('/').next().and_then(|id| id.parse().ok()).filter(|id| (1..15).contains(id));
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        if setup.as_deref() == Some("passive") {
+            return Err(String::from("the browser wants to be the DTLS server"));
+        }
+        // rav1e makes main profile AV1, 4:2:0 8 bit, what a payload type without a profile stands for
+        let payload_type = av1
+            .into_iter()
+            .find(|&payload_type| profiles.iter().all(|(found, profile)| *found != payload_type || profile == "0"))
+            .ok_or("the browser can't receive AV1")?;
+
+        Ok(Self {
+            ufrag: ufrag.ok_or("no ICE username in the offer")?,
+            fingerprint: fingerprint.ok_or("no SHA-256 fingerprint in the offer")?,
+            mid: mid.unwrap_or_else(|| String::from("0")),
+            payload_type,
+            abs_send_time,
+        })
+    }
+}
+
+/// Datagrams between the DTLS library and the socket, one per read or write
+#[derive(Default)]
+struct Datagrams {
+    received: VecDeque<Vec<u8>>,
+    to_send: Vec<Vec<u8>>,
+}
+
+impl Read for Datagrams {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let datagram = self.received.pop_front().ok_or(io::ErrorKind::WouldBlock)?;
+        let size = datagram.len().min(buffer.len());
+        buffer[..size].copy_from_slice(&datagram[..size]);
+        Ok(size)
+    }
+}
+
+impl Write for Datagrams {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        self.to_send.push(bytes.to_vec());
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// AV1 encoder of one viewer, set up for a size and bitrate
+/// Even in low latency mode rav1e holds a few frames back to look for scene cuts, so packets come out
+/// about three frames after theirs went in
+struct Encoder {
+    context: Context<u8>,
+    width: usize,
+    height: usize,
+    bitrate: u64,
+    started: Instant,
+}
+
+impl Encoder {
+    fn new(width: usize, height: usize, bitrate: u64) -> Result<Self, String> {
+        let mut config = EncoderConfig::with_speed_preset(10);
+        config.width = width;
+        config.height = height;
+        config.chroma_sampling = ChromaSampling::Cs420;
+        config.time_base = Rational::new(1, 30);
+        config.bitrate = bitrate as i32;
+        config.low_latency = true;
+        config.max_key_frame_interval = KEYFRAME_INTERVAL;
+        config.speed_settings.rdo_lookahead_frames = 1;
+
+        let context = Config::new().with_encoder_config(config).new_context().map_err(|e| e.to_string())?;
+        Ok(Self { context, width, height, bitrate, started: Instant::now() })
+    }
+}
+
+/// WebRTC connection to one browser, sending the frames of its rendition as an AV1 video track
+/// The server is ICE lite: it waits on its candidates for the browser's checks, then DTLS gives the keys
+/// of SRTP. The browser estimates the bandwidth it gets (REMB): the encoder aims below it, frames are
+/// skipped when a burst went over it, lost packets are sent again and keyframes sent when asked for
+pub struct Peer {
+    socket: UdpSocket,
+    remote: Option<SocketAddr>, // Nominated by the browser's checks
+    offer: Offer,
+    ufrag: String,
+    password: String,
+    dtls: SslStream<Datagrams>,
+    srtp: Option<(srtp::Context, srtp::Context)>, // Sending with the server's keys, receiving with the browser's
+    state: State,
+    created: Instant,
+    consent: Instant, // Of the latest check from the browser
+
+    payloader: Av1Payloader,
+    history: VecDeque<(u16, Vec<u8>)>, // Latest packets sent, with their sequence number
+    encoder: Option<Encoder>,
+    keyframe: bool,        // The browser lost a frame and can't decode the next ones without a keyframe
+    estimate: Option<u64>, // Latest REMB in bits per second
+    budget: f64,           // Bytes that can be sent before going over the estimate, negative when over
+    refilled: Instant,     // When the budget was last topped up
+    _slot: Slot,
+}
+
+impl Peer {
+    /// Answers the offer of a browser, with candidates on `ip`, the address it reached the server on
+    pub fn answer(offer: &str, ip: IpAddr, settings: &Arc<Settings>) -> Result<(Self, String), String> {
+        let offer = Offer::parse(offer)?;
+        let slot = Slot::take(&settings.peers).ok_or_else(|| format!("already {} WebRTC viewers", MAX_WEBRTC_VIEWERS))?;
+        let socket = UdpSocket::bind(SocketAddr::new(ip, 0)).map_err(|e| e.to_string())?;
+        let host = socket.local_addr().map_err(|e| e.to_string())?;
+
+        let mut random = [0u8; UFRAG_SIZE + PASSWORD_SIZE + 6];
+        getrandom::getrandom(&mut random).map_err(|e| e.to_string())?;
+        let (ufrag, rest) = random.split_at(UFRAG_SIZE);
+        let (password, rest) = rest.split_at(PASSWORD_SIZE);
+        let ssrc = u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]);
+        let seq = u16::from_be_bytes([rest[4], rest[5]]);
+
+        let mut candidates = vec![format!("a=candidate:1 1 udp {} {} {} typ host", HOST_PRIORITY, host.ip(), host.port())];
+        if let Some(reflexive) = reflexive_address(&socket, &settings.stun_servers).filter(|&reflexive| reflexive != host) {
+            candidates.push(format!(
+                "a=candidate:2 1 udp {} {} {} typ srflx raddr {} rport {}",
+                REFLEXIVE_PRIORITY,
+                reflexive.ip(),
+                reflexive.port(),
+                host.ip(),
+                host.port()
+            ));
+        }
+        socket.set_nonblocking(true).map_err(|e| e.to_string())?;
+
+        let mut ssl = Ssl::new(&settings.context).map_err(|e| e.to_string())?;
+        ssl.set_mtu(MTU as u32).map_err(|e| e.to_string())?;
+        ssl.set_accept_state();
+        let dtls = SslStream::new(ssl, Datagrams::default()).map_err(|e: ErrorStack| e.to_string())?;
+
+        let extension = match offer.abs_send_time {
+            Some(id) => format!("a=extmap:{} {}\r\n", id, ABS_SEND_TIME),
+            None => String::new(),
+        };
+        let (ufrag, password) = (auth::to_hex(ufrag), auth::to_hex(password));
+        let answer = format!(
+            "v=0\r\no=- {session} 2 IN IP4 127.0.0.1\r\ns=-\r\nt=0 0\r\na=group:BUNDLE {mid}\r\n\
+             a=msid-semantic: WMS screen-stream\r\na=ice-lite\r\n\
+             m=video 9 UDP/TLS/RTP/SAVPF {pt}\r\nc=IN IP4 0.0.0.0\r\na=rtcp:9 IN IP4 0.0.0.0\r\n\
+             {candidates}\r\na=end-of-candidates\r\n\
+             a=ice-ufrag:{ufrag}\r\na=ice-pwd:{password}\r\na=fingerprint:sha-256 {fingerprint}\r\na=setup:passive\r\n\
+             a=mid:{mid}\r\n{extension}a=sendonly\r\na=rtcp-mux\r\n\
+             a=rtpmap:{pt} AV1/90000\r\na=rtcp-fb:{pt} goog-remb\r\na=rtcp-fb:{pt} nack\r\n\
+             a=rtcp-fb:{pt} nack pli\r\na=rtcp-fb:{pt} ccm fir\r\n\
+             a=msid:screen-stream video\r\na=ssrc:{ssrc} cname:screen-stream\r\na=ssrc:{ssrc} msid:screen-stream video\r\n",
+            session = ssrc,
+            mid = offer.mid,
+            pt = offer.payload_type,
+            candidates = candidates.join("\r\n"),
+            fingerprint = settings.fingerprint,
+        );
+
+        let extension_size = if offer.abs_send_time.is_some() { ABS_SEND_TIME_SIZE } else { 0 };
+        let peer = Peer {
+            socket,
+            remote: None,
+            payloader: Av1Payloader::new(offer.payload_type, ssrc, seq, MTU - srtp::TAG_SIZE - extension_size),
+            offer,
+            ufrag,
+            password,
+            dtls,
+            srtp: None,
+            state: State::Connecting,
+            created: Instant::now(),
+            consent: Instant::now(),
+            history: VecDeque::with_capacity(HISTORY),
+            encoder: None,
+            keyframe: false,
+            estimate: None,
+            budget: 0.0,
+            refilled: Instant::now(),
+            _slot: slot,
+        };
+        Ok((peer, answer))
+    }
+
+    /// Handles what the browser sent since, returns where the connection is at
+    pub fn poll(&mut self) -> State {
+        let mut buffer = [0u8; 2048];
+        while self.state != State::Failed {
+            match self.socket.recv_from(&mut buffer) {
+                Ok((size, from)) => self.receive(&buffer[..size], from),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                // Errors of earlier sends, like an unreachable port, show up here: the checks tell if it's over
+                Err(_) => break,
+            }
+        }
+
+        if self.state == State::Connecting {
+            self.handshake(); // Also sends the flight again if the browser's answer is late
+            if self.created.elapsed() > CONNECT_TIMEOUT {
+                self.fail("the browser didn't connect");
+            }
+        }
+        if self.consent.elapsed() > CONSENT_TIMEOUT {
+            self.fail("the browser stopped checking the connection");
+        }
+        self.state
+    }
+
+    /// Encodes a JPEG frame and sends it, unless the estimate was gone over
+    pub fn send(&mut self, jpeg: &[u8]) -> Result<(), String> {
+        if self.state != State::Connected {
+            return Ok(());
+        }
+
+        let estimate = self.estimate.unwrap_or(START_BITRATE);
+        let rate = estimate as f64 / 8.0;
+        self.budget = (self.budget + self.refilled.elapsed().as_secs_f64() * rate).min(rate * BURST.as_secs_f64());
+        self.refilled = Instant::now();
+        if self.budget < 0.0 {
+            return Ok(());
+        }
+
+        let image = turbojpeg::decompress_to_yuv(jpeg).map_err(|e| e.to_string())?;
+        if image.subsamp != turbojpeg::Subsamp::Sub2x2 {
+            return Err(format!("frames are {:?}, AV1 is sent 4:2:0", image.subsamp));
+        }
+
+        let bitrate = ((estimate as f64 * TARGET_SHARE) as u64).clamp(MIN_BITRATE, MAX_BITRATE);
+        let retune = self.encoder.as_ref().is_none_or(|encoder| {
+            let ratio = bitrate as f64 / encoder.bitrate as f64;
+            (encoder.width, encoder.height) != (image.width, image.height)
+                || (encoder.started.elapsed() >= RETUNE_INTERVAL && !(1.0 / RETUNE_RATIO..RETUNE_RATIO).contains(&ratio))
+        });
+        if retune {
+            self.encoder = Some(Encoder::new(image.width, image.height, bitrate)?);
+            self.keyframe = false; // A new encoder starts with one
+        }
+        let encoder = self.encoder.as_mut().expect("Set up above");
+
+        // Planes of turbojpeg's YUV are one after the other, rows padded to its alignment
+        let mut frame = encoder.context.new_frame();
+        let (chroma_width, chroma_height) = (image.width.div_ceil(2), image.height.div_ceil(2));
+        let luma_stride = image.width.next_multiple_of(image.align);
+        let chroma_stride = chroma_width.next_multiple_of(image.align);
+        let (luma, chroma) = image.pixels.split_at(luma_stride * image.height);
+        let (u, v) = chroma.split_at(chroma_stride * chroma_height);
+        frame.planes[0].copy_from_raw_u8(luma, luma_stride, 1);
+        frame.planes[1].copy_from_raw_u8(u, chroma_stride, 1);
+        frame.planes[2].copy_from_raw_u8(&v[..chroma_stride * chroma_height], chroma_stride, 1);
+
+        let parameters = std::mem::take(&mut self.keyframe)
+            .then(|| FrameParameters { frame_type_override: FrameTypeOverride::Key, ..Default::default() });
+        encoder.context.send_frame((Arc::new(frame), parameters)).map_err(|e| e.to_string())?;
+
+        let mut units = Vec::new();
+        loop {
+            match encoder.context.receive_packet() {
+                Ok(packet) => units.push(packet.data),
+                Err(EncoderStatus::Encoded) => continue,
+                Err(EncoderStatus::NeedMoreData) => break,
+                Err(e) => return Err(e.to_string()),
+            }
+        }
+
+        let ticks = self.created.elapsed().as_micros() * rtp::CLOCK_RATE as u128 / 1_000_000;
+        for unit in units {
+            for packet in self.payloader.payload(&unit, ticks as u32)? {
+                self.send_rtp(packet)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn send_rtp(&mut self, mut packet: Vec<u8>) -> Result<(), String> {
+        let (remote, (sending, _)) = match (self.remote, self.srtp.as_mut()) {
+            (Some(remote), Some(srtp)) => (remote, srtp),
+            _ => return Ok(()),
+        };
+
+        // When the packet left, in seconds as 6.18 fixed point, for the browser to estimate the bandwidth with
+        if let Some(id) = self.offer.abs_send_time {
+            let time = ((self.created.elapsed().as_micros() << 18) / 1_000_000) as u32 & 0x00ff_ffff;
+            let mut extension = vec![0xbe, 0xde, 0, 1, id << 4 | 2];
+            extension.extend_from_slice(&time.to_be_bytes()[1..]);
+            packet[0] |= 0x10;
+            packet.splice(12..12, extension);
+        }
+
+        let seq = u16::from_be_bytes([packet[2], packet[3]]);
+        let protected = sending.protect_rtp(&packet)?;
+        self.budget -= protected.len() as f64;
+        let _ = self.socket.send_to(&protected, remote);
+
+        if self.history.len() == HISTORY {
+            self.history.pop_front();
+        }
+        self.history.push_back((seq, protected));
+        Ok(())
+    }
+
+    fn receive(&mut self, datagram: &[u8], from: SocketAddr) {
+        let first = match datagram.first() {
+            Some(&first) => first,
+            None => return,
+        };
+
+        // STUN, DTLS and SRTP share the port, told apart by their first byte (RFC 7983)
+        match first {
+            0..=3 => self.check(datagram, from),
+            20..=63 if Some(from) == self.remote => {
+                self.dtls.get_mut().received.push_back(datagram.to_vec());
+                match self.state {
+                    State::Connecting => self.handshake(),
+                    // Only an alert can come after the handshake, closing or failing the connection
+                    _ => match self.dtls.ssl_read(&mut [0u8; 2048]) {
+                        Err(e) if e.code() == ErrorCode::WANT_READ => {}
+                        Err(e) if e.code() == ErrorCode::ZERO_RETURN => self.fail("closed by the browser"),
+                        Err(e) => self.fail(&format!("DTLS error: {}", e)),
+                        Ok(_) => {}
+                    },
+                }
+            }
+            128..=191 if Some(from) == self.remote => self.rtcp(datagram),
+            _ => {}
+        }
+    }
+
+    /// Answers a connectivity check of the browser, the ones it nominates pick where packets go
+    fn check(&mut self, datagram: &[u8], from: SocketAddr) {
+        let message = match Message::parse(datagram) {
+            Ok(message) if message.kind == BINDING_REQUEST => message,
+            _ => return,
+        };
+        let username = format!("{}:{}", self.ufrag, self.offer.ufrag);
+        if message.attribute(USERNAME) != Some(username.as_bytes()) || !message.verify(self.password.as_bytes()) {
+            return;
+        }
+
+        let response = stun::Writer::new(BINDING_SUCCESS, &message.transaction)
+            .mapped_address(from)
+            .finish(Some(self.password.as_bytes()));
+        let _ = self.socket.send_to(&response, from);
+
+        self.consent = Instant::now();
+        if self.remote.is_none() || message.attribute(USE_CANDIDATE).is_some() {
+            self.remote = Some(from);
+        }
+    }
+
+    /// Moves the DTLS handshake on, and once it is done checks the browser's certificate and sets up SRTP
+    fn handshake(&mut self) {
+        let result = self.dtls.do_handshake();
+        self.flush();
+
+        match result {
+            Ok(()) => {}
+            Err(e) if matches!(e.code(), ErrorCode::WANT_READ | ErrorCode::WANT_WRITE) => return,
+            Err(e) => return self.fail(&format!("DTLS handshake failed: {}", e)),
+        }
+
+        let ssl = self.dtls.ssl();
+        let certificate = ssl.peer_certificate().and_then(|certificate| certificate.digest(MessageDigest::sha256()).ok());
+        if certificate.as_deref() != Some(&self.offer.fingerprint[..]) {
+            return self.fail("the browser's certificate doesn't match its offer");
+        }
+        if ssl.selected_srtp_profile().map(|profile| profile.name()) != Some("SRTP_AES128_CM_SHA1_80") {
+            return self.fail("the browser didn't agree on an SRTP profile");
+        }
+
+        // Client key, server key, client salt, server salt (RFC 5764 4.2)
+        let mut material = [0u8; 2 * (MASTER_KEY_SIZE + MASTER_SALT_SIZE)];
+        if let Err(e) = ssl.export_keying_material(&mut material, "EXTRACTOR-dtls_srtp", None) {
+            return self.fail(&format!("Error exporting SRTP keys: {}", e));
+        }
+        let (keys, salts) = material.split_at(2 * MASTER_KEY_SIZE);
+        let key = |i: usize| <[u8; MASTER_KEY_SIZE]>::try_from(&keys[i * MASTER_KEY_SIZE..][..MASTER_KEY_SIZE]).expect("Sliced to size");
+        let salt = |i: usize| <[u8; MASTER_SALT_SIZE]>::try_from(&salts[i * MASTER_SALT_SIZE..][..MASTER_SALT_SIZE]).expect("Sliced to size");
+
+        self.srtp = Some((srtp::Context::new(&key(1), &salt(1)), srtp::Context::new(&key(0), &salt(0))));
+        self.state = State::Connected;
+        self.refilled = Instant::now();
+    }
+
+    /// Sends what DTLS wrote to the nominated address
+    fn flush(&mut self) {
+        let datagrams = std::mem::take(&mut self.dtls.get_mut().to_send);
+        if let Some(remote) = self.remote {
+            for datagram in datagrams {
+                let _ = self.socket.send_to(&datagram, remote);
+            }
+        }
+    }
+
+    /// Reads the feedback of the browser: estimates, lost packets and keyframe requests
+    fn rtcp(&mut self, datagram: &[u8]) {
+        let compound = match self.srtp.as_mut().map(|(_, receiving)| receiving.unprotect_rtcp(datagram)) {
+            Some(Ok(compound)) => compound,
+            _ => return,
+        };
+
+        let mut rest = &compound[..];
+        while rest.len() >= 4 {
+            let size = 4 * (u16::from_be_bytes([rest[2], rest[3]]) as usize + 1);
+            let packet = match rest.get(..size) {
+                Some(packet) => packet,
+                None => return,
+            };
+            rest = &rest[size..];
+
+            let (format, kind, feedback) = (packet[0] & 0x1f, packet[1], packet.get(12..).unwrap_or(&[]));
+            match (kind, format) {
+                (PSFB, PLI | FIR) => self.keyframe = true,
+                (PSFB, REMB) if feedback.starts_with(b"REMB") && feedback.len() >= 8 => {
+                    let exponent = feedback[5] >> 2;
+                    let mantissa = u32::from_be_bytes([0, feedback[5] & 0x03, feedback[6], feedback[7]]) as u64;
+                    self.estimate = Some(mantissa.checked_shl(exponent as u32).unwrap_or(u64::MAX));
+                }
+                (RTPFB, NACK) => {
+                    for lost in feedback.chunks_exact(4) {
+                        let first = u16::from_be_bytes([lost[0], lost[1]]);
+                        let following = u16::from_be_bytes([lost[2], lost[3]]);
+                        let wanted = (0..17).filter(|&i| i == 0 || following & 1 << (i - 1) != 0).map(|i| first.wrapping_add(i));
+                        for seq in wanted {
+                            self.resend(seq);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn resend(&mut self, seq: u16) {
+        if let (Some(remote), Some((_, packet))) = (self.remote, self.history.iter().find(|(sent, _)| *sent == seq)) {
+            self.budget -= packet.len() as f64;
+            let _ = self.socket.send_to(packet, remote);
+        }
+    }
+
+    fn fail(&mut self, reason: &str) {
+        if self.state != State::Failed {
+            println!("WebRTC connection to {:?} over: {}", self.remote, reason);
+            self.state = State::Failed;
+        }
+    }
+}
+
+impl Drop for Peer {
+    fn drop(&mut self) {
+        if self.state == State::Connected {
+            let _ = self.dtls.shutdown();
+            self.flush();
+        }
+    }
+}
+
+/// The public address of `socket`, from the first STUN server that answers
+fn reflexive_address(socket: &UdpSocket, servers: &[SocketAddr]) -> Option<SocketAddr> {
+    let local = socket.local_addr().ok()?;
+    socket.set_read_timeout(Some(STUN_TIMEOUT)).ok()?;
+    let mut buffer = [0u8; 1024];
+
+    for &server in servers.iter().filter(|server| server.is_ipv4() == local.is_ipv4()) {
+        let mut transaction = [0u8; stun::TRANSACTION_SIZE];
+        getrandom::getrandom(&mut transaction).ok()?;
+        let request = stun::Writer::new(BINDING_REQUEST, &transaction).finish(None);
+
+        for _ in 0..2 {
+            if socket.send_to(&request, server).is_err() {
+                break;
+            }
+            let deadline = Instant::now() + STUN_TIMEOUT;
+            while Instant::now() < deadline {
+                let (size, from) = match socket.recv_from(&mut buffer) {
+                    Ok(received) => received,
+                    Err(_) => break,
+                };
+                let answer = match Message::parse(&buffer[..size]) {
+                    Ok(message) if from == server && message.kind == BINDING_SUCCESS && message.transaction == transaction => message,
+                    _ => continue,
+                };
+                if let Some(address) = answer.mapped_address() {
+                    return Some(address);
+                }
+            }
+        }
+        println!("No answer from STUN server {}", server);
+    }
+    None
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread::{self, JoinHandle};
+    use turbojpeg::{Image, PixelFormat};
+
+    const LOCALHOST: IpAddr = IpAddr::V4(std::net::Ipv4Addr::LOCALHOST);
+    const TIMEOUT: Duration = Duration::from_secs(20);
+    const AV1: u8 = 45;
+
+    /// A 4:2:0 JPEG of a noisy gradient, as the server encodes captures
+    pub(crate) fn jpeg(width: usize, height: usize) -> Vec<u8> {
+        let mut seed = 0x2545_f491u32;
+        let pixels: Vec<u8> = (0..width * height * 4)
+            .map(|i| {
+                seed ^= seed << 13;
+                seed ^= seed >> 17;
+                seed ^= seed << 5;
+                ((i / 4 % width) * 4) as u8 ^ (seed as u8 & 0x1f)
+            })
+            .collect();
+        let image = Image { pixels: &pixels[..], width, pitch: width * 4, height, format: PixelFormat::BGRX };
+        turbojpeg::compress(image, 80, turbojpeg::Subsamp::Sub2x2).unwrap().to_vec()
+    }
+
+    /// The socket of a headless browser once it knows the server's candidate, DTLS reads skip the rest
+    pub(crate) struct Wire {
+        socket: UdpSocket,
+        remote: SocketAddr,
+    }
+
+    impl Read for Wire {
+        fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+            loop {
+                let (size, from) = self.socket.recv_from(buffer)?;
+                if from == self.remote && (20..=63).contains(&buffer[0]) {
+                    return Ok(size);
+                }
+            }
+        }
+    }
+
+    impl Write for Wire {
+        fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+            self.socket.send_to(bytes, self.remote)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// HMAC-SHA1 of `parts` one after the other, with OpenSSL rather than the crates `stun` and `srtp` use
+    fn hmac_sha1(key: &[u8], parts: &[&[u8]]) -> Vec<u8> {
+        let key = PKey::hmac(key).unwrap();
+        let mut signer = openssl::sign::Signer::new(MessageDigest::sha1(), &key).unwrap();
+        for part in parts {
+            signer.update(part).unwrap();
+        }
+        signer.sign_to_vec().unwrap()
+    }
+
+    /// AES-128 in counter mode from `iv`, the same both ways
+    fn counter_mode(key: &[u8], iv: [u8; 16], data: &[u8]) -> Vec<u8> {
+        openssl::symm::encrypt(openssl::symm::Cipher::aes_128_ctr(), key, Some(&iv), data).unwrap()
+    }
+
+    /// A STUN attribute padded to 4 bytes (RFC 8489 14)
+    fn push_attribute(message: &mut Vec<u8>, kind: u16, value: &[u8]) {
+        message.extend_from_slice(&kind.to_be_bytes());
+        message.extend_from_slice(&(value.len() as u16).to_be_bytes());
+        message.extend_from_slice(value);
+        message.resize(message.len().next_multiple_of(4), 0);
+    }
+
+    /// The check a browser sends to nominate the candidate (RFC 8445 7.2.2), byte by byte
+    fn binding_request(transaction: &[u8; 12], username: &str, password: &[u8]) -> Vec<u8> {
+        let mut request = vec![0x00, 0x01, 0, 0, 0x21, 0x12, 0xa4, 0x42];
+        request.extend_from_slice(transaction);
+        push_attribute(&mut request, 0x0006, username.as_bytes()); // USERNAME
+        push_attribute(&mut request, 0x0025, &[]); // USE-CANDIDATE
+        // The length covers each of the last two attributes when it is worked out
+        let length = request.len() - 20 + 24;
+        request[2..4].copy_from_slice(&(length as u16).to_be_bytes());
+        let integrity = hmac_sha1(password, &[&request]);
+        push_attribute(&mut request, 0x0008, &integrity); // MESSAGE-INTEGRITY
+        request[2..4].copy_from_slice(&(length as u16 + 8).to_be_bytes());
+        let fingerprint = crc32fast::hash(&request) ^ 0x5354_554e;
+        push_attribute(&mut request, 0x8028, &fingerprint.to_be_bytes()); // FINGERPRINT
+        request
+    }
+
+    /// The address of a success response to `transaction`, once its integrity is checked with `password`
+    fn binding_success(response: &[u8], transaction: &[u8; 12], password: &[u8]) -> Option<SocketAddr> {
+        if response.len() < 20 || response[8..20] != transaction[..] {
+            return None;
+        }
+        assert_eq!(response[..2], [0x01, 0x01], "a binding success");
+        let (mut mapped, mut integrity) = (None, false);
+        let mut position = 20;
+        while position + 4 <= response.len() {
+            let kind = u16::from_be_bytes([response[position], response[position + 1]]);
+            let value = &response[position + 4..][..u16::from_be_bytes([response[position + 2], response[position + 3]]) as usize];
+            match kind {
+                0x0020 if value[1] == 1 => {
+                    // XOR-MAPPED-ADDRESS of IPv4, with the magic cookie
+                    let port = u16::from_be_bytes([value[2], value[3]]) ^ 0x2112;
+                    let ip = u32::from_be_bytes([value[4], value[5], value[6], value[7]]) ^ 0x2112_a442;
+                    mapped = Some(SocketAddr::from((ip.to_be_bytes(), port)));
+                }
+                0x0008 => {
+                    let mut covered = response[..position].to_vec();
+                    covered[2..4].copy_from_slice(&((position - 20 + 24) as u16).to_be_bytes());
+                    assert_eq!(hmac_sha1(password, &[&covered]), value, "integrity of the response");
+                    integrity = true;
+                }
+                _ => {}
+            }
+            position += 4 + value.len().next_multiple_of(4);
+        }
+        assert!(integrity, "the response has MESSAGE-INTEGRITY");
+        mapped
+    }
+
+    /// Session keys of SRTP or SRTCP with AES-128 in counter mode and HMAC-SHA1-80 (RFC 3711 4.3), written out
+    /// again here so a mistake of `srtp` doesn't cancel out in the tests
+    struct Keys {
+        cipher: Vec<u8>,
+        auth: Vec<u8>,
+        salt: Vec<u8>,
+    }
+
+    impl Keys {
+        /// `label` is 0 for the keys of SRTP, 3 for those of SRTCP
+        fn derive(master_key: &[u8], master_salt: &[u8], label: u8) -> Self {
+            let derive = |label: u8, size: usize| {
+                let mut iv = [0u8; 16];
+                iv[..14].copy_from_slice(master_salt);
+                iv[7] ^= label;
+                counter_mode(master_key, iv, &vec![0; size])
+            };
+            Self { cipher: derive(label, 16), auth: derive(label + 1, 20), salt: derive(label + 2, 14) }
+        }
+
+        /// Encrypts or decrypts the payload of the packet of `ssrc` at `index`
+        fn apply(&self, ssrc: u32, index: u64, payload: &[u8]) -> Vec<u8> {
+            let mut iv = [0u8; 16];
+            iv[..14].copy_from_slice(&self.salt);
+            iv[4..8].iter_mut().zip(ssrc.to_be_bytes()).for_each(|(byte, ssrc)| *byte ^= ssrc);
+            iv[8..14].iter_mut().zip(&index.to_be_bytes()[2..]).for_each(|(byte, index)| *byte ^= index);
+            counter_mode(&self.cipher, iv, payload)
+        }
+
+        fn tag(&self, parts: &[&[u8]]) -> Vec<u8> {
+            hmac_sha1(&self.auth, parts)[..10].to_vec()
+        }
+    }
+
+    /// Reads a LEB128 number, returns it with how many bytes it took
+    fn leb128(bytes: &[u8]) -> (usize, usize) {
+        let mut value = 0;
+        for (i, byte) in bytes.iter().enumerate() {
+            value |= ((byte & 0x7f) as usize) << (7 * i);
+            if byte & 0x80 == 0 {
+                return (value, i + 1);
+            }
+        }
+        panic!("LEB128 past the end");
+    }
+
+    /// Puts the OBUs of a temporal unit back together from its RTP packets (AV1 RTP payload format 4), written
+    /// from the specification rather than with `rtp`'s depayloader
+    #[derive(Default)]
+    struct Depacketizer {
+        obus: Vec<(u8, Vec<u8>)>, // Type and payload
+        fragment: Vec<u8>,        // Of an OBU that goes on in the next packet
+        seq: Option<u16>,         // Expected next
+        lost: bool,               // Some of the unit, which is dropped
+    }
+
+    impl Depacketizer {
+        /// Takes the next packet, decrypted, with its payload after `header`. Returns the unit it ends, if whole
+        fn push(&mut self, packet: &[u8], header: usize) -> Option<Vec<(u8, Vec<u8>)>> {
+            let seq = u16::from_be_bytes([packet[2], packet[3]]);
+            if self.seq.is_some_and(|expected| expected != seq) {
+                self.fragment.clear();
+                self.lost = true;
+            }
+            self.seq = Some(seq.wrapping_add(1));
+
+            // Z, the first element goes on from the last packet; Y, the last goes on in the next; W, the count
+            let aggregation = packet[header];
+            let (continues, goes_on, count) = (aggregation & 0x80 != 0, aggregation & 0x40 != 0, (aggregation >> 4 & 3) as usize);
+            let broken = continues && self.fragment.is_empty();
+            self.lost |= broken || (!continues && !self.fragment.is_empty());
+            if !continues {
+                self.fragment.clear();
+            }
+
+            let mut rest = &packet[header + 1..];
+            let mut elements = Vec::new();
+            while !rest.is_empty() {
+                if elements.len() + 1 == count {
+                    elements.push(rest);
+                    break;
+                }
+                let (size, read) = leb128(rest);
+                elements.push(&rest[read..read + size]);
+                rest = &rest[read + size..];
+            }
+            let last = elements.len() - 1;
+            for (i, element) in elements.into_iter().enumerate() {
+                if i == 0 && broken {
+                    continue;
+                }
+                self.fragment.extend_from_slice(element);
+                if i == last && goes_on {
+                    break;
+                }
+                let obu = std::mem::take(&mut self.fragment);
+                let mut position = if obu[0] & 0x04 != 0 { 2 } else { 1 };
+                let size = match obu[0] & 0x02 {
+                    0 => obu.len() - position,
+                    _ => {
+                        let (size, read) = leb128(&obu[position..]);
+                        position += read;
+                        size
+                    }
+                };
+                self.obus.push((obu[0] >> 3 & 0x0f, obu[position..position + size].to_vec()));
+            }
+
+            if packet[1] & 0x80 == 0 {
+                return None;
+            }
+            let unit = std::mem::take(&mut self.obus);
+            (!std::mem::take(&mut self.lost)).then_some(unit)
+        }
+    }
+
+    /// What the browser's side of the connection does: it offers AV1, runs the ICE check, the DTLS handshake as
+    /// client and SRTP, then puts back together the AV1 it gets. It is checked as a decoder would, from the
+    /// sequence and frame headers, there is no AV1 decoder to show the pictures. STUN, SRTP and the AV1 payload
+    /// are done here on their own, from the RFCs, so the server is checked against them and not against itself
+    pub(crate) struct Browser {
+        socket: Option<UdpSocket>,
+        context: SslContext,
+        fingerprint: String,
+        ufrag: String,
+        password: String,
+        dtls: Option<SslStream<Wire>>,
+        keys: Option<(Keys, Keys)>, // SRTP with the server's master key, SRTCP with the browser's
+        index: Option<u64>,         // Highest SRTP index received
+        rtcp_index: u32,
+        depacketizer: Depacketizer,
+    }
+
+    impl Browser {
+        pub(crate) fn new() -> Self {
+            let certificate = rcgen::generate_simple_self_signed(vec![String::from("browser")]).unwrap();
+            let key = PKey::private_key_from_pkcs8(&certificate.key_pair.serialize_der()).unwrap();
+            let certificate = X509::from_der(certificate.cert.der()).unwrap();
+            let fingerprint = certificate.digest(MessageDigest::sha256()).unwrap();
+
+            let mut context = SslContext::builder(SslMethod::dtls()).unwrap();
+            context.set_certificate(&certificate).unwrap();
+            context.set_private_key(&key).unwrap();
+            context.set_tlsext_use_srtp("SRTP_AES128_CM_SHA1_80").unwrap();
+            context.set_options(SslOptions::NO_QUERY_MTU);
+            context.set_verify_callback(SslVerifyMode::PEER, |_, _| true);
+
+            let socket = UdpSocket::bind((LOCALHOST, 0)).unwrap();
+            socket.set_read_timeout(Some(Duration::from_millis(50))).unwrap();
+            Self {
+                socket: Some(socket),
+                context: context.build(),
+                fingerprint: fingerprint.iter().map(|byte| format!("{:02X}", byte)).collect::<Vec<_>>().join(":"),
+                ufrag: String::from("brws"),
+                password: String::from("browser-password-1234"),
+                dtls: None,
+                keys: None,
+                index: None,
+                rtcp_index: 0,
+                depacketizer: Depacketizer::default(),
+            }
+        }
+
+        /// An offer like browsers make, with a codec the server doesn't send before AV1
+        pub(crate) fn offer(&self) -> String {
+            format!(
+                "v=0\r\no=- 1 2 IN IP4 127.0.0.1\r\ns=-\r\nt=0 0\r\na=group:BUNDLE 0\r\n\
+                 m=video 9 UDP/TLS/RTP/SAVPF 96 {av1}\r\nc=IN IP4 0.0.0.0\r\n\
+                 a=ice-ufrag:{ufrag}\r\na=ice-pwd:{password}\r\na=fingerprint:sha-256 {fingerprint}\r\na=setup:actpass\r\n\
+                 a=mid:0\r\na=extmap:3 {extension}\r\na=recvonly\r\na=rtcp-mux\r\n\
+                 a=rtpmap:96 VP8/90000\r\na=rtpmap:{av1} AV1/90000\r\na=rtcp-fb:{av1} nack\r\na=rtcp-fb:{av1} nack pli\r\n",
+                av1 = AV1,
+                ufrag = self.ufrag,
+                password = self.password,
+                fingerprint = self.fingerprint,
+                extension = ABS_SEND_TIME,
+            )
+        }
+
+        /// Checks the candidate of the answer, then sets up DTLS and SRTP over it
+        pub(crate) fn connect(&mut self, answer: &str) {
+            let attribute = |name: &str| {
+                let prefix = format!("a={}:", name);
+                answer.lines().find_map(|line| line.strip_prefix(&prefix)).unwrap_or_else(|| panic!("no {} in {}", name, answer))
+            };
+            let candidate: Vec<&str> = attribute("candidate").split(' ').collect();
+            let remote = SocketAddr::new(candidate[4].parse().unwrap(), candidate[5].parse().unwrap());
+            let (ufrag, password) = (attribute("ice-ufrag"), attribute("ice-pwd"));
+            let fingerprint = attribute("fingerprint").strip_prefix("sha-256 ").unwrap().to_string();
+            assert!(answer.contains("a=ice-lite") && answer.contains("a=setup:passive"));
+
+            // The check nominates the candidate at once, the server being lite
+            let socket = self.socket.take().unwrap();
+            let transaction = [7u8; 12];
+            let request = binding_request(&transaction, &format!("{}:{}", ufrag, self.ufrag), password.as_bytes());
+            let deadline = Instant::now() + TIMEOUT;
+            let mut buffer = [0u8; 2048];
+            'checking: loop {
+                assert!(Instant::now() < deadline, "no answer to the connectivity check");
+                socket.send_to(&request, remote).unwrap();
+                while let Ok((size, from)) = socket.recv_from(&mut buffer) {
+                    if from != remote || buffer[0] >= 4 {
+                        continue; // Not STUN
+                    }
+                    if let Some(mapped) = binding_success(&buffer[..size], &transaction, password.as_bytes()) {
+                        assert_eq!(mapped, socket.local_addr().unwrap());
+                        break 'checking;
+                    }
+                }
+            }
+
+            let mut ssl = Ssl::new(&self.context).unwrap();
+            ssl.set_mtu(MTU as u32).unwrap();
+            ssl.set_connect_state();
+            let mut dtls = SslStream::new(ssl, Wire { socket, remote }).unwrap();
+            loop {
+                match dtls.do_handshake() {
+                    Ok(()) => break,
+                    Err(e) if e.code() == ErrorCode::WANT_READ && Instant::now() < deadline => {}
+                    Err(e) => panic!("DTLS handshake failed: {}", e),
+                }
+            }
+
+            let certificate = dtls.ssl().peer_certificate().unwrap().digest(MessageDigest::sha256()).unwrap();
+            let hex = certificate.iter().map(|byte| format!("{:02X}", byte)).collect::<Vec<_>>().join(":");
+            assert_eq!(hex, fingerprint, "the server's certificate is the one of its answer");
+
+            let mut material = [0u8; 2 * (MASTER_KEY_SIZE + MASTER_SALT_SIZE)];
+            dtls.ssl().export_keying_material(&mut material, "EXTRACTOR-dtls_srtp", None).unwrap();
+            // Client then server key, then their salts (RFC 5764 4.2), the browser being the client
+            let (keys, salts) = material.split_at(2 * MASTER_KEY_SIZE);
+            let key = |i: usize| &keys[i * MASTER_KEY_SIZE..][..MASTER_KEY_SIZE];
+            let salt = |i: usize| &salts[i * MASTER_SALT_SIZE..][..MASTER_SALT_SIZE];
+            self.keys = Some((Keys::derive(key(1), salt(1), 0), Keys::derive(key(0), salt(0), 3)));
+            self.dtls = Some(dtls);
+        }
+
+        /// Next SRTP packet of the video, as it came
+        pub(crate) fn packet(&mut self) -> Vec<u8> {
+            let socket = &self.dtls.as_ref().expect("Connected").get_ref().socket;
+            let deadline = Instant::now() + TIMEOUT;
+            let mut buffer = [0u8; 2048];
+            while Instant::now() < deadline {
+                if let Ok((size, _)) = socket.recv_from(&mut buffer) {
+                    // RTCP packet types are 192 to 223 (RFC 5761)
+                    if (128..=191).contains(&buffer[0]) && size > 1 && !(192..=223).contains(&(buffer[1] & 0x7f | 0x80)) {
+                        return buffer[..size].to_vec();
+                    }
+                }
+            }
+            panic!("no video from the server");
+        }
+
+        /// Next AV1 temporal unit, the type and payload of each of its OBUs
+        pub(crate) fn unit(&mut self) -> Vec<(u8, Vec<u8>)> {
+            loop {
+                let packet = self.packet();
+                let (packet, header) = self.unprotect(&packet);
+                assert_eq!(packet[1] & 0x7f, AV1);
+                if let Some(unit) = self.depacketizer.push(&packet, header) {
+                    return unit;
+                }
+            }
+        }
+
+        /// Checks the tag of an SRTP packet and decrypts it, returns it with the size of its header. The index
+        /// is the one closest to the highest so far (RFC 3711 3.3.1)
+        fn unprotect(&mut self, packet: &[u8]) -> (Vec<u8>, usize) {
+            let (keys, _) = self.keys.as_ref().expect("Connected");
+            let (packet, tag) = packet.split_at(packet.len() - 10);
+            let seq = u16::from_be_bytes([packet[2], packet[3]]) as u64;
+            let ssrc = u32::from_be_bytes([packet[8], packet[9], packet[10], packet[11]]);
+            let index = match self.index {
+                Some(highest) => {
+                    let guess = highest & !0xffff | seq;
+                    [guess.wrapping_sub(0x1_0000), guess, guess + 0x1_0000].into_iter().min_by_key(|index| index.abs_diff(highest)).unwrap()
+                }
+                None => seq,
+            };
+            assert_eq!(keys.tag(&[packet, &((index >> 16) as u32).to_be_bytes()]), tag, "SRTP tag of packet {}", index);
+            self.index = self.index.max(Some(index));
+
+            let mut header = 12 + 4 * (packet[0] & 0x0f) as usize;
+            if packet[0] & 0x10 != 0 {
+                header += 4 + 4 * u16::from_be_bytes([packet[header + 2], packet[header + 3]]) as usize;
+            }
+            let mut decrypted = packet[..header].to_vec();
+            decrypted.extend(keys.apply(ssrc, index, &packet[header..]));
+            (decrypted, header)
+        }
+
+        /// Sends a receiver report of the browser's SSRC 1, then `feedback` about the server's `media` SSRC
+        pub(crate) fn feedback(&mut self, kind: u8, format: u8, media: u32, fci: &[u8]) {
+            let mut compound = vec![0x80, 201, 0, 1, 0, 0, 0, 1];
+            compound.extend_from_slice(&[0x80 | format, kind, 0, 2 + fci.len() as u8 / 4, 0, 0, 0, 1]);
+            compound.extend_from_slice(&media.to_be_bytes());
+            compound.extend_from_slice(fci);
+
+            // SRTCP: the first 8 bytes stay as they are, then the E flag and index and the tag of it all
+            let (_, keys) = self.keys.as_ref().expect("Connected");
+            let mut protected = compound[..8].to_vec();
+            protected.extend(keys.apply(1, self.rtcp_index as u64, &compound[8..]));
+            protected.extend_from_slice(&(self.rtcp_index | 0x8000_0000).to_be_bytes());
+            let tag = keys.tag(&[&protected]);
+            protected.extend(tag);
+            self.rtcp_index += 1;
+            let wire = self.dtls.as_ref().expect("Connected").get_ref();
+            wire.socket.send_to(&protected, wire.remote).unwrap();
+        }
+    }
+
+    /// What a decoder reads first of an AV1 stream
+    #[derive(Debug, PartialEq, Eq)]
+    pub(crate) struct SequenceHeader {
+        pub profile: u32,
+        pub max_width: u32,
+        pub max_height: u32,
+    }
+
+    /// Reads an OBU payload bit by bit, most significant first
+    struct Bits<'a> {
+        bytes: &'a [u8],
+        position: usize,
+    }
+
+    impl Bits<'_> {
+        fn read(&mut self, count: usize) -> u32 {
+            (0..count).fold(0, |value, _| {
+                let bit = self.bytes[self.position / 8] >> (7 - self.position % 8) & 1;
+                self.position += 1;
+                value << 1 | bit as u32
+            })
+        }
+    }
+
+    /// Reads the sequence header OBU of a unit (AV1 5.5), rav1e doesn't put timing info in it
+    pub(crate) fn sequence_header(unit: &[(u8, Vec<u8>)]) -> Option<SequenceHeader> {
+        let (_, payload) = unit.iter().find(|(kind, _)| *kind == 1)?;
+        let mut bits = Bits { bytes: payload, position: 0 };
+        let profile = bits.read(3);
+        bits.read(1); // Still picture
+        assert_eq!(bits.read(1), 0, "not a reduced still picture header");
+        assert_eq!(bits.read(1), 0, "no timing info");
+        let display_delays = bits.read(1) == 1;
+        for _ in 0..=bits.read(5) {
+            bits.read(12); // Operating point idc
+            if bits.read(5) > 7 {
+                bits.read(1); // Tier
+            }
+            if display_delays && bits.read(1) == 1 {
+                bits.read(4);
+            }
+        }
+        let (width_bits, height_bits) = (bits.read(4) as usize + 1, bits.read(4) as usize + 1);
+        Some(SequenceHeader { profile, max_width: bits.read(width_bits) + 1, max_height: bits.read(height_bits) + 1 })
+    }
+
+    /// Whether the frame of a unit is a key frame shown at once (AV1 5.9.2)
+    pub(crate) fn is_keyframe(unit: &[(u8, Vec<u8>)]) -> bool {
+        let (_, payload) = unit.iter().find(|(kind, _)| matches!(kind, 3 | 6)).expect("a frame in every unit");
+        // Not showing an existing frame, then a frame type of 0
+        payload[0] >> 5 == 0
+    }
+
+    /// Polls the peer and sends it `jpeg` at 30 frames a second, as the web connection does, until stopped
+    fn serve(mut peer: Peer, jpeg: Vec<u8>) -> (Arc<AtomicBool>, JoinHandle<Peer>) {
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopping = Arc::clone(&stop);
+        let thread = thread::spawn(move || {
+            while !stopping.load(Ordering::SeqCst) && peer.poll() != State::Failed {
+                peer.send(&jpeg).unwrap();
+                thread::sleep(Duration::from_millis(33));
+            }
+            peer
+        });
+        (stop, thread)
+    }
+
+    #[test]
+    fn offer_parsing() {
+        let browser = Browser::new();
+        let offer = Offer::parse(&browser.offer()).unwrap();
+        assert_eq!((offer.ufrag.as_str(), offer.mid.as_str(), offer.payload_type), ("brws", "0", AV1));
+        assert_eq!(offer.abs_send_time, Some(3));
+        assert_eq!(offer.fingerprint.len(), 32);
+
+        let without_av1 = browser.offer().replace(&format!("a=rtpmap:{} AV1/90000\r\n", AV1), "");
+        let error = Offer::parse(&without_av1).err().expect("an offer without AV1 is refused");
+        assert!(error.contains("AV1"), "{}", error);
+        let high_profile = browser.offer() + &format!("a=fmtp:{} profile=1\r\n", AV1);
+        assert!(Offer::parse(&high_profile).is_err());
+        let passive = browser.offer().replace("a=setup:actpass", "a=setup:passive");
+        assert!(Offer::parse(&passive).is_err());
+    }
+
+    #[test]
+    fn check_with_the_wrong_password_is_not_answered() {
+        let settings = Arc::new(Settings::new(Vec::new()).unwrap());
+        let browser = Browser::new();
+        let (mut peer, answer) = Peer::answer(&browser.offer(), LOCALHOST, &settings).unwrap();
+        let ufrag = answer.lines().find_map(|line| line.strip_prefix("a=ice-ufrag:")).unwrap();
+
+        let socket = browser.socket.as_ref().unwrap();
+        let request = binding_request(&[1; 12], &format!("{}:brws", ufrag), b"not the password");
+        socket.send_to(&request, peer.socket.local_addr().unwrap()).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        peer.poll();
+
+        assert_eq!(peer.remote, None);
+        assert!(socket.recv_from(&mut [0u8; 2048]).is_err());
+    }
+
+    #[test]
+    fn viewers_past_the_cap_are_not_answered() {
+        let settings = Arc::new(Settings::new(Vec::new()).unwrap());
+        let offer = Browser::new().offer();
+        let mut peers: Vec<Peer> = (0..MAX_WEBRTC_VIEWERS).map(|_| Peer::answer(&offer, LOCALHOST, &settings).unwrap().0).collect();
+        assert!(settings.full());
+        let error = Peer::answer(&offer, LOCALHOST, &settings).err().expect("one viewer too many is refused");
+        assert!(error.contains("WebRTC viewers"), "{}", error);
+
+        // A viewer that leaves makes room, a refused offer didn't take any
+        peers.pop();
+        assert!(!settings.full());
+        peers.push(Peer::answer(&offer, LOCALHOST, &settings).unwrap().0);
+        assert!(Peer::answer(&offer, LOCALHOST, &settings).is_err());
+    }
+
+    #[test]
+    fn browser_receives_av1_and_gets_keyframes_and_resends() {
+        let (width, height) = (64, 48);
+        let settings = Arc::new(Settings::new(Vec::new()).unwrap());
+        let mut browser = Browser::new();
+        let (peer, answer) = Peer::answer(&browser.offer(), LOCALHOST, &settings).unwrap();
+        let ssrc: u32 = answer.lines().find_map(|line| line.strip_prefix("a=ssrc:")).unwrap().split(' ').next().unwrap().parse().unwrap();
+        let (stop, server) = serve(peer, jpeg(width, height));
+        browser.connect(&answer);
+
+        let unit = browser.unit();
+        let expected = SequenceHeader { profile: 0, max_width: width as u32, max_height: height as u32 };
+        assert_eq!(sequence_header(&unit), Some(expected), "main profile at the size of the frames");
+        assert!(is_keyframe(&unit));
+
+        // Once the stream is on inter frames, a picture loss indication brings a keyframe
+        assert!((0..30).any(|_| !is_keyframe(&browser.unit())));
+        browser.feedback(PSFB, PLI, ssrc, &[]);
+        assert!((0..30).any(|_| is_keyframe(&browser.unit())), "no keyframe after the PLI");
+
+        // A lost packet is sent again as it was
+        let lost = browser.packet();
+        let seq = u16::from_be_bytes([lost[2], lost[3]]);
+        let mut fci = seq.to_be_bytes().to_vec();
+        fci.extend_from_slice(&[0, 0]);
+        browser.feedback(RTPFB, NACK, ssrc, &fci);
+        assert!((0..100).any(|_| browser.packet() == lost), "lost packet not sent again");
+
+        stop.store(true, Ordering::SeqCst);
+        assert_eq!(server.join().unwrap().state, State::Connected);
+    }
+}
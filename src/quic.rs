@@ -0,0 +1,663 @@
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::net::{SocketAddr, UdpSocket};
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, SyncSender, TryRecvError, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use quinn::crypto::rustls::{QuicClientConfig, QuicServerConfig};
+use quinn::rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use quinn::rustls::crypto::{self, CryptoProvider};
+use quinn::rustls::pki_types::pem::PemObject;
+use quinn::rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName, UnixTime};
+use quinn::rustls::{self, DigitallySignedStruct, SignatureScheme};
+use quinn::{Connection, Endpoint, EndpointConfig, RecvStream, SendStream, TokioRuntime, TransportConfig, VarInt};
+use sha2::{Digest, Sha256};
+use tokio::runtime::{Handle, Runtime};
+
+use crate::auth;
+use crate::packet::Packet;
+use crate::sender::Sender;
+use crate::transport::{self, Transmit, CONNECT_TIMEOUT, IDLE_TIMEOUT, LENGTH_SIZE, MAX_CONNECTIONS, WRITE_TIMEOUT};
+
+/// Protocol negotiated with ALPN, so neither side mistakes another QUIC service for its peer
+const ALPN: &[u8] = b"screen-stream";
+
+/// Name the certificate is issued for, viewers check its fingerprint instead
+const SERVER_NAME: &str = "screen-stream";
+
+/// Datagrams received but not handled yet, more are dropped like a full socket buffer would
+const MAX_QUEUED: usize = 4096;
+
+/// QUIC closes a connection after this long without a packet, viewers ping every second
+const QUIC_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Longest to wait for connections to be closed cleanly when leaving
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Datagrams from the server, then why the connection ended
+type Incoming = Receiver<Result<Vec<u8>, String>>;
+
+/// SHA-256 of a certificate, what viewers pin the server's self-signed certificate with
+pub type Fingerprint = [u8; 32];
+
+/// Sends frames as datagrams, and control messages on the reliable stream of a connection
+/// The two aren't ordered with each other, encrypted ones are sealed in their own sequence spaces so
+/// datagrams overtaking a control message don't push it out of the viewer's replay window
+pub struct QuicLink {
+    connection: Connection,
+    control: Mutex<SendStream>,
+    runtime: Handle,
+}
+
+impl Transmit for QuicLink {
+    fn send_to(&self, bytes: &[u8], _address: SocketAddr) -> io::Result<usize> {
+        match self.connection.send_datagram(bytes.to_vec().into()) {
+            Ok(()) => Ok(bytes.len()),
+            // The path got narrower than the packets the viewer asked for, lost like a dropped datagram
+            Err(quinn::SendDatagramError::TooLarge) => Err(io::ErrorKind::WouldBlock.into()),
+            Err(e) => Err(io::Error::new(io::ErrorKind::ConnectionAborted, e)),
+        }
+    }
+
+    fn send_control(&self, bytes: &[u8], _address: SocketAddr) -> io::Result<usize> {
+        let framed = transport::frame(bytes)?;
+        let mut control = self.control.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        self.runtime
+            .block_on(async { tokio::time::timeout(WRITE_TIMEOUT, control.write_all(&framed)).await })
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "control stream blocked for too long"))?
+            .map_err(|e| io::Error::new(io::ErrorKind::ConnectionAborted, e))?;
+        Ok(bytes.len())
+    }
+}
+
+/// What connection tasks tell the server loop
+enum Event {
+    Opened { address: SocketAddr, connection: Connection, control: SendStream },
+    Closed { address: SocketAddr, reason: String },
+}
+
+/// A viewer's QUIC connection, it keeps standing for the address it was opened from when the viewer migrates
+struct Peer {
+    connection: Connection,
+    sender: Arc<Sender>,
+    opened: Instant,
+}
+
+/// The server's QUIC endpoint and the connection of every viewer
+/// Connections are served by tasks on a small runtime of their own, the server loop polls what they received
+pub struct QuicServer {
+    runtime: Runtime,
+    endpoint: Endpoint,
+    events: Receiver<Event>,
+    datagrams: Receiver<(SocketAddr, Vec<u8>)>,
+    peers: HashMap<SocketAddr, Peer>,
+    closed: Vec<SocketAddr>, // Connections that ended since the last `take_closed`
+    fingerprint: Fingerprint,
+}
+
+impl QuicServer {
+    /// Serves QUIC on `socket` with the certificate kept in the `certificate` file, or a new one every start
+    pub fn bind(socket: UdpSocket, certificate: Option<&Path>) -> io::Result<Self> {
+        let runtime = runtime()?;
+
+        let (certificate, key) = match certificate {
+            Some(path) => kept_certificate(path)?,
+            None => {
+                let certificate = self_signed()?;
+                let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(certificate.key_pair.serialize_der()));
+                (certificate.cert.der().clone(), key)
+            }
+        };
+        let fingerprint = fingerprint(&certificate);
+
+        let mut tls = rustls::ServerConfig::builder_with_provider(Arc::new(crypto::ring::default_provider()))
+            .with_protocol_versions(&[&rustls::version::TLS13])
+            .map_err(io::Error::other)?
+            .with_no_client_auth()
+            .with_single_cert(vec![certificate], key)
+            .map_err(io::Error::other)?;
+        tls.alpn_protocols = vec![ALPN.to_vec()];
+
+        let mut config = quinn::ServerConfig::with_crypto(Arc::new(QuicServerConfig::try_from(tls).map_err(io::Error::other)?));
+        config.transport_config(transport_config());
+
+        socket.set_nonblocking(true)?;
+        let endpoint = {
+            let _context = runtime.enter();
+            Endpoint::new(EndpointConfig::default(), Some(config), socket, Arc::new(TokioRuntime))?
+        };
+
+        let (events, event_receiver) = mpsc::channel();
+        let (datagrams, datagram_receiver) = mpsc::sync_channel(MAX_QUEUED);
+        runtime.spawn(accept(endpoint.clone(), events, datagrams));
+
+        Ok(Self {
+            runtime,
+            endpoint,
+            events: event_receiver,
+            datagrams: datagram_receiver,
+            peers: HashMap::new(),
+            closed: Vec::new(),
+            fingerprint,
+        })
+    }
+
+    /// Fingerprint of the certificate, printed for viewers to pin
+    pub fn fingerprint(&self) -> Fingerprint {
+        self.fingerprint
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.endpoint.local_addr()
+    }
+
+    /// Takes in the connections opened and closed since the last call
+    fn update(&mut self) {
+        while let Ok(event) = self.events.try_recv() {
+            match event {
+                Event::Opened { address, connection, .. } if self.peers.len() >= MAX_CONNECTIONS => {
+                    eprintln!("Too many connections, refused {}", address);
+                    connection.close(VarInt::from_u32(0), b"too many connections");
+                }
+                Event::Opened { address, connection, control } => {
+                    let link =
+                        QuicLink { connection: connection.clone(), control: Mutex::new(control), runtime: self.runtime.handle().clone() };
+                    match Sender::spawn(link) {
+                        Ok(sender) => {
+                            let peer = Peer { connection, sender: Arc::new(sender), opened: Instant::now() };
+                            self.peers.insert(address, peer);
+                        }
+                        Err(e) => eprintln!("Error starting sender for {}: {}", address, e),
+                    }
+                }
+                Event::Closed { address, reason } => {
+                    if self.peers.remove(&address).is_some() {
+                        println!("Connection from {} closed: {}", address, reason);
+                        self.closed.push(address);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Next datagram from any viewer, `WouldBlock` when there is none
+    pub fn recv_from(&mut self, buffer: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        self.update();
+
+        match self.datagrams.try_recv() {
+            Ok((address, datagram)) => Ok((transport::truncate_into(&datagram, buffer), address)),
+            Err(TryRecvError::Empty) => Err(io::ErrorKind::WouldBlock.into()),
+            Err(TryRecvError::Disconnected) => Err(io::Error::new(io::ErrorKind::BrokenPipe, "QUIC endpoint stopped")),
+        }
+    }
+
+    /// Queues a control message on the connection from `address`
+    pub fn send_to(&self, bytes: &[u8], address: SocketAddr) -> io::Result<()> {
+        match self.peers.get(&address) {
+            Some(peer) if peer.sender.control(address, bytes.to_vec()) => Ok(()),
+            Some(_) => Err(io::Error::new(io::ErrorKind::WouldBlock, "send queue full")),
+            None => Err(io::ErrorKind::NotConnected.into()),
+        }
+    }
+
+    pub fn connection(&self, address: SocketAddr) -> Option<Arc<Sender>> {
+        self.peers.get(&address).map(|peer| Arc::clone(&peer.sender))
+    }
+
+    pub fn take_closed(&mut self) -> Vec<SocketAddr> {
+        std::mem::take(&mut self.closed)
+    }
+
+    pub fn prune(&mut self, in_use: impl Fn(&SocketAddr) -> bool) {
+        self.peers.retain(|address, peer| {
+            let keep = in_use(address) || peer.opened.elapsed() < IDLE_TIMEOUT;
+            if !keep {
+                println!("Closing idle connection from {}", address);
+                peer.connection.close(VarInt::from_u32(0), b"idle");
+            }
+            keep
+        });
+    }
+
+    /// Closes every connection, so viewers notice right away instead of after the idle timeout
+    pub fn close(&self) {
+        self.endpoint.close(VarInt::from_u32(0), b"server shut down");
+        let _ = self.runtime.block_on(async { tokio::time::timeout(CLOSE_TIMEOUT, self.endpoint.wait_idle()).await });
+    }
+}
+
+async fn accept(endpoint: Endpoint, events: mpsc::Sender<Event>, datagrams: SyncSender<(SocketAddr, Vec<u8>)>) {
+    while let Some(incoming) = endpoint.accept().await {
+        tokio::spawn(serve(incoming, events.clone(), datagrams.clone()));
+    }
+}
+
+/// Completes the handshake of a viewer and receives from it until the connection ends
+/// The viewer opens the control stream, with its hello
+async fn serve(incoming: quinn::Incoming, events: mpsc::Sender<Event>, datagrams: SyncSender<(SocketAddr, Vec<u8>)>) {
+    let address = incoming.remote_address();
+
+    let connection = match incoming.await {
+        Ok(connection) => connection,
+        Err(e) => {
+            eprintln!("QUIC handshake with {} failed: {}", address, e);
+            return;
+        }
+    };
+
+    let (control, stream) = match connection.accept_bi().await {
+        Ok(streams) => streams,
+        Err(e) => {
+            println!("Connection from {} closed before joining: {}", address, e);
+            return;
+        }
+    };
+
+    if events.send(Event::Opened { address, connection: connection.clone(), control }).is_err() {
+        return;
+    }
+
+    let reason = receive(&connection, stream, |datagram| {
+        !matches!(datagrams.try_send((address, datagram)), Err(TrySendError::Disconnected(_)))
+    })
+    .await;
+
+    let _ = events.send(Event::Closed { address, reason });
+}
+
+/// Hands every message of the control stream and every datagram to `deliver` until the connection ends
+/// or `deliver` returns false, returns why it ended
+async fn receive(connection: &Connection, mut stream: RecvStream, deliver: impl Fn(Vec<u8>) -> bool) -> String {
+    let control = async {
+        loop {
+            let datagram = read_framed(&mut stream).await?;
+            if !deliver(datagram) {
+                return Ok(());
+            }
+        }
+    };
+
+    let datagrams = async {
+        loop {
+            let datagram = connection.read_datagram().await.map_err(|e| io::Error::new(io::ErrorKind::ConnectionAborted, e))?;
+            if !deliver(datagram.to_vec()) {
+                return Ok(());
+            }
+        }
+    };
+
+    let ended: io::Result<()> = tokio::select! {
+        ended = control => ended,
+        ended = datagrams => ended,
+    };
+
+    match ended {
+        Ok(()) => String::from("stopped receiving"),
+        Err(e) => e.to_string(),
+    }
+}
+
+/// Reads a datagram written on a stream with `transport::frame`
+async fn read_framed(stream: &mut RecvStream) -> io::Result<Vec<u8>> {
+    let closed = |e| io::Error::new(io::ErrorKind::ConnectionAborted, e);
+
+    let mut length = [0u8; LENGTH_SIZE];
+    stream.read_exact(&mut length).await.map_err(closed)?;
+
+    let length = u32::from_le_bytes(length) as usize;
+    if length > Packet::MAX_DATAGRAM {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("datagram of {} bytes is too long", length)));
+    }
+
+    let mut datagram = vec![0u8; length];
+    stream.read_exact(&mut datagram).await.map_err(closed)?;
+    Ok(datagram)
+}
+
+/// The viewer's QUIC connection to the server
+pub struct QuicClient {
+    runtime: Runtime,
+    endpoint: Endpoint,
+    server: SocketAddr,
+    link: QuicLink,
+    incoming: Incoming,
+}
+
+impl QuicClient {
+    /// Connects from `bind`, accepting the server's certificate if its fingerprint is `pinned`
+    /// Without a pinned fingerprint any certificate is accepted and its fingerprint printed
+    pub fn connect(server: SocketAddr, bind: SocketAddr, pinned: Option<Fingerprint>) -> io::Result<Self> {
+        let runtime = runtime()?;
+
+        let provider = Arc::new(crypto::ring::default_provider());
+        let mut tls = rustls::ClientConfig::builder_with_provider(Arc::clone(&provider))
+            .with_protocol_versions(&[&rustls::version::TLS13])
+            .map_err(io::Error::other)?
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(Pinned { fingerprint: pinned, provider }))
+            .with_no_client_auth();
+        tls.alpn_protocols = vec![ALPN.to_vec()];
+
+        let mut config = quinn::ClientConfig::new(Arc::new(QuicClientConfig::try_from(tls).map_err(io::Error::other)?));
+        config.transport_config(transport_config());
+
+        let socket = UdpSocket::bind(bind)?;
+        socket.set_nonblocking(true)?;
+        let mut endpoint = {
+            let _context = runtime.enter();
+            Endpoint::new(EndpointConfig::default(), None, socket, Arc::new(TokioRuntime))?
+        };
+        endpoint.set_default_client_config(config);
+
+        let (link, incoming) = open(&runtime, &endpoint, server)?;
+        Ok(Self { runtime, endpoint, server, link, incoming })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.endpoint.local_addr()
+    }
+
+    /// Control messages go on the reliable stream, viewers send nothing else
+    pub fn send(&self, bytes: &[u8]) -> io::Result<usize> {
+        self.link.send_control(bytes, self.server)
+    }
+
+    /// Next datagram from the server, `WouldBlock` when there is none and `UnexpectedEof` once the connection ended
+    pub fn recv(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        match self.incoming.try_recv() {
+            Ok(Ok(datagram)) => Ok(transport::truncate_into(&datagram, buffer)),
+            Ok(Err(reason)) => Err(io::Error::new(io::ErrorKind::UnexpectedEof, reason)),
+            Err(TryRecvError::Empty) => Err(io::ErrorKind::WouldBlock.into()),
+            Err(TryRecvError::Disconnected) => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed")),
+        }
+    }
+
+    /// Opens a new connection from the same socket
+    pub fn reconnect(&mut self) -> io::Result<()> {
+        self.link.connection.close(VarInt::from_u32(0), b"reconnecting");
+        let (link, incoming) = open(&self.runtime, &self.endpoint, self.server)?;
+        self.link = link;
+        self.incoming = incoming;
+        Ok(())
+    }
+
+    /// Sends what is left on the control stream, a goodbye usually, then closes the connection
+    /// Exiting without it would drop both, the server would only notice the silence
+    pub fn close(&self) {
+        let mut control = self.link.control.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let _ = control.finish();
+
+        self.runtime.block_on(async {
+            let _ = tokio::time::timeout(CLOSE_TIMEOUT, control.stopped()).await;
+            self.link.connection.close(VarInt::from_u32(0), b"viewer left");
+            let _ = tokio::time::timeout(CLOSE_TIMEOUT, self.endpoint.wait_idle()).await;
+        });
+    }
+
+    /// Largest datagram that fits in one packet on the path to the server
+    pub fn max_datagram_size(&self) -> Option<usize> {
+        self.link.connection.max_datagram_size()
+    }
+}
+
+/// Connects to the server and opens the control stream
+fn open(runtime: &Runtime, endpoint: &Endpoint, server: SocketAddr) -> io::Result<(QuicLink, Incoming)> {
+    let (connection, (control, stream)) = runtime.block_on(async {
+        let connecting = endpoint.connect(server, SERVER_NAME).map_err(io::Error::other)?;
+        let connection = match tokio::time::timeout(CONNECT_TIMEOUT, connecting).await {
+            Ok(connection) => connection.map_err(|e| io::Error::new(io::ErrorKind::ConnectionRefused, e))?,
+            Err(_) => return Err(io::Error::new(io::ErrorKind::TimedOut, "server didn't answer")),
+        };
+        let streams = connection.open_bi().await.map_err(|e| io::Error::new(io::ErrorKind::ConnectionAborted, e))?;
+        Ok((connection, streams))
+    })?;
+
+    let (deliver, incoming) = mpsc::sync_channel(MAX_QUEUED);
+    let receiving = connection.clone();
+    runtime.spawn(async move {
+        let reason = receive(&receiving, stream, |datagram| {
+            !matches!(deliver.try_send(Ok(datagram)), Err(TrySendError::Disconnected(_)))
+        })
+        .await;
+        let _ = deliver.try_send(Err(reason));
+    });
+
+    Ok((QuicLink { connection, control: Mutex::new(control), runtime: runtime.handle().clone() }, incoming))
+}
+
+/// Accepts the server's certificate by its fingerprint, or any certificate when none is pinned
+/// The handshake signature is still checked, so the server has the key of the certificate it shows
+#[derive(Debug)]
+struct Pinned {
+    fingerprint: Option<Fingerprint>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for Pinned {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let seen = fingerprint(end_entity);
+        match self.fingerprint {
+            Some(pinned) if pinned != seen => Err(rustls::Error::General(format!(
+                "server certificate fingerprint {} is not the pinned one",
+                auth::to_hex(&seen)
+            ))),
+            Some(_) => Ok(ServerCertVerified::assertion()),
+            None => {
+                println!("Server certificate fingerprint: {} (not verified, pin it with --fingerprint)", auth::to_hex(&seen));
+                Ok(ServerCertVerified::assertion())
+            }
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+fn self_signed() -> io::Result<rcgen::CertifiedKey> {
+    rcgen::generate_simple_self_signed(vec![String::from(SERVER_NAME)]).map_err(io::Error::other)
+}
+
+/// Certificate and key from the PEM file at `path`, made and written there first when there is no such file,
+/// so the fingerprint viewers pinned stays the same when the server restarts
+fn kept_certificate(path: &Path) -> io::Result<(CertificateDer<'static>, PrivateKeyDer<'static>)> {
+    let pem = match std::fs::read(path) {
+        Ok(pem) => pem,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let certificate = self_signed()?;
+            let pem = certificate.cert.pem() + &certificate.key_pair.serialize_pem();
+
+            // Only the server's user may read the key
+            let mut options = OpenOptions::new();
+            options.write(true).create_new(true);
+            #[cfg(unix)]
+            std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+            options.open(path)?.write_all(pem.as_bytes())?;
+
+            println!("Wrote a new QUIC certificate to {}", path.display());
+            pem.into_bytes()
+        }
+        Err(e) => return Err(e),
+    };
+
+    let invalid = |what: &str, e: rustls::pki_types::pem::Error| {
+        io::Error::new(io::ErrorKind::InvalidData, format!("no {} in {}: {}", what, path.display(), e))
+    };
+    let certificate = CertificateDer::from_pem_slice(&pem).map_err(|e| invalid("certificate", e))?;
+    let key = PrivateKeyDer::from_pem_slice(&pem).map_err(|e| invalid("private key", e))?;
+    Ok((certificate, key))
+}
+
+fn fingerprint(certificate: &CertificateDer<'_>) -> Fingerprint {
+    Sha256::digest(certificate.as_ref()).into()
+}
+
+/// Frames are sent paced by the server already, QUIC only needs to notice dead peers sooner than its default
+fn transport_config() -> Arc<TransportConfig> {
+    let mut config = TransportConfig::default();
+    config.max_idle_timeout(Some(QUIC_IDLE_TIMEOUT.try_into().expect("Idle timeout fits in a QUIC varint")));
+    config.max_concurrent_uni_streams(VarInt::from_u32(0));
+    Arc::new(config)
+}
+
+/// A runtime of its own, the rest of the server and viewer runs on plain threads
+fn runtime() -> io::Result<Runtime> {
+    tokio::runtime::Builder::new_multi_thread().worker_threads(1).thread_name("quic").enable_all().build()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::thread;
+
+    use super::*;
+    use crate::commands::KeyArgs;
+    use crate::crypto::{Channel, PreSharedKey, Session};
+
+    const LOCALHOST: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+    fn server() -> QuicServer {
+        QuicServer::bind(UdpSocket::bind((LOCALHOST, 0)).unwrap(), None).unwrap()
+    }
+
+    /// Polls until `f` stops returning `WouldBlock`
+    fn poll<T>(mut f: impl FnMut() -> io::Result<T>) -> T {
+        let started = Instant::now();
+        loop {
+            match f() {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    assert!(started.elapsed() < Duration::from_secs(5), "nothing arrived");
+                    thread::sleep(Duration::from_millis(1));
+                }
+                result => return result.unwrap(),
+            }
+        }
+    }
+
+    /// Connects a viewer with the server's fingerprint pinned and sends its first control message
+    fn connect(server: &mut QuicServer, hello: &[u8]) -> (QuicClient, SocketAddr) {
+        let address = server.local_addr().unwrap();
+        let client = QuicClient::connect(address, SocketAddr::new(LOCALHOST, 0), Some(server.fingerprint())).unwrap();
+        client.send(hello).unwrap();
+
+        let mut buffer = [0u8; Packet::MAX_DATAGRAM];
+        let (amount, viewer) = poll(|| server.recv_from(&mut buffer));
+        assert_eq!(&buffer[..amount], hello);
+        assert_eq!(viewer, client.local_addr().unwrap());
+        (client, viewer)
+    }
+
+    fn receive(client: &mut QuicClient) -> Vec<u8> {
+        let mut buffer = [0u8; Packet::MAX_DATAGRAM];
+        let amount = poll(|| client.recv(&mut buffer));
+        buffer[..amount].to_vec()
+    }
+
+    #[test]
+    fn control_and_datagrams_reach_the_viewer() {
+        let mut server = server();
+        let (mut client, viewer) = connect(&mut server, b"hello");
+        let sender = server.connection(viewer).unwrap();
+
+        server.send_to(b"accept", viewer).unwrap();
+        assert_eq!(receive(&mut client), b"accept");
+
+        sender.frame(vec![(viewer, b"frame".to_vec())], Duration::ZERO);
+        assert_eq!(receive(&mut client), b"frame");
+        assert!(client.max_datagram_size().unwrap() >= 1200);
+
+        client.close();
+        let started = Instant::now();
+        while server.take_closed().is_empty() {
+            let _ = server.recv_from(&mut [0u8; 16]);
+            assert!(started.elapsed() < Duration::from_secs(5), "server didn't notice the viewer left");
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert!(server.connection(viewer).is_none());
+    }
+
+    #[test]
+    fn wrong_fingerprint_is_refused() {
+        let server = server();
+        let mut wrong = server.fingerprint();
+        wrong[0] ^= 1;
+
+        let address = server.local_addr().unwrap();
+        assert!(QuicClient::connect(address, SocketAddr::new(LOCALHOST, 0), Some(wrong)).is_err());
+        assert!(QuicClient::connect(address, SocketAddr::new(LOCALHOST, 0), None).is_ok());
+    }
+
+    #[test]
+    fn kept_certificate_survives_a_restart() {
+        let path = std::env::temp_dir().join(format!("screen-stream-certificate-{}.pem", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let bind = || QuicServer::bind(UdpSocket::bind((LOCALHOST, 0)).unwrap(), Some(&path));
+
+        let fingerprint = bind().unwrap().fingerprint();
+        let restarted = bind().unwrap();
+        assert_eq!(restarted.fingerprint(), fingerprint);
+        assert_ne!(server().fingerprint(), fingerprint);
+
+        // A viewer that pinned the first one still gets in
+        let address = restarted.local_addr().unwrap();
+        assert!(QuicClient::connect(address, SocketAddr::new(LOCALHOST, 0), Some(fingerprint)).is_ok());
+
+        std::fs::write(&path, b"not a certificate").unwrap();
+        assert_eq!(bind().err().unwrap().kind(), io::ErrorKind::InvalidData);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn sealed_control_overtaken_by_datagrams_still_opens() {
+        let args = KeyArgs { key: Some(String::from("correct horse battery staple")), key_file: None };
+        let key = PreSharedKey::load(&args).unwrap().unwrap();
+        let mut viewer_session = Session::client(&key).unwrap();
+        let hello = viewer_session.seal(Channel::Control, b"hello");
+
+        let mut server = server();
+        let (mut client, viewer) = connect(&mut server, &hello);
+        let mut session = Session::server(&key, Session::salt_of(&hello).unwrap()).unwrap();
+        session.open(&hello).unwrap();
+
+        // Sealed first but sent after more datagrams than a replay window holds
+        let accept = session.seal(Channel::Control, b"accept");
+        let frames: Vec<(SocketAddr, Vec<u8>)> =
+            (0..100).map(|_| (viewer, session.seal(Channel::Frames, b"frame"))).collect();
+        let sender = server.connection(viewer).unwrap();
+        sender.frame(frames, Duration::ZERO);
+        assert!(sender.control(viewer, accept));
+
+        let opened: Vec<Vec<u8>> = (0..101).map(|_| viewer_session.open(&receive(&mut client)).unwrap()).collect();
+        assert_eq!(opened.iter().filter(|datagram| *datagram == b"frame").count(), 100);
+        assert!(opened.contains(&b"accept".to_vec()));
+    }
+}
@@ -0,0 +1,984 @@
+/// RTP payload type of JPEG, static in the RTP/AVP profile (RFC 3551)
+pub const PAYLOAD_TYPE: u8 = 26;
+
+/// Clock of RTP video timestamps, in ticks per second
+pub const CLOCK_RATE: u32 = 90_000;
+
+/// Largest width or height RTP/JPEG can describe: it is sent in blocks of 8 pixels, in one byte
+pub const MAX_SIZE: u16 = 2040;
+
+const RTP_VERSION: u8 = 2;
+const RTP_HEADER_SIZE: usize = 12;
+const JPEG_HEADER_SIZE: usize = 8;
+const QUANT_HEADER_SIZE: usize = 4;
+const AV1_HEADER_SIZE: usize = 1;
+
+// Bits of the AV1 aggregation header: first element continues the previous packet's last one, last element
+// continues in the next packet, packet starts a new coded video sequence
+const AV1_Z: u8 = 0x80;
+const AV1_Y: u8 = 0x40;
+const AV1_N: u8 = 0x08;
+
+// AV1 OBU header bits and types
+const OBU_HAS_EXTENSION: u8 = 0x04;
+const OBU_HAS_SIZE: u8 = 0x02;
+const OBU_SEQUENCE_HEADER: u8 = 1;
+const OBU_TEMPORAL_DELIMITER: u8 = 2;
+const OBU_PADDING: u8 = 15;
+
+/// Longest scan the 24 bit fragment offset can address
+const MAX_SCAN: usize = 1 << 24;
+
+/// Q of frames that carry their quantization tables, in the first packet of every frame
+const DYNAMIC_Q: u8 = 255;
+
+/// First Q of the range whose tables are sent in the frame rather than derived from Q
+#[cfg(any(test, fuzzing))]
+const FIRST_DYNAMIC_Q: u8 = 128;
+
+// JPEG markers
+const SOI: u8 = 0xd8;
+const EOI: u8 = 0xd9;
+const SOF0: u8 = 0xc0;
+const DHT: u8 = 0xc4;
+const SOS: u8 = 0xda;
+const DQT: u8 = 0xdb;
+const DRI: u8 = 0xdd;
+
+/// Position in a block of the coefficient at each zigzag index, quantization tables are sent in zigzag order
+#[cfg(any(test, fuzzing))]
+const ZIGZAG: [usize; 64] = [
+    0, 1, 8, 16, 9, 2, 3, 10, 17, 24, 32, 25, 18, 11, 4, 5, 12, 19, 26, 33, 40, 48, 41, 34, 27, 20, 13, 6, 7, 14, 21, 28,
+    35, 42, 49, 56, 57, 50, 43, 36, 29, 22, 15, 23, 30, 37, 44, 51, 58, 59, 52, 45, 38, 31, 39, 46, 53, 60, 61, 54, 47, 55,
+    62, 63,
+];
+
+/// Quantization tables of the JPEG standard (Annex K), which a Q below 128 scales
+#[cfg(any(test, fuzzing))]
+const LUMA_QUANTIZER: [u8; 64] = [
+    16, 11, 10, 16, 24, 40, 51, 61, 12, 12, 14, 19, 26, 58, 60, 55, 14, 13, 16, 24, 40, 57, 69, 56, 14, 17, 22, 29, 51, 87,
+    80, 62, 18, 22, 37, 56, 68, 109, 103, 77, 24, 35, 55, 64, 81, 104, 113, 92, 49, 64, 78, 87, 103, 121, 120, 101, 72, 92,
+    95, 98, 112, 100, 103, 99,
+];
+#[cfg(any(test, fuzzing))]
+const CHROMA_QUANTIZER: [u8; 64] = [
+    17, 18, 24, 47, 99, 99, 99, 99, 18, 21, 26, 66, 99, 99, 99, 99, 24, 26, 56, 99, 99, 99, 99, 99, 47, 66, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99,
+];
+
+/// Huffman tables of the JPEG standard (Annex K), the only ones RTP/JPEG can carry
+/// As (class, id, code counts per length, symbols), class 0 for DC and 1 for AC, id 0 for luma and 1 for chroma
+const HUFFMAN_TABLES: [(u8, u8, [u8; 16], &[u8]); 4] = [
+    (0, 0, [0, 1, 5, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0], &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11]),
+    (0, 1, [0, 3, 1, 1, 1, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0], &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11]),
+    (1, 0, [0, 2, 1, 3, 3, 2, 4, 3, 5, 5, 4, 4, 0, 0, 1, 0x7d], &LUMA_AC_SYMBOLS),
+    (1, 1, [0, 2, 1, 2, 4, 4, 3, 4, 7, 5, 4, 4, 0, 1, 2, 0x77], &CHROMA_AC_SYMBOLS),
+];
+
+const LUMA_AC_SYMBOLS: [u8; 162] = [
+    0x01, 0x02, 0x03, 0x00, 0x04, 0x11, 0x05, 0x12, 0x21, 0x31, 0x41, 0x06, 0x13, 0x51, 0x61, 0x07, 0x22, 0x71, 0x14, 0x32,
+    0x81, 0x91, 0xa1, 0x08, 0x23, 0x42, 0xb1, 0xc1, 0x15, 0x52, 0xd1, 0xf0, 0x24, 0x33, 0x62, 0x72, 0x82, 0x09, 0x0a, 0x16,
+    0x17, 0x18, 0x19, 0x1a, 0x25, 0x26, 0x27, 0x28, 0x29, 0x2a, 0x34, 0x35, 0x36, 0x37, 0x38, 0x39, 0x3a, 0x43, 0x44, 0x45,
+    0x46, 0x47, 0x48, 0x49, 0x4a, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59, 0x5a, 0x63, 0x64, 0x65, 0x66, 0x67, 0x68, 0x69,
+    0x6a, 0x73, 0x74, 0x75, 0x76, 0x77, 0x78, 0x79, 0x7a, 0x83, 0x84, 0x85, 0x86, 0x87, 0x88, 0x89, 0x8a, 0x92, 0x93, 0x94,
+    0x95, 0x96, 0x97, 0x98, 0x99, 0x9a, 0xa2, 0xa3, 0xa4, 0xa5, 0xa6, 0xa7, 0xa8, 0xa9, 0xaa, 0xb2, 0xb3, 0xb4, 0xb5, 0xb6,
+    0xb7, 0xb8, 0xb9, 0xba, 0xc2, 0xc3, 0xc4, 0xc5, 0xc6, 0xc7, 0xc8, 0xc9, 0xca, 0xd2, 0xd3, 0xd4, 0xd5, 0xd6, 0xd7, 0xd8,
+    0xd9, 0xda, 0xe1, 0xe2, 0xe3, 0xe4, 0xe5, 0xe6, 0xe7, 0xe8, 0xe9, 0xea, 0xf1, 0xf2, 0xf3, 0xf4, 0xf5, 0xf6, 0xf7, 0xf8,
+    0xf9, 0xfa,
+];
+
+const CHROMA_AC_SYMBOLS: [u8; 162] = [
+    0x00, 0x01, 0x02, 0x03, 0x11, 0x04, 0x05, 0x21, 0x31, 0x06, 0x12, 0x41, 0x51, 0x07, 0x61, 0x71, 0x13, 0x22, 0x32, 0x81,
+    0x08, 0x14, 0x42, 0x91, 0xa1, 0xb1, 0xc1, 0x09, 0x23, 0x33, 0x52, 0xf0, 0x15, 0x62, 0x72, 0xd1, 0x0a, 0x16, 0x24, 0x34,
+    0xe1, 0x25, 0xf1, 0x17, 0x18, 0x19, 0x1a, 0x26, 0x27, 0x28, 0x29, 0x2a, 0x35, 0x36, 0x37, 0x38, 0x39, 0x3a, 0x43, 0x44,
+    0x45, 0x46, 0x47, 0x48, 0x49, 0x4a, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59, 0x5a, 0x63, 0x64, 0x65, 0x66, 0x67, 0x68,
+    0x69, 0x6a, 0x73, 0x74, 0x75, 0x76, 0x77, 0x78, 0x79, 0x7a, 0x82, 0x83, 0x84, 0x85, 0x86, 0x87, 0x88, 0x89, 0x8a, 0x92,
+    0x93, 0x94, 0x95, 0x96, 0x97, 0x98, 0x99, 0x9a, 0xa2, 0xa3, 0xa4, 0xa5, 0xa6, 0xa7, 0xa8, 0xa9, 0xaa, 0xb2, 0xb3, 0xb4,
+    0xb5, 0xb6, 0xb7, 0xb8, 0xb9, 0xba, 0xc2, 0xc3, 0xc4, 0xc5, 0xc6, 0xc7, 0xc8, 0xc9, 0xca, 0xd2, 0xd3, 0xd4, 0xd5, 0xd6,
+    0xd7, 0xd8, 0xd9, 0xda, 0xe2, 0xe3, 0xe4, 0xe5, 0xe6, 0xe7, 0xe8, 0xe9, 0xea, 0xf2, 0xf3, 0xf4, 0xf5, 0xf6, 0xf7, 0xf8,
+    0xf9, 0xfa,
+];
+
+/// What RTP/JPEG keeps of a baseline JPEG, everything else is rebuilt by the receiver
+#[derive(Debug, PartialEq, Eq)]
+pub struct Jpeg<'a> {
+    pub kind: u8,          // 0 for 4:2:2 (luma sampled 2x1), 1 for 4:2:0 (2x2), chroma 1x1 in both
+    pub width: u16,        // Rounded up to a multiple of 8, receivers decode up to 7 more columns and rows
+    pub height: u16,
+    pub tables: Vec<u8>,   // Luma then chroma quantization table, 64 bytes each in zigzag order
+    pub scan: &'a [u8],    // Entropy coded data, between the scan header and the end of image
+}
+
+impl<'a> Jpeg<'a> {
+    /// Parses a baseline JPEG the way RTP/JPEG can carry it: 3 components, luma subsampled 2x1 or 2x2,
+    /// 8 bit quantization tables, the standard Huffman tables and no restart markers, as turbojpeg makes them
+    pub fn parse(bytes: &'a [u8]) -> Result<Self, String> {
+        if bytes.get(..2) != Some(&[0xff, SOI]) {
+            return Err(String::from("Not a JPEG"));
+        }
+
+        let mut quantizers: [Option<[u8; 64]>; 4] = [None; 4];
+        let mut frame: Option<(u8, u16, u16, [u8; 3])> = None; // Type, size and table of each component
+        let mut huffman = 0; // Standard tables seen, one bit per table
+        let mut at = 2;
+
+        loop {
+            // A marker may be preceded by any number of fill bytes
+            while bytes.get(at) == Some(&0xff) && bytes.get(at + 1) == Some(&0xff) {
+                at += 1;
+            }
+            let marker = match bytes.get(at..at + 2) {
+                Some(&[0xff, marker]) => marker,
+                _ => return Err(format!("Expected a marker at byte {}", at)),
+            };
+            if marker == EOI {
+                return Err(String::from("JPEG has no scan"));
+            }
+
+            let length = match bytes.get(at + 2..at + 4) {
+                Some(length) => u16::from_be_bytes([length[0], length[1]]) as usize,
+                None => return Err(String::from("JPEG is truncated")),
+            };
+            let segment = bytes
+                .get(at + 4..at + 2 + length)
+                .filter(|_| length >= 2)
+                .ok_or_else(|| String::from("JPEG is truncated"))?;
+            at += 2 + length;
+
+            match marker {
+                DQT => {
+                    let mut tables = segment;
+                    while let Some((&precision_id, rest)) = tables.split_first() {
+                        if precision_id >> 4 != 0 {
+                            return Err(String::from("Only 8 bit quantization tables can be sent"));
+                        }
+                        let table: [u8; 64] = rest
+                            .get(..64)
+                            .and_then(|table| table.try_into().ok())
+                            .ok_or_else(|| String::from("Quantization table is truncated"))?;
+                        quantizers[(precision_id & 0x03) as usize] = Some(table);
+                        tables = &rest[64..];
+                    }
+                }
+                SOF0 => frame = Some(parse_frame(segment)?),
+                0xc1..=0xcf if marker != DHT => {
+                    return Err(String::from("Only baseline JPEGs can be sent"));
+                }
+                DHT => huffman |= parse_huffman(segment)?,
+                DRI if segment.get(..2) != Some(&[0, 0]) => {
+                    return Err(String::from("JPEGs with restart markers can't be sent"));
+                }
+                SOS => {
+                    let (kind, width, height, components) = frame.ok_or("Scan before the frame header")?;
+                    if segment.first() != Some(&3) || segment.len() < 1 + 3 * 2 {
+                        return Err(String::from("Scan must have the 3 components"));
+                    }
+                    // Luma on Huffman tables 0, both chroma components on tables 1
+                    if segment[2] != 0x00 || segment[4] != 0x11 || segment[6] != 0x11 {
+                        return Err(String::from("Scan uses other than the standard Huffman tables"));
+                    }
+                    if huffman != 0b1111 {
+                        return Err(String::from("JPEG does not use the standard Huffman tables"));
+                    }
+
+                    let luma = quantizers[components[0] as usize].ok_or("Luma quantization table is missing")?;
+                    let chroma = quantizers[components[1] as usize].ok_or("Chroma quantization table is missing")?;
+                    if components[1] != components[2] {
+                        return Err(String::from("Both chroma components must use the same quantization table"));
+                    }
+
+                    let end = match bytes[at..].iter().rposition(|&byte| byte == 0xff) {
+                        Some(end) if bytes.get(at + end + 1) == Some(&EOI) => at + end,
+                        _ => return Err(String::from("JPEG does not end with an end of image")),
+                    };
+                    if end - at >= MAX_SCAN {
+                        return Err(String::from("JPEG is too large for RTP/JPEG"));
+                    }
+
+                    let mut tables = Vec::with_capacity(128);
+                    tables.extend_from_slice(&luma);
+                    tables.extend_from_slice(&chroma);
+                    return Ok(Jpeg { kind, width, height, tables, scan: &bytes[at..end] });
+                }
+                _ => {} // Application data and comments are not sent
+            }
+        }
+    }
+}
+
+/// Type, size and quantization table of each component, from a baseline frame header
+fn parse_frame(segment: &[u8]) -> Result<(u8, u16, u16, [u8; 3]), String> {
+    if segment.len() < 6 + 3 * 3 || segment[0] != 8 || segment[5] != 3 {
+        return Err(String::from("Only 8 bit JPEGs with 3 components can be sent"));
+    }
+
+    let height = u16::from_be_bytes([segment[1], segment[2]]);
+    let width = u16::from_be_bytes([segment[3], segment[4]]);
+    if width == 0 || height == 0 || width > MAX_SIZE || height > MAX_SIZE {
+        return Err(format!("{}x{} is larger than the {}x{} RTP/JPEG can describe", width, height, MAX_SIZE, MAX_SIZE));
+    }
+
+    let component = |i: usize| &segment[6 + i * 3..9 + i * 3];
+    let kind = match component(0)[1] {
+        0x21 => 0,
+        0x22 => 1,
+        _ => return Err(String::from("Only 4:2:2 and 4:2:0 JPEGs can be sent")),
+    };
+    if component(1)[1] != 0x11 || component(2)[1] != 0x11 {
+        return Err(String::from("Only 4:2:2 and 4:2:0 JPEGs can be sent"));
+    }
+    // Baseline JPEGs have four quantization table slots
+    if (0..3).any(|i| component(i)[2] > 3) {
+        return Err(String::from("Quantization table index out of range"));
+    }
+
+    Ok((kind, width.div_ceil(8) * 8, height.div_ceil(8) * 8, [component(0)[2], component(1)[2], component(2)[2]]))
+}
+
+/// Checks a segment only holds standard Huffman tables, returns which ones as bits of their index
+fn parse_huffman(mut segment: &[u8]) -> Result<u8, String> {
+    let mut seen = 0;
+    while let Some((&class_id, rest)) = segment.split_first() {
+        let counts = rest.get(..16).ok_or("Huffman table is truncated")?;
+        let symbols = counts.iter().map(|&count| count as usize).sum::<usize>();
+        let symbols = rest.get(16..16 + symbols).ok_or("Huffman table is truncated")?;
+
+        let standard = HUFFMAN_TABLES
+            .iter()
+            .position(|(class, id, standard_counts, standard_symbols)| {
+                class_id == (class << 4 | id) && counts == standard_counts && symbols == *standard_symbols
+            })
+            .ok_or("JPEG does not use the standard Huffman tables")?;
+
+        seen |= 1 << standard;
+        segment = &rest[16 + symbols.len()..];
+    }
+    Ok(seen)
+}
+
+/// Quantization tables a Q below 128 stands for, the standard ones scaled like libjpeg does (RFC 2435 appendix A)
+#[cfg(any(test, fuzzing))] // Only the depayloader needs it
+pub fn make_tables(q: u8) -> Vec<u8> {
+    let factor = q.clamp(1, 99) as u32;
+    let scale = if factor < 50 { 5000 / factor } else { 200 - factor * 2 };
+
+    let scaled = |quantizer: &[u8; 64], i: usize| ((quantizer[ZIGZAG[i]] as u32 * scale + 50) / 100).clamp(1, 255) as u8;
+    (0..64).map(|i| scaled(&LUMA_QUANTIZER, i)).chain((0..64).map(|i| scaled(&CHROMA_QUANTIZER, i))).collect()
+}
+
+/// Splits JPEGs into RTP packets (RFC 2435), sending the quantization tables with every frame
+pub struct Payloader {
+    ssrc: u32,
+    seq: u16, // Sequence number of the next packet, wraps around
+    mtu: usize,
+}
+
+impl Payloader {
+    /// `seq` and `ssrc` should be random, `mtu` is the largest packet, RTP header included
+    pub fn new(ssrc: u32, seq: u16, mtu: usize) -> Self {
+        Self { ssrc, seq, mtu }
+    }
+
+    pub fn next_seq(&self) -> u16 {
+        self.seq
+    }
+
+    pub fn ssrc(&self) -> u32 {
+        self.ssrc
+    }
+
+    /// Packets of one frame, the last one marked
+    pub fn payload(&mut self, jpeg: &Jpeg, timestamp: u32) -> Vec<Vec<u8>> {
+        let mut packets = Vec::new();
+        let mut offset = 0;
+
+        loop {
+            let tables = if offset == 0 { QUANT_HEADER_SIZE + jpeg.tables.len() } else { 0 };
+            let room = self.mtu.saturating_sub(RTP_HEADER_SIZE + JPEG_HEADER_SIZE + tables).max(1);
+            let chunk = &jpeg.scan[offset..jpeg.scan.len().min(offset + room)];
+            let last = offset + chunk.len() == jpeg.scan.len();
+
+            let mut packet = Vec::with_capacity(RTP_HEADER_SIZE + JPEG_HEADER_SIZE + tables + chunk.len());
+            write_header(&mut packet, last, PAYLOAD_TYPE, self.seq, timestamp, self.ssrc);
+
+            // Type-specific byte, then the 24 bit offset of the fragment in the scan
+            packet.push(0);
+            packet.extend_from_slice(&(offset as u32).to_be_bytes()[1..]);
+            packet.extend_from_slice(&[jpeg.kind, DYNAMIC_Q, (jpeg.width / 8) as u8, (jpeg.height / 8) as u8]);
+
+            if offset == 0 {
+                packet.extend_from_slice(&[0, 0]); // Must be zero, then every table 8 bit
+                packet.extend_from_slice(&(jpeg.tables.len() as u16).to_be_bytes());
+                packet.extend_from_slice(&jpeg.tables);
+            }
+            packet.extend_from_slice(chunk);
+
+            packets.push(packet);
+            self.seq = self.seq.wrapping_add(1);
+            offset += chunk.len();
+            if last {
+                break;
+            }
+        }
+        packets
+    }
+}
+
+/// Splits AV1 temporal units, as encoders output them, into RTP packets (RTP Payload Format for AV1)
+/// Temporal delimiters are left out and the other OBUs sent without their size field, the packets they
+/// are in say where each starts
+pub struct Av1Payloader {
+    payload_type: u8,
+    ssrc: u32,
+    seq: u16,
+    mtu: usize,
+}
+
+impl Av1Payloader {
+    pub fn new(payload_type: u8, ssrc: u32, seq: u16, mtu: usize) -> Self {
+        Self { payload_type, ssrc, seq, mtu }
+    }
+
+    /// Packets of one temporal unit, the last one marked
+    pub fn payload(&mut self, unit: &[u8], timestamp: u32) -> Result<Vec<Vec<u8>>, String> {
+        let obus = parse_obus(unit)?;
+        let starts_sequence = obus.iter().any(|(header, _)| header[0] >> 3 & 0x0f == OBU_SEQUENCE_HEADER);
+        let room = self.mtu.saturating_sub(RTP_HEADER_SIZE + AV1_HEADER_SIZE);
+        if room < 2 {
+            return Err(String::from("MTU too small for AV1"));
+        }
+
+        let mut payloads: Vec<Vec<u8>> = Vec::new();
+        let mut payload = vec![0u8]; // Aggregation header, filled in when the payload is done
+        for (header, data) in &obus {
+            let mut obu = Vec::with_capacity(header.len() + data.len());
+            obu.push(header[0] & !OBU_HAS_SIZE);
+            obu.extend_from_slice(&header[1..]);
+            obu.extend_from_slice(data);
+
+            let mut rest = &obu[..];
+            while !rest.is_empty() {
+                let left = room + 1 - payload.len();
+                if left < 2 {
+                    payloads.push(std::mem::replace(&mut payload, vec![0]));
+                    continue;
+                }
+
+                // Every element has its length in front, what doesn't fit continues in the next packet
+                let fits = rest.len() + leb128_size(rest.len()) <= left;
+                let size = if fits { rest.len() } else { left - leb128_size(left) };
+                write_leb128(&mut payload, size);
+                payload.extend_from_slice(&rest[..size]);
+                rest = &rest[size..];
+
+                if !rest.is_empty() {
+                    payload[0] |= AV1_Y;
+                    payloads.push(std::mem::replace(&mut payload, vec![AV1_Z]));
+                }
+            }
+        }
+        if payload.len() > 1 {
+            payloads.push(payload);
+        }
+
+        if starts_sequence {
+            if let Some(first) = payloads.first_mut() {
+                first[0] |= AV1_N;
+            }
+        }
+
+        let count = payloads.len();
+        let packets = payloads
+            .into_iter()
+            .enumerate()
+            .map(|(i, payload)| {
+                let mut packet = Vec::with_capacity(RTP_HEADER_SIZE + payload.len());
+                write_header(&mut packet, i + 1 == count, self.payload_type, self.seq, timestamp, self.ssrc);
+                packet.extend_from_slice(&payload);
+                self.seq = self.seq.wrapping_add(1);
+                packet
+            })
+            .collect();
+        Ok(packets)
+    }
+}
+
+/// Header of an OBU (with its extension), then its payload
+type Obu<'a> = (&'a [u8], &'a [u8]);
+
+/// OBUs of a temporal unit but its temporal delimiters and padding
+fn parse_obus(mut unit: &[u8]) -> Result<Vec<Obu<'_>>, String> {
+    let mut obus = Vec::new();
+    while let Some(&header) = unit.first() {
+        if header & 0x80 != 0 {
+            return Err(String::from("OBU forbidden bit set"));
+        }
+        let header_size = if header & OBU_HAS_EXTENSION != 0 { 2 } else { 1 };
+        if unit.len() < header_size {
+            return Err(String::from("OBU header cut short"));
+        }
+
+        let (size, size_size) = if header & OBU_HAS_SIZE != 0 {
+            read_leb128(&unit[header_size..]).ok_or("OBU size cut short")?
+        } else {
+            (unit.len() - header_size, 0)
+        };
+        let start = header_size + size_size;
+        let end = start.checked_add(size).filter(|&end| end <= unit.len()).ok_or("OBU longer than the temporal unit")?;
+
+        let kind = header >> 3 & 0x0f;
+        if kind != OBU_TEMPORAL_DELIMITER && kind != OBU_PADDING {
+            obus.push((&unit[..header_size], &unit[start..end]));
+        }
+        unit = &unit[end..];
+    }
+    Ok(obus)
+}
+
+/// Value and size of the LEB128 number at the start of `bytes`
+fn read_leb128(bytes: &[u8]) -> Option<(usize, usize)> {
+    let mut value = 0usize;
+    for (i, &byte) in bytes.iter().take(8).enumerate() {
+        value |= ((byte & 0x7f) as usize) << (i * 7);
+        if byte & 0x80 == 0 {
+            return Some((value, i + 1));
+        }
+    }
+    None
+}
+
+fn leb128_size(mut value: usize) -> usize {
+    let mut size = 1;
+    while value >= 0x80 {
+        value >>= 7;
+        size += 1;
+    }
+    size
+}
+
+fn write_leb128(bytes: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        bytes.push(value as u8 & 0x7f | 0x80);
+        value >>= 7;
+    }
+    bytes.push(value as u8);
+}
+
+/// Writes an RTP header without CSRCs or extension
+fn write_header(packet: &mut Vec<u8>, marker: bool, payload_type: u8, seq: u16, timestamp: u32, ssrc: u32) {
+    packet.push(RTP_VERSION << 6);
+    packet.push(if marker { 0x80 } else { 0 } | payload_type);
+    packet.extend_from_slice(&seq.to_be_bytes());
+    packet.extend_from_slice(&timestamp.to_be_bytes());
+    packet.extend_from_slice(&ssrc.to_be_bytes());
+}
+
+/// What depayloaders need of an RTP packet
+#[cfg(any(test, fuzzing))]
+struct Rtp<'a> {
+    payload_type: u8,
+    marker: bool,
+    seq: u16,
+    timestamp: u32,
+    payload: &'a [u8], // Without the CSRCs, header extension and padding
+}
+
+#[cfg(any(test, fuzzing))]
+impl<'a> Rtp<'a> {
+    fn parse(packet: &'a [u8]) -> Result<Self, String> {
+        if packet.len() < RTP_HEADER_SIZE || packet[0] >> 6 != RTP_VERSION {
+            return Err(String::from("Not an RTP packet"));
+        }
+
+        let csrcs = (packet[0] & 0x0f) as usize * 4;
+        let mut payload = packet.get(RTP_HEADER_SIZE + csrcs..).ok_or("RTP CSRCs are truncated")?;
+        if packet[0] & 0x10 != 0 {
+            let length = payload.get(2..4).ok_or("RTP extension is truncated")?;
+            let length = 4 + u16::from_be_bytes([length[0], length[1]]) as usize * 4;
+            payload = payload.get(length..).ok_or("RTP extension is truncated")?;
+        }
+        if packet[0] & 0x20 != 0 {
+            let padding = *payload.last().ok_or("RTP padding is truncated")? as usize;
+            payload = payload.get(..payload.len().saturating_sub(padding)).filter(|_| padding > 0).ok_or("RTP padding is invalid")?;
+        }
+
+        Ok(Self {
+            payload_type: packet[1] & 0x7f,
+            marker: packet[1] & 0x80 != 0,
+            seq: u16::from_be_bytes([packet[2], packet[3]]),
+            timestamp: u32::from_be_bytes([packet[4], packet[5], packet[6], packet[7]]),
+            payload,
+        })
+    }
+}
+
+/// Frame being put back together by the depayloader
+#[cfg(any(test, fuzzing))]
+struct Assembly {
+    timestamp: u32,
+    kind: u8,
+    width: u16,
+    height: u16,
+    tables: Vec<u8>,
+    scan: Vec<u8>,
+}
+
+/// Rebuilds JPEGs from RTP/JPEG packets, as players do
+/// Frames missing a fragment are dropped, the next one starts over
+/// The server never depayloads, this is what the payloader is checked against (fuzz/fuzz_targets/rtp.rs)
+#[cfg(any(test, fuzzing))]
+#[derive(Default)]
+pub struct Depayloader {
+    assembly: Option<Assembly>,
+    expected_seq: Option<u16>,
+}
+
+#[cfg(any(test, fuzzing))]
+impl Depayloader {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Takes the next packet, returns the JPEG it completes
+    pub fn push(&mut self, packet: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        let Rtp { payload_type, marker, seq, timestamp, payload } = Rtp::parse(packet)?;
+        if payload_type != PAYLOAD_TYPE {
+            return Err(format!("Payload type {} is not JPEG", payload_type));
+        }
+
+        // A sequence gap loses the frame being assembled
+        if self.expected_seq.is_some_and(|expected| expected != seq) {
+            self.assembly = None;
+        }
+        self.expected_seq = Some(seq.wrapping_add(1));
+
+        let header = payload.get(..JPEG_HEADER_SIZE).ok_or("JPEG header is truncated")?;
+        let offset = u32::from_be_bytes([0, header[1], header[2], header[3]]) as usize;
+        let (kind, q, width, height) = (header[4], header[5], header[6] as u16 * 8, header[7] as u16 * 8);
+        let mut data = &payload[JPEG_HEADER_SIZE..];
+
+        if kind > 1 {
+            return Err(format!("JPEG type {} is not supported", kind));
+        }
+
+        if offset == 0 {
+            let tables = if q >= FIRST_DYNAMIC_Q {
+                let quant = data.get(..QUANT_HEADER_SIZE).ok_or("Quantization table header is truncated")?;
+                let length = u16::from_be_bytes([quant[2], quant[3]]) as usize;
+                if quant[1] != 0 || length != 128 {
+                    return Err(String::from("Expected two 8 bit quantization tables"));
+                }
+                let tables = data.get(QUANT_HEADER_SIZE..QUANT_HEADER_SIZE + length).ok_or("Quantization tables are truncated")?;
+                data = &data[QUANT_HEADER_SIZE + length..];
+                tables.to_vec()
+            } else {
+                make_tables(q)
+            };
+            self.assembly = Some(Assembly { timestamp, kind, width, height, tables, scan: Vec::new() });
+        }
+
+        let assembly = match &mut self.assembly {
+            Some(assembly) if assembly.timestamp == timestamp && assembly.scan.len() == offset => assembly,
+            _ => {
+                self.assembly = None;
+                return Ok(None);
+            }
+        };
+        assembly.scan.extend_from_slice(data);
+
+        if !marker {
+            return Ok(None);
+        }
+        Ok(self.assembly.take().map(|assembly| assembly.to_jpeg()))
+    }
+}
+
+#[cfg(any(test, fuzzing))]
+impl Assembly {
+    /// The JPEG the fragments came from, with the headers RTP/JPEG left out rebuilt
+    fn to_jpeg(&self) -> Vec<u8> {
+        let mut jpeg = Vec::with_capacity(self.scan.len() + 1024);
+        let segment = |jpeg: &mut Vec<u8>, marker: u8, body: &[u8]| {
+            jpeg.extend_from_slice(&[0xff, marker]);
+            jpeg.extend_from_slice(&(body.len() as u16 + 2).to_be_bytes());
+            jpeg.extend_from_slice(body);
+        };
+
+        jpeg.extend_from_slice(&[0xff, SOI]);
+
+        let mut dqt = Vec::with_capacity(130);
+        for (id, table) in self.tables.chunks(64).enumerate() {
+            dqt.push(id as u8);
+            dqt.extend_from_slice(table);
+        }
+        segment(&mut jpeg, DQT, &dqt);
+
+        let [height, width] = [self.height.to_be_bytes(), self.width.to_be_bytes()];
+        let luma_sampling = if self.kind == 0 { 0x21 } else { 0x22 };
+        segment(
+            &mut jpeg,
+            SOF0,
+            &[8, height[0], height[1], width[0], width[1], 3, 1, luma_sampling, 0, 2, 0x11, 1, 3, 0x11, 1],
+        );
+
+        let mut dht = Vec::new();
+        for (class, id, counts, symbols) in HUFFMAN_TABLES {
+            dht.push(class << 4 | id);
+            dht.extend_from_slice(&counts);
+            dht.extend_from_slice(symbols);
+        }
+        segment(&mut jpeg, DHT, &dht);
+
+        segment(&mut jpeg, SOS, &[3, 1, 0x00, 2, 0x11, 3, 0x11, 0, 63, 0]);
+        jpeg.extend_from_slice(&self.scan);
+        jpeg.extend_from_slice(&[0xff, EOI]);
+        jpeg
+    }
+}
+
+/// Rebuilds AV1 temporal units from RTP packets, as players do before decoding
+/// OBUs get their size field back and units start with a temporal delimiter, units missing a packet are dropped
+#[cfg(any(test, fuzzing))]
+#[derive(Default)]
+pub struct Av1Depayloader {
+    unit: Vec<u8>, // OBUs of the unit being assembled
+    obu: Vec<u8>,  // OBU continuing in the next packet
+    expected_seq: Option<u16>,
+    lost: bool, // A packet of the unit is missing, skip to the next one
+}
+
+#[cfg(any(test, fuzzing))]
+impl Av1Depayloader {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Takes the next packet, returns the temporal unit it completes
+    pub fn push(&mut self, packet: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        let Rtp { marker, seq, payload, .. } = Rtp::parse(packet)?;
+
+        // A sequence gap loses the unit being assembled
+        if self.expected_seq.is_some_and(|expected| expected != seq) {
+            self.lost = true;
+        }
+        self.expected_seq = Some(seq.wrapping_add(1));
+
+        if !self.lost {
+            if let Err(error) = self.read(payload) {
+                self.lost = !marker;
+                self.unit.clear();
+                self.obu.clear();
+                return Err(error);
+            }
+        }
+        if !marker {
+            return Ok(None);
+        }
+
+        let complete = !std::mem::take(&mut self.lost) && self.obu.is_empty();
+        let obus = std::mem::take(&mut self.unit);
+        self.obu.clear();
+        if !complete {
+            return Ok(None);
+        }
+        let mut unit = vec![OBU_TEMPORAL_DELIMITER << 3 | OBU_HAS_SIZE, 0];
+        unit.extend_from_slice(&obus);
+        Ok(Some(unit))
+    }
+
+    /// Adds the OBU elements of one packet to the unit
+    fn read(&mut self, payload: &[u8]) -> Result<(), String> {
+        let (&aggregation, mut elements) = payload.split_first().ok_or("AV1 aggregation header is missing")?;
+        if (aggregation & AV1_Z != 0) == self.obu.is_empty() {
+            return Err(String::from("AV1 packet does not continue the previous one"));
+        }
+
+        // With W set, the last of W elements has no length in front
+        let count = (aggregation >> 4 & 0x03) as usize;
+        let mut index = 0;
+        while !elements.is_empty() {
+            index += 1;
+            let element = if index == count {
+                std::mem::take(&mut elements)
+            } else {
+                let (size, size_size) = read_leb128(elements).ok_or("AV1 element length cut short")?;
+                let end = size_size.checked_add(size).filter(|&end| end <= elements.len()).ok_or("AV1 element longer than the packet")?;
+                let element = &elements[size_size..end];
+                elements = &elements[end..];
+                element
+            };
+
+            self.obu.extend_from_slice(element);
+            if elements.is_empty() && aggregation & AV1_Y != 0 {
+                break;
+            }
+            let obu = std::mem::take(&mut self.obu);
+            write_obu(&mut self.unit, &obu)?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes an OBU as it came in an RTP packet with its size field, temporal delimiters and padding left out
+#[cfg(any(test, fuzzing))]
+fn write_obu(unit: &mut Vec<u8>, obu: &[u8]) -> Result<(), String> {
+    let header = *obu.first().ok_or("AV1 OBU is empty")?;
+    let header_size = if header & OBU_HAS_EXTENSION != 0 { 2 } else { 1 };
+    let mut data = obu.get(header_size..).ok_or("OBU header cut short")?;
+    if header & OBU_HAS_SIZE != 0 {
+        let (size, size_size) = read_leb128(data).ok_or("OBU size cut short")?;
+        data = size_size.checked_add(size).and_then(|end| data.get(size_size..end)).ok_or("OBU longer than its element")?;
+    }
+
+    let kind = header >> 3 & 0x0f;
+    if kind != OBU_TEMPORAL_DELIMITER && kind != OBU_PADDING {
+        unit.push(header | OBU_HAS_SIZE);
+        unit.extend_from_slice(&obu[1..header_size]);
+        write_leb128(unit, data.len());
+        unit.extend_from_slice(data);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use turbojpeg::{Image, PixelFormat};
+
+    const WIDTH: usize = 64;
+    const HEIGHT: usize = 48;
+
+    /// A 4:2:0 JPEG of a noisy gradient, as the server encodes captures
+    fn jpeg() -> Vec<u8> {
+        let mut seed = 0x2545_f491u32;
+        let pixels: Vec<u8> = (0..WIDTH * HEIGHT * 4)
+            .map(|i| {
+                seed ^= seed << 13;
+                seed ^= seed >> 17;
+                seed ^= seed << 5;
+                ((i / 4 % WIDTH) * 4) as u8 ^ (seed as u8 & 0x1f)
+            })
+            .collect();
+        let image = Image { pixels: &pixels[..], width: WIDTH, pitch: WIDTH * 4, height: HEIGHT, format: PixelFormat::BGRX };
+        turbojpeg::compress(image, 80, turbojpeg::Subsamp::Sub2x2).unwrap().to_vec()
+    }
+
+    fn decode(jpeg: &[u8]) -> Vec<u8> {
+        turbojpeg::decompress(jpeg, PixelFormat::RGB).unwrap().pixels
+    }
+
+    fn depayload(packets: &[Vec<u8>]) -> Option<Vec<u8>> {
+        let mut depayloader = Depayloader::new();
+        packets.iter().map(|packet| depayloader.push(packet).unwrap()).last().flatten()
+    }
+
+    /// JPEG with a restart interval segment inserted after the start of image
+    fn with_restart_interval(jpeg: &[u8], interval: u16) -> Vec<u8> {
+        let mut bytes = jpeg[..2].to_vec();
+        bytes.extend_from_slice(&[0xff, DRI, 0, 4]);
+        bytes.extend_from_slice(&interval.to_be_bytes());
+        bytes.extend_from_slice(&jpeg[2..]);
+        bytes
+    }
+
+    #[test]
+    fn parse_keeps_the_scan_and_tables() {
+        let bytes = jpeg();
+        let jpeg = Jpeg::parse(&bytes).unwrap();
+        assert_eq!((jpeg.kind, jpeg.width, jpeg.height), (1, WIDTH as u16, HEIGHT as u16));
+        assert_eq!(jpeg.tables.len(), 128);
+        assert!(bytes.ends_with(&[0xff, EOI]));
+        assert_eq!(jpeg.scan, &bytes[bytes.len() - 2 - jpeg.scan.len()..bytes.len() - 2]);
+    }
+
+    #[test]
+    fn restart_markers_are_refused() {
+        let bytes = jpeg();
+        let error = Jpeg::parse(&with_restart_interval(&bytes, 4)).unwrap_err();
+        assert!(error.contains("restart markers"), "{}", error);
+
+        // An interval of zero turns them off
+        assert_eq!(Jpeg::parse(&with_restart_interval(&bytes, 0)).unwrap(), Jpeg::parse(&bytes).unwrap());
+    }
+
+    #[test]
+    fn quantization_table_index_out_of_range_is_refused() {
+        let mut bytes = jpeg();
+        let frame = bytes.windows(2).position(|marker| marker == [0xff, SOF0]).unwrap();
+        // Tq of the second component: marker, length, precision, size, component count, then 3 bytes each
+        bytes[frame + 4 + 6 + 3 + 2] = 4;
+        let error = Jpeg::parse(&bytes).unwrap_err();
+        assert!(error.contains("out of range"), "{}", error);
+    }
+
+    #[test]
+    fn fragments_fit_the_mtu() {
+        let bytes = jpeg();
+        let jpeg = Jpeg::parse(&bytes).unwrap();
+        let mtu = 200;
+        let packets = Payloader::new(0x1234_5678, 0xfffe, mtu).payload(&jpeg, 90_000);
+        assert!(packets.len() > 3);
+
+        let mut scan = Vec::new();
+        for (i, packet) in packets.iter().enumerate() {
+            assert!(packet.len() <= mtu);
+            assert_eq!(packet[1] & 0x80 != 0, i + 1 == packets.len(), "only the last packet is marked");
+            assert_eq!(u16::from_be_bytes([packet[2], packet[3]]), 0xfffeu16.wrapping_add(i as u16));
+
+            let header = &packet[RTP_HEADER_SIZE..RTP_HEADER_SIZE + JPEG_HEADER_SIZE];
+            assert_eq!(u32::from_be_bytes([0, header[1], header[2], header[3]]) as usize, scan.len());
+            assert_eq!(header[5], DYNAMIC_Q);
+
+            // Only the first packet carries the tables
+            let mut data = &packet[RTP_HEADER_SIZE + JPEG_HEADER_SIZE..];
+            if i == 0 {
+                assert_eq!(&data[..QUANT_HEADER_SIZE], &[0, 0, 0, 128]);
+                assert_eq!(&data[QUANT_HEADER_SIZE..QUANT_HEADER_SIZE + 128], &jpeg.tables[..]);
+                data = &data[QUANT_HEADER_SIZE + 128..];
+            }
+            scan.extend_from_slice(data);
+        }
+        assert_eq!(scan, jpeg.scan);
+    }
+
+    #[test]
+    fn reassembled_jpeg_decodes_to_the_same_picture() {
+        let bytes = jpeg();
+        let packets = Payloader::new(1, 0, 300).payload(&Jpeg::parse(&bytes).unwrap(), 0);
+        let rebuilt = depayload(&packets).unwrap();
+
+        assert_eq!(Jpeg::parse(&rebuilt).unwrap(), Jpeg::parse(&bytes).unwrap());
+        assert_eq!(decode(&rebuilt), decode(&bytes));
+    }
+
+    #[test]
+    fn frame_missing_a_fragment_is_dropped() {
+        let bytes = jpeg();
+        let jpeg = Jpeg::parse(&bytes).unwrap();
+        let mut payloader = Payloader::new(1, 0, 300);
+        let mut first = payloader.payload(&jpeg, 0);
+        let second = payloader.payload(&jpeg, 3000);
+        first.remove(1);
+
+        let mut depayloader = Depayloader::new();
+        for packet in &first {
+            assert_eq!(depayloader.push(packet).unwrap(), None);
+        }
+        let rebuilt = second.iter().map(|packet| depayloader.push(packet).unwrap()).last().flatten();
+        assert_eq!(decode(&rebuilt.unwrap()), decode(&bytes));
+    }
+
+    #[test]
+    fn tables_of_q_scale_the_standard_ones() {
+        let tables = make_tables(50);
+        for i in 0..64 {
+            assert_eq!(tables[i], LUMA_QUANTIZER[ZIGZAG[i]]);
+            assert_eq!(tables[64 + i], CHROMA_QUANTIZER[ZIGZAG[i]]);
+        }
+        assert!(make_tables(1).iter().all(|&value| value == 255));
+        assert!(make_tables(90).iter().zip(&tables).all(|(finer, standard)| finer <= standard));
+    }
+
+    #[test]
+    fn depayloader_derives_tables_from_q() {
+        let bytes = jpeg();
+        let mut packets = Payloader::new(1, 0, 1400).payload(&Jpeg::parse(&bytes).unwrap(), 0);
+
+        // A sender using a Q below 128 leaves the tables out, the receiver scales the standard ones
+        let first = &mut packets[0];
+        first[RTP_HEADER_SIZE + 5] = 50;
+        let tables = RTP_HEADER_SIZE + JPEG_HEADER_SIZE;
+        first.drain(tables..tables + QUANT_HEADER_SIZE + 128);
+
+        let rebuilt = depayload(&packets).unwrap();
+        assert_eq!(Jpeg::parse(&rebuilt).unwrap().tables, make_tables(50));
+        assert_eq!(decode(&rebuilt).len(), WIDTH * HEIGHT * 3);
+    }
+
+    /// Header of an OBU of `kind`, with the extension if `extension`
+    fn obu(unit: &mut Vec<u8>, kind: u8, extension: bool, data: &[u8]) {
+        unit.push(kind << 3 | OBU_HAS_SIZE | if extension { OBU_HAS_EXTENSION } else { 0 });
+        if extension {
+            unit.push(0x28);
+        }
+        write_leb128(unit, data.len());
+        unit.extend_from_slice(data);
+    }
+
+    /// A temporal unit as encoders output it, and as the depayloader gives it back without the padding
+    fn av1_unit(frame_size: usize) -> (Vec<u8>, Vec<u8>) {
+        let frame: Vec<u8> = (0..frame_size).map(|i| (i * 7 % 251) as u8).collect();
+        let (mut unit, mut expected) = (Vec::new(), Vec::new());
+        for (bytes, padding) in [(&mut unit, true), (&mut expected, false)] {
+            obu(bytes, OBU_TEMPORAL_DELIMITER, false, &[]);
+            obu(bytes, OBU_SEQUENCE_HEADER, false, &[0x00, 0x00, 0x00, 0x0a, 0x0b, 0x80]);
+            obu(bytes, 5, true, &[1, 2, 3]); // Metadata
+            if padding {
+                obu(bytes, OBU_PADDING, false, &[0; 9]);
+            }
+            obu(bytes, 6, false, &frame); // Frame
+        }
+        (unit, expected)
+    }
+
+    #[test]
+    fn av1_round_trip() {
+        for mtu in [20, 50, 300, 1200] {
+            for frame_size in [0, 100, 5000] {
+                let (unit, expected) = av1_unit(frame_size);
+                let packets = Av1Payloader::new(45, 0x1234_5678, 0xfffe, mtu).payload(&unit, 3000).unwrap();
+                for (i, packet) in packets.iter().enumerate() {
+                    assert!(packet.len() <= mtu);
+                    assert_eq!(packet[1] & 0x80 != 0, i + 1 == packets.len(), "only the last packet is marked");
+                    assert_eq!(packet[RTP_HEADER_SIZE] & AV1_N != 0, i == 0, "only the first packet starts the sequence");
+                }
+
+                let mut depayloader = Av1Depayloader::new();
+                let units: Vec<_> = packets.iter().map(|packet| depayloader.push(packet).unwrap()).collect();
+                assert!(units[..units.len() - 1].iter().all(Option::is_none));
+                assert_eq!(units.last().unwrap().as_ref(), Some(&expected), "MTU {}, frame of {}", mtu, frame_size);
+            }
+        }
+    }
+
+    #[test]
+    fn av1_unit_missing_a_packet_is_dropped() {
+        let (unit, expected) = av1_unit(3000);
+        let mut payloader = Av1Payloader::new(45, 1, 0, 1200);
+        let mut first = payloader.payload(&unit, 0).unwrap();
+        let second = payloader.payload(&unit, 3000).unwrap();
+        first.remove(1);
+
+        let mut depayloader = Av1Depayloader::new();
+        assert!(first.iter().all(|packet| depayloader.push(packet).unwrap().is_none()));
+        let units: Vec<_> = second.iter().map(|packet| depayloader.push(packet).unwrap()).collect();
+        assert_eq!(units.last().unwrap().as_ref(), Some(&expected), "the next unit starts over");
+    }
+
+    #[test]
+    fn av1_last_element_may_leave_out_its_length() {
+        // W of 2 as browsers send: the first element has its length, the second takes the rest of the packet
+        let mut packet = Vec::new();
+        write_header(&mut packet, true, 45, 7, 0, 1);
+        packet.extend_from_slice(&[0x20, 3, OBU_SEQUENCE_HEADER << 3, 0xaa, 0xbb, 6 << 3, 1, 2, 3, 4]);
+
+        let mut expected = vec![OBU_TEMPORAL_DELIMITER << 3 | OBU_HAS_SIZE, 0];
+        obu(&mut expected, OBU_SEQUENCE_HEADER, false, &[0xaa, 0xbb]);
+        obu(&mut expected, 6, false, &[1, 2, 3, 4]);
+        assert_eq!(Av1Depayloader::new().push(&packet).unwrap(), Some(expected));
+    }
+
+    #[test]
+    fn av1_fragment_without_its_start_is_refused() {
+        let mut packet = Vec::new();
+        write_header(&mut packet, true, 45, 7, 0, 1);
+        packet.extend_from_slice(&[AV1_Z, 2, 0xaa, 0xbb]);
+        assert!(Av1Depayloader::new().push(&packet).is_err());
+
+        // Nor are elements longer than the packet
+        packet.truncate(RTP_HEADER_SIZE);
+        packet.extend_from_slice(&[0, 9, 6 << 3, 1]);
+        assert!(Av1Depayloader::new().push(&packet).is_err());
+    }
+}
@@ -0,0 +1,671 @@
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream, UdpSocket};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::auth::{self, Secret};
+use crate::rtp::{self, Jpeg, Payloader};
+use crate::transport::WRITE_TIMEOUT;
+use crate::web::{Event, Events, Mailbox, Mode, Reply, Request, ViewerId, JOIN_TIMEOUT, MAX_REQUEST, POLL_INTERVAL};
+
+/// Players playing over UDP send a request at least this often, or they are dropped
+const SESSION_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Largest RTP packet, below the usual Ethernet MTU with room for the IP and UDP headers
+const MTU: usize = 1400;
+
+/// Methods a player can use
+const METHODS: &str = "OPTIONS, DESCRIBE, SETUP, PLAY, PAUSE, TEARDOWN, GET_PARAMETER, SET_PARAMETER";
+
+/// Realm players are asked credentials for
+const REALM: &str = "screen-stream";
+
+/// Where a player gets its RTP packets
+enum Output {
+    Udp { socket: UdpSocket, player: SocketAddr },
+    Interleaved { channel: u8 }, // On the RTSP connection, framed with a '$'
+}
+
+/// Answer to a request
+struct Response {
+    status: &'static str,
+    headers: Vec<String>,
+    body: Option<(&'static str, String)>, // Content type and content
+}
+
+impl Response {
+    fn new(status: &'static str) -> Self {
+        Self { status, headers: Vec::new(), body: None }
+    }
+
+    fn header(mut self, header: String) -> Self {
+        self.headers.push(header);
+        self
+    }
+}
+
+/// One RTSP player, from its connection to the end of it
+struct Player<'a> {
+    stream: TcpStream,
+    buffer: Vec<u8>, // Read from the connection, not handled yet
+    id: ViewerId,
+    address: SocketAddr,
+    events: &'a Events,
+    mailbox: Arc<Mailbox>,
+    opened: bool,            // Whether the server was told about this player
+    joined: Option<String>,  // Name of the stream the server let the player watch
+    session: String,         // Id of the RTSP session, once set up
+    output: Option<Output>,
+    playing: bool,
+    payloader: Payloader,
+    clock: (Instant, u32),   // When the RTP clock read the second value, it starts at a random one
+    last_request: Instant,
+    unsendable: bool,        // A frame could not be sent as RTP/JPEG, said once
+}
+
+/// Serves one RTSP connection until the player tears the session down or leaves
+pub fn serve(stream: TcpStream, id: ViewerId, events: &Events) {
+    let address = match stream.peer_addr() {
+        Ok(address) => address,
+        Err(_) => return,
+    };
+
+    let setup = stream
+        .set_read_timeout(Some(POLL_INTERVAL))
+        .and_then(|_| stream.set_write_timeout(Some(WRITE_TIMEOUT)))
+        .and_then(|_| stream.set_nodelay(true));
+    let random = random();
+    if let Err(e) = setup.and(random.as_ref().map(|_| ()).map_err(|e| io::Error::other(e.to_string()))) {
+        eprintln!("Error setting up RTSP connection from {}: {}", address, e);
+        return;
+    }
+
+    let mut player = Player::new(stream, id, address, events, random.expect("Checked above"));
+    let reason = match player.run() {
+        Ok(reason) => reason,
+        Err(e) => e.to_string(),
+    };
+    if player.opened {
+        let _ = events.send((id, Event::Closed { reason }));
+    }
+}
+
+/// SSRC, first sequence number and first timestamp of a player's RTP stream
+fn random() -> Result<(u32, u16, u32), getrandom::Error> {
+    let mut bytes = [0u8; 10];
+    getrandom::getrandom(&mut bytes)?;
+    Ok((
+        u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+        u16::from_be_bytes([bytes[4], bytes[5]]),
+        u32::from_be_bytes([bytes[6], bytes[7], bytes[8], bytes[9]]),
+    ))
+}
+
+impl<'a> Player<'a> {
+    fn new(stream: TcpStream, id: ViewerId, address: SocketAddr, events: &'a Events, random: (u32, u16, u32)) -> Self {
+        let (ssrc, seq, timestamp) = random;
+        Self {
+            stream,
+            buffer: Vec::new(),
+            id,
+            address,
+            events,
+            mailbox: Arc::new(Mailbox::default()),
+            opened: false,
+            joined: None,
+            session: String::new(),
+            output: None,
+            playing: false,
+            payloader: Payloader::new(ssrc, seq, MTU),
+            clock: (Instant::now(), timestamp),
+            last_request: Instant::now(),
+            unsendable: false,
+        }
+    }
+
+    /// Handles requests and sends frames, returns why the player is gone
+    fn run(&mut self) -> io::Result<String> {
+        let mut chunk = [0u8; 4096];
+
+        loop {
+            match self.stream.read(&mut chunk) {
+                Ok(0) => return Ok(String::from("closed by the player")),
+                Ok(read) => self.buffer.extend_from_slice(&chunk[..read]),
+                Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {}
+                Err(e) => return Err(e),
+            }
+
+            while let Some(request) = self.next_request()? {
+                self.last_request = Instant::now();
+                let (response, done) = self.handle(&request);
+                self.respond(&request, response)?;
+                if done {
+                    return Ok(String::from("torn down by the player"));
+                }
+            }
+
+            if !self.opened {
+                continue;
+            }
+
+            let (replies, frame, closing) = self.mailbox.take();
+            if closing || replies.iter().any(|reply| matches!(reply, Reply::Goodbye(_))) {
+                return Ok(String::from("closed by the server"));
+            }
+            if let Some(frame) = frame.filter(|_| self.playing) {
+                self.send(&frame)?;
+            }
+
+            let interleaved = matches!(self.output, Some(Output::Interleaved { .. }));
+            if self.output.is_some() && !interleaved && self.last_request.elapsed() > SESSION_TIMEOUT {
+                return Ok(String::from("session timed out"));
+            }
+        }
+    }
+
+    /// Next complete request in the buffer, interleaved RTCP from the player is skipped
+    fn next_request(&mut self) -> io::Result<Option<Request>> {
+        loop {
+            if self.buffer.first() == Some(&b'$') {
+                let length = match self.buffer.get(2..4) {
+                    Some(length) => 4 + u16::from_be_bytes([length[0], length[1]]) as usize,
+                    None => return Ok(None),
+                };
+                if self.buffer.len() < length {
+                    return Ok(None);
+                }
+                self.buffer.drain(..length);
+                self.last_request = Instant::now();
+                continue;
+            }
+
+            let end = match self.buffer.windows(4).position(|window| window == b"\r\n\r\n") {
+                Some(end) => end + 4,
+                None if self.buffer.len() > MAX_REQUEST => {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "request too large"));
+                }
+                None => return Ok(None),
+            };
+
+            let head = std::str::from_utf8(&self.buffer[..end])
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "request is not UTF-8"))?;
+            let request = Request::parse(head)?;
+
+            // Bodies, of SET_PARAMETER for one, are not needed
+            let body = request.header("content-length").and_then(|length| length.parse().ok()).unwrap_or(0);
+            if body > MAX_REQUEST {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "request body too large"));
+            }
+            if self.buffer.len() < end + body {
+                return Ok(None);
+            }
+            self.buffer.drain(..end + body);
+            return Ok(Some(request));
+        }
+    }
+
+    /// Answers a request, and whether the player is done
+    fn handle(&mut self, request: &Request) -> (Response, bool) {
+        let session = request.header("session").map(|session| session.split(';').next().unwrap_or("").trim());
+        if session.is_some_and(|session| session != self.session) {
+            return (Response::new("454 Session Not Found"), false);
+        }
+
+        let response = match request.method.as_str() {
+            "OPTIONS" => Response::new("200 OK").header(format!("Public: {}", METHODS)),
+            "DESCRIBE" => match self.join(request) {
+                Ok(()) => self.describe(request),
+                Err(response) => response,
+            },
+            "SETUP" => match self.join(request) {
+                Ok(()) => self.setup(request),
+                Err(response) => response,
+            },
+            "PLAY" if self.output.is_some() => {
+                self.playing = true;
+                let base = request.target.split('?').next().unwrap_or("");
+                Response::new("200 OK")
+                    .header(String::from("Range: npt=0.000-"))
+                    .header(format!("RTP-Info: url={};seq={};rtptime={}", base, self.payloader.next_seq(), self.timestamp()))
+            }
+            "PAUSE" if self.output.is_some() => {
+                self.playing = false;
+                Response::new("200 OK")
+            }
+            "PLAY" | "PAUSE" => Response::new("455 Method Not Valid in This State"),
+            "TEARDOWN" => return (Response::new("200 OK"), true),
+            "GET_PARAMETER" | "SET_PARAMETER" => Response::new("200 OK"),
+            _ => Response::new("501 Not Implemented").header(format!("Public: {}", METHODS)),
+        };
+        (response, false)
+    }
+
+    /// Joins the stream named by the path, like the viewer page does, answering the challenge with the
+    /// credentials of the request. Once joined, the player keeps watching that stream
+    fn join(&mut self, request: &Request) -> Result<(), Response> {
+        if self.joined.is_some() {
+            return Ok(());
+        }
+
+        let size = |name: &str| request.param(name).and_then(|value| value.parse().ok()).unwrap_or(rtp::MAX_SIZE);
+        let hello = Event::Hello {
+            mode: Mode::Rtsp,
+            stream: stream_name(&request.target),
+            max_width: size("max_width").min(rtp::MAX_SIZE),
+            max_height: size("max_height").min(rtp::MAX_SIZE),
+            rendition: request.param("rendition").and_then(|value| value.parse().ok()),
+        };
+
+        if !self.opened {
+            let opened = Event::Opened { address: self.address, mailbox: Arc::clone(&self.mailbox) };
+            if self.events.send((self.id, opened)).is_err() {
+                return Err(Response::new("503 Service Unavailable"));
+            }
+            self.opened = true;
+        }
+        if self.events.send((self.id, hello)).is_err() {
+            return Err(Response::new("503 Service Unavailable"));
+        }
+
+        let unauthorized = || {
+            Response::new("401 Unauthorized").header(format!("WWW-Authenticate: Basic realm=\"{}\"", REALM))
+        };
+        let secret = credentials(request);
+        let mut answered = false;
+        let deadline = Instant::now() + JOIN_TIMEOUT;
+
+        while Instant::now() < deadline {
+            let (replies, _, closing) = self.mailbox.wait(deadline.saturating_duration_since(Instant::now()));
+            for reply in replies {
+                match reply {
+                    Reply::Challenge(nonce) => match &secret {
+                        Some(secret) => {
+                            answered = true;
+                            let _ = self.events.send((self.id, Event::Answer(secret.respond(&nonce))));
+                        }
+                        None => return Err(unauthorized()),
+                    },
+                    Reply::Accept { stream } => {
+                        self.joined = Some(stream);
+                        return Ok(());
+                    }
+                    Reply::Reject(reason) if answered => {
+                        println!("RTSP player {} rejected: {}", self.address, reason);
+                        return Err(unauthorized());
+                    }
+                    Reply::Reject(_) => return Err(Response::new("404 Not Found")),
+                    Reply::Renditions { .. } | Reply::Goodbye(_) => {}
+                }
+            }
+            if closing {
+                return Err(Response::new("503 Service Unavailable"));
+            }
+        }
+        Err(Response::new("503 Service Unavailable"))
+    }
+
+    /// The session description of the stream: one JPEG video track
+    fn describe(&self, request: &Request) -> Response {
+        let local = self.stream.local_addr().map(|address| address.ip().to_canonical());
+        let (family, ip) = match local {
+            Ok(ip) if ip.is_ipv6() => ("IP6", ip.to_string()),
+            Ok(ip) => ("IP4", ip.to_string()),
+            Err(_) => ("IP4", String::from("0.0.0.0")),
+        };
+
+        let name = self.joined.as_deref().unwrap_or("");
+        let sdp = format!(
+            "v=0\r\no=- {} 1 IN {} {}\r\ns={}\r\nc=IN {} {}\r\nt=0 0\r\na=control:*\r\n\
+             m=video 0 RTP/AVP {}\r\na=rtpmap:{} JPEG/{}\r\na=control:track0\r\n",
+            self.payloader.next_seq(),
+            family,
+            ip,
+            if name.is_empty() { "screen-stream" } else { name },
+            family,
+            if family == "IP6" { "::" } else { "0.0.0.0" },
+            rtp::PAYLOAD_TYPE,
+            rtp::PAYLOAD_TYPE,
+            rtp::CLOCK_RATE
+        );
+
+        let base = request.target.split('?').next().unwrap_or("").trim_end_matches('/');
+        Response {
+            status: "200 OK",
+            headers: vec![format!("Content-Base: {}/", base)],
+            body: Some(("application/sdp", sdp)),
+        }
+    }
+
+    /// Sets up where RTP goes: interleaved on this connection, or over UDP to the player's own address
+    fn setup(&mut self, request: &Request) -> Response {
+        let transport = request.header("transport").unwrap_or("");
+        let spec = transport.split(',').next().unwrap_or("");
+        let parameter = |name: &str| {
+            spec.split(';').find_map(|part| part.trim().strip_prefix(name).and_then(|rest| rest.strip_prefix('=')))
+        };
+        let first_port = |range: &str| range.split('-').next().and_then(|port| port.parse::<u16>().ok());
+
+        if spec.split(';').any(|part| part.trim() == "multicast") {
+            return Response::new("461 Unsupported Transport");
+        }
+
+        let (output, answer) = if spec.starts_with("RTP/AVP/TCP") {
+            let channel = parameter("interleaved").and_then(|range| range.split('-').next()?.parse::<u8>().ok()).unwrap_or(0);
+            (
+                Output::Interleaved { channel },
+                format!("RTP/AVP/TCP;unicast;interleaved={}-{};ssrc={:08X}", channel, channel.wrapping_add(1), self.ssrc()),
+            )
+        } else if spec.starts_with("RTP/AVP") {
+            let port = match parameter("client_port").and_then(first_port) {
+                Some(port) => port,
+                None => return Response::new("461 Unsupported Transport"),
+            };
+
+            // Only ever to the player's own address, so nobody can point a stream at someone else
+            let local = self.stream.local_addr().map(|address| address.ip());
+            let socket = local.and_then(|ip| UdpSocket::bind(SocketAddr::new(ip, 0)));
+            let (socket, server_port) = match socket.and_then(|socket| Ok((socket.local_addr()?.port(), socket))) {
+                Ok((server_port, socket)) => (socket, server_port),
+                Err(e) => {
+                    eprintln!("Error binding RTP socket for {}: {}", self.address, e);
+                    return Response::new("500 Internal Server Error");
+                }
+            };
+
+            let player = SocketAddr::new(self.address.ip(), port);
+            (
+                Output::Udp { socket, player },
+                format!(
+                    "RTP/AVP;unicast;client_port={};server_port={};ssrc={:08X}",
+                    parameter("client_port").unwrap_or(""),
+                    server_port,
+                    self.ssrc()
+                ),
+            )
+        } else {
+            return Response::new("461 Unsupported Transport");
+        };
+
+        if self.session.is_empty() {
+            self.session = match auth::nonce() {
+                Ok(nonce) => auth::to_hex(&nonce[..8]),
+                Err(e) => {
+                    eprintln!("Error generating RTSP session for {}: {}", self.address, e);
+                    return Response::new("500 Internal Server Error");
+                }
+            };
+        }
+        self.output = Some(output);
+        Response::new("200 OK").header(format!("Transport: {}", answer))
+    }
+
+    fn ssrc(&self) -> u32 {
+        self.payloader.ssrc()
+    }
+
+    /// RTP timestamp of now, on the 90 kHz video clock
+    fn timestamp(&self) -> u32 {
+        let (start, first) = self.clock;
+        let ticks = start.elapsed().as_micros() * rtp::CLOCK_RATE as u128 / 1_000_000;
+        first.wrapping_add(ticks as u32)
+    }
+
+    fn respond(&mut self, request: &Request, response: Response) -> io::Result<()> {
+        let mut head = format!("RTSP/1.0 {}\r\nCSeq: {}\r\n", response.status, request.header("cseq").unwrap_or("0"));
+        if !self.session.is_empty() {
+            head.push_str(&format!("Session: {};timeout={}\r\n", self.session, SESSION_TIMEOUT.as_secs()));
+        }
+        for header in &response.headers {
+            head.push_str(header);
+            head.push_str("\r\n");
+        }
+        match &response.body {
+            Some((content_type, body)) => {
+                head.push_str(&format!("Content-Type: {}\r\nContent-Length: {}\r\n\r\n{}", content_type, body.len(), body));
+            }
+            None => head.push_str("\r\n"),
+        }
+
+        self.stream.write_all(head.as_bytes())
+    }
+
+    /// Sends a frame as RTP/JPEG packets
+    fn send(&mut self, frame: &[u8]) -> io::Result<()> {
+        let jpeg = match Jpeg::parse(frame) {
+            Ok(jpeg) => jpeg,
+            Err(e) => {
+                if !self.unsendable {
+                    eprintln!("Frames can't be sent to RTSP player {}: {}", self.address, e);
+                    self.unsendable = true;
+                }
+                return Ok(());
+            }
+        };
+
+        let timestamp = self.timestamp();
+        for packet in self.payloader.payload(&jpeg, timestamp) {
+            match &self.output {
+                Some(Output::Udp { socket, player }) => {
+                    // A player that stopped listening is dropped by the session timeout
+                    let _ = socket.send_to(&packet, player);
+                }
+                Some(Output::Interleaved { channel }) => {
+                    let mut framed = Vec::with_capacity(4 + packet.len());
+                    framed.push(b'$');
+                    framed.push(*channel);
+                    framed.extend_from_slice(&(packet.len() as u16).to_be_bytes());
+                    framed.extend_from_slice(&packet);
+                    self.stream.write_all(&framed)?;
+                }
+                None => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Name of the stream in an RTSP URL: its path, without the track players add when setting up
+fn stream_name(target: &str) -> String {
+    let target = target.split('?').next().unwrap_or("");
+    let path = match target.split_once("://") {
+        Some((_, rest)) => rest.split_once('/').map(|(_, path)| path).unwrap_or(""),
+        None => target.trim_start_matches('/'),
+    };
+    let path = path.trim_end_matches('/');
+    path.strip_suffix("/track0").or(path.strip_suffix("track0")).unwrap_or(path).trim_end_matches('/').to_string()
+}
+
+/// Secret from the request: a password or invite token as the password of basic authentication, or in the query
+fn credentials(request: &Request) -> Option<Secret> {
+    let basic = request
+        .header("authorization")
+        .and_then(|authorization| authorization.strip_prefix("Basic "))
+        .and_then(|encoded| base64(encoded.trim()))
+        .and_then(|decoded| String::from_utf8(decoded).ok())
+        .and_then(|decoded| decoded.split_once(':').map(|(_, password)| password.to_string()));
+
+    if let Some(token) = request.param("token") {
+        return Secret::from_token(&token).ok();
+    }
+    let password = basic.or_else(|| request.param("password"))?;
+    Some(Secret::from_token(&password).unwrap_or(Secret::Password(password.into_bytes())))
+}
+
+/// Decodes standard base64, None if it isn't
+fn base64(text: &str) -> Option<Vec<u8>> {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut decoded = Vec::with_capacity(text.len() * 3 / 4);
+    let (mut bits, mut count) = (0u32, 0);
+    for byte in text.trim_end_matches('=').bytes() {
+        bits = bits << 6 | ALPHABET.iter().position(|&symbol| symbol == byte)? as u32;
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            decoded.push((bits >> count) as u8);
+            bits &= (1 << count) - 1;
+        }
+    }
+    Some(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, TcpListener};
+    use std::sync::mpsc;
+
+    use super::*;
+
+    const TOKEN: &str = "0011223344556677-00112233445566778899aabbccddeeff";
+
+    /// A player on one end of a loopback connection, and the other end
+    fn player(events: &Events) -> (Player<'_>, TcpStream) {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, address) = listener.accept().unwrap();
+        (Player::new(stream, 1, address, events, (0x1234_5678, 0, 0)), client)
+    }
+
+    fn request(head: &str) -> Request {
+        Request::parse(head).unwrap()
+    }
+
+    fn setup(player: &mut Player, transport: &str) -> Response {
+        player.setup(&request(&format!("SETUP rtsp://host/screen/track0 RTSP/1.0\r\nTransport: {}\r\n\r\n", transport)))
+    }
+
+    #[test]
+    fn interleaved_packets_are_skipped_and_bodies_waited_for() {
+        let (events, _) = mpsc::channel();
+        let (mut player, _client) = player(&events);
+
+        // Half an interleaved RTCP packet, then all of it
+        player.buffer.extend_from_slice(b"$\x01\x00\x04ab");
+        assert!(player.next_request().unwrap().is_none());
+        assert_eq!(player.buffer.len(), 6);
+        player.buffer.extend_from_slice(b"cdOPTIONS rtsp://host/screen RTSP/1.0\r\nCSeq: 1\r\n\r\n");
+        assert_eq!(player.next_request().unwrap().unwrap().method, "OPTIONS");
+
+        player.buffer.extend_from_slice(b"SET_PARAMETER rtsp://host/screen RTSP/1.0\r\nCSeq: 2\r\nContent-Length: 5\r\n\r\nab");
+        assert!(player.next_request().unwrap().is_none());
+        player.buffer.extend_from_slice(b"cdeGET_PARAMETER rtsp://host/screen RTSP/1.0\r\n\r\n");
+        assert_eq!(player.next_request().unwrap().unwrap().method, "SET_PARAMETER");
+        assert_eq!(player.next_request().unwrap().unwrap().method, "GET_PARAMETER");
+        assert!(player.next_request().unwrap().is_none());
+        assert!(player.buffer.is_empty());
+    }
+
+    #[test]
+    fn oversized_requests_are_refused() {
+        let (events, _) = mpsc::channel();
+        let (mut player, _client) = player(&events);
+
+        player.buffer = vec![b'a'; MAX_REQUEST + 1];
+        assert_eq!(player.next_request().err().unwrap().kind(), io::ErrorKind::InvalidData);
+
+        player.buffer = format!("SET_PARAMETER * RTSP/1.0\r\nContent-Length: {}\r\n\r\n", MAX_REQUEST + 1).into_bytes();
+        assert_eq!(player.next_request().err().unwrap().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn setup_reads_the_transport() {
+        let (events, _) = mpsc::channel();
+        let (mut player, _client) = player(&events);
+
+        let response = setup(&mut player, "RTP/AVP/TCP;unicast;interleaved=2-3");
+        assert_eq!(response.status, "200 OK");
+        assert_eq!(response.headers, vec![String::from("Transport: RTP/AVP/TCP;unicast;interleaved=2-3;ssrc=12345678")]);
+        assert!(matches!(player.output, Some(Output::Interleaved { channel: 2 })));
+        let session = player.session.clone();
+        assert_eq!(session.len(), 16);
+
+        // Over UDP, to the port the player gave on its own address, in the same session
+        let response = setup(&mut player, "RTP/AVP;unicast;client_port=5000-5001");
+        assert_eq!(response.status, "200 OK");
+        assert!(response.headers[0].starts_with("Transport: RTP/AVP;unicast;client_port=5000-5001;server_port="));
+        match &player.output {
+            Some(Output::Udp { player: destination, .. }) => assert_eq!(*destination, SocketAddr::new(player.address.ip(), 5000)),
+            _ => panic!("expected a UDP output"),
+        }
+        assert_eq!(player.session, session);
+    }
+
+    #[test]
+    fn setup_refuses_multicast_and_unknown_transports() {
+        let (events, _) = mpsc::channel();
+        let (mut player, _client) = player(&events);
+
+        for transport in ["RTP/AVP;multicast;client_port=5000-5001", "RTP/AVP;unicast", "RAW/RAW/UDP;unicast", ""] {
+            assert_eq!(setup(&mut player, transport).status, "461 Unsupported Transport", "{}", transport);
+        }
+        assert!(player.output.is_none());
+        assert!(player.session.is_empty());
+    }
+
+    #[test]
+    fn stream_name_is_the_path_without_the_track() {
+        assert_eq!(stream_name("rtsp://host:8554/screen"), "screen");
+        assert_eq!(stream_name("rtsp://host:8554/screen/"), "screen");
+        assert_eq!(stream_name("rtsp://host:8554/screen/track0"), "screen");
+        assert_eq!(stream_name("rtsp://host:8554/screen?max_width=640"), "screen");
+        assert_eq!(stream_name("rtsp://host:8554/screen/track0?token=x"), "screen");
+        assert_eq!(stream_name("rtsp://[::1]:8554/track0"), "");
+        assert_eq!(stream_name("rtsp://host:8554"), "");
+        assert_eq!(stream_name("/camera/track0"), "camera");
+        assert_eq!(stream_name("*"), "*");
+    }
+
+    #[test]
+    fn base64_decodes_with_and_without_padding() {
+        assert_eq!(base64("dXNlcjpodW50ZXIy").unwrap(), b"user:hunter2");
+        assert_eq!(base64("YQ==").unwrap(), b"a");
+        assert_eq!(base64("YWI=").unwrap(), b"ab");
+        assert_eq!(base64("YWI").unwrap(), b"ab");
+        assert_eq!(base64("+/+/").unwrap(), [0xfb, 0xff, 0xbf]);
+        assert!(base64("").unwrap().is_empty());
+        assert!(base64("a b").is_none());
+        assert!(base64("YQ-_").is_none());
+    }
+
+    #[test]
+    fn credentials_from_basic_authentication_or_the_query() {
+        let password = |secret: Option<Secret>| match secret {
+            Some(Secret::Password(password)) => Some(password),
+            _ => None,
+        };
+        let is_invite = |secret: Option<Secret>| matches!(secret, Some(Secret::Invite { id, .. }) if id[7] == 0x77);
+
+        // "user:hunter2"
+        let basic = request("DESCRIBE rtsp://host/screen RTSP/1.0\r\nAuthorization: Basic dXNlcjpodW50ZXIy\r\n\r\n");
+        assert_eq!(password(credentials(&basic)).unwrap(), b"hunter2");
+
+        let query = request("DESCRIBE rtsp://host/screen?password=hunter%202 RTSP/1.0\r\n\r\n");
+        assert_eq!(password(credentials(&query)).unwrap(), b"hunter 2");
+
+        // An invite token works as the password too
+        let authorization = encode(&format!("user:{}", TOKEN));
+        let basic = request(&format!("DESCRIBE rtsp://host/screen RTSP/1.0\r\nAuthorization: Basic {}\r\n\r\n", authorization));
+        assert!(is_invite(credentials(&basic)));
+        assert!(is_invite(credentials(&request(&format!("DESCRIBE rtsp://host/screen?token={} RTSP/1.0\r\n\r\n", TOKEN)))));
+
+        assert!(credentials(&request("DESCRIBE rtsp://host/screen?token=nonsense RTSP/1.0\r\n\r\n")).is_none());
+        assert!(credentials(&request("DESCRIBE rtsp://host/screen RTSP/1.0\r\nAuthorization: Digest x\r\n\r\n")).is_none());
+        assert!(credentials(&request("DESCRIBE rtsp://host/screen RTSP/1.0\r\n\r\n")).is_none());
+    }
+
+    /// Standard base64 with padding, what players send
+    fn encode(text: &str) -> String {
+        const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+        let mut encoded = String::new();
+        for chunk in text.as_bytes().chunks(3) {
+            let bits = chunk.iter().enumerate().fold(0u32, |bits, (i, &byte)| bits | (byte as u32) << (16 - 8 * i));
+            for i in 0..4 {
+                match i <= chunk.len() {
+                    true => encoded.push(ALPHABET[(bits >> (18 - 6 * i) & 0x3f) as usize] as char),
+                    false => encoded.push('='),
+                }
+            }
+        }
+        encoded
+    }
+}
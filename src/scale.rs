@@ -0,0 +1,55 @@
+/// Largest size with the aspect ratio of `width`x`height` that fits in `max_width`x`max_height`
+/// Never larger than the source, and rounded down to even sizes for chroma subsampling
+pub fn fit(width: usize, height: usize, max_width: usize, max_height: usize) -> (usize, usize) {
+    let scale = (max_width as f64 / width as f64).min(max_height as f64 / height as f64).min(1.0);
+    let even = |size: f64| ((size as usize) & !1).max(2);
+    (even(width as f64 * scale), even(height as f64 * scale))
+}
+
+/// Shrinks a frame of 4 byte pixels (BGRX) to `out_width`x`out_height`
+/// Every output pixel is the average of the source pixels it covers, which keeps text readable
+/// where picking one source pixel would drop strokes. The output is packed, its pitch is `out_width * 4`
+pub fn downscale(
+    pixels: &[u8],
+    width: usize,
+    height: usize,
+    pitch: usize,
+    out_width: usize,
+    out_height: usize,
+) -> Vec<u8> {
+    let mut out = vec![0u8; out_width * out_height * 4];
+
+    // Source range covered by every output column, at least one pixel
+    let span = |i: usize, size: usize, out_size: usize| {
+        let start = i * size / out_size;
+        (start, ((i + 1) * size / out_size).max(start + 1).min(size))
+    };
+    let columns: Vec<(usize, usize)> = (0..out_width).map(|x| span(x, width, out_width)).collect();
+
+    let mut sums = vec![0u32; out_width * 4];
+    for y in 0..out_height {
+        let (top, bottom) = span(y, height, out_height);
+        sums.fill(0);
+
+        for row in top..bottom {
+            let row = &pixels[row * pitch..row * pitch + width * 4];
+            for (sum, &(left, right)) in sums.chunks_exact_mut(4).zip(&columns) {
+                for pixel in row[left * 4..right * 4].chunks_exact(4) {
+                    for (channel, &value) in sum.iter_mut().zip(pixel) {
+                        *channel += value as u32;
+                    }
+                }
+            }
+        }
+
+        let line = &mut out[y * out_width * 4..(y + 1) * out_width * 4];
+        for ((pixel, sum), &(left, right)) in line.chunks_exact_mut(4).zip(sums.chunks_exact(4)).zip(&columns) {
+            let count = ((right - left) * (bottom - top)) as u32;
+            for (channel, &total) in pixel.iter_mut().zip(sum) {
+                *channel = (total / count) as u8;
+            }
+        }
+    }
+
+    out
+}
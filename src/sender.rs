@@ -0,0 +1,231 @@
+use std::collections::VecDeque;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::pacer::Pacer;
+use crate::transport::Transmit;
+
+/// What a sender sends, in the order it was queued
+enum Item {
+    Frame { datagrams: Vec<(SocketAddr, Vec<u8>)>, span: Duration },
+    Control { address: SocketAddr, bytes: Vec<u8> },
+}
+
+#[derive(Default)]
+struct Queue {
+    items: VecDeque<Item>,
+    closed: bool,                // No more items will be queued, the worker stops once control messages are out
+    failed: Option<io::Error>,   // Why the worker stopped sending, taken by `failure`
+}
+
+struct Shared {
+    queue: Mutex<Queue>,
+    ready: Condvar,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, Queue> {
+        // The worker never panics while holding the lock, the queue is consistent either way
+        self.queue.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// Sends to one client from its own thread, so a congested client only holds up its own stream
+/// The queue is bounded: while a frame is being sent, at most one more waits, and a newer frame replaces it.
+/// Control messages are never replaced and keep their place between frames: datagrams are sealed in the
+/// order they are queued, sending them in another order would trip the client's replay window
+pub struct Sender {
+    shared: Arc<Shared>,
+    worker: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Sender {
+    /// Frames waiting behind the one being sent
+    const MAX_FRAMES: usize = 1;
+
+    /// Control messages waiting, more are dropped: the client asks faster than it can be answered
+    const MAX_CONTROL: usize = 64;
+
+    /// Sends through `link`, a clone of the server's UDP socket or the viewer's connection
+    pub fn spawn(link: impl Transmit) -> io::Result<Self> {
+        let shared = Arc::new(Shared { queue: Mutex::new(Queue::default()), ready: Condvar::new() });
+
+        let worker = {
+            let shared = Arc::clone(&shared);
+            thread::Builder::new().name(String::from("sender")).spawn(move || work(link, shared))?
+        };
+
+        Ok(Self { shared, worker: Mutex::new(Some(worker)) })
+    }
+
+    /// Queues the datagrams of a frame, paced over `span`
+    /// Returns how many older frames it replaced, they were never sent
+    pub fn frame(&self, datagrams: Vec<(SocketAddr, Vec<u8>)>, span: Duration) -> usize {
+        let mut queue = self.shared.lock();
+        let before = queue.items.len();
+
+        // Latest frame wins, an older one would only be shown late
+        let frames = queue.items.iter().filter(|item| matches!(item, Item::Frame { .. })).count();
+        if frames >= Self::MAX_FRAMES {
+            queue.items.retain(|item| matches!(item, Item::Control { .. }));
+        }
+        let replaced = before - queue.items.len();
+
+        queue.items.push_back(Item::Frame { datagrams, span });
+        self.shared.ready.notify_one();
+        replaced
+    }
+
+    /// Queues a control message, false if the queue is full and it was dropped
+    pub fn control(&self, address: SocketAddr, bytes: Vec<u8>) -> bool {
+        let mut queue = self.shared.lock();
+
+        let waiting = queue.items.iter().filter(|item| matches!(item, Item::Control { .. })).count();
+        if waiting >= Self::MAX_CONTROL {
+            return false;
+        }
+
+        queue.items.push_back(Item::Control { address, bytes });
+        self.shared.ready.notify_one();
+        true
+    }
+
+    /// Why the worker stopped sending, once: the client should be removed
+    pub fn failure(&self) -> Option<io::Error> {
+        self.shared.lock().failed.take()
+    }
+
+    /// Drops the frames not sent yet, the worker stops once the control messages queued are sent
+    pub fn close(&self) {
+        let mut queue = self.shared.lock();
+        queue.closed = true;
+        queue.items.retain(|item| matches!(item, Item::Control { .. }));
+        self.shared.ready.notify_one();
+    }
+
+    /// Closes and waits for the control messages queued to be sent, e.g. a goodbye before exiting
+    /// A TCP connection and its client share a sender, either can finish it
+    pub fn finish(&self) {
+        self.close();
+        let worker = self.worker.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).take();
+        if let Some(worker) = worker {
+            let _ = worker.join();
+        }
+    }
+}
+
+impl Drop for Sender {
+    /// Doesn't wait for the worker, removing a client must not stall the capture loop
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+fn work(link: impl Transmit, shared: Arc<Shared>) {
+    let mut pacer = Pacer::new(Duration::ZERO);
+
+    loop {
+        let item = {
+            let mut queue = shared.lock();
+            loop {
+                if let Some(item) = queue.items.pop_front() {
+                    break item;
+                }
+                if queue.closed {
+                    return;
+                }
+                queue = shared.ready.wait(queue).unwrap_or_else(|poisoned| poisoned.into_inner());
+            }
+        };
+
+        match item {
+            Item::Frame { datagrams, span } => {
+                pacer.set_span(span);
+                let result = pacer.send(&link, &datagrams);
+
+                if result.dropped > 0 {
+                    println!("Socket buffer full, dropped {} of {} packets", result.dropped, datagrams.len());
+                }
+
+                if let Some((_, e)) = result.failed.into_iter().next() {
+                    shared.lock().failed = Some(e);
+                    return;
+                }
+            }
+            Item::Control { address, bytes } => {
+                if let Err(e) = link.send_control(&bytes, address) {
+                    eprintln!("Error sending control message to {}: {}", address, e);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    /// Hands what it sends to the test, each send held until the test lets go of the other end of `held`
+    struct Held {
+        sent: mpsc::Sender<Vec<u8>>,
+        held: Mutex<mpsc::Receiver<()>>,
+    }
+
+    impl Transmit for Held {
+        fn send_to(&self, bytes: &[u8], _: SocketAddr) -> io::Result<usize> {
+            let _ = self.sent.send(bytes.to_vec());
+            let _ = self.held.lock().unwrap().recv();
+            Ok(bytes.len())
+        }
+    }
+
+    #[test]
+    fn latest_frame_wins_and_control_messages_are_bounded() {
+        let (sent, sending) = mpsc::channel();
+        let (hold, held) = mpsc::channel();
+        let sender = Sender::spawn(Held { sent, held: Mutex::new(held) }).unwrap();
+        let address = SocketAddr::from(([127, 0, 0, 1], 1));
+        let next = || sending.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        // The worker is held sending the first frame, the rest waits in the queue
+        sender.frame(vec![(address, b"first".to_vec())], Duration::ZERO);
+        assert_eq!(next(), b"first");
+        assert!(sender.control(address, b"0".to_vec()));
+        assert_eq!(sender.frame(vec![(address, b"older".to_vec())], Duration::ZERO), 0);
+        assert_eq!(sender.frame(vec![(address, b"newer".to_vec())], Duration::ZERO), 1);
+        for i in 1..Sender::MAX_CONTROL {
+            assert!(sender.control(address, i.to_string().into_bytes()));
+        }
+        assert!(!sender.control(address, b"one too many".to_vec()));
+
+        // Control messages keep their place around the frame that replaced the older one
+        drop(hold);
+        let mut expected = vec![b"0".to_vec(), b"newer".to_vec()];
+        expected.extend((1..Sender::MAX_CONTROL).map(|i| i.to_string().into_bytes()));
+        assert_eq!((0..expected.len()).map(|_| next()).collect::<Vec<_>>(), expected);
+        assert!(sending.recv_timeout(Duration::from_millis(50)).is_err());
+        assert!(sender.failure().is_none());
+    }
+
+    #[test]
+    fn finishing_sends_the_control_messages_left_but_not_the_frames() {
+        let (sent, sending) = mpsc::channel();
+        let (hold, held) = mpsc::channel();
+        let sender = Sender::spawn(Held { sent, held: Mutex::new(held) }).unwrap();
+        let address = SocketAddr::from(([127, 0, 0, 1], 1));
+
+        sender.frame(vec![(address, b"first".to_vec())], Duration::ZERO);
+        assert_eq!(sending.recv_timeout(Duration::from_secs(5)).unwrap(), b"first");
+        sender.frame(vec![(address, b"frame".to_vec())], Duration::ZERO);
+        sender.control(address, b"goodbye".to_vec());
+
+        sender.close();
+        drop(hold);
+        sender.finish();
+        assert_eq!(sending.try_iter().collect::<Vec<_>>(), [b"goodbye".to_vec()]);
+    }
+}
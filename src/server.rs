@@ -1,38 +1,1791 @@
-use std::net::{SocketAddr, UdpSocket};
-use std::time::Duration;
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use scrap::{Capturer, Display};
 use turbojpeg::{Image, PixelFormat, compress};
 
-use crate::comm::Actions;
+use crate::auth::{self, Credentials, Invite, NONCE_SIZE};
+use crate::comm::{
+    Capabilities, Codec, Credential, Message, Rendition, SessionId, StreamParams, COOKIE_SIZE, NO_SESSION, PROOF_SIZE,
+    SESSION_SECRET_SIZE,
+};
 use crate::commands;
-use crate::packet::Packet;
+use crate::congestion::Congestion;
+use crate::cookie::{CookieJar, RateLimiter};
+use crate::crypto::{Channel, PreSharedKey, Session};
+use crate::multicast::{self, Multicast};
+use crate::packet::{Header, Kind, Packet, ProtocolError, StreamId};
+use crate::peer;
+use crate::scale;
+use crate::sender::Sender;
+use crate::source::Source;
+use crate::stats::{ClientStats, SessionStats, SessionSummary, Summary};
+use crate::transport::Listeners;
+use crate::web::{self, Frame, Mailbox, Mode, Reply, ViewerId, Web};
 
-use rgb::*;
+/// Upper bound of control messages handled per frame, so a flood can't starve capture
+const MAX_MESSAGES_PER_TICK: usize = 1024;
 
-use std::thread::available_parallelism;
+/// Smallest frame payload per datagram a client may ask for
+const MIN_PAYLOAD: usize = 512;
+
+/// Share of the frame time packets are paced over, the rest is slack for capture and encoding
+const PACING_SHARE: f64 = 0.8;
+
+/// How often the per-viewer summary is printed
+const SUMMARY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Exit status of a server stopped by a signal
+const EXIT_OK: i32 = 0;
+
+/// Exit status when sending to every viewer failed
+const EXIT_SEND_FAILED: i32 = 1;
+
+/// Exit status when a second signal stopped the server before it could shut down cleanly
+const EXIT_INTERRUPTED: i32 = 130;
+
+/// How long a client has to answer its challenge
+const CHALLENGE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Upper bound of clients waiting to authenticate, so a flood of hellos can't grow memory
+const MAX_PENDING: usize = 256;
+
+/// Join attempts (hellos and authentications) a host can make per second, after a burst
+const JOIN_RATE: f64 = 1.0;
+const JOIN_BURST: u32 = 6;
+
+/// Most renditions encoded from each capture, the full one included
+const MAX_RENDITIONS: usize = 8;
+
+/// Most streams one server publishes
+const MAX_STREAMS: usize = 16;
+
+/// Name of the stream of a server started without `--stream`
+const DEFAULT_STREAM: &str = "screen";
+
+/// Least time between two automatic rendition switches of a client, so it doesn't flap
+const SWITCH_HOLD: Duration = Duration::from_secs(5);
+
+/// Share of its target bitrate a larger rendition may need before a client is switched up to it
+const SWITCH_UP_HEADROOM: f64 = 0.8;
+
+/// Weight of the newest frame in the average frame size of a rendition
+const FRAME_SIZE_SMOOTHING: f64 = 0.1;
+
+/// What a viewer asked for with a quality request, the server's settings until it does
+#[derive(Clone, Copy)]
+struct Preferences {
+    quality: u8,
+    fps: u8,
+}
+
+/// Which rendition a viewer is sent
+struct Subscription {
+    renditions: Vec<Rendition>, // Renditions that fit the viewer, largest first
+    current: Rendition,
+    automatic: bool,   // Switched from the viewer's bandwidth, until it picks one
+    switched: Instant, // Last time the current rendition changed
+}
+
+impl Subscription {
+    fn message(&self) -> Message {
+        Message::Renditions {
+            current: self.current.id,
+            automatic: self.automatic,
+            renditions: self.renditions.clone(),
+        }
+    }
+}
+
+/// A viewer the stream is sent to
+struct Client {
+    id: SessionId,
+    address: SocketAddr,    // Where the client is now, it can move
+    params: StreamParams,
+    next_seq: u32,          // Sequence number of the next packet sent to this client, wraps around
+    congestion: Congestion, // Bitrate and quality this client can take right now
+    stats: ClientStats,
+    session: Option<Session>, // Encryption state, when the stream is encrypted
+    last_seen: Instant,       // Last control message received from this client
+    preferences: Preferences,
+    next_frame: Instant,      // When the client is due its next frame, at its preferred frame rate
+    sender: Arc<Sender>,      // Frames and control messages to this client, sent from its own thread
+    subscription: Subscription,
+    multicast: bool,          // Frames go to the group of its rendition, it is only sent control messages and resent packets
+    secret: [u8; SESSION_SECRET_SIZE], // Known to the client only, it proves it owns the session to rejoin from another address
+}
+
+impl Client {
+    fn new(
+        id: SessionId,
+        address: SocketAddr,
+        params: StreamParams,
+        session: Option<Session>,
+        preferences: Preferences,
+        sender: Arc<Sender>,
+        subscription: Subscription,
+    ) -> Result<Self, getrandom::Error> {
+        Ok(Self {
+            id,
+            address,
+            congestion: Congestion::new(params.max_bitrate_kbps),
+            params,
+            next_seq: 0,
+            stats: ClientStats::new(),
+            session,
+            last_seen: Instant::now(),
+            preferences,
+            next_frame: Instant::now(),
+            sender,
+            subscription,
+            multicast: false,
+            secret: auth::session_secret()?,
+        })
+    }
+
+    /// Encrypts a datagram for this client, if the stream is encrypted
+    fn seal(&mut self, channel: Channel, datagram: Vec<u8>) -> Vec<u8> {
+        match &mut self.session {
+            Some(session) => session.seal(channel, &datagram),
+            None => datagram,
+        }
+    }
+
+    /// Frame bytes that fit in one datagram to this client
+    fn payload_size(&self) -> usize {
+        let overhead = if self.session.is_some() { Session::OVERHEAD } else { 0 };
+        self.params.mtu as usize - Packet::META_SIZE - overhead
+    }
+
+    /// Whether the client wants a frame now, at its preferred frame rate
+    /// A little early is fine, capture timing jitters
+    fn frame_due(&mut self) -> bool {
+        let now = Instant::now();
+        if now < self.next_frame {
+            return false;
+        }
+
+        let interval = Duration::from_millis(1000 / self.preferences.fps as u64);
+        self.next_frame = now + interval.mul_f64(0.9);
+        true
+    }
+
+    /// Quality the client wants its rendition encoded at: its preference, capped by the rendition's
+    fn quality(&self) -> u8 {
+        self.preferences.quality.min(self.subscription.current.quality)
+    }
+
+    /// Sends the client another rendition from the next frame on
+    fn switch(&mut self, rendition: Rendition) {
+        let subscription = &mut self.subscription;
+        subscription.current = rendition;
+        subscription.switched = Instant::now();
+
+        self.params.width = rendition.width;
+        self.params.height = rendition.height;
+    }
+
+    fn summary(&self) -> Summary<'_> {
+        Summary {
+            id: self.id,
+            address: self.address,
+            stats: &self.stats,
+            target_kbps: self.congestion.target_kbps(),
+            quality: self.quality(),
+            resolution: (self.subscription.current.width, self.subscription.current.height),
+            multicast: self.multicast,
+        }
+    }
+}
+
+/// A viewer watching through the web server, in the viewer page or as MJPEG
+/// They get every frame of their rendition at its quality, a slow one skips frames
+struct WebViewer {
+    session: SessionId, // Invites are bound to it, like to the session of a native client
+    mode: Mode,
+    address: SocketAddr,
+    mailbox: Arc<Mailbox>,
+    stream: StreamId,
+    subscription: Option<Subscription>, // Renditions that fit the browser, from its hello
+    nonce: Option<[u8; NONCE_SIZE]>,    // Challenge the browser did not answer yet
+    admitted: bool,
+    joined: Instant,
+    frames_sent: u64,
+    frames_replaced: u64, // Replaced by a newer one before the browser took them
+    bytes_sent: u64,
+}
+
+impl WebViewer {
+    /// Rendition of `stream` the browser is sent, once admitted
+    fn watching(&self, stream: StreamId) -> Option<Rendition> {
+        match &self.subscription {
+            Some(subscription) if self.admitted && self.stream == stream => Some(subscription.current),
+            _ => None,
+        }
+    }
+
+    /// Whether joining and leaving are worth printing, dashboards fetch snapshots all the time
+    fn announced(&self) -> bool {
+        self.admitted && self.mode != Mode::Snapshot
+    }
+
+    fn renditions(&self) -> Option<Reply> {
+        self.subscription.as_ref().map(|subscription| Reply::Renditions {
+            current: subscription.current.id,
+            automatic: subscription.automatic,
+            renditions: subscription.renditions.clone(),
+        })
+    }
+}
+
+impl fmt::Display for WebViewer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {:016x} {} | up {}s | frames sent {} replaced {} | {} KiB",
+            self.mode,
+            self.session,
+            self.address,
+            self.joined.elapsed().as_secs(),
+            self.frames_sent,
+            self.frames_replaced,
+            self.bytes_sent / 1024
+        )?;
+        if let Some(rendition) = self.watching(self.stream) {
+            write!(f, " | {}x{} at quality {}", rendition.width, rendition.height, rendition.quality)?;
+        }
+        Ok(())
+    }
+}
+
+/// Reason sent back in clear to a host that can't talk to us because of encryption
+/// None for errors that don't deserve an answer
+fn encryption_rejection(error: &ProtocolError, bytes: &[u8]) -> Option<&'static str> {
+    match error {
+        // Only answer hellos, so tiny datagrams can't be turned into bigger replies
+        ProtocolError::NotEncrypted if matches!(Message::from_bytes(bytes), Ok((_, Message::Hello { .. }))) => {
+            Some("This stream is encrypted, connect with the same --key or --key-file as the server")
+        }
+        ProtocolError::UnexpectedEncryption | ProtocolError::Undecryptable => {
+            Some("Could not decrypt, connect with the same --key or --key-file as the server")
+        }
+        _ => None,
+    }
+}
+
+/// Picks the parameters a client will be streamed `stream` with, the rendition it is sent and the renditions
+/// it can take, or explains why it can't be served. It is sent `choice` if it fits, otherwise the largest that fits
+/// `overhead` is what encryption adds to every datagram
+fn negotiate(
+    capabilities: &Capabilities,
+    stream: &Stream,
+    choice: Option<u8>,
+    quality: u8,
+    fps: u8,
+    overhead: usize,
+) -> Result<(StreamParams, Rendition, Vec<Rendition>), String> {
+    if !capabilities.supports(Codec::Jpeg) {
+        return Err(String::from("Server only streams JPEG, which the viewer does not support"));
+    }
+
+    let fitting = fitting(&stream.renditions, capabilities.max_width, capabilities.max_height)?;
+    let rendition = *fitting.iter().find(|rendition| Some(rendition.id) == choice).unwrap_or(&fitting[0]);
+
+    let mtu = (capabilities.mtu as usize).min(Packet::CHUNK_SIZE + overhead);
+    if mtu < Packet::META_SIZE + overhead + MIN_PAYLOAD {
+        return Err(format!(
+            "MTU of {} is too small, at least {} is required",
+            capabilities.mtu,
+            Packet::META_SIZE + overhead + MIN_PAYLOAD
+        ));
+    }
+
+    let params = StreamParams {
+        stream: stream.id,
+        codec: Codec::Jpeg,
+        width: rendition.width,
+        height: rendition.height,
+        fps,
+        quality: quality.min(rendition.quality),
+        max_bitrate_kbps: capabilities.max_bitrate_kbps,
+        mtu: mtu as u16,
+    };
+
+    Ok((params, rendition, fitting))
+}
+
+/// Renditions a viewer of at most `max_width` x `max_height` can display, largest first
+fn fitting(renditions: &[Rendition], max_width: u16, max_height: u16) -> Result<Vec<Rendition>, String> {
+    let fitting: Vec<Rendition> = renditions
+        .iter()
+        .filter(|rendition| rendition.width <= max_width && rendition.height <= max_height)
+        .copied()
+        .collect();
+
+    if fitting.is_empty() {
+        let smallest = renditions.last().expect("The full capture is always a rendition");
+        return Err(format!(
+            "Stream is at least {}x{} but the viewer supports at most {}x{}",
+            smallest.width, smallest.height, max_width, max_height
+        ));
+    }
+    Ok(fitting)
+}
+
+/// Bitrate a client taking `rendition` at `fps` would need, from the average frame sizes of the renditions
+/// Renditions nobody took yet are estimated from another one, scaled by the number of pixels
+fn rendition_kbps(frame_bytes: &[Option<f64>], renditions: &[Rendition], rendition: &Rendition, fps: u8) -> Option<f64> {
+    let pixels = |rendition: &Rendition| rendition.width as f64 * rendition.height as f64;
+
+    let bytes = match frame_bytes.get(rendition.id as usize).copied().flatten() {
+        Some(bytes) => bytes,
+        None => renditions.iter().find_map(|known| {
+            let bytes = frame_bytes.get(known.id as usize).copied().flatten()?;
+            Some(bytes * pixels(rendition) / pixels(known))
+        })?,
+    };
+
+    Some(bytes * 8.0 * fps as f64 / 1000.0)
+}
+
+
+/// Renditions of a capture `width`x`height`: the full one, then the smaller `sizes` asked for, largest first
+/// Sizes keep the aspect ratio of the capture, so they are fitted in the size asked for
+fn renditions(width: usize, height: usize, quality: u8, sizes: &[(u16, u16, Option<u8>)]) -> Vec<Rendition> {
+    let mut fitted = vec![(width, height, quality)];
+    for &(max_width, max_height, rendition_quality) in sizes {
+        let (scaled_width, scaled_height) = scale::fit(width, height, max_width as usize, max_height as usize);
+        if scaled_width < width {
+            fitted.push((scaled_width, scaled_height, rendition_quality.unwrap_or(quality)));
+        }
+    }
+    fitted.sort_by_key(|&(width, height, _)| std::cmp::Reverse(width * height));
+    fitted.dedup_by_key(|&mut (width, height, _)| (width, height));
+    fitted.truncate(MAX_RENDITIONS);
+
+    fitted
+        .into_iter()
+        .enumerate()
+        .map(|(id, (width, height, quality))| Rendition {
+            id: id as u8,
+            width: width as u16,
+            height: height as u16,
+            quality: quality.clamp(1, 100),
+        })
+        .collect()
+}
+
+/// One of the streams the server publishes, captured and encoded on its own
+struct Stream {
+    id: StreamId,
+    name: String,
+    source: Source,
+    renditions: Vec<Rendition>,    // Sizes each capture is encoded in, largest first, indexed by id
+    frame_bytes: Vec<Option<f64>>, // Average frame size of each rendition, once it was encoded
+    frame_id: u32,                 // Sequence number of the next frame, wraps around
+    latest: Vec<Option<Frame>>,    // Last JPEG of each rendition someone watches, for browsers that join
+}
+
+impl Stream {
+    /// `quality` and `sizes` are the renditions asked for, see `renditions`
+    fn new(id: StreamId, name: String, source: Source, quality: u8, sizes: &[(u16, u16, Option<u8>)]) -> Self {
+        let renditions = renditions(source.width(), source.height(), quality, sizes);
+        Self { id, name, source, frame_bytes: vec![None; renditions.len()], latest: vec![None; renditions.len()], renditions, frame_id: 0 }
+    }
+}
+
+/// A host that said hello and was challenged, it is not streamed to until it authenticates
+struct PendingJoin {
+    nonce: [u8; NONCE_SIZE],
+    claimed: SessionId, // Session the host says it had, NO_SESSION for a new client
+    capabilities: Capabilities,
+    stream: String,     // Name of the stream the host wants to watch
+    session: Option<Session>,
+    challenged: Instant,
+}
+
+/// Who is watching and how the stream is configured
+struct Server {
+    listeners: Listeners,
+    key: Option<PreSharedKey>,
+    credentials: Credentials,
+    clients: HashMap<SessionId, Client>,       // Authenticated clients, the stream is sent to them
+    pending: HashMap<SocketAddr, PendingJoin>, // Challenged clients that did not answer yet
+    cookies: CookieJar,                        // Proves joining hosts own their address
+    limiter: RateLimiter,                      // Join attempts per host
+    client_timeout: Duration,                  // Clients silent for longer are removed
+    malformed: u64,                            // Datagrams that failed to decode
+    throttled: u64,                            // Join attempts dropped by the rate limiter
+    session_stats: SessionStats,
+    streams: Vec<Stream>, // Published streams, indexed by id
+    quality: u8,    // Quality clients get until they ask for another
+    frame_rate: u8, // Frame rate clients get until they ask for another
+    fps: Duration,  // Frame time, of the highest frame rate any client wants
+    span: Duration, // Time the packets of a frame are paced over
+    web: Option<Web>,                          // Browser viewer, when started with --web
+    web_viewers: HashMap<ViewerId, WebViewer>, // Browsers connected to it
+    multicast: Option<Multicast>,              // Groups frames are sent to once for every client that joined them
+}
+
+impl Server {
+    /// What encryption adds to every datagram
+    fn overhead(&self) -> usize {
+        if self.key.is_some() { Session::OVERHEAD } else { 0 }
+    }
+
+    /// Session of the client at `address`
+    fn client_at(&self, address: SocketAddr) -> Option<SessionId> {
+        self.clients.values().find(|client| client.address == address).map(|client| client.id)
+    }
+
+    /// Whether a datagram comes from the client `id`: the address and encryption session must be its own
+    /// Anyone can write any id in a control message
+    fn is_from(&self, id: SessionId, address: SocketAddr, bytes: &[u8]) -> bool {
+        match self.clients.get(&id) {
+            Some(client) if client.address == address => match &client.session {
+                Some(session) => Session::salt_of(bytes).is_ok_and(|salt| salt == session.salt()),
+                None => true,
+            },
+            _ => false,
+        }
+    }
+
+    /// Picks an id no client has
+    fn new_session_id(&self) -> Result<SessionId, getrandom::Error> {
+        loop {
+            let mut bytes = [0u8; 8];
+            getrandom::getrandom(&mut bytes)?;
+
+            let id = SessionId::from_le_bytes(bytes);
+            let taken = self.clients.contains_key(&id) || self.web_viewers.values().any(|viewer| viewer.session == id);
+            if id != NO_SESSION && !taken {
+                return Ok(id);
+            }
+        }
+    }
+
+    fn default_preferences(&self) -> Preferences {
+        Preferences { quality: self.quality, fps: self.frame_rate }
+    }
+
+    /// Captures as fast as the most demanding client wants, the others skip frames
+    fn update_frame_rate(&mut self) {
+        let frame_rate = self
+            .clients
+            .values()
+            .map(|client| client.preferences.fps)
+            .max()
+            .unwrap_or(self.frame_rate);
+
+        let fps = Duration::from_millis(1000u64 / frame_rate as u64);
+        if fps != self.fps {
+            self.fps = fps;
+            self.span = fps.mul_f64(PACING_SHARE);
+            println!("Frame Time: {:?}", fps);
+        }
+    }
+
+    /// Decrypts a datagram if the stream is encrypted, and decodes the control message inside
+    /// When the datagram opened a session nobody has yet (a new client joining), the session is returned
+    fn receive(
+        &mut self,
+        bytes: &[u8],
+        address: SocketAddr,
+    ) -> Result<(SessionId, Message, Option<Session>), ProtocolError> {
+        let key = match (&self.key, Header::read(bytes)?) {
+            (None, Kind::Sealed) => return Err(ProtocolError::UnexpectedEncryption),
+            (None, _) => {
+                let (id, message) = Message::from_bytes(bytes)?;
+                return Ok((id, message, None));
+            }
+            (Some(_), Kind::Frame | Kind::Control) => return Err(ProtocolError::NotEncrypted),
+            (Some(key), Kind::Sealed) => key,
+        };
+
+        let salt = Session::salt_of(bytes)?;
+
+        let known = self
+            .clients
+            .values_mut()
+            .filter_map(|client| client.session.as_mut().filter(|_| client.address == address))
+            .chain(self.pending.get_mut(&address).and_then(|pending| pending.session.as_mut()))
+            .find(|session| session.salt() == salt);
+
+        match known {
+            Some(session) => {
+                let (id, message) = Message::from_bytes(&session.open(bytes)?)?;
+                Ok((id, message, None))
+            }
+            // A new server session even for a hello seen before, so replaying it never reuses keys
+            None => {
+                let mut session = Session::server(key, salt).expect("Error generating session salt");
+                let (id, message) = Message::from_bytes(&session.open(bytes)?)?;
+                Ok((id, message, Some(session)))
+            }
+        }
+    }
+
+    /// Sends a control message for session `id`, sealed with `session` or with the encryption session of client `id`
+    fn send_message(
+        &mut self,
+        address: SocketAddr,
+        id: SessionId,
+        message: &Message,
+        session: Option<&mut Session>,
+    ) {
+        let bytes = message.to_bytes(id);
+        let bytes = match (session, self.clients.get_mut(&id)) {
+            (Some(session), _) => session.seal(Channel::Control, &bytes),
+            // Queued behind the frames already sealed for the client
+            (None, Some(client)) => {
+                let bytes = client.seal(Channel::Control, bytes);
+                if !client.sender.control(address, bytes) {
+                    eprintln!("Send queue of {} full, dropped {:?}", address, message);
+                }
+                return;
+            }
+            (None, None) => bytes,
+        };
+
+        if let Err(e) = self.listeners.send_to(&bytes, address) {
+            eprintln!("Error sending control message to {}: {}", address, e);
+        }
+    }
+
+    /// Handles every pending control message
+    fn handle_messages(&mut self) {
+        let mut buffer = [0u8; Message::MAX_SIZE + Session::OVERHEAD + 1];
+
+        for _ in 0..MAX_MESSAGES_PER_TICK {
+            let (amount, address) = match self.listeners.recv_from(&mut buffer) {
+                Ok(received) => received,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(_e) => {
+                    // eprintln!("Error receiving from socket. Error: {:?}", _e);
+                    continue;
+                }
+            };
+
+            let bytes = &buffer[..amount];
+
+            match self.receive(bytes, address) {
+                Ok((id, message, session)) => {
+                    let from_client = self.is_from(id, address, bytes);
+                    self.handle_message(address, amount, id, from_client, message, session);
+                }
+                Err(e) => {
+                    self.malformed += 1;
+                    eprintln!("Malformed datagram from {} ({} so far): {}", address, self.malformed, e);
+
+                    // Tell hosts that are not clients why they get nothing
+                    let is_client = self.client_at(address).is_some();
+                    if let (false, Some(reason)) = (is_client, encryption_rejection(&e, bytes)) {
+                        let reply = Message::Reject { reason: String::from(reason) };
+                        self.answer_stranger(address, amount, &reply, None);
+                    }
+                }
+            }
+        }
+
+        // A viewer that closed its TCP connection is gone, whether it joined or not
+        for address in self.listeners.take_closed() {
+            if let Some(id) = self.client_at(address) {
+                self.remove(id, "connection closed");
+            }
+            self.pending.remove(&address);
+        }
+
+        // Forget challenges nobody answered
+        self.pending.retain(|address, pending| {
+            let waiting = pending.challenged.elapsed() < CHALLENGE_TIMEOUT;
+            if !waiting {
+                println!("Challenge to {} expired", address);
+            }
+            waiting
+        });
+
+        let (clients, pending) = (&self.clients, &self.pending);
+        self.listeners.prune(|address| {
+            pending.contains_key(address) || clients.values().any(|client| client.address == *address)
+        });
+    }
+
+    /// `size` is the size of the datagram the message came in, `id` the session it claims to be from
+    /// and `from_client` whether it really is from that client
+    fn handle_message(
+        &mut self,
+        address: SocketAddr,
+        size: usize,
+        id: SessionId,
+        from_client: bool,
+        message: Message,
+        session: Option<Session>,
+    ) {
+        let client = match self.clients.get_mut(&id) {
+            Some(client) if from_client => {
+                client.last_seen = Instant::now();
+                Some(client)
+            }
+            _ => None,
+        };
+
+        match message {
+            // Renegotiation of a client
+            Message::Hello { capabilities, stream, .. } if from_client => {
+                self.admit(address, id, capabilities, &stream, None);
+            }
+
+            // New connection, or a client rejoining (from this or another address)
+            Message::Hello { capabilities, cookie, stream, proof } => {
+                // Hosts already streamed to or challenged proved their address
+                let proven = self.pending.contains_key(&address) || self.client_at(address).is_some();
+                if proven {
+                    let claimed = self.verified_claim(address, id, cookie.as_ref(), proof.as_ref());
+                    self.hello(address, claimed, capabilities, stream, session);
+                    return;
+                }
+
+                if !self.allow_join(address) {
+                    return;
+                }
+
+                match cookie {
+                    Some(cookie) if self.cookies.verify(address, &cookie) => {
+                        let claimed = self.verified_claim(address, id, Some(&cookie), proof.as_ref());
+                        self.hello(address, claimed, capabilities, stream, session)
+                    }
+                    Some(_) => println!("Invalid or expired cookie from {}", address),
+                    None => {
+                        let cookie = self.cookies.issue(address);
+                        self.answer_stranger(address, size, &Message::Cookie { cookie }, session);
+                    }
+                }
+            }
+
+            Message::Authenticate(credential) => {
+                if self.allow_join(address) {
+                    self.authenticate(address, credential);
+                }
+            }
+
+            // Echoed back so the client can measure the round trip time
+            Message::Ping { timestamp } => {
+                if client.is_some() {
+                    self.send_message(address, id, &Message::Pong { timestamp }, None);
+                }
+            }
+
+            // Clients only answer pings, the server doesn't send any
+            Message::Pong { .. } => {}
+
+            Message::ReceiverReport(report) => {
+                if let Some(client) = client {
+                    client.stats.on_report(report.clone());
+                    if client.congestion.on_report(&report, client.stats.estimated_kbps) {
+                        println!("Congestion on {}: target {} kbps", address, client.congestion.target_kbps());
+                    }
+                    self.adapt(id);
+                }
+            }
+
+            Message::Subscribe { rendition } => {
+                let client = match client {
+                    Some(client) => client,
+                    None => {
+                        println!("Ignoring subscription from {}, it is not a client", address);
+                        return;
+                    }
+                };
+
+                if rendition == Rendition::AUTOMATIC {
+                    println!("{} subscribed to the rendition its bandwidth allows", address);
+                    client.subscription.automatic = true;
+                    self.announce(id);
+                    self.adapt(id);
+                    return;
+                }
+
+                let chosen = client.subscription.renditions.iter().find(|candidate| candidate.id == rendition);
+                match chosen.copied() {
+                    Some(chosen) => {
+                        println!("{} subscribed to rendition {} ({}x{})", address, chosen.id, chosen.width, chosen.height);
+                        client.subscription.automatic = false;
+                        client.switch(chosen);
+                        self.announce(id);
+                    }
+                    None => println!("Ignoring subscription from {} to rendition {}, it doesn't fit", address, rendition),
+                }
+            }
+
+            // Every JPEG frame can be decoded on its own
+            Message::KeyframeRequest => {}
+
+            // Packets its group lost on the way to a client, resent to it alone behind its control messages
+            // Packets of another rendition than the client's are not, it asked before it was switched
+            Message::Nack { rendition, seqs } => {
+                let (client, multicast) = match (client, &self.multicast) {
+                    (Some(client), Some(multicast))
+                        if client.multicast && client.subscription.current.id == rendition =>
+                    {
+                        (client, multicast)
+                    }
+                    _ => return,
+                };
+
+                for seq in seqs.into_iter().take(Message::MAX_NACKS) {
+                    let packet = match multicast.packet(client.params.stream, rendition, seq) {
+                        Some(packet) => packet.to_vec(),
+                        None => continue,
+                    };
+                    // Resent on the control path, sealed in its sequence space
+                    let bytes = client.seal(Channel::Control, packet);
+                    let size = bytes.len();
+                    if !client.sender.control(address, bytes) {
+                        break;
+                    }
+                    client.stats.on_resent(size);
+                    self.session_stats.bytes_sent += size as u64;
+                }
+            }
+
+            Message::QualityRequest { quality, fps } => {
+                let client = match client {
+                    Some(client) => client,
+                    None => {
+                        println!("Ignoring quality request from {}, it is not a client", address);
+                        return;
+                    }
+                };
+
+                client.preferences = Preferences { quality: quality.clamp(1, 100), fps: fps.clamp(1, 120) };
+                println!(
+                    "{} requested quality {} at {} fps",
+                    address, client.preferences.quality, client.preferences.fps
+                );
+                self.update_frame_rate();
+            }
+
+            // Server to client only
+            message @ (Message::Accept { .. }
+            | Message::Renditions { .. }
+            | Message::Multicast { .. }
+            | Message::Reject { .. }
+            | Message::Challenge { .. }
+            | Message::Cookie { .. }) => {
+                println!("Unexpected message from {}: {:?}", address, message);
+            }
+
+            // Disconnection
+            Message::Goodbye { reason } => {
+                if from_client {
+                    self.remove(id, &format!("said goodbye: {}", reason));
+                }
+                self.pending.remove(&address);
+            }
+        }
+    }
+
+    /// Switches a client subscribed automatically to the largest rendition its target bitrate allows
+    /// Going up needs some headroom and switches are spaced out, so it doesn't flap between two
+    fn adapt(&mut self, id: SessionId) {
+        let client = match self.clients.get_mut(&id) {
+            Some(client) if client.subscription.automatic => client,
+            _ => return,
+        };
+
+        if client.subscription.switched.elapsed() < SWITCH_HOLD {
+            return;
+        }
+
+        let budget = client.congestion.target_kbps() as f64;
+        let current = client.subscription.current;
+        let larger = |rendition: &Rendition| {
+            rendition.width as u32 * rendition.height as u32 > current.width as u32 * current.height as u32
+        };
+
+        let mut affordable = client.subscription.renditions.iter().filter(|rendition| {
+            let headroom = if larger(rendition) { SWITCH_UP_HEADROOM } else { 1.0 };
+            let stream = &self.streams[client.params.stream as usize];
+            rendition_kbps(&stream.frame_bytes, &stream.renditions, rendition, client.preferences.fps)
+                .is_some_and(|kbps| kbps <= budget * headroom)
+        });
+
+        // Nothing is affordable: the smallest one, frames are skipped to stay under the target
+        let target = match affordable.next().or(client.subscription.renditions.last()) {
+            Some(target) if target.id != current.id => *target,
+            _ => return,
+        };
+
+        println!(
+            "Switching {} to rendition {} ({}x{}), target {} kbps",
+            client.address, target.id, target.width, target.height, client.congestion.target_kbps()
+        );
+        client.switch(target);
+        self.announce(id);
+    }
+
+    /// Tells a client which rendition it is sent, and which group it comes from when it is sent through one
+    fn announce(&mut self, id: SessionId) {
+        let client = match self.clients.get(&id) {
+            Some(client) => client,
+            None => return,
+        };
+
+        let (address, renditions) = (client.address, client.subscription.message());
+        let group = match &self.multicast {
+            Some(multicast) if client.multicast => multicast.message(client.params.stream, client.subscription.current.id),
+            _ => None,
+        };
+
+        self.send_message(address, id, &renditions, None);
+        if let Some(group) = group {
+            self.send_message(address, id, &group, None);
+        }
+    }
+
+    /// Stops streaming to a client, logging why
+    fn remove(&mut self, id: SessionId, reason: &str) {
+        if let Some(client) = self.clients.remove(&id) {
+            println!("Client Removed: {} ({})", client.summary(), reason);
+            self.update_frame_rate();
+        }
+    }
+
+    /// Removes clients that stopped sending anything, they crashed or lost their connection
+    fn reap(&mut self) {
+        let silent: Vec<(SessionId, Duration)> = self
+            .clients
+            .values()
+            .map(|client| (client.id, client.last_seen.elapsed()))
+            .filter(|(_, silence)| *silence > self.client_timeout)
+            .collect();
+
+        for (id, silence) in silent {
+            self.remove(id, &format!("timed out, nothing received for {:.1?}", silence));
+        }
+    }
+
+    /// Answers a host whose address is not proven yet, sealed with the session its datagram opened if any
+    /// Never with more bytes than it sent, so spoofed datagrams can't be amplified toward a victim
+    fn answer_stranger(&self, address: SocketAddr, size: usize, message: &Message, session: Option<Session>) {
+        let bytes = message.to_bytes(NO_SESSION);
+        let bytes = match session {
+            Some(mut session) => session.seal(Channel::Control, &bytes),
+            None => bytes,
+        };
+        if bytes.len() > size {
+            return;
+        }
+
+        if let Err(e) = self.listeners.send_to(&bytes, address) {
+            eprintln!("Error answering {}: {}", address, e);
+        }
+    }
+
+    /// Whether the host can make another join attempt right now
+    fn allow_join(&mut self, address: SocketAddr) -> bool {
+        if self.limiter.allow(address.ip()) {
+            return true;
+        }
+
+        self.throttled += 1;
+        eprintln!("Too many join attempts from {} ({} dropped so far)", address.ip(), self.throttled);
+        false
+    }
+
+    /// Session a hello from `address` takes over: the one it claims if its client is at this address already,
+    /// was proven from here before, or the hello proves it owns it with a cookie of this address.
+    /// NO_SESSION otherwise, anyone can write any id in a hello, the host starts a new session
+    fn verified_claim(
+        &self,
+        address: SocketAddr,
+        claimed: SessionId,
+        cookie: Option<&[u8; COOKIE_SIZE]>,
+        proof: Option<&[u8; PROOF_SIZE]>,
+    ) -> SessionId {
+        let client = match self.clients.get(&claimed) {
+            Some(client) => client,
+            None => return NO_SESSION,
+        };
+
+        if client.address == address || self.pending.get(&address).is_some_and(|pending| pending.claimed == claimed) {
+            return claimed;
+        }
+
+        match (cookie, proof) {
+            (Some(cookie), Some(proof))
+                if self.cookies.verify(address, cookie) && auth::verify_proof(&client.secret, cookie, proof) =>
+            {
+                claimed
+            }
+            _ => {
+                println!("{} claimed session {:016x} without proving it owns it, starting a new one", address, claimed);
+                NO_SESSION
+            }
+        }
+    }
+
+    /// Challenges a joining client when the stream needs authentication, otherwise lets it in
+    /// `claimed` is the session the client proved it had, a client rejoining has to authenticate again
+    /// `stream` is the name of the stream the client wants to watch
+    fn hello(
+        &mut self,
+        address: SocketAddr,
+        claimed: SessionId,
+        capabilities: Capabilities,
+        stream: String,
+        session: Option<Session>,
+    ) {
+        // A hello while challenged starts over, in the same session
+        let retried = self.pending.remove(&address);
+        let mut session = session.or(retried.and_then(|pending| pending.session));
+
+        if !self.credentials.required() {
+            self.admit(address, claimed, capabilities, &stream, session);
+            return;
+        }
+
+        if self.pending.len() >= MAX_PENDING {
+            println!("Too many clients authenticating, ignoring hello from {}", address);
+            return;
+        }
+
+        let nonce = match auth::nonce() {
+            Ok(nonce) => nonce,
+            Err(e) => {
+                eprintln!("Error generating challenge for {}: {}", address, e);
+                return;
+            }
+        };
+
+        self.send_message(address, NO_SESSION, &Message::Challenge { nonce }, session.as_mut());
+        self.pending.insert(
+            address,
+            PendingJoin { nonce, claimed, capabilities, stream, session, challenged: Instant::now() },
+        );
+    }
+
+    /// Checks the answer to a challenge, the client gets in if it is right
+    fn authenticate(&mut self, address: SocketAddr, credential: Credential) {
+        let mut pending = match self.pending.remove(&address) {
+            Some(pending) => pending,
+            None => {
+                println!("Unexpected authentication from {}, it was not challenged", address);
+                return;
+            }
+        };
+
+        match self.credentials.verify(&pending.nonce, &credential, pending.claimed) {
+            Ok(invite) => {
+                println!("Client Authenticated: {}", address);
+                let id = self.admit(address, pending.claimed, pending.capabilities, &pending.stream, pending.session);
+                if let (Some(invite), Some(id)) = (invite, id) {
+                    self.credentials.bind(invite, id);
+                }
+            }
+            Err(reason) => {
+                println!("Authentication failed: {} ({})", address, reason);
+                let reject = Message::Reject { reason: String::from(reason) };
+                self.send_message(address, NO_SESSION, &reject, pending.session.as_mut());
+            }
+        }
+    }
+
+    /// Negotiates the stream parameters and streams to the client from now on, or tells it why it can't be served
+    /// A client rejoining as `claimed` keeps its session, stats and preferences, even from another address
+    /// `claimed` must have been checked with `verified_claim`
+    /// Returns the session of the client
+    fn admit(
+        &mut self,
+        address: SocketAddr,
+        claimed: SessionId,
+        capabilities: Capabilities,
+        stream: &str,
+        mut session: Option<Session>,
+    ) -> Option<SessionId> {
+        let id = match self.clients.contains_key(&claimed) {
+            true => claimed,
+            false => match self.new_session_id() {
+                Ok(id) => id,
+                Err(e) => {
+                    eprintln!("Error generating session id for {}: {}", address, e);
+                    return None;
+                }
+            },
+        };
+
+        let preferences = self.clients.get(&id).map_or(self.default_preferences(), |client| client.preferences);
+
+        let stream = match self.find_stream(stream) {
+            Ok(stream) => stream,
+            Err(reason) => {
+                println!("Client Rejected: {} ({})", address, reason);
+                self.send_message(address, id, &Message::Reject { reason }, session.as_mut());
+                return None;
+            }
+        };
+
+        // A rendition the client picked stays, if it still fits and it watches the same stream
+        let choice = self
+            .clients
+            .get(&id)
+            .filter(|client| !client.subscription.automatic && client.params.stream == stream)
+            .map(|client| client.subscription.current.id);
+
+        let negotiated = negotiate(
+            &capabilities,
+            &self.streams[stream as usize],
+            choice,
+            preferences.quality,
+            preferences.fps,
+            self.overhead(),
+        );
+
+        let (params, rendition, renditions) = match negotiated {
+            Ok(negotiated) => negotiated,
+            Err(reason) => {
+                println!("Client Rejected: {} ({})", address, reason);
+                self.send_message(address, id, &Message::Reject { reason }, session.as_mut());
+                return None;
+            }
+        };
+
+        // Clients that can join a group are sent through it, if they can take its datagrams
+        let multicast = self.multicast.is_some() && capabilities.multicast && capabilities.mtu as usize >= multicast::MTU;
+
+        // Sessions left at this address by a client that restarted without saying goodbye
+        let stale: Vec<SessionId> = self
+            .clients
+            .values()
+            .filter(|client| client.address == address && client.id != id)
+            .map(|client| client.id)
+            .collect();
+        for stale in stale {
+            self.remove(stale, "replaced by a new session from the same address");
+        }
+
+        match self.clients.get_mut(&id) {
+            Some(client) => {
+                if client.address != address {
+                    println!("Client Moved: {:016x} from {} to {}", id, client.address, address);
+                    client.address = address;
+                    if let Some(sender) = self.listeners.connection(address) {
+                        client.sender = sender;
+                    }
+                }
+                if client.params.stream != stream {
+                    println!("Client Switched: {:016x} to stream {}", id, self.streams[stream as usize].name);
+                }
+                client.congestion = Congestion::new(params.max_bitrate_kbps);
+                client.params = params.clone();
+                client.subscription.renditions = renditions;
+                client.subscription.automatic = choice.is_none_or(|choice| choice != rendition.id);
+                client.subscription.current = rendition;
+                client.multicast = multicast;
+                // Rejoined with a new encryption session
+                if session.is_some() {
+                    client.session = session;
+                }
+            }
+            None => {
+                let sender = match self.listeners.sender(address) {
+                    Ok(sender) => sender,
+                    Err(e) => {
+                        eprintln!("Error starting sender for {}: {}", address, e);
+                        return None;
+                    }
+                };
+
+                let subscription =
+                    Subscription { renditions, current: rendition, automatic: true, switched: Instant::now() };
+
+                println!(
+                    "Client Connected: {} as {:016x} ({:?}){}",
+                    address, id, params, if multicast { " through multicast" } else { "" }
+                );
+                let mut client =
+                    match Client::new(id, address, params.clone(), session, preferences, sender, subscription) {
+                        Ok(client) => client,
+                        Err(e) => {
+                            eprintln!("Error generating session secret for {}: {}", address, e);
+                            return None;
+                        }
+                    };
+                client.multicast = multicast;
+                self.clients.insert(id, client);
+                self.session_stats.on_join(self.clients.len());
+            }
+        }
+
+        let secret = self.clients[&id].secret;
+        self.send_message(address, id, &Message::Accept { params, secret }, None);
+        self.announce(id);
+        self.update_frame_rate();
+        Some(id)
+    }
+
+    /// Tells every client the stream is over, and waits for the goodbyes to be sent
+    fn shutdown(&mut self, reason: &str) {
+        let goodbye = Message::Goodbye { reason: String::from(reason) };
+        let clients: Vec<(SessionId, SocketAddr)> =
+            self.clients.values().map(|client| (client.id, client.address)).collect();
+
+        for &(id, address) in &clients {
+            self.send_message(address, id, &goodbye, None);
+        }
+
+        // Every sender stops at once, then they are waited for
+        let removed: Vec<Client> = clients.iter().filter_map(|(id, _)| self.clients.remove(id)).collect();
+        for client in &removed {
+            println!("Client Removed: {} ({})", client.summary(), reason);
+            client.sender.close();
+        }
+        for client in removed {
+            client.sender.finish();
+        }
+        self.listeners.close();
+
+        for viewer in self.web_viewers.values().filter(|viewer| viewer.announced()) {
+            println!("Client Removed: {} ({})", viewer, reason);
+        }
+        if let Some(web) = &mut self.web {
+            web.close(reason);
+        }
+        self.web_viewers.clear();
+    }
+
+    /// Whether anyone is watching, natively or in a browser
+    fn has_viewers(&self) -> bool {
+        !self.clients.is_empty() || self.web_viewers.values().any(|viewer| viewer.admitted)
+    }
+
+    /// Handles what browsers asked since the last frame
+    fn handle_web(&mut self) {
+        for _ in 0..MAX_MESSAGES_PER_TICK {
+            let (id, event) = match self.web.as_mut().and_then(Web::next) {
+                Some(next) => next,
+                None => return,
+            };
+
+            match event {
+                web::Event::Opened { address, mailbox } => match self.new_session_id() {
+                    Ok(session) => {
+                        let viewer = WebViewer {
+                            session,
+                            mode: Mode::Page,
+                            address,
+                            mailbox,
+                            stream: 0,
+                            subscription: None,
+                            nonce: None,
+                            admitted: false,
+                            joined: Instant::now(),
+                            frames_sent: 0,
+                            frames_replaced: 0,
+                            bytes_sent: 0,
+                        };
+                        self.web_viewers.insert(id, viewer);
+                    }
+                    Err(e) => {
+                        eprintln!("Error generating session id for {}: {}", address, e);
+                        mailbox.close("Server error");
+                    }
+                },
+                web::Event::Hello { mode, stream, max_width, max_height, rendition } => {
+                    self.web_hello(id, mode, &stream, (max_width, max_height), rendition)
+                }
+                web::Event::Answer(credential) => self.web_authenticate(id, credential),
+                web::Event::Subscribe { rendition } => self.web_subscribe(id, rendition),
+                web::Event::Closed { reason } => {
+                    if let Some(viewer) = self.web_viewers.remove(&id).filter(WebViewer::announced) {
+                        println!("Client Removed: {} ({})", viewer, reason);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Picks the renditions that fit the viewer, then challenges it or lets it in like `hello`
+    /// `choice` is the rendition it asked for, otherwise it gets the largest one that fits
+    fn web_hello(&mut self, id: ViewerId, mode: Mode, stream: &str, max_size: (u16, u16), choice: Option<u8>) {
+        let address = match self.web_viewers.get(&id) {
+            Some(viewer) => viewer.address,
+            None => return,
+        };
+        let allowed = self.allow_join(address);
+        let found = self.find_stream(stream).and_then(|stream| {
+            let renditions = fitting(&self.streams[stream as usize].renditions, max_size.0, max_size.1)?;
+            Ok((stream, renditions))
+        });
+        let viewer = self.web_viewers.get_mut(&id).expect("Looked up above");
+
+        if !allowed {
+            viewer.mailbox.reply(Reply::Reject(String::from("Too many join attempts, try again later")));
+            return;
+        }
+
+        let (stream, renditions) = match found {
+            Ok(found) => found,
+            Err(reason) => {
+                println!("{} viewer rejected: {} ({})", mode, address, reason);
+                viewer.mailbox.reply(Reply::Reject(reason));
+                return;
+            }
+        };
+
+        viewer.mode = mode;
+        viewer.stream = stream;
+        viewer.admitted = false;
+        let current = *renditions.iter().find(|rendition| Some(rendition.id) == choice).unwrap_or(&renditions[0]);
+        viewer.subscription = Some(Subscription {
+            current,
+            automatic: choice.is_none_or(|choice| choice != current.id),
+            renditions,
+            switched: Instant::now(),
+        });
+
+        if !self.credentials.required() {
+            self.web_admit(id);
+            return;
+        }
+
+        match auth::nonce() {
+            Ok(nonce) => {
+                viewer.nonce = Some(nonce);
+                viewer.mailbox.reply(Reply::Challenge(nonce));
+            }
+            Err(e) => eprintln!("Error generating challenge for {}: {}", address, e),
+        }
+    }
+
+    /// Checks the answer of a browser to its challenge, like `authenticate`
+    fn web_authenticate(&mut self, id: ViewerId, credential: Credential) {
+        let viewer = match self.web_viewers.get_mut(&id) {
+            Some(viewer) => viewer,
+            None => return,
+        };
+
+        let nonce = match viewer.nonce.take() {
+            Some(nonce) => nonce,
+            None => {
+                println!("Unexpected authentication from {}, it was not challenged", viewer.address);
+                return;
+            }
+        };
+
+        match self.credentials.verify(&nonce, &credential, viewer.session) {
+            Ok(invite) => {
+                println!("Client Authenticated: {}", viewer.address);
+                if let Some(invite) = invite {
+                    self.credentials.bind(invite, viewer.session);
+                }
+                self.web_admit(id);
+            }
+            Err(reason) => {
+                println!("Authentication failed: {} ({})", viewer.address, reason);
+                viewer.mailbox.reply(Reply::Reject(String::from(reason)));
+            }
+        }
+    }
+
+    /// Streams to the browser, from the latest frame of its rendition when someone watches it already
+    fn web_admit(&mut self, id: ViewerId) {
+        let viewer = match self.web_viewers.get_mut(&id) {
+            Some(viewer) => viewer,
+            None => return,
+        };
+
+        viewer.admitted = true;
+        viewer.joined = Instant::now();
+        viewer.mailbox.reply(Reply::Accept { stream: self.streams[viewer.stream as usize].name.clone() });
+        if let Some(renditions) = viewer.renditions() {
+            viewer.mailbox.reply(renditions);
+        }
+        // A snapshot is sent at once rather than after the next capture
+        let rendition = viewer.watching(viewer.stream).map(|rendition| rendition.id as usize);
+        if let Some(frame) = rendition.and_then(|rendition| self.streams[viewer.stream as usize].latest[rendition].clone()) {
+            viewer.frames_sent += 1;
+            viewer.bytes_sent += frame.len() as u64;
+            self.session_stats.bytes_sent += frame.len() as u64;
+            viewer.mailbox.frame(frame);
+        }
+        if viewer.announced() {
+            println!("New Client: {}", viewer);
+        }
+    }
+
+    /// Switches the browser to the rendition it picked, or back to the largest one that fits it
+    fn web_subscribe(&mut self, id: ViewerId, rendition: Option<u8>) {
+        let viewer = match self.web_viewers.get_mut(&id).filter(|viewer| viewer.admitted) {
+            Some(viewer) => viewer,
+            None => return,
+        };
+        let subscription = match &mut viewer.subscription {
+            Some(subscription) => subscription,
+            None => return,
+        };
+
+        let picked = match rendition {
+            Some(rendition) => subscription.renditions.iter().find(|candidate| candidate.id == rendition),
+            None => subscription.renditions.first(),
+        };
+        match picked {
+            Some(picked) => {
+                subscription.current = *picked;
+                subscription.automatic = rendition.is_none();
+                subscription.switched = Instant::now();
+            }
+            None => println!("{} asked for rendition {:?}, which does not fit it", viewer.address, rendition),
+        }
+
+        if let Some(renditions) = viewer.renditions() {
+            viewer.mailbox.reply(renditions);
+        }
+    }
+
+    /// Hands the frame of its rendition to every browser watching `stream`, sent from the browser's own thread
+    fn broadcast_web(&mut self, stream: StreamId, frames: &[Option<Vec<u8>>]) {
+        let mut shared: Vec<Option<Frame>> = vec![None; frames.len()];
+
+        for viewer in self.web_viewers.values_mut() {
+            let rendition = match viewer.watching(stream) {
+                Some(rendition) => rendition.id as usize,
+                None => continue,
+            };
+            let bytes = match frames.get(rendition) {
+                Some(Some(bytes)) => bytes,
+                _ => continue,
+            };
+
+            // One copy per rendition, shared by every browser taking it
+            let frame = shared[rendition].get_or_insert_with(|| Frame::from(bytes.clone())).clone();
+            if viewer.mailbox.frame(frame) {
+                viewer.frames_replaced += 1;
+            }
+            viewer.frames_sent += 1;
+            viewer.bytes_sent += bytes.len() as u64;
+            self.session_stats.bytes_sent += bytes.len() as u64;
+        }
+
+        if self.web.is_some() {
+            let latest = &mut self.streams[stream as usize].latest;
+            for ((latest, shared), bytes) in latest.iter_mut().zip(shared).zip(frames) {
+                if let Some(bytes) = bytes {
+                    *latest = Some(shared.unwrap_or_else(|| Frame::from(bytes.clone())));
+                }
+            }
+        }
+    }
+
+    /// Drops the latest frame of the renditions nobody watches, it would be stale by the time someone does
+    fn forget_unwatched(&mut self) {
+        for stream in 0..self.streams.len() as StreamId {
+            let wanted = self.wanted_renditions(stream);
+            for (id, latest) in self.streams[stream as usize].latest.iter_mut().enumerate() {
+                if !wanted.iter().any(|(rendition, _)| rendition.id as usize == id) {
+                    *latest = None;
+                }
+            }
+        }
+    }
+
+    /// Removes clients whose sender failed, returns whether there were any
+    fn remove_failed(&mut self) -> bool {
+        let failed: Vec<(SessionId, io::Error)> = self
+            .clients
+            .values()
+            .filter_map(|client| client.sender.failure().map(|e| (client.id, e)))
+            .collect();
+
+        let any = !failed.is_empty();
+        for (id, e) in failed {
+            self.remove(id, &format!("error sending packet: {}", e));
+        }
+        any
+    }
+
+    /// Id of the stream named `name`, the first one for an empty name
+    fn find_stream(&self, name: &str) -> Result<StreamId, String> {
+        if name.is_empty() {
+            return Ok(0);
+        }
+
+        match self.streams.iter().find(|stream| stream.name == name) {
+            Some(stream) => Ok(stream.id),
+            None => {
+                let names: Vec<&str> = self.streams.iter().map(|stream| stream.name.as_str()).collect();
+                Err(format!("No stream named {:?}, this server publishes: {}", name, names.join(", ")))
+            }
+        }
+    }
+
+    /// Renditions of `stream` someone is subscribed to, with the quality to encode them at:
+    /// the highest any of its viewers asked for. Browsers take it at the rendition's quality.
+    /// Congestion doesn't lower it, that would lower it for every viewer of the rendition
+    fn wanted_renditions(&self, stream: StreamId) -> Vec<(Rendition, u8)> {
+        self.streams[stream as usize]
+            .renditions
+            .iter()
+            .filter_map(|rendition| {
+                let clients = self
+                    .clients
+                    .values()
+                    .filter(|client| client.params.stream == stream && client.subscription.current.id == rendition.id)
+                    .map(Client::quality);
+                let browsers = self
+                    .web_viewers
+                    .values()
+                    .filter(|viewer| viewer.watching(stream).is_some_and(|watched| watched.id == rendition.id))
+                    .map(|_| rendition.quality);
+
+                let quality = clients.chain(browsers).max()?;
+                Some((*rendition, quality))
+            })
+            .collect()
+    }
+
+    /// Records the size of a frame encoded in a rendition, to know which renditions clients can afford
+    fn on_encoded(&mut self, stream: StreamId, rendition: u8, size: usize) {
+        let average = &mut self.streams[stream as usize].frame_bytes[rendition as usize];
+        *average = Some(match *average {
+            Some(average) => average + (size as f64 - average) * FRAME_SIZE_SMOOTHING,
+            None => size as f64,
+        });
+    }
+
+    /// Captures the next frame of `stream` and encodes it in every rendition someone watches
+    /// Returned frames are indexed by rendition id, None for renditions that were not encoded
+    /// None when nobody watches or the source has no new frame yet
+    fn capture(&mut self, stream: StreamId) -> Option<Vec<Option<Vec<u8>>>> {
+        let wanted = self.wanted_renditions(stream);
+        if wanted.is_empty() {
+            return None;
+        }
+
+        let source = &mut self.streams[stream as usize].source;
+        let (width, height) = (source.width(), source.height());
+
+        // ! Frame Format
+        // The frame format is BGRA, displays may pad every row past the width.
+        // The width and height are guaranteed to remain constant.
+        // Frame is just an array of bytes
+        let encoded = source.with_frame(|frame| {
+            let pitch = frame.len() / height;
+
+            // * Encode & Compress frame as AVIF
+            // let res = encoder.encode_rgba(
+            //         ravif::Img::new(frame, width, height),
+            //     )
+            //     .expect("Error encoding frame");
+
+            wanted
+                .iter()
+                .map(|&(rendition, quality)| {
+                    let (rendition_width, rendition_height) = (rendition.width as usize, rendition.height as usize);
+
+                    let scaled;
+                    let (pixels, pitch): (&[u8], usize) = if rendition_width == width && rendition_height == height {
+                        (frame, pitch)
+                    } else {
+                        scaled = scale::downscale(frame, width, height, pitch, rendition_width, rendition_height);
+                        (&scaled, rendition_width * PixelFormat::BGRX.size())
+                    };
+
+                    let image = Image {
+                        pixels,
+                        width: rendition_width,
+                        height: rendition_height,
+                        format: PixelFormat::BGRX,
+                        pitch,
+                    };
+
+                    let bytes = compress(image, quality as i32, turbojpeg::Subsamp::Sub2x2)
+                        .expect("Error compressing image");
+                    (rendition.id, bytes.to_vec())
+                })
+                .collect::<Vec<_>>()
+        });
+
+        let encoded = match encoded {
+            Ok(encoded) => encoded,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return None,
+            Err(e) => {
+                eprintln!("Error capturing stream {}: {}", self.streams[stream as usize].name, e);
+                return None;
+            }
+        };
+
+        let mut frames = vec![None; self.streams[stream as usize].renditions.len()];
+        for (rendition, bytes) in encoded {
+            self.on_encoded(stream, rendition, bytes.len());
+            frames[rendition as usize] = Some(bytes);
+        }
+        Some(frames)
+    }
+
+    /// Packetizes the compressed frame of its rendition for every client of `stream` and queues it on the
+    /// client's sender, or once on the group of the rendition for clients that joined it
+    /// `frames` is indexed by rendition id, None for renditions that were not encoded
+    fn broadcast(&mut self, stream: StreamId, frames: &[Option<Vec<u8>>]) {
+        let overhead = self.overhead();
+        let mut sent = false;
+        let mut grouped: Vec<u8> = Vec::new(); // Renditions a client of a group is due a frame of
+
+        let frame_id = self.streams[stream as usize].frame_id;
+        self.streams[stream as usize].frame_id = frame_id.wrapping_add(1);
+
+        // * Packetize frame for every connected client
+        for client in self.clients.values_mut().filter(|client| client.params.stream == stream) {
+
+            let bytes = match frames.get(client.subscription.current.id as usize) {
+                Some(Some(bytes)) => bytes,
+                _ => continue,
+            };
+
+            if !client.frame_due() {
+                continue;
+            }
+
+            // Sent to the group below, whoever else in it is due
+            if client.multicast {
+                if !grouped.contains(&client.subscription.current.id) {
+                    grouped.push(client.subscription.current.id);
+                }
+                continue;
+            }
+
+            // * Frames are send on packets of the client's MTU
+            let chunks: Vec<&[u8]> = bytes.chunks(client.payload_size()).collect();
+
+            let count = match u8::try_from(chunks.len()) {
+                Ok(count) => count,
+                Err(_) => {
+                    eprintln!(
+                        "Frame too large to send to {}: {} bytes in {} packets",
+                        client.address, bytes.len(), chunks.len()
+                    );
+                    continue;
+                }
+            };
+
+            // Skip the frame for this client rather than go over its bitrate, lowering its frame rate
+            let size = bytes.len() + chunks.len() * (Packet::META_SIZE + overhead);
+            if !client.congestion.allows(size) {
+                client.stats.on_frame_skipped();
+                continue;
+            }
+            client.congestion.on_sent(size);
+            client.stats.on_frame_sent(size, chunks.len());
+            self.session_stats.bytes_sent += size as u64;
+
+            let mut datagrams = Vec::with_capacity(chunks.len());
+            for (i, chunk) in chunks.iter().enumerate() {
+                let packet = Packet::new(stream, frame_id, client.next_seq, i as u8, count, chunk);
+                client.next_seq = client.next_seq.wrapping_add(1);
+                datagrams.push((client.address, client.seal(Channel::Frames, packet.to_bytes())));
+            }
+
+            // * Sent from the client's own thread, spread over the frame time
+            let replaced = client.sender.frame(datagrams, self.span);
+            client.stats.on_frames_replaced(replaced);
+            sent = true;
+        }
+
+        // * Once per group, every client in it receives the frame
+        if let Some(multicast) = &mut self.multicast {
+            for rendition in grouped {
+                let bytes = match frames.get(rendition as usize) {
+                    Some(Some(bytes)) => bytes,
+                    _ => continue,
+                };
+
+                let group = match multicast.send(stream, rendition, frame_id, bytes, self.span) {
+                    Ok(group) => group,
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        continue;
+                    }
+                };
+
+                let members = self.clients.values_mut().filter(|client| {
+                    client.multicast && client.params.stream == stream && client.subscription.current.id == rendition
+                });
+                for client in members {
+                    client.stats.on_frame_sent(group.bytes, group.packets);
+                    client.stats.on_frames_replaced(group.replaced);
+                }
+                self.session_stats.bytes_sent += group.bytes as u64;
+                sent = true;
+            }
+        }
+
+        if sent {
+            self.session_stats.frames += 1;
+        }
+    }
+
+    /// Stops sending through groups once sending to one failed, multicast doesn't work on this network
+    /// Their clients are removed, and are sent to on their own when they join again
+    fn check_multicast(&mut self) {
+        let (group, e) = match self.multicast.as_ref().and_then(Multicast::failure) {
+            Some(failure) => failure,
+            None => return,
+        };
+
+        eprintln!("Error sending to multicast group {}: {}, sending to every viewer on its own from now on", group, e);
+        self.multicast = None;
+
+        let members: Vec<SessionId> =
+            self.clients.values().filter(|client| client.multicast).map(|client| client.id).collect();
+        for id in members {
+            self.remove(id, "multicast failed");
+        }
+    }
+}
 
 pub fn run(options: commands::StartCmd) {
-    let mut cap = Capturer::new(
-        Display::primary()
-        .expect("Failed to find primary display")
-    ).expect("Failed to create capturer");
+    let key = match PreSharedKey::load(&options.encryption) {
+        Ok(key) => key,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+
+    if options.password.as_ref().is_some_and(String::is_empty) {
+        eprintln!("Password must not be empty");
+        std::process::exit(2);
+    }
+
+    // * Single use invites, printed for the host to hand out
+    let invite_ttl = Duration::from_secs(options.invite_ttl);
+    let invites: Vec<Invite> = (0..options.invite.unwrap_or(0))
+        .map(|_| Invite::generate(invite_ttl).expect("Error generating invite token"))
+        .collect();
+
+    for invite in &invites {
+        println!("Invite token (single use, expires in {:?}): {}", invite_ttl, invite.token());
+    }
+
+    let credentials = Credentials::new(options.password, invites);
+
+    // * Streams, the primary display when none is given
+    let sources: Vec<(String, Result<Source, String>)> = match options.streams.is_empty() {
+        true => vec![(String::from(DEFAULT_STREAM), Source::primary())],
+        false => options.streams.iter().map(|(name, spec)| (name.clone(), Source::open(spec))).collect(),
+    };
+
+    if sources.len() > MAX_STREAMS {
+        eprintln!("At most {} streams can be published, {} were given", MAX_STREAMS, sources.len());
+        std::process::exit(2);
+    }
+
+    let mut streams: Vec<Stream> = Vec::with_capacity(sources.len());
+    for (name, source) in sources {
+        if streams.iter().any(|stream| stream.name == name) {
+            eprintln!("Stream {:?} is given twice", name);
+            std::process::exit(2);
+        }
+
+        let source = match source {
+            Ok(source) => source,
+            Err(e) => {
+                eprintln!("Stream {:?}: {}", name, e);
+                std::process::exit(2);
+            }
+        };
+
+        streams.push(Stream::new(streams.len() as StreamId, name, source, options.quality, &options.renditions));
+    }
+
+    if options.quic.is_some() && options.transport == commands::Transport::Quic {
+        eprintln!("--quic is for serving QUIC next to --transport udp or tcp, the server already uses QUIC");
+        std::process::exit(2);
+    }
+    if options.certificate.is_some() && options.quic.is_none() && options.transport != commands::Transport::Quic {
+        eprintln!("--certificate is only used with --transport quic or --quic");
+        std::process::exit(2);
+    }
 
+    let listeners = Listeners::bind(options.transport, options.bind, options.port, options.quic, options.certificate.as_deref())
+        .expect("Error binding to port");
 
-    let listener = UdpSocket::bind(format!("0.0.0.0:{}", options.port))
-        .expect("While creating UdpSocket: Error binding to port");
+    println!(
+        "Server listening on: {} over {:?} ({}, {})",
+        listeners.local_addr().expect("Error reading local address"),
+        options.transport,
+        if key.is_some() { "encrypted" } else { "not encrypted" },
+        if credentials.required() { "authentication required" } else { "open to anyone" }
+    );
+    if let Some(address) = listeners.quic_addr() {
+        println!("Also listening on: {} over Quic", address.expect("Error reading local address"));
+    }
+    if let Some(fingerprint) = listeners.fingerprint() {
+        println!("QUIC certificate fingerprint: {}", auth::to_hex(&fingerprint));
+    }
 
-    let mut clients = Vec::new(); // Connected clients
+    let web = (options.web.is_some() || options.rtsp.is_some()).then(Web::new);
+    if let (Some(web), Some((ip, port))) = (&web, options.web) {
+        let webrtc = options.webrtc.then(|| {
+            Arc::new(peer::Settings::new(options.stun_servers.clone()).expect("Error making the WebRTC certificate"))
+        });
+        let address = web.serve_http(ip, port, webrtc).expect("Error binding the web viewer port");
+        println!(
+            "Browser viewer on: http://{}/{}",
+            address,
+            if options.webrtc { " (AV1 over WebRTC where the browser can decode it)" } else { "" }
+        );
+    }
+    if let (Some(web), Some((ip, port))) = (&web, options.rtsp) {
+        let address = web.serve_rtsp(ip, port).expect("Error binding the RTSP port");
+        println!("RTSP on: rtsp://{}/<stream>", address);
+    }
 
-    listener
-        .set_nonblocking(true)
-        .expect("Error setting UdpSocket to non-blocking mode");
+    // * Multicast groups, one per rendition of every stream, for viewers on the local network
+    let multicast = options.multicast.map(|group| {
+        if options.transport != commands::Transport::Udp {
+            eprintln!("--multicast only works with --transport udp");
+            std::process::exit(2);
+        }
+        // Anyone on the network can join a group, so with viewers to authenticate only keys keep them out
+        if credentials.required() && key.is_none() {
+            eprintln!("--multicast with --password or --invite needs --key, groups are open to anyone on the network");
+            std::process::exit(2);
+        }
 
-    println!("Server listening on port: {}", options.port);
+        let renditions = streams
+            .iter()
+            .flat_map(|stream| stream.renditions.iter().map(|rendition| (stream.id, rendition.id)));
+        let interface = options.multicast_interface.unwrap_or(Ipv4Addr::UNSPECIFIED);
+        match Multicast::open(group, options.multicast_ttl, interface, renditions, key.as_ref()) {
+            Ok(multicast) => multicast,
+            Err(e) => {
+                eprintln!("Error opening multicast group {}: {}", group, e);
+                std::process::exit(2);
+            }
+        }
+    });
+    if multicast.is_some() {
+        println!("Multicast to viewers connecting with --multicast, TTL {}", options.multicast_ttl);
+    }
 
-    
-    let width = cap.width();
-    let height = cap.height();
+    for stream in &streams {
+        println!("Stream {} {:?}: {}x{}", stream.id, stream.name, stream.source.width(), stream.source.height());
+        for rendition in &stream.renditions {
+            let group = multicast
+                .as_ref()
+                .and_then(|multicast| multicast.group(stream.id, rendition.id))
+                .map_or(String::new(), |group| format!(", multicast to {}", group));
+            println!(
+                "  Rendition {}: {}x{} at quality {}{}",
+                rendition.id, rendition.width, rendition.height, rendition.quality, group
+            );
+        }
+    }
 
     let fps = Duration::from_millis(1000u64 / (options.fps as u64)); // Frame time
     let record_start = std::time::Instant::now(); // Time since recording started
@@ -48,140 +1801,535 @@ pub fn run(options: commands::StartCmd) {
 
     println!("Frame Time: {:?}", fps);
 
-    // ! Main loop
-    loop {
-
-        println!("Streaming since: {:?}", record_start.elapsed());
-
-        // * Handle incoming connections and disconnections
-        let mut buffer = [0u8; 1];
+    let mut server = Server {
+        listeners,
+        key,
+        credentials,
+        clients: HashMap::new(),
+        pending: HashMap::new(),
+        cookies: CookieJar::new().expect("Error generating cookie secret"),
+        limiter: RateLimiter::new(JOIN_RATE, JOIN_BURST),
+        client_timeout: Duration::from_secs(options.client_timeout),
+        malformed: 0,
+        throttled: 0,
+        session_stats: SessionStats::default(),
+        streams,
+        quality: options.quality,
+        frame_rate: options.fps,
+        fps,
+        span: fps.mul_f64(PACING_SHARE),
+        web,
+        web_viewers: HashMap::new(),
+        multicast,
+    };
 
-        match listener.recv_from(&mut buffer) {
-            Ok((_amount, address)) => {
-                match Actions::from(buffer[0]) {
-                    // Ping 
-                    Actions::Ping => {
-                        // listener.send_to(&[Actions::Ping as u8], address).expect("Failed to send pong");
-                    }
+    let mut last_summary = Instant::now();
 
-                    // New connection
-                    Actions::NewConnection => {
-                        println!("Client Connected");
-                        clients.push(address);
-                    }
+    // * Stop cleanly on Ctrl-C or SIGTERM, at the end of the current frame. A second signal doesn't wait
+    let running = Arc::new(AtomicBool::new(true));
+    let handler = Arc::clone(&running);
+    let handled = ctrlc::set_handler(move || {
+        if !handler.swap(false, Ordering::SeqCst) {
+            std::process::exit(EXIT_INTERRUPTED);
+        }
+    });
+    if let Err(e) = handled {
+        eprintln!("Error handling signals, viewers won't be told when the server stops: {}", e);
+    }
 
-                    // Disconnection
-                    Actions::Disconnection => {
-                        println!("Client Disconnected");
-                        clients.retain(|&x| x != address);
-                    }
+    // ! Main loop
+    let status = loop {
 
-                    Actions::Unknown => {
-                        println!("Received Unknown Message: {} from {}", buffer[0], address);
-                    }
-                }
-            }
-            Err(_e) => {
-                // eprintln!("Error receiving from socket. Error: {:?}", _e);
-            }
+        if !running.load(Ordering::SeqCst) {
+            println!("Shutting down");
+            server.shutdown("Server shut down");
+            break EXIT_OK;
         }
 
-        if clients.len() == 0 {
+        // * Handle every pending control message
+        server.handle_messages();
+        server.handle_web();
+        server.reap();
+        server.forget_unwatched();
+
+        if !server.has_viewers() {
             println!("No clients connected");
             // wait whole frame time
-            std::thread::sleep(fps);
+            std::thread::sleep(server.fps);
             continue;
         }
         
         // * Sending frames to clients
 
         let start = std::time::Instant::now();
-        
-        // ! Frame Format
-        // The frame format is guaranteed to be packed BGRA.
-        // The width and height are guaranteed to remain constant.
-        // The stride might be greater than the width, and it may also vary between frames.
-        // Frame is just an array of bytes
-        let frame = match cap.frame() {
-            Ok(frame) => frame,
-            Err(err) => {
-                if err.kind() == std::io::ErrorKind::WouldBlock {
-                    // wait whole frame time
-                     std::thread::sleep(fps);
-                }
-                continue;
+
+        // * Capture, encode and send every stream someone watches
+        for stream in 0..server.streams.len() as StreamId {
+            if let Some(frames) = server.capture(stream) {
+                server.broadcast(stream, &frames);
+                server.broadcast_web(stream, &frames);
             }
-        };
+        }
+
+        if last_summary.elapsed() >= SUMMARY_INTERVAL {
+            let browsers: Vec<&WebViewer> = server.web_viewers.values().filter(|viewer| viewer.admitted).collect();
+            println!(
+                "Streaming since: {:?}, {} viewer(s)",
+                record_start.elapsed(),
+                server.clients.len() + browsers.len()
+            );
+            for client in server.clients.values() {
+                println!("  {}", client.summary());
+            }
+            for viewer in browsers {
+                println!("  {}", viewer);
+            }
+            last_summary = Instant::now();
+        }
 
+        // * Remove clients with errors
+        server.check_multicast();
+        let failed = server.remove_failed();
 
-        let image = Image {
-            pixels: &*frame,
-            width,
-            height,
-            format: PixelFormat::BGRX,
-            pitch: width * PixelFormat::BGRX.size(),
-        };
+        if failed && !server.has_viewers() {
+            println!("All clients disconnected");
+            break EXIT_SEND_FAILED;
+        }
+
+        // * Wait for the rest of the frame time
+        let delta = start.elapsed();
+        if delta < server.fps {
+            std::thread::sleep(server.fps - delta);
+        }
+    };
 
-        println!("Frame Size: {}", frame.len());
+    // Frames are only streamed, never recorded, so there is nothing to flush
+    println!(
+        "Session summary: {}",
+        SessionSummary { duration: record_start.elapsed(), stats: &server.session_stats }
+    );
 
-        // * Encode & Compress frame as AVIF
-        // let res = encoder.encode_rgba(
-        //         ravif::Img::new(frame, width, height),
-        //     )
-        //     .expect("Error encoding frame");
+    std::process::exit(status);
+}
 
-        let bytes = compress(image, options.quality as i32, turbojpeg::Subsamp::Sub2x2).expect("Error compressing image");
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::{TcpStream, UdpSocket};
 
-        println!("Compressed Frame Size: {}", bytes.len());
+    use super::*;
+    use crate::comm::{Codec, ReceiverReport, COOKIE_SIZE};
+    use crate::frame_buffer::{FrameBuffer, GetFrameResult};
+    use crate::transport::Link;
 
-        let mut clients_to_remove: Vec<SocketAddr> = Vec::new();
+    /// A server on a loopback UDP port, publishing a still image
+    fn server(key: Option<&str>) -> Server {
+        server_over(commands::Transport::Udp, key)
+    }
 
-        // Frame ID - unique identifier for the frame
-        let frame_id = record_start.elapsed().as_millis() as u32;
+    fn server_over(transport: commands::Transport, key: Option<&str>) -> Server {
+        let key = PreSharedKey::load(&commands::KeyArgs { key: key.map(String::from), key_file: None }).unwrap();
+        let listeners = Listeners::bind(transport, Some(Ipv4Addr::LOCALHOST.into()), 0, None, None).unwrap();
+        let source = Source::Images { frames: vec![vec![0x80; 64 * 48 * 4]], width: 64, height: 48, next: 0 };
+        let fps = Duration::from_millis(33);
 
-        // * Send frame to all connected clients
-        for client in &clients {
-    
-            // * Frames are send on packets chunk size
-            let chunk_size = Packet::CHUNK_SIZE;
+        Server {
+            listeners,
+            key,
+            credentials: Credentials::new(None, Vec::new()),
+            clients: HashMap::new(),
+            pending: HashMap::new(),
+            cookies: CookieJar::new().unwrap(),
+            // Every test viewer is on 127.0.0.1
+            limiter: RateLimiter::new(JOIN_RATE, 64),
+            client_timeout: Duration::from_secs(10),
+            malformed: 0,
+            throttled: 0,
+            session_stats: SessionStats::default(),
+            streams: vec![Stream::new(0, String::from(DEFAULT_STREAM), source, 50, &[])],
+            quality: 50,
+            frame_rate: 30,
+            fps,
+            span: fps.mul_f64(PACING_SHARE),
+            web: None,
+            web_viewers: HashMap::new(),
+            multicast: None,
+        }
+    }
 
-            // chunk_size - 1 because the first byte is the index
-            for (i, chunk) in bytes.chunks(chunk_size - Packet::META_SIZE).enumerate() {
+    fn hello(cookie: Option<[u8; COOKIE_SIZE]>) -> Message {
+        claim(cookie, None)
+    }
 
-                let packet = Packet {
-                    index: i as u8,
-                    frame_id,
-                    data: chunk.to_vec(),
-                };
+    /// Hello of a client rejoining with a proof it owns its session
+    fn claim(cookie: Option<[u8; COOKIE_SIZE]>, proof: Option<[u8; PROOF_SIZE]>) -> Message {
+        let capabilities = Capabilities {
+            codecs: Codec::Jpeg.bit(),
+            max_width: 1920,
+            max_height: 1080,
+            max_bitrate_kbps: 10_000,
+            mtu: 1400,
+            multicast: false,
+        };
+        Message::Hello { capabilities, cookie, stream: String::new(), proof }
+    }
 
-                match listener.send_to(&packet.to_bytes(), client) {
-                    Ok(bytes_send) => {
-                        println!("\nPacket {} : size {}", i, bytes_send);
-                    }
-                    Err(e) => {
-                        println!("Error sending packet to client: {}", e);
-                        clients_to_remove.push(*client);
-                        break;
-                    }
+    /// Non-blocking like the server's socket, tests poll until what they wait for arrives
+    fn viewer() -> UdpSocket {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        socket.set_nonblocking(true).unwrap();
+        socket
+    }
+
+    /// What the main loop does every frame, minus the sleeping
+    fn tick(server: &mut Server) {
+        server.handle_messages();
+        server.handle_web();
+        server.forget_unwatched();
+        for stream in 0..server.streams.len() as StreamId {
+            if let Some(frames) = server.capture(stream) {
+                server.broadcast(stream, &frames);
+                server.broadcast_web(stream, &frames);
+            }
+        }
+    }
+
+    /// Every datagram that arrived so far
+    fn received(socket: &UdpSocket) -> Vec<Vec<u8>> {
+        let mut buffer = [0u8; 65536];
+        let mut datagrams = Vec::new();
+        while let Ok(amount) = socket.recv(&mut buffer) {
+            datagrams.push(buffer[..amount].to_vec());
+        }
+        datagrams
+    }
+
+    /// Ticks the server until the datagrams `viewer` got are `done`, returns them
+    fn until(server: &mut Server, viewer: &UdpSocket, done: impl Fn(&[Vec<u8>]) -> bool) -> Vec<Vec<u8>> {
+        let started = Instant::now();
+        let mut datagrams = Vec::new();
+        loop {
+            tick(server);
+            datagrams.extend(received(viewer));
+            if done(&datagrams) {
+                return datagrams;
+            }
+            assert!(started.elapsed() < Duration::from_secs(5), "nothing wanted arrived");
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    /// Ticks the server until `viewer` gets anything
+    fn answer(server: &mut Server, viewer: &UdpSocket) -> Vec<Vec<u8>> {
+        until(server, viewer, |datagrams| !datagrams.is_empty())
+    }
+
+    /// Ticks the server until it handled every datagram sent to it so far, whether answered or not: datagrams
+    /// are read in order, so once a hello sent after them gets its cookie they are all handled
+    fn settle(server: &mut Server) {
+        let probe = viewer();
+        probe.send_to(&hello(None).to_bytes(NO_SESSION), server.listeners.local_addr().unwrap()).unwrap();
+        cookie_in(&answer(server, &probe)[0]);
+    }
+
+    fn accept_in(datagrams: &[Vec<u8>]) -> Option<(SessionId, [u8; SESSION_SECRET_SIZE])> {
+        datagrams.iter().find_map(|datagram| match Message::from_bytes(datagram) {
+            Ok((id, Message::Accept { secret, .. })) => Some((id, secret)),
+            _ => None,
+        })
+    }
+
+    fn cookie_in(datagram: &[u8]) -> [u8; COOKIE_SIZE] {
+        match Message::from_bytes(datagram) {
+            Ok((_, Message::Cookie { cookie })) => cookie,
+            other => panic!("expected a cookie, got {:?}", other),
+        }
+    }
+
+    /// Ticks the server until `link` receives a datagram `wanted` picks something from
+    fn wait_for<T>(server: &mut Server, link: &mut Link, mut wanted: impl FnMut(&[u8]) -> Option<T>) -> T {
+        let started = Instant::now();
+        let mut buffer = [0u8; Packet::MAX_DATAGRAM];
+        loop {
+            tick(server);
+            while let Ok(amount) = link.recv(&mut buffer) {
+                if let Some(found) = wanted(&buffer[..amount]) {
+                    return found;
                 }
             }
+            assert!(started.elapsed() < Duration::from_secs(5), "nothing wanted arrived");
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    /// Join, frames, a ping and leaving through the server, the same over every transport
+    fn join_and_watch(transport: commands::Transport) {
+        let mut server = server_over(transport, None);
+        let address = server.listeners.local_addr().unwrap();
+        let mut link = Link::connect(transport, address, SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0), None).unwrap();
+        let message = |datagram: &[u8]| Message::from_bytes(datagram).ok();
+
+        link.send(&hello(None).to_bytes(NO_SESSION)).unwrap();
+        let cookie = wait_for(&mut server, &mut link, |datagram| match message(datagram) {
+            Some((_, Message::Cookie { cookie })) => Some(cookie),
+            _ => None,
+        });
+
+        link.send(&hello(Some(cookie)).to_bytes(NO_SESSION)).unwrap();
+        let id = wait_for(&mut server, &mut link, |datagram| match message(datagram) {
+            Some((id, Message::Accept { .. })) => Some(id),
+            _ => None,
+        });
+        assert_eq!(server.clients[&id].address, link.local_addr().unwrap());
+
+        let mut frames = FrameBuffer::new();
+        let frame = wait_for(&mut server, &mut link, |datagram| {
+            frames.add_packet(Packet::from_bytes(datagram).ok()?);
+            match frames.get_frame() {
+                GetFrameResult::Ok(frame) => Some(frame),
+                GetFrameResult::NoFrame => None,
+            }
+        });
+        assert!(!frame.is_empty());
 
+        link.send(&Message::Ping { timestamp: 7 }.to_bytes(id)).unwrap();
+        wait_for(&mut server, &mut link, |datagram| match message(datagram) {
+            Some((_, Message::Pong { timestamp: 7 })) => Some(()),
+            _ => None,
+        });
+
+        link.send(&Message::Goodbye { reason: String::from("done") }.to_bytes(id)).unwrap();
+        let started = Instant::now();
+        while !server.clients.is_empty() {
+            tick(&mut server);
+            assert!(started.elapsed() < Duration::from_secs(5), "the client wasn't removed");
         }
+        link.close();
+    }
 
-        if clients_to_remove.len() == clients.len() {
-            println!("All clients disconnected");
-            break;
+    #[test]
+    fn join_and_watch_over_udp() {
+        join_and_watch(commands::Transport::Udp);
+    }
+
+    #[test]
+    fn join_and_watch_over_tcp() {
+        join_and_watch(commands::Transport::Tcp);
+    }
+
+    #[test]
+    fn spoofed_hello_is_only_answered_with_a_cookie() {
+        let mut server = server(None);
+        let victim = viewer();
+
+        // Loopback can't forge source addresses, the hello is handed over as if it came from the victim
+        let size = hello(None).to_bytes(NO_SESSION).len();
+        server.handle_message(victim.local_addr().unwrap(), size, NO_SESSION, false, hello(None), None);
+        let mut datagrams = answer(&mut server, &victim);
+        settle(&mut server);
+        datagrams.extend(received(&victim));
+
+        assert_eq!(datagrams.len(), 1, "one cookie and no frames");
+        assert!(datagrams[0].len() <= size, "never more than the hello");
+        cookie_in(&datagrams[0]);
+
+        assert!(server.clients.is_empty() && server.pending.is_empty());
+        assert_eq!(server.session_stats.bytes_sent, 0);
+    }
+
+    #[test]
+    fn echoed_cookie_starts_the_stream() {
+        let mut server = server(None);
+        let address = server.listeners.local_addr().unwrap();
+        let viewer = viewer();
+
+        viewer.send_to(&hello(None).to_bytes(NO_SESSION), address).unwrap();
+        let cookie = cookie_in(&answer(&mut server, &viewer)[0]);
+        assert!(server.clients.is_empty());
+
+        viewer.send_to(&hello(Some(cookie)).to_bytes(NO_SESSION), address).unwrap();
+        let frames = |datagrams: &[Vec<u8>]| datagrams.iter().filter(|datagram| Packet::from_bytes(datagram).is_ok()).count();
+        let datagrams = until(&mut server, &viewer, |datagrams| frames(datagrams) > 0);
+        assert!(accept_in(&datagrams).is_some());
+        assert_eq!(server.clients.len(), 1);
+    }
+
+    #[test]
+    fn cookie_of_another_address_is_refused() {
+        let mut server = server(None);
+        let address = server.listeners.local_addr().unwrap();
+        let (victim, attacker) = (viewer(), viewer());
+
+        let size = hello(None).to_bytes(NO_SESSION).len();
+        server.handle_message(victim.local_addr().unwrap(), size, NO_SESSION, false, hello(None), None);
+        let cookie = cookie_in(&answer(&mut server, &victim)[0]);
+
+        attacker.send_to(&hello(Some(cookie)).to_bytes(NO_SESSION), address).unwrap();
+        settle(&mut server);
+
+        assert!(received(&attacker).is_empty());
+        assert!(received(&victim).is_empty());
+        assert!(server.clients.is_empty() && server.pending.is_empty());
+    }
+
+    #[test]
+    fn cookie_is_sealed_under_a_key() {
+        let mut server = server(Some("key"));
+        let address = server.listeners.local_addr().unwrap();
+        let viewer = viewer();
+        let key = PreSharedKey::load(&commands::KeyArgs { key: Some(String::from("key")), key_file: None }).unwrap();
+        let mut session = Session::client(&key.unwrap()).unwrap();
+
+        viewer.send_to(&session.seal(Channel::Control, &hello(None).to_bytes(NO_SESSION)), address).unwrap();
+
+        let datagrams = answer(&mut server, &viewer);
+        assert_eq!(datagrams.len(), 1);
+        assert!(matches!(Header::read(&datagrams[0]), Ok(Kind::Sealed)));
+        cookie_in(&session.open(&datagrams[0]).unwrap());
+        assert!(server.clients.is_empty() && server.pending.is_empty());
+    }
+
+    /// Goes through the cookie round trip from `viewer` as session `claimed`, returns the session
+    /// it is accepted as and its secret
+    fn join(
+        server: &mut Server,
+        viewer: &UdpSocket,
+        claimed: SessionId,
+        secret: Option<&[u8; SESSION_SECRET_SIZE]>,
+    ) -> (SessionId, [u8; SESSION_SECRET_SIZE]) {
+        let address = server.listeners.local_addr().unwrap();
+        viewer.send_to(&hello(None).to_bytes(claimed), address).unwrap();
+        let cookie = cookie_in(&answer(server, viewer)[0]);
+
+        let proof = secret.map(|secret| auth::prove(secret, &cookie));
+        viewer.send_to(&claim(Some(cookie), proof).to_bytes(claimed), address).unwrap();
+        accept_in(&until(server, viewer, |datagrams| accept_in(datagrams).is_some())).unwrap()
+    }
+
+    #[test]
+    fn session_moves_with_proof_only() {
+        let mut server = server(None);
+        let (first, second, third, moved) = (viewer(), viewer(), viewer(), viewer());
+
+        let (id, secret) = join(&mut server, &first, NO_SESSION, None);
+
+        // Knowing the id is not enough to take the session over
+        let (other, _) = join(&mut server, &second, id, None);
+        assert_ne!(other, id);
+        assert_eq!(server.clients[&id].address, first.local_addr().unwrap());
+
+        let wrong = [0u8; SESSION_SECRET_SIZE];
+        let (other, _) = join(&mut server, &third, id, Some(&wrong));
+        assert_ne!(other, id);
+        assert_eq!(server.clients[&id].address, first.local_addr().unwrap());
+
+        // The client that owns it moves to its new address, with the same secret
+        assert_eq!(join(&mut server, &moved, id, Some(&secret)), (id, secret));
+        assert_eq!(server.clients[&id].address, moved.local_addr().unwrap());
+        assert_eq!(server.clients.len(), 3);
+    }
+
+    #[test]
+    fn proof_is_bound_to_the_cookie_of_the_new_address() {
+        let mut server = server(None);
+        let (first, second, attacker) = (viewer(), viewer(), viewer());
+        let address = server.listeners.local_addr().unwrap();
+
+        let (id, secret) = join(&mut server, &first, NO_SESSION, None);
+
+        // A hello the owner sent from its new address, replayed from another one
+        second.send_to(&hello(None).to_bytes(id), address).unwrap();
+        let cookie = cookie_in(&answer(&mut server, &second)[0]);
+        let captured = claim(Some(cookie), Some(auth::prove(&secret, &cookie)));
+
+        attacker.send_to(&hello(None).to_bytes(id), address).unwrap();
+        let own = cookie_in(&answer(&mut server, &attacker)[0]);
+        attacker.send_to(&captured.to_bytes(id), address).unwrap();
+        settle(&mut server);
+        assert!(received(&attacker).is_empty(), "the cookie of another address is refused");
+
+        // With a cookie of its own, the proof doesn't match
+        let Message::Hello { proof, .. } = captured else { unreachable!() };
+        attacker.send_to(&claim(Some(own), proof).to_bytes(id), address).unwrap();
+        let (accepted, _) = accept_in(&until(&mut server, &attacker, |datagrams| accept_in(datagrams).is_some())).unwrap();
+        assert_ne!(accepted, id, "a new session");
+        assert_eq!(server.clients[&id].address, first.local_addr().unwrap());
+    }
+
+    #[test]
+    fn padding_of_display_rows_is_left_out() {
+        let mut server = server(None);
+        join(&mut server, &viewer(), NO_SESSION, None);
+
+        // Rows of 64 gray pixels padded to 80 with white, as displays may hand them out
+        let frame: Vec<u8> = (0..48).flat_map(|_| [vec![0x80; 64 * 4], vec![0xff; 16 * 4]].concat()).collect();
+        server.streams[0].source = Source::Images { frames: vec![frame], width: 64, height: 48, next: 0 };
+
+        let jpeg = server.capture(0).unwrap().swap_remove(0).unwrap();
+        let decoded = turbojpeg::decompress(&jpeg, PixelFormat::RGB).unwrap();
+        assert_eq!((decoded.width, decoded.height), (64, 48));
+        assert!(decoded.pixels.iter().all(|&value| value.abs_diff(0x80) < 8), "padding leaked into the picture");
+    }
+
+    #[test]
+    fn congestion_leaves_the_shared_encode_alone() {
+        let mut server = server(None);
+        let address = server.listeners.local_addr().unwrap();
+        let (congested, clear) = (viewer(), viewer());
+
+        let (id, _) = join(&mut server, &congested, NO_SESSION, None);
+        join(&mut server, &clear, NO_SESSION, None);
+        let before = server.clients[&id].congestion.target_kbps();
+
+        for received in 1..=5 {
+            let report = ReceiverReport { received: received * 10, lost: received * 10, ..ReceiverReport::default() };
+            congested.send_to(&Message::ReceiverReport(report).to_bytes(id), address).unwrap();
         }
+        settle(&mut server);
 
-        // * Remove clients with errors
-        for client in clients_to_remove {
-            clients.retain(|&x| x != client);
+        assert!(server.clients[&id].congestion.target_kbps() < before);
+        let rendition = server.clients[&id].subscription.current;
+        assert_eq!(server.wanted_renditions(0), vec![(rendition, 50)]);
+    }
+
+    #[test]
+    fn snapshot_of_a_watched_rendition_is_sent_at_once() {
+        let mut server = server(None);
+        let web = Web::new();
+        let address = web.serve_http(Some(Ipv4Addr::LOCALHOST.into()), 0, None).unwrap();
+        server.web = Some(web);
+        let started = Instant::now();
+
+        // An MJPEG player watches, so the rendition is captured
+        let mut player = TcpStream::connect(address).unwrap();
+        player.write_all(b"GET /stream.mjpg HTTP/1.1\r\n\r\n").unwrap();
+        while server.streams[0].latest[0].is_none() {
+            assert!(started.elapsed() < Duration::from_secs(5), "the player's rendition is not kept");
+            tick(&mut server);
         }
+        let latest = server.streams[0].latest[0].clone().unwrap();
 
-        // * Wait for the rest of the frame time
-        let delta = start.elapsed();
-        if delta < fps {
-            std::thread::sleep(fps - delta);
+        // Nothing is captured while the snapshot is taken, it can only be the kept frame
+        let snapshot = std::thread::spawn(move || {
+            let mut snapshot = TcpStream::connect(address).unwrap();
+            snapshot.write_all(b"GET /snapshot.jpg HTTP/1.1\r\n\r\n").unwrap();
+            let mut response = Vec::new();
+            snapshot.read_to_end(&mut response).unwrap();
+            response
+        });
+        while !snapshot.is_finished() {
+            assert!(started.elapsed() < Duration::from_secs(5), "no snapshot");
+            server.handle_web();
+        }
+        let response = snapshot.join().unwrap();
+        assert!(response.starts_with(b"HTTP/1.1 200 OK\r\nContent-Type: image/jpeg\r\n"));
+        assert!(response.ends_with(&latest));
+
+        // Once nobody watches it the frame would get stale
+        drop(player);
+        while server.has_viewers() || server.streams[0].latest[0].is_some() {
+            assert!(started.elapsed() < Duration::from_secs(5), "the frame of an unwatched rendition is kept");
+            tick(&mut server);
         }
     }
-} 
\ No newline at end of file
+}
@@ -0,0 +1,145 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use scrap::{Capturer, Display};
+
+use crate::commands::SourceSpec;
+
+/// Where the frames of a stream come from
+/// Every source hands out BGRX frames of a constant size. Displays may pad their rows, so the pitch is
+/// `frame.len() / height`, regions and images are packed with a pitch of `width * 4`
+pub enum Source {
+    Display(Capturer),
+    Region { capturer: Capturer, x: usize, y: usize, width: usize, height: usize },
+    Images { frames: Vec<Vec<u8>>, width: usize, height: usize, next: usize },
+}
+
+impl Source {
+    /// The primary display, what a server without `--stream` publishes
+    pub fn primary() -> Result<Self, String> {
+        let display = Display::primary().map_err(|e| format!("Failed to find primary display: {}", e))?;
+        let capturer = Capturer::new(display).map_err(|e| format!("Failed to create capturer: {}", e))?;
+        Ok(Source::Display(capturer))
+    }
+
+    pub fn open(spec: &SourceSpec) -> Result<Self, String> {
+        match *spec {
+            SourceSpec::Display(index) => Ok(Source::Display(capturer(index)?)),
+            SourceSpec::Region { display, x, y, width, height } => {
+                let capturer = capturer(display)?;
+                if x + width > capturer.width() || y + height > capturer.height() {
+                    return Err(format!(
+                        "Region {}x{} at {},{} is outside of display {} ({}x{})",
+                        width, height, x, y, display, capturer.width(), capturer.height()
+                    ));
+                }
+                Ok(Source::Region { capturer, x, y, width, height })
+            }
+            SourceSpec::Files(ref path) => images(path),
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        match self {
+            Source::Display(capturer) => capturer.width(),
+            Source::Region { width, .. } | Source::Images { width, .. } => *width,
+        }
+    }
+
+    pub fn height(&self) -> usize {
+        match self {
+            Source::Display(capturer) => capturer.height(),
+            Source::Region { height, .. } | Source::Images { height, .. } => *height,
+        }
+    }
+
+    /// Hands the next frame to `f`
+    /// Displays return `WouldBlock` until they have a new frame
+    pub fn with_frame<R>(&mut self, f: impl FnOnce(&[u8]) -> R) -> io::Result<R> {
+        match self {
+            Source::Display(capturer) => Ok(f(&capturer.frame()?)),
+            Source::Region { capturer, x, y, width, height } => {
+                let display_height = capturer.height();
+                let frame = capturer.frame()?;
+
+                // The stride might be greater than the width of the display
+                let stride = frame.len() / display_height;
+                let mut region = Vec::with_capacity(*width * *height * 4);
+                for row in *y..*y + *height {
+                    let start = row * stride + *x * 4;
+                    region.extend_from_slice(&frame[start..start + *width * 4]);
+                }
+
+                Ok(f(&region))
+            }
+            Source::Images { frames, next, .. } => {
+                let frame = &frames[*next];
+                *next = (*next + 1) % frames.len();
+                Ok(f(frame))
+            }
+        }
+    }
+}
+
+fn capturer(index: usize) -> Result<Capturer, String> {
+    let display = Display::all()
+        .map_err(|e| format!("Failed to list displays: {}", e))?
+        .into_iter()
+        .nth(index)
+        .ok_or_else(|| format!("There is no display {}", index))?;
+
+    Capturer::new(display).map_err(|e| format!("Failed to create capturer for display {}: {}", index, e))
+}
+
+/// A still image, or every image of a directory in name order, decoded up front
+/// Images of another size than the first one are skipped, a stream's size can't change
+fn images(path: &Path) -> Result<Source, String> {
+    let paths = match fs::metadata(path) {
+        Ok(metadata) if metadata.is_dir() => {
+            let mut paths: Vec<_> = fs::read_dir(path)
+                .map_err(|e| format!("Error reading {}: {}", path.display(), e))?
+                .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                .filter(|path| path.is_file())
+                .collect();
+            paths.sort();
+            paths
+        }
+        Ok(_) => vec![path.to_path_buf()],
+        Err(e) => return Err(format!("Error reading {}: {}", path.display(), e)),
+    };
+
+    let mut frames = Vec::new();
+    let mut size: Option<(usize, usize)> = None;
+
+    for path in paths {
+        let image = match image::open(&path) {
+            Ok(image) => image.to_rgba8(),
+            Err(e) => {
+                println!("Skipping {}: {}", path.display(), e);
+                continue;
+            }
+        };
+
+        let (width, height) = (image.width() as usize, image.height() as usize);
+        match size {
+            Some(size) if size != (width, height) => {
+                println!("Skipping {}: {}x{} instead of {}x{}", path.display(), width, height, size.0, size.1);
+                continue;
+            }
+            _ => size = Some((width, height)),
+        }
+
+        // RGBA to BGRX, the layout displays are captured in
+        let mut frame = image.into_raw();
+        for pixel in frame.chunks_exact_mut(4) {
+            pixel.swap(0, 2);
+        }
+        frames.push(frame);
+    }
+
+    match size {
+        Some((width, height)) => Ok(Source::Images { frames, width, height, next: 0 }),
+        None => Err(format!("No image could be read from {}", path.display())),
+    }
+}
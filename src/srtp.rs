@@ -0,0 +1,336 @@
+use aes::cipher::{KeyIvInit, StreamCipher};
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+
+type Aes128Ctr = ctr::Ctr128BE<aes::Aes128>;
+type HmacSha1 = Hmac<Sha1>;
+
+/// Sizes of SRTP_AES128_CM_SHA1_80, the profile WebRTC requires
+pub const MASTER_KEY_SIZE: usize = 16;
+pub const MASTER_SALT_SIZE: usize = 14;
+pub const TAG_SIZE: usize = 10;
+const AUTH_KEY_SIZE: usize = 20;
+
+const RTP_HEADER_SIZE: usize = 12;
+const RTCP_HEADER_SIZE: usize = 8;
+const SRTCP_INDEX_SIZE: usize = 4;
+
+/// Labels session keys are derived with (RFC 3711 4.3.1)
+const RTP_ENCRYPTION: u8 = 0;
+const RTP_AUTHENTICATION: u8 = 1;
+const RTP_SALT: u8 = 2;
+const RTCP_ENCRYPTION: u8 = 3;
+const RTCP_AUTHENTICATION: u8 = 4;
+const RTCP_SALT: u8 = 5;
+
+/// Session keys of RTP or RTCP in one direction
+struct Keys {
+    encryption: [u8; MASTER_KEY_SIZE],
+    authentication: [u8; AUTH_KEY_SIZE],
+    salt: [u8; MASTER_SALT_SIZE],
+}
+
+impl Keys {
+    fn derive(key: &[u8; MASTER_KEY_SIZE], salt: &[u8; MASTER_SALT_SIZE], labels: [u8; 3]) -> Self {
+        let mut keys = Keys { encryption: [0; MASTER_KEY_SIZE], authentication: [0; AUTH_KEY_SIZE], salt: [0; MASTER_SALT_SIZE] };
+        derive(key, salt, labels[0], &mut keys.encryption);
+        derive(key, salt, labels[1], &mut keys.authentication);
+        derive(key, salt, labels[2], &mut keys.salt);
+        keys
+    }
+
+    /// Encrypts or decrypts in place, AES in counter mode from the salt, SSRC and packet index
+    fn apply(&self, ssrc: u32, index: u64, bytes: &mut [u8]) {
+        let mut iv = [0u8; 16];
+        iv[..MASTER_SALT_SIZE].copy_from_slice(&self.salt);
+        for (i, byte) in ssrc.to_be_bytes().iter().enumerate() {
+            iv[4 + i] ^= byte;
+        }
+        for (i, byte) in index.to_be_bytes()[2..].iter().enumerate() {
+            iv[8 + i] ^= byte;
+        }
+        Aes128Ctr::new(&self.encryption.into(), &iv.into()).apply_keystream(bytes);
+    }
+
+    fn mac(&self) -> HmacSha1 {
+        HmacSha1::new_from_slice(&self.authentication).expect("HMAC takes keys of any size")
+    }
+}
+
+/// The key derivation function: AES in counter mode from the master salt with the label in it, rate 0
+fn derive(key: &[u8; MASTER_KEY_SIZE], salt: &[u8; MASTER_SALT_SIZE], label: u8, output: &mut [u8]) {
+    let mut iv = [0u8; 16];
+    iv[..MASTER_SALT_SIZE].copy_from_slice(salt);
+    iv[7] ^= label;
+    output.fill(0);
+    Aes128Ctr::new(key.into(), &iv.into()).apply_keystream(output);
+}
+
+/// Indexes of the latest SRTCP packets, to reject replays (RFC 3711 3.3.2)
+#[derive(Default)]
+struct ReplayList {
+    highest: Option<u32>,
+    window: u64, // Bit i set -> `highest - i` was received
+}
+
+impl ReplayList {
+    /// Packets further behind than the window are taken for replays too
+    const SIZE: u32 = 64;
+
+    fn check(&self, index: u32) -> bool {
+        match self.highest {
+            None => true,
+            Some(highest) if index > highest => true,
+            Some(highest) => highest - index < Self::SIZE && self.window & 1 << (highest - index) == 0,
+        }
+    }
+
+    /// Only called once the packet authenticated, so forged indexes can't move the window
+    fn accept(&mut self, index: u32) {
+        match self.highest {
+            Some(highest) if index <= highest => self.window |= 1 << (highest - index),
+            Some(highest) => {
+                let ahead = index - highest;
+                self.window = if ahead >= Self::SIZE { 1 } else { self.window << ahead | 1 };
+                self.highest = Some(index);
+            }
+            None => {
+                self.highest = Some(index);
+                self.window = 1;
+            }
+        }
+    }
+}
+
+/// SRTP and SRTCP (RFC 3711) of one direction of a DTLS-SRTP session, with the keys of one side
+/// The server only sends one RTP stream and receives RTCP, so that is all it does
+pub struct Context {
+    rtp: Keys,
+    rtcp: Keys,
+    rollover: u32,         // Times the sequence number of the sent stream wrapped
+    last_seq: Option<u16>, // Of the last RTP packet protected
+    replay: ReplayList,    // Of the SRTCP packets unprotected
+}
+
+impl Context {
+    pub fn new(key: &[u8; MASTER_KEY_SIZE], salt: &[u8; MASTER_SALT_SIZE]) -> Self {
+        Self {
+            rtp: Keys::derive(key, salt, [RTP_ENCRYPTION, RTP_AUTHENTICATION, RTP_SALT]),
+            rtcp: Keys::derive(key, salt, [RTCP_ENCRYPTION, RTCP_AUTHENTICATION, RTCP_SALT]),
+            rollover: 0,
+            last_seq: None,
+            replay: ReplayList::default(),
+        }
+    }
+
+    /// Encrypts an RTP packet and appends its tag. Packets are expected in sequence order
+    pub fn protect_rtp(&mut self, packet: &[u8]) -> Result<Vec<u8>, String> {
+        let header_size = rtp_header_size(packet)?;
+        let seq = u16::from_be_bytes([packet[2], packet[3]]);
+        let ssrc = u32::from_be_bytes([packet[8], packet[9], packet[10], packet[11]]);
+
+        if self.last_seq.is_some_and(|last| seq < last && last - seq > 0x8000) {
+            self.rollover = self.rollover.wrapping_add(1);
+        }
+        self.last_seq = Some(seq);
+        let index = (self.rollover as u64) << 16 | seq as u64;
+
+        let mut protected = Vec::with_capacity(packet.len() + TAG_SIZE);
+        protected.extend_from_slice(packet);
+        self.rtp.apply(ssrc, index, &mut protected[header_size..]);
+
+        let mut mac = self.rtp.mac();
+        mac.update(&protected);
+        mac.update(&self.rollover.to_be_bytes());
+        protected.extend_from_slice(&mac.finalize().into_bytes()[..TAG_SIZE]);
+        Ok(protected)
+    }
+
+    /// Checks the tag of an SRTCP packet and decrypts it
+    pub fn unprotect_rtcp(&mut self, packet: &[u8]) -> Result<Vec<u8>, String> {
+        if packet.len() < RTCP_HEADER_SIZE + SRTCP_INDEX_SIZE + TAG_SIZE {
+            return Err(String::from("SRTCP packet too short"));
+        }
+        let (authenticated, tag) = packet.split_at(packet.len() - TAG_SIZE);
+        let mut mac = self.rtcp.mac();
+        mac.update(authenticated);
+        mac.verify_truncated_left(tag).map_err(|_| String::from("SRTCP authentication failed"))?;
+
+        let (body, index) = authenticated.split_at(authenticated.len() - SRTCP_INDEX_SIZE);
+        let index = u32::from_be_bytes(index.try_into().expect("Split at the index size"));
+        let (encrypted, index) = (index & 0x8000_0000 != 0, index & 0x7fff_ffff);
+        if !self.replay.check(index) {
+            return Err(format!("SRTCP replay of index {}", index));
+        }
+        self.replay.accept(index);
+
+        let mut plain = body.to_vec();
+        if encrypted {
+            let ssrc = u32::from_be_bytes([plain[4], plain[5], plain[6], plain[7]]);
+            self.rtcp.apply(ssrc, index as u64, &mut plain[RTCP_HEADER_SIZE..]);
+        }
+        Ok(plain)
+    }
+}
+
+/// Size of the header of an RTP packet, CSRCs and extension included: what SRTP leaves in the clear
+fn rtp_header_size(packet: &[u8]) -> Result<usize, String> {
+    let csrcs = (*packet.first().ok_or("Empty RTP packet")? & 0x0f) as usize;
+    let mut size = RTP_HEADER_SIZE + 4 * csrcs;
+    if packet[0] & 0x10 != 0 {
+        let length = packet.get(size + 2..size + 4).ok_or("RTP extension cut short")?;
+        size += 4 + 4 * u16::from_be_bytes([length[0], length[1]]) as usize;
+    }
+    if packet.len() < size {
+        return Err(String::from("RTP header cut short"));
+    }
+    Ok(size)
+}
+
+/// The other side of a context: what a browser does with the packets the server protects, and with its feedback
+#[cfg(test)]
+impl Context {
+    /// Checks the tag of an SRTP packet and decrypts it, packets are expected in sequence order
+    pub fn unprotect_rtp(&mut self, packet: &[u8]) -> Result<Vec<u8>, String> {
+        if packet.len() < RTP_HEADER_SIZE + TAG_SIZE {
+            return Err(String::from("SRTP packet too short"));
+        }
+        let (protected, tag) = packet.split_at(packet.len() - TAG_SIZE);
+        let header_size = rtp_header_size(protected)?;
+        let seq = u16::from_be_bytes([packet[2], packet[3]]);
+        let ssrc = u32::from_be_bytes([packet[8], packet[9], packet[10], packet[11]]);
+
+        let rollover = match self.last_seq {
+            Some(last) if seq < last && last - seq > 0x8000 => self.rollover.wrapping_add(1),
+            _ => self.rollover,
+        };
+        let mut mac = self.rtp.mac();
+        mac.update(protected);
+        mac.update(&rollover.to_be_bytes());
+        mac.verify_truncated_left(tag).map_err(|_| String::from("SRTP authentication failed"))?;
+        self.rollover = rollover;
+        self.last_seq = Some(seq);
+
+        let mut plain = protected.to_vec();
+        self.rtp.apply(ssrc, (rollover as u64) << 16 | seq as u64, &mut plain[header_size..]);
+        Ok(plain)
+    }
+
+    /// Encrypts an RTCP compound packet as SRTCP packet `index`
+    pub fn protect_rtcp(&self, packet: &[u8], index: u32) -> Vec<u8> {
+        let ssrc = u32::from_be_bytes([packet[4], packet[5], packet[6], packet[7]]);
+        let mut protected = packet.to_vec();
+        self.rtcp.apply(ssrc, index as u64, &mut protected[RTCP_HEADER_SIZE..]);
+        protected.extend_from_slice(&(index | 0x8000_0000).to_be_bytes());
+
+        let mut mac = self.rtcp.mac();
+        mac.update(&protected);
+        protected.extend_from_slice(&mac.finalize().into_bytes()[..TAG_SIZE]);
+        protected
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(text: &str) -> Vec<u8> {
+        (0..text.len()).step_by(2).map(|i| u8::from_str_radix(&text[i..i + 2], 16).unwrap()).collect()
+    }
+
+    fn context() -> Context {
+        Context::new(&[7; MASTER_KEY_SIZE], &[9; MASTER_SALT_SIZE])
+    }
+
+    /// Receiver report of SSRC 0x01020304 without report blocks, then a PLI
+    fn feedback() -> Vec<u8> {
+        let mut compound = vec![0x80, 201, 0, 1, 1, 2, 3, 4];
+        compound.extend_from_slice(&[0x81, 206, 0, 2, 1, 2, 3, 4, 5, 6, 7, 8]);
+        compound
+    }
+
+    #[test]
+    fn session_keys_match_rfc_3711() {
+        // Appendix B.3
+        let key = hex("E1F97A0D3E018BE0D64FA32C06DE4139").try_into().unwrap();
+        let salt = hex("0EC675AD498AFEEBB6960B3AABE6").try_into().unwrap();
+        let keys = Keys::derive(&key, &salt, [RTP_ENCRYPTION, RTP_AUTHENTICATION, RTP_SALT]);
+        assert_eq!(keys.encryption.to_vec(), hex("C61E7A93744F39EE10734AFE3FF7A087"));
+        assert_eq!(keys.salt.to_vec(), hex("30CBBC08863D8C85D49DB34A9AE1"));
+        assert_eq!(keys.authentication.to_vec(), hex("CEBE321F6FF7716B6FD4AB49AF256A156D38BAA4"));
+    }
+
+    #[test]
+    fn keystream_matches_rfc_3711() {
+        // Appendix B.2
+        let keys = Keys {
+            encryption: hex("2B7E151628AED2A6ABF7158809CF4F3C").try_into().unwrap(),
+            authentication: [0; AUTH_KEY_SIZE],
+            salt: hex("F0F1F2F3F4F5F6F7F8F9FAFBFCFD").try_into().unwrap(),
+        };
+        let mut keystream = [0u8; 48];
+        keys.apply(0, 0, &mut keystream);
+        let expected = "E03EAD0935C95E80E166B16DD92B4EB4D23513162B02D0F72A43A2FE4A5F97AB41E95B3BB0A2E8DD477901E4FCA894C0";
+        assert_eq!(keystream.to_vec(), hex(expected));
+    }
+
+    #[test]
+    fn rtp_round_trip_across_the_rollover() {
+        let (mut sending, mut receiving) = (context(), context());
+        for seq in [0xfffe, 0xffff, 0, 1] {
+            let mut packet = vec![0x80, 45, 0, 0, 0, 0, 0, 90, 1, 2, 3, 4];
+            packet[2..4].copy_from_slice(&u16::to_be_bytes(seq));
+            packet.extend_from_slice(b"payload");
+
+            let protected = sending.protect_rtp(&packet).unwrap();
+            assert_ne!(&protected[RTP_HEADER_SIZE..packet.len()], b"payload");
+            assert_eq!(receiving.unprotect_rtp(&protected).unwrap(), packet);
+        }
+        assert_eq!((sending.rollover, receiving.rollover), (1, 1));
+    }
+
+    #[test]
+    fn rtcp_round_trip() {
+        let (sending, mut receiving) = (context(), context());
+        let protected = sending.protect_rtcp(&feedback(), 0);
+        assert_eq!(protected.len(), feedback().len() + SRTCP_INDEX_SIZE + TAG_SIZE);
+        assert_eq!(receiving.unprotect_rtcp(&protected).unwrap(), feedback());
+
+        let mut tampered = sending.protect_rtcp(&feedback(), 1);
+        tampered[RTCP_HEADER_SIZE] ^= 1;
+        assert!(receiving.unprotect_rtcp(&tampered).is_err());
+
+        let other = Context::new(&[8; MASTER_KEY_SIZE], &[9; MASTER_SALT_SIZE]);
+        assert!(receiving.unprotect_rtcp(&other.protect_rtcp(&feedback(), 2)).is_err());
+    }
+
+    #[test]
+    fn rtcp_replays_are_rejected() {
+        let (sending, mut receiving) = (context(), context());
+        let packets: Vec<Vec<u8>> = (0..200).map(|index| sending.protect_rtcp(&feedback(), index)).collect();
+
+        assert!(receiving.unprotect_rtcp(&packets[100]).is_ok());
+        assert!(receiving.unprotect_rtcp(&packets[100]).is_err(), "same index again");
+
+        // Late but within the window, once each
+        assert!(receiving.unprotect_rtcp(&packets[60]).is_ok());
+        assert!(receiving.unprotect_rtcp(&packets[60]).is_err());
+        assert!(receiving.unprotect_rtcp(&packets[36]).is_err(), "behind the window");
+
+        // Moving the window ahead keeps what it saw
+        assert!(receiving.unprotect_rtcp(&packets[130]).is_ok());
+        assert!(receiving.unprotect_rtcp(&packets[100]).is_err());
+        assert!(receiving.unprotect_rtcp(&packets[99]).is_ok());
+        assert!(receiving.unprotect_rtcp(&packets[60]).is_err());
+    }
+
+    #[test]
+    fn forged_indexes_do_not_move_the_window() {
+        let (sending, mut receiving) = (context(), context());
+        let mut forged = sending.protect_rtcp(&feedback(), 1000);
+        let tag = forged.len() - TAG_SIZE;
+        forged[tag] ^= 1;
+        assert!(receiving.unprotect_rtcp(&forged).is_err());
+        assert!(receiving.unprotect_rtcp(&sending.protect_rtcp(&feedback(), 1)).is_ok());
+    }
+}
@@ -0,0 +1,208 @@
+use std::fmt;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use crate::comm::{ReceiverReport, SessionId};
+
+/// What the server knows about one viewer: what was sent to it and what it reported back
+pub struct ClientStats {
+    pub connected: Instant,
+    pub bytes_sent: u64,
+    pub packets_sent: u64,
+    pub frames_sent: u64,
+    pub frames_skipped: u64, // Frames not sent because the client was over its bitrate
+    pub frames_replaced: u64, // Frames dropped from the send queue because a newer one came before they were sent
+    pub packets_resent: u64,  // Packets its multicast group lost, sent again to it alone
+    pub last_report: Option<ReceiverReport>,
+    pub estimated_kbps: Option<f64>, // Smoothed rate the client actually receives
+    previous: Option<Snapshot>,      // Counters at the time of the previous report
+}
+
+/// Counters at the time a report arrived
+struct Snapshot {
+    at: Instant,
+    bytes_sent: u64,
+    packets_sent: u64,
+    received: u64,
+}
+
+impl ClientStats {
+    /// Weight of the newest sample in the bandwidth estimate
+    const SMOOTHING: f64 = 0.25;
+
+    pub fn new() -> Self {
+        Self {
+            connected: Instant::now(),
+            bytes_sent: 0,
+            packets_sent: 0,
+            frames_sent: 0,
+            frames_skipped: 0,
+            frames_replaced: 0,
+            packets_resent: 0,
+            last_report: None,
+            estimated_kbps: None,
+            previous: None,
+        }
+    }
+
+    pub fn on_frame_sent(&mut self, bytes: usize, packets: usize) {
+        self.bytes_sent += bytes as u64;
+        self.packets_sent += packets as u64;
+        self.frames_sent += 1;
+    }
+
+    pub fn on_frame_skipped(&mut self) {
+        self.frames_skipped += 1;
+    }
+
+    pub fn on_frames_replaced(&mut self, frames: usize) {
+        self.frames_replaced += frames as u64;
+    }
+
+    pub fn on_resent(&mut self, bytes: usize) {
+        self.bytes_sent += bytes as u64;
+        self.packets_resent += 1;
+    }
+
+    /// Updates the bandwidth estimate: bytes sent since the previous report,
+    /// scaled by the share of packets the client says it received, over the time between reports
+    pub fn on_report(&mut self, report: ReceiverReport) {
+        let now = Instant::now();
+
+        if let Some(previous) = &self.previous {
+            let elapsed = now.duration_since(previous.at).as_secs_f64();
+            let packets = self.packets_sent.saturating_sub(previous.packets_sent);
+            let received = report.received.saturating_sub(previous.received);
+
+            if elapsed > 0.0 && packets > 0 {
+                let delivered = received.min(packets) as f64 / packets as f64;
+                let bytes = self.bytes_sent.saturating_sub(previous.bytes_sent) as f64 * delivered;
+                let sample = bytes * 8.0 / 1000.0 / elapsed;
+
+                self.estimated_kbps = Some(match self.estimated_kbps {
+                    Some(estimate) => estimate + (sample - estimate) * Self::SMOOTHING,
+                    None => sample,
+                });
+            }
+        }
+
+        self.previous = Some(Snapshot {
+            at: now,
+            bytes_sent: self.bytes_sent,
+            packets_sent: self.packets_sent,
+            received: report.received,
+        });
+        self.last_report = Some(report);
+    }
+
+    /// Fraction of packets lost over the whole session, from the last report
+    pub fn loss(&self) -> f64 {
+        match &self.last_report {
+            Some(report) if report.received + report.lost > 0 => {
+                report.lost as f64 / (report.received + report.lost) as f64
+            }
+            _ => 0.0,
+        }
+    }
+}
+
+impl Default for ClientStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// One line summary of a viewer
+pub struct Summary<'a> {
+    pub id: SessionId,
+    pub address: SocketAddr,
+    pub stats: &'a ClientStats,
+    pub target_kbps: u32,
+    pub quality: u8,
+    pub resolution: (u16, u16), // Of the rendition the viewer is sent
+    pub multicast: bool,        // Whether the viewer gets its frames from a multicast group
+}
+
+impl fmt::Display for Summary<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let stats = self.stats;
+
+        write!(
+            f,
+            "{:016x} {} | up {}s | frames sent {} skipped {} replaced {} | {} KiB",
+            self.id,
+            self.address,
+            stats.connected.elapsed().as_secs(),
+            stats.frames_sent,
+            stats.frames_skipped,
+            stats.frames_replaced,
+            stats.bytes_sent / 1024
+        )?;
+
+        match stats.estimated_kbps {
+            Some(estimate) => write!(f, " | est. {:.0} kbps", estimate)?,
+            None => write!(f, " | est. n/a")?,
+        }
+
+        if self.target_kbps != u32::MAX {
+            write!(f, " (target {} kbps)", self.target_kbps)?;
+        }
+
+        write!(f, " | {}x{} quality {}", self.resolution.0, self.resolution.1, self.quality)?;
+
+        if self.multicast {
+            write!(f, " | multicast, {} packets resent", stats.packets_resent)?;
+        }
+
+        match &stats.last_report {
+            Some(report) => write!(
+                f,
+                " | loss {:.1}% | jitter {:.1} ms | decode {:.1} ms | latency {:.1} ms | last frame {}",
+                stats.loss() * 100.0,
+                report.jitter_us as f64 / 1000.0,
+                report.decode_us as f64 / 1000.0,
+                report.display_latency_us as f64 / 1000.0,
+                report.last_frame
+            ),
+            None => write!(f, " | no reports yet"),
+        }
+    }
+}
+
+/// What a whole server run streamed, printed when it stops
+#[derive(Default)]
+pub struct SessionStats {
+    pub frames: u64,         // Frames captured and sent to at least one viewer
+    pub bytes_sent: u64,     // Bytes sent to every viewer together
+    pub viewers: u64,        // Viewers that joined
+    pub peak_viewers: usize, // Most viewers watching at the same time
+}
+
+impl SessionStats {
+    pub fn on_join(&mut self, viewers: usize) {
+        self.viewers += 1;
+        self.peak_viewers = self.peak_viewers.max(viewers);
+    }
+}
+
+/// One line summary of a server run
+pub struct SessionSummary<'a> {
+    pub duration: Duration,
+    pub stats: &'a SessionStats,
+}
+
+impl fmt::Display for SessionSummary<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let stats = self.stats;
+
+        write!(
+            f,
+            "streamed for {}s | frames {} | {} KiB sent | {} viewer(s) joined, at most {} at once",
+            self.duration.as_secs(),
+            stats.frames,
+            stats.bytes_sent / 1024,
+            stats.viewers,
+            stats.peak_viewers
+        )
+    }
+}
@@ -0,0 +1,279 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+
+type HmacSha1 = Hmac<Sha1>;
+
+pub const TRANSACTION_SIZE: usize = 12;
+
+pub const BINDING_REQUEST: u16 = 0x0001;
+pub const BINDING_SUCCESS: u16 = 0x0101;
+
+// Attributes
+pub const USERNAME: u16 = 0x0006;
+const MESSAGE_INTEGRITY: u16 = 0x0008;
+const XOR_MAPPED_ADDRESS: u16 = 0x0020;
+pub const USE_CANDIDATE: u16 = 0x0025;
+const FINGERPRINT: u16 = 0x8028;
+
+const HEADER_SIZE: usize = 20;
+const MAGIC_COOKIE: u32 = 0x2112_a442;
+const INTEGRITY_SIZE: usize = 20;
+
+/// XORed into the CRC-32 of a message, so a FINGERPRINT tells STUN apart from other protocols on the port
+const FINGERPRINT_XOR: u32 = 0x5354_554e;
+
+/// A STUN message (RFC 5389), as ICE and servers of public addresses speak it
+pub struct Message<'a> {
+    pub kind: u16,
+    pub transaction: [u8; TRANSACTION_SIZE],
+    bytes: &'a [u8],
+}
+
+impl<'a> Message<'a> {
+    /// Whether a datagram can be STUN, rather than DTLS or SRTP sharing the port (RFC 7983)
+    pub fn is_stun(bytes: &[u8]) -> bool {
+        bytes.len() >= HEADER_SIZE && bytes[0] < 4 && bytes[4..8] == MAGIC_COOKIE.to_be_bytes()
+    }
+
+    pub fn parse(bytes: &'a [u8]) -> Result<Self, String> {
+        if !Self::is_stun(bytes) {
+            return Err(String::from("Not a STUN message"));
+        }
+        let length = u16::from_be_bytes([bytes[2], bytes[3]]) as usize;
+        if !length.is_multiple_of(4) || bytes.len() != HEADER_SIZE + length {
+            return Err(String::from("STUN length doesn't match the datagram"));
+        }
+
+        let message = Self {
+            kind: u16::from_be_bytes([bytes[0], bytes[1]]),
+            transaction: bytes[8..HEADER_SIZE].try_into().expect("Sliced to the transaction size"),
+            bytes,
+        };
+        // Walks the attributes once, so the lookups after can't run off the end
+        message.attributes().try_for_each(|attribute| attribute.map(|_| ()))?;
+        Ok(message)
+    }
+
+    /// Value of the first attribute of type `kind`
+    pub fn attribute(&self, kind: u16) -> Option<&'a [u8]> {
+        self.attributes().map_while(Result::ok).find(|&(_, found, _)| found == kind).map(|(_, _, value)| value)
+    }
+
+    /// Checks MESSAGE-INTEGRITY, the HMAC of everything before it keyed with the ICE password
+    pub fn verify(&self, key: &[u8]) -> bool {
+        let found = self.attributes().map_while(Result::ok).find(|&(_, kind, _)| kind == MESSAGE_INTEGRITY);
+        let (offset, value) = match found {
+            Some((offset, _, value)) if value.len() == INTEGRITY_SIZE => (offset, value),
+            _ => return false,
+        };
+
+        // The length covers the message up to the integrity, whatever comes after it
+        let mut header = [0u8; HEADER_SIZE];
+        header.copy_from_slice(&self.bytes[..HEADER_SIZE]);
+        header[2..4].copy_from_slice(&((offset + 4 + INTEGRITY_SIZE - HEADER_SIZE) as u16).to_be_bytes());
+
+        let mut mac = HmacSha1::new_from_slice(key).expect("HMAC takes keys of any size");
+        mac.update(&header);
+        mac.update(&self.bytes[HEADER_SIZE..offset]);
+        mac.verify_slice(value).is_ok()
+    }
+
+    /// The address a server saw the request come from, in XOR-MAPPED-ADDRESS
+    pub fn mapped_address(&self) -> Option<SocketAddr> {
+        let value = self.attribute(XOR_MAPPED_ADDRESS)?;
+        let port = u16::from_be_bytes([*value.get(2)?, *value.get(3)?]) ^ (MAGIC_COOKIE >> 16) as u16;
+        let mut mask = MAGIC_COOKIE.to_be_bytes().to_vec();
+        mask.extend_from_slice(&self.transaction);
+
+        let ip = match (value[1], value.get(4..)) {
+            (1, Some(ip)) if ip.len() == 4 => {
+                IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(xor(ip, &mask)).expect("Checked the length")))
+            }
+            (2, Some(ip)) if ip.len() == 16 => {
+                IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(xor(ip, &mask)).expect("Checked the length")))
+            }
+            _ => return None,
+        };
+        Some(SocketAddr::new(ip, port))
+    }
+
+    /// Offset, type and value of every attribute, an error for one that runs past the end
+    fn attributes(&self) -> impl Iterator<Item = Result<(usize, u16, &'a [u8]), String>> + '_ {
+        let bytes = self.bytes;
+        let mut offset = HEADER_SIZE;
+        std::iter::from_fn(move || {
+            let header = bytes.get(offset..offset + 4)?;
+            let kind = u16::from_be_bytes([header[0], header[1]]);
+            let length = u16::from_be_bytes([header[2], header[3]]) as usize;
+            let start = offset;
+            let value = match bytes.get(offset + 4..offset + 4 + length) {
+                Some(value) => value,
+                None => {
+                    offset = bytes.len();
+                    return Some(Err(String::from("STUN attribute runs past the message")));
+                }
+            };
+            offset += 4 + length.next_multiple_of(4);
+            Some(Ok((start, kind, value)))
+        })
+    }
+}
+
+fn xor(bytes: &[u8], mask: &[u8]) -> Vec<u8> {
+    bytes.iter().zip(mask).map(|(byte, mask)| byte ^ mask).collect()
+}
+
+/// Builds a STUN message attribute by attribute
+pub struct Writer {
+    bytes: Vec<u8>,
+}
+
+impl Writer {
+    pub fn new(kind: u16, transaction: &[u8; TRANSACTION_SIZE]) -> Self {
+        let mut bytes = Vec::with_capacity(128);
+        bytes.extend_from_slice(&kind.to_be_bytes());
+        bytes.extend_from_slice(&[0, 0]);
+        bytes.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+        bytes.extend_from_slice(transaction);
+        Self { bytes }
+    }
+
+    pub fn attribute(mut self, kind: u16, value: &[u8]) -> Self {
+        self.bytes.extend_from_slice(&kind.to_be_bytes());
+        self.bytes.extend_from_slice(&(value.len() as u16).to_be_bytes());
+        self.bytes.extend_from_slice(value);
+        self.bytes.resize(self.bytes.len().next_multiple_of(4), 0);
+        self.set_length(self.bytes.len());
+        self
+    }
+
+    pub fn mapped_address(self, address: SocketAddr) -> Self {
+        let mut mask = MAGIC_COOKIE.to_be_bytes().to_vec();
+        mask.extend_from_slice(&self.bytes[8..HEADER_SIZE]);
+
+        let mut value = vec![0, if address.is_ipv4() { 1 } else { 2 }];
+        value.extend_from_slice(&(address.port() ^ (MAGIC_COOKIE >> 16) as u16).to_be_bytes());
+        match address.ip() {
+            IpAddr::V4(ip) => value.extend_from_slice(&xor(&ip.octets(), &mask)),
+            IpAddr::V6(ip) => value.extend_from_slice(&xor(&ip.octets(), &mask)),
+        }
+        self.attribute(XOR_MAPPED_ADDRESS, &value)
+    }
+
+    /// Adds MESSAGE-INTEGRITY keyed with `key`, then FINGERPRINT, and returns the message
+    pub fn finish(mut self, key: Option<&[u8]>) -> Vec<u8> {
+        if let Some(key) = key {
+            self.set_length(self.bytes.len() + 4 + INTEGRITY_SIZE);
+            let mut mac = HmacSha1::new_from_slice(key).expect("HMAC takes keys of any size");
+            mac.update(&self.bytes);
+            let integrity = mac.finalize().into_bytes();
+            self = self.attribute(MESSAGE_INTEGRITY, &integrity);
+        }
+
+        self.set_length(self.bytes.len() + 8);
+        let fingerprint = crc32fast::hash(&self.bytes) ^ FINGERPRINT_XOR;
+        self.attribute(FINGERPRINT, &fingerprint.to_be_bytes()).bytes
+    }
+
+    /// Sets the length in the header as if the message ended at `end`
+    fn set_length(&mut self, end: usize) {
+        self.bytes[2..4].copy_from_slice(&((end - HEADER_SIZE) as u16).to_be_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(text: &str) -> Vec<u8> {
+        let text: String = text.split_whitespace().collect();
+        (0..text.len()).step_by(2).map(|i| u8::from_str_radix(&text[i..i + 2], 16).unwrap()).collect()
+    }
+
+    /// RFC 5769 2.1, an ICE check as a browser sends it
+    const REQUEST: &str = "0001 0058 2112a442 b7e7a701 bc34d686 fa87dfae
+        80220010 5354554e 20746573 7420636c 69656e74 00240004 6e0001ff 80290008 932ff9b1 51263b36
+        00060009 6576746a 3a683676 59202020 00080014 9aeaa70c bfd8cb56 781ef2b5 b2d3f249 c1b571a2
+        80280004 e57a3bcf";
+    const PASSWORD: &[u8] = b"VOkJxbRl1RmTxUk/WvJxBt";
+
+    /// RFC 5769 2.2, the answer of a server seeing 192.0.2.1:32853
+    const RESPONSE: &str = "0101 003c 2112a442 b7e7a701 bc34d686 fa87dfae
+        8022000b 74657374 20766563 746f7220 00200008 0001a147 e112a643
+        00080014 2b91f599 fd9e90c3 8c7489f9 2af9ba53 f06be7d7 80280004 c07d4c96";
+
+    #[test]
+    fn request_of_rfc_5769() {
+        let bytes = hex(REQUEST);
+        let message = Message::parse(&bytes).unwrap();
+        assert_eq!(message.kind, BINDING_REQUEST);
+        assert_eq!(message.attribute(USERNAME), Some(&b"evtj:h6vY"[..]));
+        assert!(message.verify(PASSWORD));
+        assert!(!message.verify(b"VOkJxbRl1RmTxUk/WvJxBu"));
+    }
+
+    #[test]
+    fn response_of_rfc_5769() {
+        let bytes = hex(RESPONSE);
+        let message = Message::parse(&bytes).unwrap();
+        assert_eq!(message.kind, BINDING_SUCCESS);
+        assert_eq!(message.mapped_address(), Some("192.0.2.1:32853".parse().unwrap()));
+        assert!(message.verify(PASSWORD));
+    }
+
+    #[test]
+    fn fingerprint_of_rfc_5769() {
+        let bytes = hex(REQUEST);
+        let (message, fingerprint) = bytes.split_at(bytes.len() - 4);
+        let expected = u32::from_be_bytes(fingerprint.try_into().unwrap());
+        assert_eq!(crc32fast::hash(&message[..message.len() - 4]) ^ FINGERPRINT_XOR, expected);
+    }
+
+    #[test]
+    fn writer_round_trip() {
+        let request = hex(REQUEST);
+        let transaction = request[8..HEADER_SIZE].try_into().unwrap();
+        let written = Writer::new(BINDING_REQUEST, &transaction)
+            .attribute(0x8022, b"STUN test client")
+            .attribute(0x0024, &[0x6e, 0x00, 0x01, 0xff])
+            .attribute(0x8029, &[0x93, 0x2f, 0xf9, 0xb1, 0x51, 0x26, 0x3b, 0x36])
+            .attribute(USERNAME, b"evtj:h6vY")
+            .finish(Some(PASSWORD));
+
+        // Same as the RFC up to the padding of the username, which it fills with spaces and the integrity covers
+        let padding = HEADER_SIZE + 20 + 8 + 12 + 4 + 9;
+        assert_eq!(written.len(), request.len());
+        assert_eq!(written[..padding], request[..padding]);
+
+        let (message, fingerprint) = written.split_at(written.len() - 4);
+        let expected = u32::from_be_bytes(fingerprint.try_into().unwrap());
+        assert_eq!(crc32fast::hash(&message[..message.len() - 4]) ^ FINGERPRINT_XOR, expected);
+        let message = Message::parse(&written).unwrap();
+        assert!(message.verify(PASSWORD));
+        assert!(!message.verify(b"another password"));
+
+        let address = "192.0.2.1:32853".parse().unwrap();
+        let response = Writer::new(BINDING_SUCCESS, &transaction).mapped_address(address).finish(Some(PASSWORD));
+        assert_eq!(response[HEADER_SIZE..HEADER_SIZE + 12], hex(RESPONSE)[HEADER_SIZE + 16..HEADER_SIZE + 28]);
+        assert_eq!(Message::parse(&response).unwrap().mapped_address(), Some(address));
+
+        let address = "[2001:db8:1234:5678:11:2233:4455:6677]:32853".parse().unwrap();
+        let response = Writer::new(BINDING_SUCCESS, &transaction).mapped_address(address).finish(None);
+        assert_eq!(Message::parse(&response).unwrap().mapped_address(), Some(address));
+    }
+
+    #[test]
+    fn malformed_messages_are_refused() {
+        let mut bytes = hex(REQUEST);
+        assert!(Message::parse(&bytes[..bytes.len() - 4]).is_err(), "length doesn't match");
+
+        // An attribute running past the end
+        bytes[HEADER_SIZE + 3] = 0xff;
+        assert!(Message::parse(&bytes).is_err());
+
+        bytes[0] = 0x16; // DTLS handshake
+        assert!(!Message::is_stun(&bytes));
+    }
+}
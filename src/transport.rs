@@ -0,0 +1,733 @@
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use socket2::{Domain, Protocol, Socket, Type};
+
+use crate::commands::Transport;
+use crate::packet::Packet;
+use crate::quic::{Fingerprint, QuicClient, QuicServer};
+use crate::sender::Sender;
+
+/// Bytes of the length written in front of every datagram on a TCP connection
+pub const LENGTH_SIZE: usize = 4;
+
+/// Longest a write waits on a full send buffer or stream before the connection is given up
+pub const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Longest a viewer waits for the server to accept its connection
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Connections without a client or a join in progress are closed after this long
+pub const IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Upper bound of open TCP connections, new ones are refused past it
+pub const MAX_CONNECTIONS: usize = 256;
+
+/// Upper bound of datagrams read from one connection per poll, so one host can't starve the others
+const MAX_READS_PER_POLL: usize = 64;
+
+/// Pending TCP connections the kernel queues before they are accepted
+const BACKLOG: i32 = 128;
+
+/// Sends a datagram, over UDP, framed on a TCP connection or on a QUIC connection
+pub trait Transmit: Send + 'static {
+    /// Frame packets, which may be lost
+    fn send_to(&self, bytes: &[u8], address: SocketAddr) -> io::Result<usize>;
+
+    /// Control messages, on a reliable stream where the transport has one
+    fn send_control(&self, bytes: &[u8], address: SocketAddr) -> io::Result<usize> {
+        self.send_to(bytes, address)
+    }
+}
+
+impl Transmit for UdpSocket {
+    fn send_to(&self, bytes: &[u8], address: SocketAddr) -> io::Result<usize> {
+        UdpSocket::send_to(self, bytes, address)
+    }
+}
+
+/// Sending half of a TCP connection, every datagram goes to the peer whatever the address
+pub struct Framed(TcpStream);
+
+impl Transmit for Framed {
+    /// A write that failed may have stopped halfway through a datagram, the connection is shut down
+    /// so what is queued behind it fails right away instead of waiting out the timeout again
+    fn send_to(&self, bytes: &[u8], _address: SocketAddr) -> io::Result<usize> {
+        write_framed(&self.0, bytes)
+            .inspect_err(|_| {
+                let _ = self.0.shutdown(Shutdown::Both);
+            })
+            .map(|()| bytes.len())
+    }
+}
+
+/// A datagram with its length in front, how datagrams are written on streams
+pub fn frame(bytes: &[u8]) -> io::Result<Vec<u8>> {
+    if bytes.len() > Packet::MAX_DATAGRAM {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("datagram of {} bytes is too long", bytes.len())));
+    }
+
+    let mut framed = Vec::with_capacity(LENGTH_SIZE + bytes.len());
+    framed.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    framed.extend_from_slice(bytes);
+    Ok(framed)
+}
+
+/// Copies a datagram into `buffer`, truncated like UDP does when it doesn't fit, and returns its length
+pub fn truncate_into(datagram: &[u8], buffer: &mut [u8]) -> usize {
+    let amount = datagram.len().min(buffer.len());
+    buffer[..amount].copy_from_slice(&datagram[..amount]);
+    amount
+}
+
+/// Writes a datagram with its length in front
+/// Streams are non-blocking (the flag is shared with the reading half), a full send buffer is waited out
+/// here: giving up halfway through would leave the peer reading a length in the middle of a datagram.
+/// While it waits, the sender's queue keeps replacing frames, so a slow viewer gets the latest one
+fn write_framed(mut stream: &TcpStream, bytes: &[u8]) -> io::Result<()> {
+    let framed = frame(bytes)?;
+
+    let started = Instant::now();
+    let mut written = 0;
+    while written < framed.len() {
+        match stream.write(&framed[written..]) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(amount) => written += amount,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                if started.elapsed() > WRITE_TIMEOUT {
+                    return Err(io::Error::new(io::ErrorKind::TimedOut, "send buffer full for too long"));
+                }
+                thread::sleep(Duration::from_millis(1));
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(())
+}
+
+/// Splits what arrives on a TCP connection back into the datagrams written with `write_framed`
+#[derive(Default)]
+pub struct FrameReader {
+    buffer: Vec<u8>, // Bytes read but not returned yet, a partial datagram at most once drained
+}
+
+impl FrameReader {
+    /// Next datagram, reading from `stream` when none is buffered
+    /// None until the rest of it arrives, an error once the peer closed the connection or sent garbage
+    pub fn next(&mut self, mut stream: &TcpStream) -> io::Result<Option<Vec<u8>>> {
+        if let Some(datagram) = self.take()? {
+            return Ok(Some(datagram));
+        }
+
+        let mut chunk = [0u8; 16 * 1024];
+        match stream.read(&mut chunk) {
+            Ok(0) => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed by peer")),
+            Ok(amount) => {
+                self.buffer.extend_from_slice(&chunk[..amount]);
+                self.take()
+            }
+            Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// First buffered datagram, if all of it is there
+    fn take(&mut self) -> io::Result<Option<Vec<u8>>> {
+        if self.buffer.len() < LENGTH_SIZE {
+            return Ok(None);
+        }
+
+        let length = u32::from_le_bytes([self.buffer[0], self.buffer[1], self.buffer[2], self.buffer[3]]) as usize;
+        if length > Packet::MAX_DATAGRAM {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("datagram of {} bytes is too long", length)));
+        }
+
+        if self.buffer.len() < LENGTH_SIZE + length {
+            return Ok(None);
+        }
+
+        let datagram = self.buffer[LENGTH_SIZE..LENGTH_SIZE + length].to_vec();
+        self.buffer.drain(..LENGTH_SIZE + length);
+        Ok(Some(datagram))
+    }
+}
+
+/// A viewer's TCP connection to the server
+struct Connection {
+    stream: TcpStream,
+    reader: FrameReader,
+    sender: Arc<Sender>, // Every write to the connection goes through it, so datagrams are never interleaved
+    opened: Instant,
+}
+
+impl Connection {
+    fn open(stream: TcpStream) -> io::Result<Self> {
+        stream.set_nonblocking(true)?;
+        stream.set_nodelay(true)?;
+        let sender = Sender::spawn(Framed(stream.try_clone()?))?;
+        Ok(Self { stream, reader: FrameReader::default(), sender: Arc::new(sender), opened: Instant::now() })
+    }
+}
+
+/// The TCP connections of every viewer, each one stands for the address it comes from
+pub struct Connections {
+    listener: TcpListener,
+    connections: HashMap<SocketAddr, Connection>,
+    ready: VecDeque<(SocketAddr, Vec<u8>)>, // Datagrams read but not received yet
+    closed: Vec<SocketAddr>,                // Connections the peer closed since the last `take_closed`
+}
+
+impl Connections {
+    /// Accepts new connections and reads what every connection has
+    fn poll(&mut self) {
+        loop {
+            match self.listener.accept() {
+                Ok((_, address)) if self.connections.len() >= MAX_CONNECTIONS => {
+                    eprintln!("Too many connections, refused {}", address);
+                }
+                Ok((stream, address)) => match Connection::open(stream) {
+                    Ok(connection) => {
+                        self.connections.insert(address, connection);
+                    }
+                    Err(e) => eprintln!("Error setting up connection from {}: {}", address, e),
+                },
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    eprintln!("Error accepting connection: {}", e);
+                    break;
+                }
+            }
+        }
+
+        let mut closed = Vec::new();
+        for (address, connection) in &mut self.connections {
+            for _ in 0..MAX_READS_PER_POLL {
+                match connection.reader.next(&connection.stream) {
+                    Ok(Some(datagram)) => self.ready.push_back((*address, datagram)),
+                    Ok(None) => break,
+                    Err(e) => {
+                        println!("Connection from {} closed: {}", address, e);
+                        closed.push(*address);
+                        break;
+                    }
+                }
+            }
+        }
+
+        for address in &closed {
+            self.connections.remove(address);
+        }
+        self.closed.extend(closed);
+    }
+}
+
+/// Where the server receives from and sends to: one UDP socket, or a TCP listener or QUIC endpoint
+/// and its connections
+pub enum Listener {
+    Udp(UdpSocket),
+    Tcp(Connections),
+    Quic(QuicServer),
+}
+
+impl Listener {
+    /// `certificate` is the file QUIC keeps its certificate in, see `QuicServer::bind`
+    pub fn bind(transport: Transport, ip: Option<IpAddr>, port: u16, certificate: Option<&Path>) -> io::Result<Self> {
+        let listener = match transport {
+            Transport::Udp => Listener::Udp(bind(ip, port, Type::DGRAM, Protocol::UDP)?.into()),
+            Transport::Tcp => {
+                Listener::Tcp(Connections {
+                    listener: listen(ip, port)?,
+                    connections: HashMap::new(),
+                    ready: VecDeque::new(),
+                    closed: Vec::new(),
+                })
+            }
+            Transport::Quic => {
+                Listener::Quic(QuicServer::bind(bind(ip, port, Type::DGRAM, Protocol::UDP)?.into(), certificate)?)
+            }
+        };
+
+        match &listener {
+            Listener::Udp(socket) => socket.set_nonblocking(true)?,
+            Listener::Tcp(connections) => connections.listener.set_nonblocking(true)?,
+            Listener::Quic(_) => {}
+        }
+        Ok(listener)
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            Listener::Udp(socket) => socket.local_addr(),
+            Listener::Tcp(connections) => connections.listener.local_addr(),
+            Listener::Quic(quic) => quic.local_addr(),
+        }
+    }
+
+    /// Next datagram from any viewer, `WouldBlock` when there is none
+    /// Datagrams longer than `buffer` are truncated, like UDP does
+    pub fn recv_from(&mut self, buffer: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let connections = match self {
+            Listener::Udp(socket) => return socket.recv_from(buffer),
+            Listener::Tcp(connections) => connections,
+            Listener::Quic(quic) => return quic.recv_from(buffer),
+        };
+
+        if connections.ready.is_empty() {
+            connections.poll();
+        }
+
+        match connections.ready.pop_front() {
+            Some((address, datagram)) => Ok((truncate_into(&datagram, buffer), address)),
+            None => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+
+    /// Sends to a host that has no sender of its own yet
+    /// Over TCP and QUIC it is queued on the connection's sender, behind what it already sends
+    pub fn send_to(&self, bytes: &[u8], address: SocketAddr) -> io::Result<()> {
+        match self {
+            Listener::Udp(socket) => socket.send_to(bytes, address).map(|_| ()),
+            Listener::Tcp(connections) => match connections.connections.get(&address) {
+                Some(connection) if connection.sender.control(address, bytes.to_vec()) => Ok(()),
+                Some(_) => Err(io::Error::new(io::ErrorKind::WouldBlock, "send queue full")),
+                None => Err(io::ErrorKind::NotConnected.into()),
+            },
+            Listener::Quic(quic) => quic.send_to(bytes, address),
+        }
+    }
+
+    /// Sender for a new client at `address`: its own over UDP, the one of its connection otherwise
+    pub fn sender(&self, address: SocketAddr) -> io::Result<Arc<Sender>> {
+        match self {
+            Listener::Udp(socket) => Ok(Arc::new(Sender::spawn(socket.try_clone()?)?)),
+            Listener::Tcp(_) | Listener::Quic(_) => {
+                self.connection(address).ok_or_else(|| io::ErrorKind::NotConnected.into())
+            }
+        }
+    }
+
+    /// Sender of the connection from `address`, a client that rejoins on a new connection moves to it
+    /// None over UDP, where a client keeps its sender wherever it comes from
+    pub fn connection(&self, address: SocketAddr) -> Option<Arc<Sender>> {
+        match self {
+            Listener::Udp(_) => None,
+            Listener::Tcp(connections) => connections.connections.get(&address).map(|connection| Arc::clone(&connection.sender)),
+            Listener::Quic(quic) => quic.connection(address),
+        }
+    }
+
+    /// Connections the viewer closed since the last call, its client and join are over
+    pub fn take_closed(&mut self) -> Vec<SocketAddr> {
+        match self {
+            Listener::Udp(_) => Vec::new(),
+            Listener::Tcp(connections) => std::mem::take(&mut connections.closed),
+            Listener::Quic(quic) => quic.take_closed(),
+        }
+    }
+
+    /// Closes connections nothing uses anymore: the client left or was removed, or the host never joined
+    pub fn prune(&mut self, in_use: impl Fn(&SocketAddr) -> bool) {
+        match self {
+            Listener::Udp(_) => {}
+            Listener::Tcp(connections) => connections.connections.retain(|address, connection| {
+                let keep = in_use(address) || connection.opened.elapsed() < IDLE_TIMEOUT;
+                if !keep {
+                    println!("Closing idle connection from {}", address);
+                }
+                keep
+            }),
+            Listener::Quic(quic) => quic.prune(in_use),
+        }
+    }
+
+    /// Closes every connection once the goodbyes are sent
+    /// TCP connections are closed by the system when the server exits, QUIC ones have to be told
+    pub fn close(&self) {
+        if let Listener::Quic(quic) = self {
+            quic.close();
+        }
+    }
+}
+
+/// Every listener of the server: the one of its `--transport`, and a QUIC endpoint next to it with `--quic`,
+/// so each viewer picks its transport with its own `--transport`
+/// Viewers are told apart by address, QUIC connections are looked up first: a TCP and a QUIC viewer only mix up
+/// when they come from the same host and port number
+pub struct Listeners {
+    main: Listener,
+    quic: Option<Listener>, // Always a `Listener::Quic`
+    quic_first: bool,       // Which one `recv_from` asks first, they take turns so neither starves the other
+}
+
+impl Listeners {
+    /// `quic` is where to also accept QUIC viewers, `certificate` the file QUIC keeps its certificate in
+    pub fn bind(
+        transport: Transport,
+        ip: Option<IpAddr>,
+        port: u16,
+        quic: Option<(Option<IpAddr>, u16)>,
+        certificate: Option<&Path>,
+    ) -> io::Result<Self> {
+        let main = Listener::bind(transport, ip, port, certificate)?;
+        let quic = quic.map(|(ip, port)| Listener::bind(Transport::Quic, ip, port, certificate)).transpose()?;
+        Ok(Self { main, quic, quic_first: false })
+    }
+
+    /// Address of the `--transport` listener
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.main.local_addr()
+    }
+
+    /// Address of the QUIC endpoint next to it, if there is one
+    pub fn quic_addr(&self) -> Option<io::Result<SocketAddr>> {
+        self.quic.as_ref().map(Listener::local_addr)
+    }
+
+    /// Fingerprint of the QUIC certificate, when either listener is QUIC
+    pub fn fingerprint(&self) -> Option<Fingerprint> {
+        match (&self.main, &self.quic) {
+            (Listener::Quic(quic), _) | (_, Some(Listener::Quic(quic))) => Some(quic.fingerprint()),
+            _ => None,
+        }
+    }
+
+    /// Next datagram from any viewer on either listener, `WouldBlock` when there is none
+    pub fn recv_from(&mut self, buffer: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let Some(quic) = &mut self.quic else {
+            return self.main.recv_from(buffer);
+        };
+
+        self.quic_first = !self.quic_first;
+        let (first, second) = if self.quic_first { (quic, &mut self.main) } else { (&mut self.main, quic) };
+        match first.recv_from(buffer) {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => second.recv_from(buffer),
+            received => received,
+        }
+    }
+
+    /// The listener the viewer at `address` came from
+    fn of(&self, address: SocketAddr) -> &Listener {
+        match &self.quic {
+            Some(quic) if quic.connection(address).is_some() => quic,
+            _ => &self.main,
+        }
+    }
+
+    pub fn send_to(&self, bytes: &[u8], address: SocketAddr) -> io::Result<()> {
+        self.of(address).send_to(bytes, address)
+    }
+
+    pub fn sender(&self, address: SocketAddr) -> io::Result<Arc<Sender>> {
+        self.of(address).sender(address)
+    }
+
+    pub fn connection(&self, address: SocketAddr) -> Option<Arc<Sender>> {
+        self.of(address).connection(address)
+    }
+
+    pub fn take_closed(&mut self) -> Vec<SocketAddr> {
+        let mut closed = self.main.take_closed();
+        if let Some(quic) = &mut self.quic {
+            closed.extend(quic.take_closed());
+        }
+        closed
+    }
+
+    pub fn prune(&mut self, in_use: impl Fn(&SocketAddr) -> bool) {
+        self.main.prune(&in_use);
+        if let Some(quic) = &mut self.quic {
+            quic.prune(&in_use);
+        }
+    }
+
+    pub fn close(&self) {
+        self.main.close();
+        if let Some(quic) = &self.quic {
+            quic.close();
+        }
+    }
+}
+
+/// Listens for TCP connections on `ip`, or on every address when None, like the server's own socket
+pub fn listen(ip: Option<IpAddr>, port: u16) -> io::Result<TcpListener> {
+    let socket = bind(ip, port, Type::STREAM, Protocol::TCP)?;
+    socket.listen(BACKLOG)?;
+    Ok(socket.into())
+}
+
+/// Binds the server's socket to `ip`, or to every address when None: a single dual-stack IPv6
+/// socket that also receives IPv4 (as mapped addresses), or IPv4 only if the host has no IPv6
+fn bind(ip: Option<IpAddr>, port: u16, kind: Type, protocol: Protocol) -> io::Result<Socket> {
+    let socket = |ip: IpAddr, dual_stack: bool| -> io::Result<Socket> {
+        let address = SocketAddr::new(ip, port);
+        let socket = Socket::new(Domain::for_address(address), kind, Some(protocol))?;
+        if dual_stack {
+            socket.set_only_v6(false)?;
+        }
+        // A restarted server can listen again while the connections of the last one linger
+        if kind == Type::STREAM {
+            socket.set_reuse_address(true)?;
+        }
+        socket.bind(&address.into())?;
+        Ok(socket)
+    };
+
+    match ip {
+        Some(ip) => socket(ip, false),
+        None => socket(IpAddr::V6(Ipv6Addr::UNSPECIFIED), true).or_else(|e| {
+            println!("No dual-stack IPv6 socket ({}), listening on IPv4 only", e);
+            socket(IpAddr::V4(Ipv4Addr::UNSPECIFIED), false)
+        }),
+    }
+}
+
+/// The viewer's end: a UDP socket connected to the server, or a TCP or QUIC connection to it
+/// Over TCP, datagrams are written from a sender thread: a full connection must not freeze the window
+pub enum Link {
+    Udp(UdpSocket),
+    Tcp { stream: TcpStream, reader: FrameReader, sender: Sender, server: SocketAddr, bind: SocketAddr },
+    Quic(QuicClient),
+}
+
+impl Link {
+    /// `fingerprint` pins the certificate of a QUIC server
+    pub fn connect(
+        transport: Transport,
+        server: SocketAddr,
+        bind: SocketAddr,
+        fingerprint: Option<Fingerprint>,
+    ) -> io::Result<Self> {
+        match transport {
+            Transport::Udp => {
+                let socket = UdpSocket::bind(bind)?;
+                socket.set_nonblocking(true)?;
+                socket.connect(server)?;
+                Ok(Link::Udp(socket))
+            }
+            Transport::Tcp => {
+                let stream = connect(server, bind)?;
+                let sender = Sender::spawn(Framed(stream.try_clone()?))?;
+                Ok(Link::Tcp { stream, reader: FrameReader::default(), sender, server, bind })
+            }
+            Transport::Quic => Ok(Link::Quic(QuicClient::connect(server, bind, fingerprint)?)),
+        }
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            Link::Udp(socket) => socket.local_addr(),
+            Link::Tcp { stream, .. } => stream.local_addr(),
+            Link::Quic(quic) => quic.local_addr(),
+        }
+    }
+
+    /// Over TCP, `WouldBlock` when the connection is so backed up that the sender's queue is full
+    pub fn send(&self, bytes: &[u8]) -> io::Result<usize> {
+        match self {
+            Link::Udp(socket) => socket.send(bytes),
+            Link::Tcp { sender, server, .. } => match sender.control(*server, bytes.to_vec()) {
+                true => Ok(bytes.len()),
+                false => Err(io::Error::new(io::ErrorKind::WouldBlock, "send queue full")),
+            },
+            Link::Quic(quic) => quic.send(bytes),
+        }
+    }
+
+    /// Next datagram from the server, `WouldBlock` when there is none
+    /// Over TCP and QUIC, `UnexpectedEof` once the connection ended
+    pub fn recv(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Link::Udp(socket) => socket.recv(buffer),
+            Link::Tcp { stream, reader, .. } => match reader.next(stream)? {
+                Some(datagram) => Ok(truncate_into(&datagram, buffer)),
+                None => Err(io::ErrorKind::WouldBlock.into()),
+            },
+            Link::Quic(quic) => quic.recv(buffer),
+        }
+    }
+
+    /// Opens a new connection before joining again, the old one died with the server or the network
+    /// Nothing to do over UDP
+    pub fn reconnect(&mut self) -> io::Result<()> {
+        match self {
+            Link::Udp(_) => {}
+            Link::Tcp { stream, reader, sender, server, bind } => {
+                *stream = connect(*server, *bind)?;
+                *reader = FrameReader::default();
+                // The old sender stops once it gave up on the old connection
+                *sender = Sender::spawn(Framed(stream.try_clone()?))?;
+            }
+            Link::Quic(quic) => quic.reconnect()?,
+        }
+        Ok(())
+    }
+
+    /// Leaves, once what was sent is on its way
+    /// The system does it for UDP when the viewer exits, over TCP the sender is waited for, and QUIC
+    /// connections have to be closed
+    pub fn close(&self) {
+        match self {
+            Link::Udp(_) => {}
+            Link::Tcp { sender, .. } => sender.finish(),
+            Link::Quic(quic) => quic.close(),
+        }
+    }
+
+    /// Largest datagram the server can send in one packet, when the transport has such a limit
+    pub fn max_datagram_size(&self) -> Option<usize> {
+        match self {
+            Link::Quic(quic) => quic.max_datagram_size(),
+            Link::Udp(_) | Link::Tcp { .. } => None,
+        }
+    }
+}
+
+fn connect(server: SocketAddr, bind: SocketAddr) -> io::Result<TcpStream> {
+    let socket = Socket::new(Domain::for_address(server), Type::STREAM, Some(Protocol::TCP))?;
+    socket.bind(&bind.into())?;
+    socket.connect_timeout(&server.into(), CONNECT_TIMEOUT)?;
+
+    let stream: TcpStream = socket.into();
+    stream.set_nodelay(true)?;
+    stream.set_nonblocking(true)?;
+    Ok(stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOCALHOST: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+    /// Polls until `f` stops returning `WouldBlock`
+    fn poll<T>(mut f: impl FnMut() -> io::Result<T>) -> T {
+        let started = Instant::now();
+        loop {
+            match f() {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    assert!(started.elapsed() < Duration::from_secs(5), "nothing arrived");
+                    thread::sleep(Duration::from_millis(1));
+                }
+                result => return result.unwrap(),
+            }
+        }
+    }
+
+    fn server_receive(listener: &mut Listener) -> (Vec<u8>, SocketAddr) {
+        let mut buffer = [0u8; Packet::MAX_DATAGRAM];
+        let (amount, address) = poll(|| listener.recv_from(&mut buffer));
+        (buffer[..amount].to_vec(), address)
+    }
+
+    fn viewer_receive(link: &mut Link) -> Vec<u8> {
+        let mut buffer = [0u8; Packet::MAX_DATAGRAM];
+        let amount = poll(|| link.recv(&mut buffer));
+        buffer[..amount].to_vec()
+    }
+
+    #[test]
+    fn udp_and_quic_viewers_on_one_server() {
+        let mut listeners = Listeners::bind(Transport::Udp, Some(LOCALHOST), 0, Some((Some(LOCALHOST), 0)), None).unwrap();
+        let quic = listeners.quic_addr().unwrap().unwrap();
+        let mut links = [
+            Link::connect(Transport::Udp, listeners.local_addr().unwrap(), SocketAddr::new(LOCALHOST, 0), None).unwrap(),
+            Link::connect(Transport::Quic, quic, SocketAddr::new(LOCALHOST, 0), listeners.fingerprint()).unwrap(),
+        ];
+
+        let mut buffer = [0u8; Packet::MAX_DATAGRAM];
+        for (i, link) in links.iter().enumerate() {
+            link.send(&[i as u8]).unwrap();
+            let (amount, viewer) = poll(|| listeners.recv_from(&mut buffer));
+            assert_eq!(&buffer[..amount], [i as u8]);
+            assert_eq!(viewer, link.local_addr().unwrap());
+        }
+
+        // Each answer goes back over the transport its viewer picked
+        let (udp, quic) = (links[0].local_addr().unwrap(), links[1].local_addr().unwrap());
+        assert!(listeners.connection(udp).is_none());
+        assert!(listeners.connection(quic).is_some());
+        listeners.send_to(b"udp", udp).unwrap();
+        listeners.send_to(b"quic", quic).unwrap();
+        assert_eq!(viewer_receive(&mut links[0]), b"udp");
+        assert_eq!(viewer_receive(&mut links[1]), b"quic");
+
+        for link in &links {
+            link.close();
+        }
+    }
+
+    #[test]
+    fn full_tcp_connection_only_gets_the_newest_frames() {
+        let mut listener = Listener::bind(Transport::Tcp, Some(LOCALHOST), 0, None).unwrap();
+        let server = listener.local_addr().unwrap();
+        let mut link = Link::connect(Transport::Tcp, server, SocketAddr::new(LOCALHOST, 0), None).unwrap();
+        link.send(b"hello").unwrap();
+        let (_, viewer) = server_receive(&mut listener);
+        let sender = listener.sender(viewer).unwrap();
+
+        // Far more than the socket buffers of both sides hold, while the viewer doesn't read
+        const FRAMES: u32 = 1000;
+        for frame_id in 0..FRAMES {
+            let packet = Packet::new(0, frame_id, frame_id, 0, 1, &[frame_id as u8; 60_000]);
+            sender.frame(vec![(viewer, packet.to_bytes())], Duration::ZERO);
+        }
+        thread::sleep(Duration::from_millis(100));
+
+        let mut received = Vec::new();
+        while received.last() != Some(&(FRAMES - 1)) {
+            received.push(Packet::from_bytes(&viewer_receive(&mut link)).unwrap().frame_id);
+        }
+        assert!(received.len() < FRAMES as usize / 2, "{} of {} frames were queued", received.len(), FRAMES);
+        assert!(received.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn framing_round_trip() {
+        let datagrams: Vec<Vec<u8>> = vec![vec![1], vec![], vec![2; Packet::MAX_DATAGRAM], vec![3; 10]];
+        let mut bytes = Vec::new();
+        for datagram in &datagrams {
+            bytes.extend_from_slice(&frame(datagram).unwrap());
+        }
+
+        let mut reader = FrameReader::default();
+        let mut read = Vec::new();
+        // Arriving a few bytes at a time, lengths and datagrams split anywhere
+        for chunk in bytes.chunks(3) {
+            reader.buffer.extend_from_slice(chunk);
+            while let Some(datagram) = reader.take().unwrap() {
+                read.push(datagram);
+            }
+        }
+        assert_eq!(read, datagrams);
+    }
+
+    #[test]
+    fn tcp_sends_never_block() {
+        // Accepts the connection and never reads from it
+        let listener = TcpListener::bind((LOCALHOST, 0)).unwrap();
+        let server = listener.local_addr().unwrap();
+        let link = Link::connect(Transport::Tcp, server, SocketAddr::new(LOCALHOST, 0), None).unwrap();
+        let _connection = listener.accept().unwrap();
+
+        let datagram = vec![0u8; 60_000];
+        let started = Instant::now();
+        let mut full = false;
+        for _ in 0..1000 {
+            match link.send(&datagram) {
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => full = true,
+                Err(e) => panic!("{}", e),
+            }
+        }
+
+        assert!(full, "the queue is bounded");
+        assert!(started.elapsed() < Duration::from_secs(1), "took {:?}", started.elapsed());
+    }
+}
@@ -0,0 +1,1158 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use tungstenite::handshake::derive_accept_key;
+use tungstenite::protocol::{Role, WebSocket, WebSocketConfig};
+use tungstenite::Message;
+
+use crate::auth::{self, Secret, NONCE_SIZE};
+use crate::comm::{Credential, Rendition};
+use crate::peer::{self, Peer};
+use crate::rtsp;
+use crate::transport::{self, WRITE_TIMEOUT};
+
+/// Encoded JPEG frame, shared by every browser viewer of its rendition
+pub type Frame = tungstenite::Bytes;
+
+/// Identifies a browser connection, in the order they were accepted
+pub type ViewerId = u64;
+
+/// Viewer page, the script in it joins over the WebSocket and shows the frames it is sent
+const PAGE: &str = include_str!("viewer.html");
+
+/// Longest request head a browser may send, the page and the WebSocket need nothing more
+pub const MAX_REQUEST: usize = 8 * 1024;
+
+/// How long a browser has to send its request once connected
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Longest a connection waits for a message from its browser before it looks for frames to send
+pub const POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+/// Longest message a browser may send: short lines of text, and its WebRTC offer
+const MAX_MESSAGE: usize = 16 * 1024;
+
+/// Upper bound of open browser connections, new ones are refused past it
+const MAX_CONNECTIONS: usize = 64;
+
+/// How long closing waits for the goodbyes to reach the browsers
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long an MJPEG or snapshot request waits to be let in, then for its first frame
+pub const JOIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Separates the JPEGs of an MJPEG stream
+const BOUNDARY: &str = "frame";
+
+/// Where WHEP players post their offer, their session is a resource under it
+const WHEP: &str = "/whep";
+
+/// Mailboxes of the WHEP sessions by id, for the player to close its own
+type Sessions = Arc<Mutex<HashMap<String, Arc<Mailbox>>>>;
+
+/// Offer of a WHEP player, answered once the server lets it in
+struct Whep<'a> {
+    offer: String,
+    settings: &'a Arc<peer::Settings>,
+    sessions: &'a Sessions,
+}
+
+/// How a viewer of the web server watches
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    Page,     // The viewer page, over a WebSocket
+    Mjpeg,    // Every frame as a part of a multipart/x-mixed-replace response
+    Snapshot, // The latest frame as a single JPEG
+    Rtsp,     // RTP/JPEG packets, to an RTSP player
+    Webrtc,   // AV1 over WebRTC, to a WHEP player
+}
+
+impl fmt::Display for Mode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Mode::Page => "browser",
+            Mode::Mjpeg => "mjpeg",
+            Mode::Snapshot => "snapshot",
+            Mode::Rtsp => "rtsp",
+            Mode::Webrtc => "whep",
+        })
+    }
+}
+
+/// What browsers ask the server, in the order each of them asks it
+pub enum Event {
+    Opened { address: SocketAddr, mailbox: Arc<Mailbox> },
+    Hello { mode: Mode, stream: String, max_width: u16, max_height: u16, rendition: Option<u8> },
+    Answer(Credential),
+    Subscribe { rendition: Option<u8> }, // None to let the server pick again
+    Closed { reason: String },
+}
+
+impl Event {
+    /// Parses a message from the page:
+    /// `hello <width> <height> [stream]`, `password <mac>`, `invite <id> <mac>` or `subscribe <id | auto>`
+    fn parse(text: &str) -> Option<Self> {
+        let (command, arguments) = text.split_once(' ').unwrap_or((text, ""));
+        let mut words = arguments.split(' ');
+
+        match command {
+            "hello" => {
+                let mut parts = arguments.splitn(3, ' ');
+                Some(Event::Hello {
+                    mode: Mode::Page,
+                    max_width: parts.next()?.parse().ok()?,
+                    max_height: parts.next()?.parse().ok()?,
+                    stream: parts.next().unwrap_or("").to_string(),
+                    rendition: None,
+                })
+            }
+            "password" => Some(Event::Answer(Credential::Password { mac: auth::from_hex(words.next()?)? })),
+            "invite" => Some(Event::Answer(Credential::Invite {
+                id: auth::from_hex(words.next()?)?,
+                mac: auth::from_hex(words.next()?)?,
+            })),
+            "subscribe" => match words.next()? {
+                "auto" => Some(Event::Subscribe { rendition: None }),
+                id => Some(Event::Subscribe { rendition: Some(id.parse().ok()?) }),
+            },
+            _ => None,
+        }
+    }
+}
+
+/// What the server tells a browser, sent as a line of text
+pub enum Reply {
+    Challenge([u8; NONCE_SIZE]),
+    Accept { stream: String },
+    Renditions { current: u8, automatic: bool, renditions: Vec<Rendition> },
+    Reject(String),
+    Goodbye(String),
+}
+
+impl fmt::Display for Reply {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Reply::Challenge(nonce) => write!(f, "challenge {}", auth::to_hex(nonce)),
+            Reply::Accept { stream } => write!(f, "accept {}", stream),
+            Reply::Renditions { current, automatic, renditions } => {
+                write!(f, "renditions {} {}", current, if *automatic { "auto" } else { "manual" })?;
+                for rendition in renditions {
+                    write!(f, " {}:{}x{}", rendition.id, rendition.width, rendition.height)?;
+                }
+                Ok(())
+            }
+            Reply::Reject(reason) => write!(f, "reject {}", reason),
+            Reply::Goodbye(reason) => write!(f, "goodbye {}", reason),
+        }
+    }
+}
+
+/// What waits to be sent to a browser
+#[derive(Default)]
+struct Post {
+    replies: VecDeque<Reply>,
+    frame: Option<Frame>, // Only the latest one, a slow browser skips frames rather than fall behind
+    closing: bool,
+}
+
+/// Hands replies and frames from the server to the thread of a browser connection
+#[derive(Default)]
+pub struct Mailbox {
+    post: Mutex<Post>,
+    posted: Condvar, // For the connections that have nothing to read, and wait on the mailbox
+}
+
+impl Mailbox {
+    fn lock(&self) -> MutexGuard<'_, Post> {
+        self.post.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    pub fn reply(&self, reply: Reply) {
+        self.lock().replies.push_back(reply);
+        self.posted.notify_one();
+    }
+
+    /// Queues a frame in place of the one not sent yet, returns whether there was one
+    pub fn frame(&self, frame: Frame) -> bool {
+        let replaced = self.lock().frame.replace(frame).is_some();
+        self.posted.notify_one();
+        replaced
+    }
+
+    /// Says goodbye to the browser and closes the connection
+    pub fn close(&self, reason: &str) {
+        let mut post = self.lock();
+        post.replies.push_back(Reply::Goodbye(reason.to_string()));
+        post.closing = true;
+        self.posted.notify_one();
+    }
+
+    pub fn take(&self) -> (VecDeque<Reply>, Option<Frame>, bool) {
+        Self::empty(&mut self.lock())
+    }
+
+    /// Like `take`, but waits up to `timeout` for something to be posted
+    pub fn wait(&self, timeout: Duration) -> (VecDeque<Reply>, Option<Frame>, bool) {
+        let post = self.lock();
+        let (mut post, _) = self
+            .posted
+            .wait_timeout_while(post, timeout, |post| post.replies.is_empty() && post.frame.is_none() && !post.closing)
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        Self::empty(&mut post)
+    }
+
+    fn empty(post: &mut Post) -> (VecDeque<Reply>, Option<Frame>, bool) {
+        (std::mem::take(&mut post.replies), post.frame.take(), post.closing)
+    }
+}
+
+/// Where connection threads tell the server what their viewer asks
+pub type Events = mpsc::Sender<(ViewerId, Event)>;
+
+/// Serves one connection, from its own thread
+type Serve = Arc<dyn Fn(TcpStream, ViewerId, &Events) + Send + Sync>;
+
+/// Servers of the viewers that don't speak our protocol: browsers and players, every connection served
+/// from its own thread. The server learns what they ask from `next` and answers through their mailbox
+pub struct Web {
+    events: Receiver<(ViewerId, Event)>,
+    sender: Events,
+    ids: Arc<AtomicU64>, // Next viewer id, shared by every listener
+    mailboxes: HashMap<ViewerId, Arc<Mailbox>>, // Of the open connections, to close them
+}
+
+impl Web {
+    pub fn new() -> Self {
+        let (sender, events) = mpsc::channel();
+        Self { events, sender, ids: Arc::new(AtomicU64::new(1)), mailboxes: HashMap::new() }
+    }
+
+    /// Serves the viewer page, MJPEG and snapshots over HTTP, returns the address listened on
+    /// With `webrtc`, pages whose browser can decode AV1 are offered it over WebRTC instead of JPEGs, and
+    /// WHEP players can watch that way too
+    pub fn serve_http(&self, ip: Option<IpAddr>, port: u16, webrtc: Option<Arc<peer::Settings>>) -> io::Result<SocketAddr> {
+        let sessions = Sessions::default();
+        self.listen(
+            ip,
+            port,
+            "web",
+            Arc::new(move |stream, id, events: &Events| serve(stream, id, events, webrtc.as_ref(), &sessions)),
+        )
+    }
+
+    /// Serves RTSP players, returns the address listened on
+    pub fn serve_rtsp(&self, ip: Option<IpAddr>, port: u16) -> io::Result<SocketAddr> {
+        self.listen(ip, port, "rtsp", Arc::new(rtsp::serve))
+    }
+
+    fn listen(&self, ip: Option<IpAddr>, port: u16, name: &'static str, serve: Serve) -> io::Result<SocketAddr> {
+        let listener = transport::listen(ip, port)?;
+        let address = listener.local_addr()?;
+        let (events, ids) = (self.sender.clone(), Arc::clone(&self.ids));
+
+        thread::Builder::new()
+            .name(String::from(name))
+            .spawn(move || accept(listener, name, events, ids, serve))?;
+        Ok(address)
+    }
+
+    /// Next thing a browser asked, None when there is nothing new
+    pub fn next(&mut self) -> Option<(ViewerId, Event)> {
+        let (id, event) = self.events.try_recv().ok()?;
+        self.track(id, &event);
+        Some((id, event))
+    }
+
+    fn track(&mut self, id: ViewerId, event: &Event) {
+        match event {
+            Event::Opened { mailbox, .. } => {
+                self.mailboxes.insert(id, Arc::clone(mailbox));
+            }
+            Event::Closed { .. } => {
+                self.mailboxes.remove(&id);
+            }
+            _ => {}
+        }
+    }
+
+    /// Says goodbye to every browser, and waits a little for the connections to close
+    pub fn close(&mut self, reason: &str) {
+        for mailbox in self.mailboxes.values() {
+            mailbox.close(reason);
+        }
+
+        let deadline = Instant::now() + CLOSE_TIMEOUT;
+        while !self.mailboxes.is_empty() {
+            let left = deadline.saturating_duration_since(Instant::now());
+            match self.events.recv_timeout(left) {
+                Ok((id, event)) => self.track(id, &event),
+                Err(_) => break,
+            }
+        }
+    }
+}
+
+/// Accepts connections until the server exits
+fn accept(listener: TcpListener, name: &'static str, events: Events, ids: Arc<AtomicU64>, serve: Serve) {
+    let open = Arc::new(AtomicUsize::new(0));
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("Error accepting {} connection: {}", name, e);
+                continue;
+            }
+        };
+
+        if open.load(Ordering::SeqCst) >= MAX_CONNECTIONS {
+            println!("Too many {} connections, refusing {:?}", name, stream.peer_addr());
+            continue;
+        }
+
+        open.fetch_add(1, Ordering::SeqCst);
+        let id = ids.fetch_add(1, Ordering::SeqCst);
+        let (events, open, serve) = (events.clone(), Arc::clone(&open), Arc::clone(&serve));
+        let spawned = thread::Builder::new().name(format!("{} viewer", name)).spawn(move || {
+            serve(stream, id, &events);
+            open.fetch_sub(1, Ordering::SeqCst);
+        });
+
+        if let Err(e) = spawned {
+            eprintln!("Error starting {} connection thread: {}", name, e);
+        }
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// Head of an HTTP or RTSP request, they are alike
+pub struct Request {
+    pub method: String,
+    pub target: String,
+    headers: Vec<(String, String)>,
+}
+
+impl Request {
+    /// Reads the request line and headers, the body is left to `read_body`
+    fn read(stream: &mut TcpStream) -> io::Result<Self> {
+        stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+        let mut head = Vec::new();
+        let mut chunk = [0u8; 1024];
+        while !head.ends_with(b"\r\n\r\n") {
+            if head.len() >= MAX_REQUEST {
+                return Err(invalid("request too large"));
+            }
+
+            // Byte by byte past the first read, so nothing after the head is consumed
+            let wanted = if head.is_empty() { 4 } else { 1 };
+            let read = stream.read(&mut chunk[..wanted])?;
+            if read == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            head.extend_from_slice(&chunk[..read]);
+        }
+
+        Self::parse(&String::from_utf8(head).map_err(|_| invalid("request is not UTF-8"))?)
+    }
+
+    /// Parses the request line and headers, up to the empty line that ends them
+    pub fn parse(head: &str) -> io::Result<Self> {
+        let mut lines = head.split("\r\n");
+        let mut request_line = lines.next().unwrap_or("").split(' ');
+        let (method, target) = match (request_line.next(), request_line.next()) {
+            (Some(method), Some(target)) => (method.to_string(), target.to_string()),
+            _ => return Err(invalid("malformed request line")),
+        };
+
+        let headers = lines
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, value)| (name.trim().to_ascii_lowercase(), value.trim().to_string()))
+            .collect();
+
+        Ok(Self { method, target, headers })
+    }
+
+    /// Value of the header `name`, in lowercase
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(header, _)| header == name).map(|(_, value)| value.as_str())
+    }
+
+    /// Reads the body the Content-Length header announces, of up to `limit` bytes
+    fn read_body(&self, stream: &mut TcpStream, limit: usize) -> io::Result<Vec<u8>> {
+        let length = self.header("content-length").unwrap_or("0").parse::<usize>().map_err(|_| invalid("bad Content-Length"))?;
+        if length > limit {
+            return Err(invalid("body too large"));
+        }
+        let mut body = vec![0u8; length];
+        stream.read_exact(&mut body)?;
+        Ok(body)
+    }
+
+    pub fn path(&self) -> &str {
+        self.target.split('?').next().unwrap_or("")
+    }
+
+    /// Value of the query parameter `name`, percent-decoded
+    pub fn param(&self, name: &str) -> Option<String> {
+        let (_, query) = self.target.split_once('?')?;
+        query
+            .split('&')
+            .filter_map(|pair| pair.split_once('=').or(Some((pair, ""))))
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| decode(value))
+    }
+
+    fn is_upgrade(&self) -> bool {
+        self.header("upgrade").is_some_and(|upgrade| upgrade.eq_ignore_ascii_case("websocket"))
+    }
+}
+
+/// Decodes `%XX` escapes and `+` for spaces, escapes that aren't valid are kept as they are
+fn decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes.get(i + 1..i + 3).and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match (bytes[i], escaped) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+                continue;
+            }
+            (b'+', _) => decoded.push(b' '),
+            (byte, _) => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Writes a whole response and closes the connection
+fn respond(stream: &mut TcpStream, status: &str, content_type: &str, body: &[u8]) -> io::Result<()> {
+    stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    )?;
+    stream.write_all(body)?;
+    stream.flush()
+}
+
+/// Serves one browser connection: the page, or the WebSocket the page opens
+/// Players get MJPEG, snapshots, or with WHEP a WebRTC session they start with a POST and end with a DELETE
+fn serve(
+    mut stream: TcpStream,
+    id: ViewerId,
+    events: &mpsc::Sender<(ViewerId, Event)>,
+    webrtc: Option<&Arc<peer::Settings>>,
+    sessions: &Sessions,
+) {
+    let address = match stream.peer_addr() {
+        Ok(address) => address,
+        Err(_) => return,
+    };
+
+    let request = match Request::read(&mut stream) {
+        Ok(request) => request,
+        Err(e) => {
+            println!("Bad web request from {}: {}", address, e);
+            return;
+        }
+    };
+
+    let served = match (request.method.as_str(), request.path()) {
+        ("GET", "/") => respond(&mut stream, "200 OK", "text/html; charset=utf-8", PAGE.as_bytes()),
+        ("GET", "/ws") if request.is_upgrade() => websocket(stream, &request, id, address, events, webrtc),
+        ("GET", "/stream.mjpg") => watch(stream, &request, Mode::Mjpeg, id, address, events, None),
+        ("GET", "/snapshot.jpg") => watch(stream, &request, Mode::Snapshot, id, address, events, None),
+        ("GET", _) => respond(&mut stream, "404 Not Found", "text/plain", b"Not found\n"),
+        ("POST", WHEP) => whep(stream, &request, id, address, events, webrtc, sessions),
+        ("DELETE", path) if path.starts_with(WHEP) => {
+            let session = path.strip_prefix(WHEP).and_then(|rest| rest.strip_prefix('/')).unwrap_or("");
+            let mailbox = sessions.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).remove(session);
+            match mailbox {
+                Some(mailbox) => {
+                    mailbox.close("closed by the player");
+                    respond(&mut stream, "200 OK", "text/plain", b"")
+                }
+                None => respond(&mut stream, "404 Not Found", "text/plain", b"No such WHEP session\n"),
+            }
+        }
+        _ => respond(&mut stream, "405 Method Not Allowed", "text/plain", b"Only GET, and POST and DELETE for WHEP, are supported\n"),
+    };
+
+    if let Err(e) = served {
+        println!("Error serving {} to {}: {}", request.path(), address, e);
+    }
+}
+
+/// Upgrades the connection to a WebSocket, then relays between the browser and the server until either closes it
+fn websocket(
+    mut stream: TcpStream,
+    request: &Request,
+    id: ViewerId,
+    address: SocketAddr,
+    events: &mpsc::Sender<(ViewerId, Event)>,
+    webrtc: Option<&Arc<peer::Settings>>,
+) -> io::Result<()> {
+    let key = match request.header("sec-websocket-key") {
+        Some(key) => key,
+        None => return respond(&mut stream, "400 Bad Request", "text/plain", b"Missing Sec-WebSocket-Key\n"),
+    };
+
+    stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+    write!(
+        stream,
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+        derive_accept_key(key.as_bytes())
+    )?;
+    stream.set_read_timeout(Some(POLL_INTERVAL))?;
+    stream.set_nodelay(true)?;
+
+    let config = WebSocketConfig::default().max_message_size(Some(MAX_MESSAGE)).max_frame_size(Some(MAX_MESSAGE));
+    let mut socket = WebSocket::from_raw_socket(stream, Role::Server, Some(config));
+
+    let mailbox = Arc::new(Mailbox::default());
+    if events.send((id, Event::Opened { address, mailbox: Arc::clone(&mailbox) })).is_err() {
+        return Ok(());
+    }
+
+    let reason = match relay(&mut socket, &mailbox, id, events, webrtc) {
+        Ok(reason) => reason.to_string(),
+        Err(e) => e.to_string(),
+    };
+
+    let _ = events.send((id, Event::Closed { reason }));
+    Ok(())
+}
+
+/// Sends what the server posted and forwards what the browser says, returns why the connection ended
+/// With `webrtc`, a joined page is told it can connect that way: once its offer is answered and the
+/// connection is up, frames go over it rather than the WebSocket
+fn relay(
+    socket: &mut WebSocket<TcpStream>,
+    mailbox: &Mailbox,
+    id: ViewerId,
+    events: &mpsc::Sender<(ViewerId, Event)>,
+    webrtc: Option<&Arc<peer::Settings>>,
+) -> tungstenite::Result<&'static str> {
+    let mut connection: Option<Peer> = None;
+    let mut accepted = false; // Offers are only answered once the server let the page in
+
+    loop {
+        let (replies, frame, closing) = mailbox.take();
+        for reply in replies {
+            let accept = matches!(reply, Reply::Accept { .. });
+            socket.send(Message::text(reply.to_string()))?;
+            if let (true, Some(settings)) = (accept, webrtc) {
+                socket.send(Message::text(format!("webrtc {}", settings.ice_servers())))?;
+            }
+            accepted |= accept;
+        }
+
+        let state = connection.as_mut().map(Peer::poll);
+        if state == Some(peer::State::Failed) {
+            connection = None; // Back to JPEGs over the WebSocket
+        }
+        if let Some(frame) = frame {
+            match connection.as_mut().filter(|_| state == Some(peer::State::Connected)) {
+                Some(connected) => {
+                    if let Err(e) = connected.send(&frame) {
+                        println!("Error sending a frame over WebRTC to viewer {}: {}", id, e);
+                        connection = None;
+                    }
+                }
+                None => socket.send(Message::Binary(frame))?,
+            }
+        }
+        if closing {
+            socket.close(None)?;
+            socket.flush()?;
+            return Ok("closed by the server");
+        }
+
+        match socket.read() {
+            Ok(Message::Text(text)) if text.as_str().starts_with("offer ") => {
+                let (offer, settings) = match (text.as_str().strip_prefix("offer "), webrtc) {
+                    (Some(offer), Some(settings)) if accepted => (offer, settings),
+                    _ => continue,
+                };
+                // IPv4 browsers of a dual-stack listener show up mapped into IPv6, candidates need the IPv4 address
+                let ip = socket.get_ref().local_addr().map_err(tungstenite::Error::Io)?.ip().to_canonical();
+                match Peer::answer(offer, ip, settings) {
+                    Ok((answered, answer)) => {
+                        socket.send(Message::text(format!("answer {}", answer)))?;
+                        connection = Some(answered);
+                    }
+                    Err(e) => println!("Can't answer the WebRTC offer of viewer {}: {}", id, e),
+                }
+            }
+            Ok(Message::Text(text)) => match Event::parse(text.as_str()) {
+                Some(event) => {
+                    if events.send((id, event)).is_err() {
+                        return Ok("server stopped");
+                    }
+                }
+                None => println!("Unexpected web message: {:?}", text.as_str()),
+            },
+            Ok(Message::Close(_)) => return Ok("closed by the viewer"),
+            Ok(_) => {}
+            Err(tungstenite::Error::Io(e)) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Answers the SDP offer a WHEP player posts (RFC 9725), and sends it the stream over WebRTC like to pages
+/// It joins like MJPEG players do, with the same query parameters
+fn whep(
+    mut stream: TcpStream,
+    request: &Request,
+    id: ViewerId,
+    address: SocketAddr,
+    events: &mpsc::Sender<(ViewerId, Event)>,
+    webrtc: Option<&Arc<peer::Settings>>,
+    sessions: &Sessions,
+) -> io::Result<()> {
+    // Read first, closing with some of it unread would reset the connection before the player gets the response
+    let body = match request.read_body(&mut stream, MAX_MESSAGE) {
+        Ok(body) => body,
+        Err(e) => return respond(&mut stream, "400 Bad Request", "text/plain", format!("{}\n", e).as_bytes()),
+    };
+    let settings = match webrtc {
+        Some(settings) => settings,
+        None => return respond(&mut stream, "404 Not Found", "text/plain", b"WebRTC is off, start the server with --webrtc\n"),
+    };
+    if settings.full() {
+        let reason = format!("Already {} WebRTC viewers, try again later\n", peer::MAX_WEBRTC_VIEWERS);
+        return respond(&mut stream, "503 Service Unavailable", "text/plain", reason.as_bytes());
+    }
+    if !request.header("content-type").is_some_and(|kind| kind.eq_ignore_ascii_case("application/sdp")) {
+        return respond(&mut stream, "415 Unsupported Media Type", "text/plain", b"The offer must be application/sdp\n");
+    }
+    let offer = match String::from_utf8(body) {
+        Ok(offer) => offer,
+        Err(_) => return respond(&mut stream, "400 Bad Request", "text/plain", b"The offer is not UTF-8\n"),
+    };
+
+    watch(stream, request, Mode::Webrtc, id, address, events, Some(Whep { offer, settings, sessions }))
+}
+
+/// Serves players that can't run the page: every frame as MJPEG, the latest one as a snapshot, or with `whep`
+/// AV1 over WebRTC. Joins like the page does, answering the challenge with the `password` or `token` query
+/// parameter. `stream`, `rendition` and `max_width`/`max_height` pick what is watched like `connect` options do
+fn watch(
+    mut stream: TcpStream,
+    request: &Request,
+    mode: Mode,
+    id: ViewerId,
+    address: SocketAddr,
+    events: &mpsc::Sender<(ViewerId, Event)>,
+    whep: Option<Whep>,
+) -> io::Result<()> {
+    let secret = match (request.param("password"), request.param("token")) {
+        (_, Some(token)) => match Secret::from_token(&token) {
+            Ok(secret) => Some(secret),
+            Err(reason) => return respond(&mut stream, "400 Bad Request", "text/plain", reason.as_bytes()),
+        },
+        (Some(password), None) => Some(Secret::Password(password.into_bytes())),
+        (None, None) => None,
+    };
+    let size = |name: &str| request.param(name).and_then(|value| value.parse().ok()).unwrap_or(u16::MAX);
+
+    let mailbox = Arc::new(Mailbox::default());
+    let hello = Event::Hello {
+        mode,
+        stream: request.param("stream").unwrap_or_default(),
+        max_width: size("max_width"),
+        max_height: size("max_height"),
+        rendition: request.param("rendition").and_then(|value| value.parse().ok()),
+    };
+    if events.send((id, Event::Opened { address, mailbox: Arc::clone(&mailbox) })).is_err() || events.send((id, hello)).is_err() {
+        return Ok(());
+    }
+
+    stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+    stream.set_nodelay(true)?;
+    let reason = match play(&mut stream, &mailbox, mode, secret, id, events, whep.as_ref()) {
+        Ok(reason) => reason,
+        Err(e) => e.to_string(),
+    };
+    if let Some(whep) = whep {
+        whep.sessions.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).retain(|_, open| !Arc::ptr_eq(open, &mailbox));
+    }
+
+    let _ = events.send((id, Event::Closed { reason }));
+    Ok(())
+}
+
+/// Writes the frames the server posts until either side is done, returns why it ended
+/// A WHEP player is answered once let in, its frames then go over WebRTC and the request is over
+fn play(
+    stream: &mut TcpStream,
+    mailbox: &Arc<Mailbox>,
+    mode: Mode,
+    secret: Option<Secret>,
+    id: ViewerId,
+    events: &mpsc::Sender<(ViewerId, Event)>,
+    whep: Option<&Whep>,
+) -> io::Result<String> {
+    let mut accepted = false;
+    let mut deadline = Instant::now() + JOIN_TIMEOUT;
+    let mut connection: Option<Peer> = None;
+
+    loop {
+        // The WebRTC connection is polled as often as a page's is
+        let timeout = match connection {
+            Some(_) => POLL_INTERVAL,
+            None => POLL_INTERVAL.max(deadline.saturating_duration_since(Instant::now())),
+        };
+        let (replies, frame, closing) = mailbox.wait(timeout);
+
+        for reply in replies {
+            match reply {
+                Reply::Challenge(nonce) => match &secret {
+                    Some(secret) => {
+                        let _ = events.send((id, Event::Answer(secret.respond(&nonce))));
+                    }
+                    None => {
+                        let body = b"This stream needs a password or an invite token, add ?password= or ?token=\n";
+                        respond(stream, "401 Unauthorized", "text/plain", body)?;
+                        return Ok(String::from("no password or token"));
+                    }
+                },
+                Reply::Accept { .. } => {
+                    accepted = true;
+                    deadline = Instant::now() + JOIN_TIMEOUT;
+                    if mode == Mode::Mjpeg {
+                        write!(
+                            stream,
+                            "HTTP/1.1 200 OK\r\nContent-Type: multipart/x-mixed-replace; boundary={}\r\n\
+                             Cache-Control: no-store\r\nConnection: close\r\n\r\n",
+                            BOUNDARY
+                        )?;
+                    }
+                    if let Some(whep) = whep {
+                        // IPv4 players of a dual-stack listener show up mapped into IPv6, candidates need the IPv4 address
+                        let ip = stream.local_addr()?.ip().to_canonical();
+                        let (answered, answer) = match Peer::answer(&whep.offer, ip, whep.settings) {
+                            Ok(answered) => answered,
+                            Err(reason) => {
+                                respond(stream, "400 Bad Request", "text/plain", format!("{}\n", reason).as_bytes())?;
+                                return Ok(reason);
+                            }
+                        };
+                        let session = auth::to_hex(&auth::nonce().map_err(|e| io::Error::other(e.to_string()))?);
+                        write!(
+                            stream,
+                            "HTTP/1.1 201 Created\r\nContent-Type: application/sdp\r\nLocation: {}/{}\r\nContent-Length: {}\r\n\
+                             Cache-Control: no-store\r\nConnection: close\r\n\r\n{}",
+                            WHEP,
+                            session,
+                            answer.len(),
+                            answer
+                        )?;
+                        stream.flush()?;
+                        let _ = stream.shutdown(Shutdown::Both);
+                        whep.sessions.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).insert(session, Arc::clone(mailbox));
+                        connection = Some(answered);
+                    }
+                }
+                Reply::Renditions { .. } => {}
+                Reply::Reject(reason) => {
+                    respond(stream, "403 Forbidden", "text/plain", format!("{}\n", reason).as_bytes())?;
+                    return Ok(reason);
+                }
+                Reply::Goodbye(reason) => {
+                    if connection.is_some() {
+                        return Ok(reason); // The player's request was answered already
+                    }
+                    if mode == Mode::Mjpeg && accepted {
+                        write!(stream, "--{}--\r\n", BOUNDARY)?;
+                    } else {
+                        respond(stream, "503 Service Unavailable", "text/plain", format!("{}\n", reason).as_bytes())?;
+                    }
+                    return Ok(String::from("closed by the server"));
+                }
+            }
+        }
+
+        if connection.as_mut().map(Peer::poll) == Some(peer::State::Failed) {
+            return Ok(String::from("WebRTC connection over"));
+        }
+
+        match (frame, mode) {
+            (Some(frame), Mode::Webrtc) => {
+                if let Some(connected) = connection.as_mut() {
+                    if let Err(e) = connected.send(&frame) {
+                        return Ok(format!("Error sending a frame over WebRTC: {}", e));
+                    }
+                    deadline = Instant::now() + JOIN_TIMEOUT;
+                }
+            }
+            (Some(frame), Mode::Snapshot) if accepted => {
+                respond(stream, "200 OK", "image/jpeg", &frame)?;
+                return Ok(String::from("snapshot sent"));
+            }
+            (Some(frame), Mode::Mjpeg) if accepted => {
+                write!(stream, "--{}\r\nContent-Type: image/jpeg\r\nContent-Length: {}\r\n\r\n", BOUNDARY, frame.len())?;
+                stream.write_all(&frame)?;
+                stream.write_all(b"\r\n")?;
+                stream.flush()?;
+                deadline = Instant::now() + JOIN_TIMEOUT;
+            }
+            _ => {}
+        }
+
+        if closing {
+            return Ok(String::from("closed by the server"));
+        }
+        if Instant::now() >= deadline {
+            if !accepted || mode == Mode::Snapshot {
+                respond(stream, "504 Gateway Timeout", "text/plain", b"No frame in time\n")?;
+                return Ok(String::from("timed out"));
+            }
+            // A stream that doesn't change can go quiet, the player is still there
+            deadline = Instant::now() + JOIN_TIMEOUT;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::Credentials;
+    use hmac::{Hmac, Mac};
+    use sha2::Sha256;
+    use crate::peer::tests::{is_keyframe, jpeg, sequence_header, Browser, SequenceHeader};
+    use std::net::Ipv4Addr;
+    use std::sync::atomic::AtomicBool;
+    use std::thread::JoinHandle;
+
+    const LOCALHOST: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+    /// Plays the server: lets every viewer in, once it answers the challenge of `password` if there is one, and
+    /// posts it `jpeg` at 30 frames a second if there is one. Once stopped it closes the viewers left, and
+    /// returns what the viewers said when they are all gone
+    fn server(mut web: Web, jpeg: Option<Vec<u8>>, password: Option<&str>) -> (Arc<AtomicBool>, JoinHandle<Vec<String>>) {
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopping = Arc::clone(&stop);
+        let credentials = Credentials::new(password.map(String::from), Vec::new());
+        let thread = thread::spawn(move || {
+            let (mut mailboxes, mut nonces, mut said) = (HashMap::new(), HashMap::new(), Vec::new());
+            let mut accepted = std::collections::HashSet::new(); // Posted frames, like the server only posts to viewers let in
+            let mut deadline = None;
+            while deadline.is_none_or(|deadline| !mailboxes.is_empty() && Instant::now() < deadline) {
+                while let Some((id, event)) = web.next() {
+                    match event {
+                        Event::Opened { mailbox, .. } => {
+                            mailboxes.insert(id, mailbox);
+                        }
+                        Event::Hello { mode, stream, .. } => {
+                            said.push(format!("hello {} {}", mode, stream));
+                            if credentials.required() {
+                                let nonce = auth::nonce().unwrap();
+                                nonces.insert(id, (nonce, stream));
+                                mailboxes[&id].reply(Reply::Challenge(nonce));
+                            } else {
+                                mailboxes[&id].reply(Reply::Accept { stream });
+                                accepted.insert(id);
+                            }
+                        }
+                        Event::Answer(credential) => {
+                            let (nonce, stream) = nonces.remove(&id).expect("Challenged");
+                            match credentials.verify(&nonce, &credential, 0) {
+                                Ok(_) => {
+                                    mailboxes[&id].reply(Reply::Accept { stream });
+                                    accepted.insert(id);
+                                }
+                                Err(reason) => mailboxes[&id].reply(Reply::Reject(reason.to_string())),
+                            }
+                        }
+                        Event::Closed { reason } => {
+                            said.push(format!("closed {}", reason));
+                            mailboxes.remove(&id);
+                        }
+                        _ => {}
+                    }
+                }
+                for (_, mailbox) in mailboxes.iter().filter(|(id, _)| jpeg.is_some() && accepted.contains(*id)) {
+                    mailbox.frame(Frame::from(jpeg.clone().unwrap()));
+                }
+                if deadline.is_none() && stopping.load(Ordering::SeqCst) {
+                    deadline = Some(Instant::now() + Duration::from_secs(5));
+                    mailboxes.values().for_each(|mailbox| mailbox.close("server stopped"));
+                }
+                thread::sleep(Duration::from_millis(33));
+            }
+            said
+        });
+        (stop, thread)
+    }
+
+    /// Sends a request and reads the response, up to the server closing the connection
+    fn request(address: SocketAddr, head: &str, body: &str) -> String {
+        send(address, &format!("{}\r\nContent-Length: {}\r\n\r\n{}", head, body.len(), body))
+    }
+
+    /// Gets `target`, returns the head of the response and its body
+    fn get(address: SocketAddr, target: &str) -> (String, Vec<u8>) {
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(20))).unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\n\r\n", target).unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).unwrap();
+        let end = response.windows(4).position(|window| window == b"\r\n\r\n").expect("a whole head");
+        (String::from_utf8(response[..end].to_vec()).unwrap(), response[end + 4..].to_vec())
+    }
+
+    fn send(address: SocketAddr, request: &str) -> String {
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(20))).unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn page_messages_are_parsed() {
+        let hello = Event::parse("hello 1280 720 second screen");
+        assert!(matches!(hello, Some(Event::Hello { mode: Mode::Page, max_width: 1280, max_height: 720, ref stream, rendition: None })
+            if stream == "second screen"));
+        assert!(matches!(Event::parse("hello 1280 720"), Some(Event::Hello { ref stream, .. }) if stream.is_empty()));
+        let mac = "ab".repeat(32);
+        let password = Event::parse(&format!("password {}", mac));
+        assert!(matches!(password, Some(Event::Answer(Credential::Password { mac })) if mac == [0xab; 32]));
+        let invite = Event::parse(&format!("invite 0102030405060708 {}", mac));
+        assert!(matches!(invite, Some(Event::Answer(Credential::Invite { id: [1, 2, 3, 4, 5, 6, 7, 8], .. }))));
+        assert!(matches!(Event::parse("subscribe 2"), Some(Event::Subscribe { rendition: Some(2) })));
+        assert!(matches!(Event::parse("subscribe auto"), Some(Event::Subscribe { rendition: None })));
+
+        for malformed in ["", "hello", "hello 1280", "hello wide 720", "hello 70000 720", "password", "password abcd",
+            "invite 0102030405060708", &format!("invite 01020304 {}", mac), "subscribe", "subscribe 300", "goodbye"]
+        {
+            assert!(Event::parse(malformed).is_none(), "{:?} is taken", malformed);
+        }
+    }
+
+    #[test]
+    fn request_head_and_query_parameters() {
+        let head = "GET /stream.mjpg?stream=second+screen&password=a%26b%3D&flag HTTP/1.1\r\n\
+                    Host: example\r\nContent-Type:  application/sdp \r\nX-Odd\r\n\r\n";
+        let request = Request::parse(head).unwrap();
+        assert_eq!((request.method.as_str(), request.path()), ("GET", "/stream.mjpg"));
+        assert_eq!(request.header("content-type"), Some("application/sdp"));
+        assert_eq!(request.header("host"), Some("example"));
+        assert_eq!(request.header("x-odd"), None);
+        assert_eq!(request.param("stream").as_deref(), Some("second screen"));
+        assert_eq!(request.param("password").as_deref(), Some("a&b="));
+        assert_eq!(request.param("flag").as_deref(), Some(""));
+        assert_eq!(request.param("token"), None);
+        assert_eq!(Request::parse("GET /\r\n\r\n").unwrap().param("stream"), None);
+
+        assert!(Request::parse("").is_err());
+        assert!(Request::parse("GET\r\n\r\n").is_err());
+    }
+
+    #[test]
+    fn percent_decoding() {
+        assert_eq!(decode("a%20b+c"), "a b c");
+        assert_eq!(decode("%e2%82%ac%41"), "\u{20ac}A");
+        // Escapes that aren't valid are kept
+        assert_eq!(decode("100%"), "100%");
+        assert_eq!(decode("%zz%4"), "%zz%4");
+        assert_eq!(decode("%ff"), "\u{fffd}");
+    }
+
+    #[test]
+    fn page_joins_over_the_websocket_with_its_password() {
+        let web = Web::new();
+        let address = web.serve_http(Some(LOCALHOST), 0, None).unwrap();
+        let (stop, server) = server(web, Some(jpeg(64, 48)), Some("hunter2"));
+
+        let (mut socket, _) = tungstenite::connect(format!("ws://{}/ws", address)).unwrap();
+        socket.send(Message::text("hello 1920 1080 screen")).unwrap();
+        let challenge = socket.read().unwrap().into_text().unwrap();
+        let nonce = challenge.as_str().strip_prefix("challenge ").unwrap_or_else(|| panic!("{}", challenge));
+        // The answer of the page's script, an HMAC-SHA256 of the challenge keyed with the password
+        let mut mac = Hmac::<Sha256>::new_from_slice(b"hunter2").unwrap();
+        mac.update(&auth::from_hex::<NONCE_SIZE>(nonce).unwrap());
+        socket.send(Message::text(format!("password {}", auth::to_hex(&mac.finalize().into_bytes())))).unwrap();
+
+        assert_eq!(socket.read().unwrap().into_text().unwrap().as_str(), "accept screen");
+        let frame = loop {
+            match socket.read().unwrap() {
+                Message::Binary(bytes) => break bytes,
+                Message::Text(text) => panic!("unexpected {}", text),
+                _ => {}
+            }
+        };
+        assert_eq!(frame, jpeg(64, 48));
+
+        socket.close(None).unwrap();
+        while socket.read().is_ok() {}
+        stop.store(true, Ordering::SeqCst);
+        assert_eq!(server.join().unwrap(), ["hello browser screen", "closed closed by the viewer"]);
+    }
+
+    #[test]
+    fn whep_player_watches_av1_until_it_deletes_its_session() {
+        let web = Web::new();
+        let settings = Arc::new(peer::Settings::new(Vec::new()).unwrap());
+        let address = web.serve_http(Some(LOCALHOST), 0, Some(settings)).unwrap();
+        let (stop, server) = server(web, Some(jpeg(64, 48)), None);
+
+        let mut browser = Browser::new();
+        let response = request(address, "POST /whep?stream=screen HTTP/1.1\r\nContent-Type: application/sdp", &browser.offer());
+        let (head, answer) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 201 Created"), "{}", head);
+        assert!(head.contains("Content-Type: application/sdp"));
+        let location = head.lines().find_map(|line| line.strip_prefix("Location: ")).unwrap();
+        assert!(location.starts_with("/whep/") && location.len() == 6 + 2 * NONCE_SIZE, "{}", location);
+
+        browser.connect(answer);
+        let unit = browser.unit();
+        assert_eq!(sequence_header(&unit), Some(SequenceHeader { profile: 0, max_width: 64, max_height: 48 }));
+        assert!(is_keyframe(&unit));
+
+        let deleted = request(address, &format!("DELETE {} HTTP/1.1", location), "");
+        assert!(deleted.starts_with("HTTP/1.1 200 OK"), "{}", deleted);
+        let again = request(address, &format!("DELETE {} HTTP/1.1", location), "");
+        assert!(again.starts_with("HTTP/1.1 404 Not Found"), "{}", again);
+
+        stop.store(true, Ordering::SeqCst);
+        assert_eq!(server.join().unwrap(), ["hello whep screen", "closed closed by the player"]);
+    }
+
+    #[test]
+    fn whep_needs_webrtc_and_an_sdp_offer() {
+        let off = Web::new().serve_http(Some(LOCALHOST), 0, None).unwrap();
+        let response = request(off, "POST /whep HTTP/1.1\r\nContent-Type: application/sdp", &Browser::new().offer());
+        assert!(response.starts_with("HTTP/1.1 404 Not Found"), "{}", response);
+
+        let settings = Arc::new(peer::Settings::new(Vec::new()).unwrap());
+        let on = Web::new().serve_http(Some(LOCALHOST), 0, Some(settings)).unwrap();
+        let response = request(on, "POST /whep HTTP/1.1\r\nContent-Type: text/plain", "offer");
+        assert!(response.starts_with("HTTP/1.1 415 Unsupported Media Type"), "{}", response);
+        let too_large = format!("POST /whep HTTP/1.1\r\nContent-Type: application/sdp\r\nContent-Length: {}\r\n\r\n", MAX_MESSAGE + 1);
+        let response = send(on, &too_large);
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request") && response.ends_with("body too large\n"), "{}", response);
+
+        // Trickled candidates are not taken, the answer has them all
+        let response = request(on, "PATCH /whep/1234 HTTP/1.1\r\nContent-Type: application/trickle-ice-sdpfrag", "");
+        assert!(response.starts_with("HTTP/1.1 405 Method Not Allowed"), "{}", response);
+    }
+
+    #[test]
+    fn snapshot_is_a_single_jpeg() {
+        let web = Web::new();
+        let address = web.serve_http(Some(LOCALHOST), 0, None).unwrap();
+        let frame = jpeg(64, 48);
+        let (stop, server) = server(web, Some(frame.clone()), None);
+
+        let (head, body) = get(address, "/snapshot.jpg?stream=screen&max_width=64");
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\nContent-Type: image/jpeg\r\n"), "{}", head);
+        assert!(head.contains(&format!("Content-Length: {}", frame.len())), "{}", head);
+        assert_eq!(body, frame);
+
+        stop.store(true, Ordering::SeqCst);
+        assert_eq!(server.join().unwrap(), ["hello snapshot screen", "closed snapshot sent"]);
+    }
+
+    #[test]
+    fn mjpeg_is_a_part_per_frame_and_ends_with_the_closing_boundary() {
+        let web = Web::new();
+        let address = web.serve_http(Some(LOCALHOST), 0, None).unwrap();
+        let frame = jpeg(64, 48);
+        let (stop, server) = server(web, Some(frame.clone()), None);
+
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(20))).unwrap();
+        stream.write_all(b"GET /stream.mjpg HTTP/1.1\r\n\r\n").unwrap();
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            let mut byte = [0u8];
+            stream.read_exact(&mut byte).unwrap();
+            head.push(byte[0]);
+        }
+        let head = String::from_utf8(head).unwrap();
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\nContent-Type: multipart/x-mixed-replace; boundary=frame\r\n"), "{}", head);
+
+        // A few frames, then the server goes away
+        thread::sleep(Duration::from_millis(200));
+        stop.store(true, Ordering::SeqCst);
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).unwrap();
+        let mut rest = &response[..];
+        let mut parts = 0;
+        while let Some(part) = rest.strip_prefix(b"--frame\r\n") {
+            let end = part.windows(4).position(|window| window == b"\r\n\r\n").expect("a part head");
+            let head = std::str::from_utf8(&part[..end]).unwrap();
+            assert_eq!(head, format!("Content-Type: image/jpeg\r\nContent-Length: {}", frame.len()));
+            let body = &part[end + 4..];
+            assert_eq!(&body[..frame.len()], &frame[..]);
+            rest = body[frame.len()..].strip_prefix(b"\r\n").expect("a line break after each part");
+            parts += 1;
+        }
+        assert!(parts > 1, "{} parts", parts);
+        assert_eq!(rest, b"--frame--\r\n");
+        assert_eq!(server.join().unwrap(), ["hello mjpeg ", "closed closed by the server"]);
+    }
+
+    #[test]
+    fn players_answer_the_challenge_with_the_password_or_token_parameter() {
+        let web = Web::new();
+        let address = web.serve_http(Some(LOCALHOST), 0, None).unwrap();
+        let frame = jpeg(64, 48);
+        let (stop, server) = server(web, Some(frame.clone()), Some("hunter2"));
+
+        let (head, body) = get(address, "/stream.mjpg");
+        assert!(head.starts_with("HTTP/1.1 401 Unauthorized"), "{}", head);
+        assert!(String::from_utf8(body).unwrap().contains("?password= or ?token="));
+        let (head, _) = get(address, "/snapshot.jpg?password=hunter3");
+        assert!(head.starts_with("HTTP/1.1 403 Forbidden"), "{}", head);
+        let (head, _) = get(address, "/snapshot.jpg?token=nonsense");
+        assert!(head.starts_with("HTTP/1.1 400 Bad Request"), "{}", head);
+        let (head, body) = get(address, "/snapshot.jpg?password=hunter%32");
+        assert!(head.starts_with("HTTP/1.1 200 OK"), "{}", head);
+        assert_eq!(body, frame);
+
+        stop.store(true, Ordering::SeqCst);
+        let said = server.join().unwrap();
+        assert_eq!(said.iter().filter(|said| said.starts_with("closed")).collect::<Vec<_>>(), [
+            "closed no password or token",
+            "closed Wrong password",
+            "closed snapshot sent"
+        ]);
+    }
+
+    #[test]
+    fn snapshot_without_frames_times_out() {
+        let web = Web::new();
+        let address = web.serve_http(Some(LOCALHOST), 0, None).unwrap();
+        let (stop, server) = server(web, None, None);
+
+        let started = Instant::now();
+        let (head, _) = get(address, "/snapshot.jpg");
+        assert!(head.starts_with("HTTP/1.1 504 Gateway Timeout"), "{}", head);
+        assert!(started.elapsed() >= JOIN_TIMEOUT);
+
+        stop.store(true, Ordering::SeqCst);
+        assert_eq!(server.join().unwrap(), ["hello snapshot ", "closed timed out"]);
+    }
+}